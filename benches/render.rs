@@ -0,0 +1,101 @@
+//! Frame rendering benchmarks: a 300-room channel list and 5000 and 50000
+//! message timelines drawn into a `TestBackend`, separately and side by
+//! side.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use ratatui::backend::TestBackend;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::Terminal;
+
+use marty::matrix::{RoomInfo, RoomListState};
+use marty::ui::{render_channels, render_messages_area, App, MessageItem};
+
+const ROOMS: usize = 300;
+const WIDTH: u16 = 160;
+const HEIGHT: u16 = 50;
+
+fn synthetic_app(messages: usize) -> App {
+    let mut app = App::new();
+    for idx in 0..ROOMS {
+        app.rooms.push(RoomInfo {
+            room_id: format!("!room{}:example.org", idx),
+            name: format!("room {}", idx % 120),
+            state: RoomListState::Joined,
+            inviter: None,
+            alias: None,
+            direct_user: (idx % 3 == 0).then(|| format!("@user{}:example.org", idx % 40)),
+            topic: Some("a topic".to_string()),
+            send_restricted: false,
+            can_invite: true,
+            can_set_topic: true,
+            can_redact_others: true,
+            member_count: 2,
+        });
+        app.unread_counts
+            .insert(format!("!room{}:example.org", idx), idx % 4);
+    }
+    let items = (0..messages)
+        .map(|idx| MessageItem::Message {
+            time: "12:00".to_string(),
+            timestamp: 1_700_000_000_000 + idx as i64 * 1_000,
+            sender_id: format!("@user{}:example.org", idx % 7),
+            name: format!("user{} 日本", idx % 7),
+            text: format!(
+                "message {} with some words in it 🎉 and more words to wrap around",
+                idx
+            ),
+            event_id: Some(format!("$event{}", idx)),
+            reply_to: None,
+            edited: false,
+        })
+        .collect();
+    let room_id = app.rooms[0].room_id.clone();
+    app.messages_by_room.insert(room_id, items);
+    app
+}
+
+fn bench_render(c: &mut Criterion) {
+    let mut terminal = Terminal::new(TestBackend::new(WIDTH, HEIGHT)).expect("terminal");
+    let mut group = c.benchmark_group("render");
+    let mut app = synthetic_app(5_000);
+    group.bench_function(BenchmarkId::new("channel_list", ROOMS), |b| {
+        b.iter(|| {
+            terminal
+                .draw(|f| render_channels(f, f.size(), &app))
+                .expect("draw");
+        });
+    });
+    group.bench_function(BenchmarkId::new("timeline", 5_000), |b| {
+        b.iter(|| {
+            terminal
+                .draw(|f| render_messages_area(f, f.size(), &mut app))
+                .expect("draw");
+        });
+    });
+    let mut large = synthetic_app(50_000);
+    group.bench_function(BenchmarkId::new("timeline", 50_000), |b| {
+        b.iter(|| {
+            terminal
+                .draw(|f| render_messages_area(f, f.size(), &mut large))
+                .expect("draw");
+        });
+    });
+    group.bench_function(BenchmarkId::new("frame", 5_000), |b| {
+        b.iter(|| {
+            terminal
+                .draw(|f| {
+                    let chunks = Layout::default()
+                        .direction(Direction::Horizontal)
+                        .constraints([Constraint::Length(app.channels_width()), Constraint::Min(1)])
+                        .split(f.size());
+                    render_channels(f, chunks[0], &app);
+                    render_messages_area(f, chunks[1], &mut app);
+                })
+                .expect("draw");
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_render);
+criterion_main!(benches);
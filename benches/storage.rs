@@ -0,0 +1,186 @@
+//! Storage layer benchmarks over synthetic rooms.
+//!
+//! Runs 10k and 100k message datasets by default; set `MARTY_BENCH_LARGE=1`
+//! to include the 1M message dataset.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+
+use marty::storage::{
+    append_message, ensure_room_dir, load_all_messages, room_log_path, write_encrypted,
+    SearchIndex, StoredMessage,
+};
+
+const PASSPHRASE: &str = "bench-passphrase";
+const ROOM_ID: &str = "!bench:example.org";
+/// Word prefixes, as typed into `/search` before the words are complete.
+const PREFIX_QUERIES: [&str; 4] = ["out", "outage roll", "dep rev", "coffee lunch backup"];
+const WORDS: [&str; 12] = [
+    "deploy", "matrix", "release", "coffee", "server", "ticket", "review", "merge", "outage",
+    "lunch", "backup", "rollout",
+];
+
+fn dataset_sizes() -> Vec<usize> {
+    let mut sizes = vec![10_000, 100_000];
+    if std::env::var_os("MARTY_BENCH_LARGE").is_some() {
+        sizes.push(1_000_000);
+    }
+    sizes
+}
+
+fn synthetic_message(idx: usize) -> StoredMessage {
+    let body = (0..8)
+        .map(|n| WORDS[(idx * 7 + n * 3) % WORDS.len()])
+        .collect::<Vec<_>>()
+        .join(" ");
+    StoredMessage {
+        timestamp: 1_700_000_000_000 + idx as i64 * 1_000,
+        sender: format!("@user{}:example.org", idx % 25),
+        body,
+        event_id: Some(format!("$event{}", idx)),
+        reply_to: None,
+        thread_root: None,
+        attachment_path: None,
+        attachment_name: None,
+        attachment_kind: None,
+        edited: false,
+        redacted: false,
+        reactions: Vec::new(),
+        poll: None,
+        votes: Vec::new(),
+    }
+}
+
+/// Writes `count` messages spread over `rooms` rooms in one go per room,
+/// bypassing `append_message` so large datasets build quickly.
+fn build_dataset(base: &Path, count: usize, rooms: usize) {
+    for room in 0..rooms {
+        let room_id = format!("!room{}:example.org", room);
+        ensure_room_dir(base, &room_id).expect("room dir");
+        let records: Vec<StoredMessage> = (0..count)
+            .filter(|idx| idx % rooms == room)
+            .map(synthetic_message)
+            .collect();
+        let data = serde_json::to_vec(&records).expect("serialize");
+        write_encrypted(&room_log_path(base, &room_id), PASSPHRASE, &data).expect("write");
+    }
+}
+
+fn bench_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("marty-bench-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("bench dir");
+    dir
+}
+
+fn bench_append(c: &mut Criterion) {
+    let mut group = c.benchmark_group("append_message");
+    group.sample_size(10);
+    for size in dataset_sizes() {
+        let dir = bench_dir(&format!("append-{}", size));
+        ensure_room_dir(&dir, ROOM_ID).expect("room dir");
+        let records: Vec<StoredMessage> = (0..size).map(synthetic_message).collect();
+        let data = serde_json::to_vec(&records).expect("serialize");
+        write_encrypted(&room_log_path(&dir, ROOM_ID), PASSPHRASE, &data).expect("write");
+        let mut next = size;
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+            b.iter(|| {
+                append_message(&dir, PASSPHRASE, ROOM_ID, synthetic_message(next)).expect("append");
+                next += 1;
+            })
+        });
+        let _ = fs::remove_dir_all(&dir);
+    }
+    group.finish();
+}
+
+fn bench_load_all(c: &mut Criterion) {
+    let mut group = c.benchmark_group("load_all_messages");
+    group.sample_size(10);
+    for size in dataset_sizes() {
+        let dir = bench_dir(&format!("load-{}", size));
+        build_dataset(&dir, size, 20);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+            b.iter(|| load_all_messages(&dir, PASSPHRASE).expect("load"))
+        });
+        let _ = fs::remove_dir_all(&dir);
+    }
+    group.finish();
+}
+
+/// Search without the index: decrypt every room log and scan it.
+fn bench_search(c: &mut Criterion) {
+    let mut group = c.benchmark_group("search");
+    group.sample_size(10);
+    for size in dataset_sizes() {
+        let dir = bench_dir(&format!("search-{}", size));
+        build_dataset(&dir, size, 20);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+            b.iter(|| {
+                let loaded = load_all_messages(&dir, PASSPHRASE).expect("load");
+                loaded
+                    .rooms
+                    .iter()
+                    .flat_map(|(_, records)| records.iter())
+                    .filter(|msg| msg.body.to_lowercase().contains("outage rollout"))
+                    .count()
+            })
+        });
+        let _ = fs::remove_dir_all(&dir);
+    }
+    group.finish();
+}
+
+/// `SearchIndex::search` over an index of the whole dataset, per query.
+fn bench_search_index(c: &mut Criterion) {
+    let mut group = c.benchmark_group("search_index");
+    for size in dataset_sizes() {
+        let dir = bench_dir(&format!("search-index-{}", size));
+        build_dataset(&dir, size, 20);
+        let loaded = load_all_messages(&dir, PASSPHRASE).expect("load");
+        let mut index = SearchIndex::default();
+        index.catch_up(&loaded.rooms);
+        for query in PREFIX_QUERIES {
+            let id = BenchmarkId::new(query, size);
+            group.bench_with_input(id, &query, |b, query| b.iter(|| index.search(query).len()));
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+    group.finish();
+}
+
+/// Building the index from scratch with `catch_up`, as on the first start
+/// with an existing history.
+fn bench_index_catch_up(c: &mut Criterion) {
+    let mut group = c.benchmark_group("index_catch_up");
+    group.sample_size(10);
+    for size in dataset_sizes() {
+        let dir = bench_dir(&format!("catch-up-{}", size));
+        build_dataset(&dir, size, 20);
+        let loaded = load_all_messages(&dir, PASSPHRASE).expect("load");
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+            b.iter_batched(
+                SearchIndex::default,
+                |mut index| {
+                    index.catch_up(&loaded.rooms);
+                    index
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        let _ = fs::remove_dir_all(&dir);
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_append,
+    bench_load_all,
+    bench_search,
+    bench_search_index,
+    bench_index_catch_up
+);
+criterion_main!(benches);
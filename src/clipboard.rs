@@ -0,0 +1,107 @@
+use std::env;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+/// Where copied text goes. `auto` probes the backends in the order listed
+/// here, starting with `wl_copy` under Wayland.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClipboardProvider {
+    #[default]
+    Auto,
+    /// The `arboard` crate (X11, macOS, Windows).
+    Arboard,
+    WlCopy,
+    Xclip,
+    Xsel,
+    Pbcopy,
+    /// OSC 52 escape sequence; lets the terminal set the clipboard, which
+    /// also works over SSH.
+    Osc52,
+}
+
+const AUTO_ORDER: [ClipboardProvider; 6] = [
+    ClipboardProvider::Arboard,
+    ClipboardProvider::WlCopy,
+    ClipboardProvider::Xclip,
+    ClipboardProvider::Xsel,
+    ClipboardProvider::Pbcopy,
+    ClipboardProvider::Osc52,
+];
+
+impl ClipboardProvider {
+    pub fn name(self) -> &'static str {
+        match self {
+            ClipboardProvider::Auto => "auto",
+            ClipboardProvider::Arboard => "arboard",
+            ClipboardProvider::WlCopy => "wl-copy",
+            ClipboardProvider::Xclip => "xclip",
+            ClipboardProvider::Xsel => "xsel",
+            ClipboardProvider::Pbcopy => "pbcopy",
+            ClipboardProvider::Osc52 => "OSC 52",
+        }
+    }
+}
+
+/// Copies `text` with `provider`, or with the first working backend for
+/// `auto`, and returns the backend that took it.
+pub fn copy(provider: ClipboardProvider, text: &str) -> io::Result<ClipboardProvider> {
+    if provider != ClipboardProvider::Auto {
+        return copy_with(provider, text).map(|()| provider);
+    }
+    let wayland = env::var_os("WAYLAND_DISPLAY").is_some();
+    let mut order = AUTO_ORDER.to_vec();
+    if wayland {
+        // arboard talks to XWayland at best; prefer the native tool.
+        order.retain(|p| *p != ClipboardProvider::WlCopy);
+        order.insert(0, ClipboardProvider::WlCopy);
+    }
+    let mut last_err = io::Error::other("no clipboard backend available");
+    for candidate in order {
+        match copy_with(candidate, text) {
+            Ok(()) => return Ok(candidate),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+fn copy_with(provider: ClipboardProvider, text: &str) -> io::Result<()> {
+    match provider {
+        ClipboardProvider::Auto => copy(provider, text).map(|_| ()),
+        ClipboardProvider::Arboard => arboard::Clipboard::new()
+            .and_then(|mut cb| cb.set_text(text.to_string()))
+            .map_err(io::Error::other),
+        ClipboardProvider::WlCopy => pipe_to("wl-copy", &[], text),
+        ClipboardProvider::Xclip => pipe_to("xclip", &["-selection", "clipboard"], text),
+        ClipboardProvider::Xsel => pipe_to("xsel", &["--clipboard", "--input"], text),
+        ClipboardProvider::Pbcopy => pipe_to("pbcopy", &[], text),
+        ClipboardProvider::Osc52 => {
+            let mut stdout = io::stdout();
+            write!(stdout, "\x1b]52;c;{}\x07", BASE64_STANDARD.encode(text))?;
+            stdout.flush()
+        }
+    }
+}
+
+fn pipe_to(program: &str, args: &[&str], text: &str) -> io::Result<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("{} exited with {}", program, status)))
+    }
+}
@@ -1,17 +1,256 @@
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::io;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use matrix_sdk::matrix_auth::MatrixSession;
 use chrono::Local;
 use serde::{Deserialize, Serialize};
 
+use crate::clipboard::ClipboardProvider;
 use crate::storage::{decrypt_value, encrypt_value, EncryptedValue};
 
+/// How message senders are labelled in the timeline.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SenderFormat {
+    /// `alice`, or `alice:example.org` when another sender in the room
+    /// shares the localpart.
+    #[default]
+    Localpart,
+    /// The full `@alice:example.org`.
+    Mxid,
+    /// The room display name, followed by the MXID when it is ambiguous.
+    DisplayName,
+}
+
+/// Order of the channel list.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomSort {
+    /// As the server lists them, joined rooms before invites.
+    #[default]
+    Default,
+    /// Alphabetical by name, joined rooms before invites.
+    Name,
+    /// Most recent message first, joined rooms before invites.
+    Activity,
+}
+
+/// Modifier that, held with a digit 1-9, jumps to that channel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JumpModifier {
+    #[default]
+    Alt,
+    Ctrl,
+    /// No jump keys.
+    Off,
+}
+
+/// A named view of the channel list, e.g. "work" or "communities".
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Workspace {
+    /// Room IDs or aliases to list; empty lists every room.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rooms: Vec<String>,
+    /// Only list rooms whose name contains this (case-insensitive).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    /// Overrides `room_sort` while the workspace is active.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sort: Option<RoomSort>,
+    /// Width of the channel list in columns; defaults to 28.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channels_width: Option<u16>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct AppConfig {
     pub accounts: Vec<AccountConfig>,
     pub active: Option<usize>,
+    /// Show unknown message types and custom events as raw JSON snippets.
+    #[serde(default)]
+    pub render_unknown_events: bool,
+    /// Minutes without a keypress before presence is set to unavailable.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_away_minutes: Option<u64>,
+    /// Periodically write runtime counters (messages, sync restarts, decrypt
+    /// failures, notifications) to this file as JSON.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats_file: Option<PathBuf>,
+    /// Seconds between stats file writes; defaults to 60.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats_interval_secs: Option<u64>,
+    /// Keep syncing in a detached background instance after the UI quits;
+    /// `marty attach` (or plain `marty`) reconnects to it.
+    #[serde(default)]
+    pub background_on_quit: bool,
+    /// Commands (`/join`, `/msg`, ...) run once the initial sync and backfill
+    /// have finished, e.g. to post a standup message on start.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub startup_commands: Vec<String>,
+    /// Rooms whose messages are sent exactly as typed, without emoji
+    /// shortcode conversion or mention pills (for bridges that mangle them).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub plain_text_rooms: Vec<String>,
+    /// Root event IDs of threads that don't notify or count as unread.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub muted_threads: Vec<String>,
+    /// Sender labels: `localpart` (default), `mxid` or `display_name`.
+    #[serde(default)]
+    pub sender_format: SenderFormat,
+    /// Local nicknames by user ID, shown instead of their names in the
+    /// timeline, member completion and notifications.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub nicknames: HashMap<String, String>,
+    /// Nicknames that only apply in one room (room ID, then user ID); these
+    /// win over `nicknames`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub room_nicknames: HashMap<String, HashMap<String, String>>,
+    /// Text expansions by trigger word (`";sig" = "Sent from marty"`),
+    /// applied on send or with Tab after the trigger.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub snippets: HashMap<String, String>,
+    /// Message templates for `/template <name>`: `{date}`, `{weekday}`,
+    /// `{time}` and `{room}` are filled in, `{?Field}` is asked for.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub templates: HashMap<String, String>,
+    /// Templates that only apply in one room (room ID, then name); these
+    /// win over `templates`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub room_templates: HashMap<String, HashMap<String, String>>,
+    /// Bot commands sent by `/bridge <action>`, keyed by action or by
+    /// `bridge.action` (e.g. `"whatsapp.relink" = "login qr"`); `{room}` is
+    /// replaced with the current room ID.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub bridge_commands: HashMap<String, String>,
+    /// Clipboard backend: `auto` (default), `arboard`, `wl_copy`, `xclip`,
+    /// `xsel`, `pbcopy` or `osc52`.
+    #[serde(default)]
+    pub clipboard: ClipboardProvider,
+    /// Underline misspelled words in the input using `hunspell`.
+    #[serde(default)]
+    pub spellcheck: bool,
+    /// Dictionary for `hunspell -d`: a language such as `en_US` or a path
+    /// to a `.dic`/`.aff` pair without the extension.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spell_dictionary: Option<String>,
+    /// Named channel list views, switched with F2 or `/workspace`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub workspaces: BTreeMap<String, Workspace>,
+    /// The workspace shown on start; updated when switching.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_workspace: Option<String>,
+    /// Show a bottom bar listing rooms with unread activity.
+    #[serde(default)]
+    pub hotlist: bool,
+    /// Put the selected room and the unread count in the terminal title.
+    #[serde(default)]
+    pub window_title: bool,
+    /// Order of the channel list, unless the workspace sets its own.
+    #[serde(default)]
+    pub room_sort: RoomSort,
+    /// Modifier for the digit keys that jump to a channel.
+    #[serde(default)]
+    pub room_jump_modifier: JumpModifier,
+    /// Number the first nine channels in the list (F3 toggles).
+    #[serde(default)]
+    pub room_numbers: bool,
+    /// Send `m.read.private` receipts, which clear unread counts on other
+    /// devices without showing other members how far we've read.
+    #[serde(default)]
+    pub private_read_receipts: bool,
+    /// Print the time and name once for consecutive messages from the same
+    /// sender within a few minutes (F5 toggles).
+    #[serde(default)]
+    pub compact_layout: bool,
+    /// Leave joins, leaves, invites, renames and topic changes out of the
+    /// timeline (F6 toggles).
+    #[serde(default)]
+    pub hide_state_events: bool,
+    /// Don't download attachments or fetch link previews and thumbnails,
+    /// and long-poll longer, for metered connections (F4 toggles).
+    #[serde(default)]
+    pub bandwidth_saver: bool,
+    /// Room IDs pinned to the top of the channel list, in this order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned_rooms: Vec<String>,
+    /// Room IDs or aliases of ops alert rooms: messages are colored by
+    /// severity and criticals raise urgent notifications.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alert_rooms: Vec<String>,
+    /// Reply sent by `/ack`; `{severity}`, `{sender}` and `{summary}` (the
+    /// alert's first line) are filled in. Defaults to `Acknowledged: {summary}`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alert_ack_template: Option<String>,
+    /// Command that speaks new messages in `tts_rooms`, e.g. `espeak` or
+    /// `say`; the text is passed as its last argument.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tts_command: Option<String>,
+    /// Room IDs or aliases whose new messages are spoken.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tts_rooms: Vec<String>,
+    /// Messages with more lines than this ask whether to send them as a
+    /// file or a paste link instead; defaults to 20, 0 never asks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub long_message_lines: Option<usize>,
+    /// Pastebin command for long messages: it reads the text on stdin and
+    /// prints the link, e.g. `curl -F file=@- https://0x0.st`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paste_command: Option<String>,
+    /// Inline thumbnails for image attachments: `auto` (default), `kitty`,
+    /// `iterm2`, `sixel` or `off`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_previews: Option<String>,
+    /// Show the title of a message's first link beneath it, taken from the
+    /// homeserver's URL preview. Off by default, since the homeserver then
+    /// fetches every linked page.
+    #[serde(default)]
+    pub link_previews: bool,
+    /// Palette sender names are colored from, as `#rrggbb` or color names;
+    /// empty uses the built-in one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sender_colors: Vec<String>,
+    /// Text rows the input grows to before it scrolls; defaults to 8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_max_lines: Option<u16>,
+    /// How often the UI wakes up while in use, in ms; defaults to 100.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tick_rate_ms: Option<u64>,
+    /// Device display name used at login; `{hostname}` is replaced with
+    /// this machine's name. Defaults to `marty ({hostname})`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_name: Option<String>,
+}
+
+impl AppConfig {
+    /// The device display name for new logins, with `{hostname}` filled in.
+    pub fn device_display_name(&self) -> String {
+        let template = self.device_name.as_deref().unwrap_or("marty ({hostname})");
+        if !template.contains("{hostname}") {
+            return template.to_string();
+        }
+        match hostname() {
+            Some(host) => template.replace("{hostname}", &host),
+            // Drop the placeholder along with any brackets around it.
+            None => template
+                .replace("({hostname})", "")
+                .replace("{hostname}", "")
+                .trim()
+                .to_string(),
+        }
+    }
+}
+
+fn hostname() -> Option<String> {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .find_map(|path| fs::read_to_string(path).ok())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -40,6 +279,45 @@ pub fn data_dir() -> io::Result<PathBuf> {
     Ok(dir)
 }
 
+/// Advisory lock on the data dir, released when dropped (or when the process
+/// exits). Two instances rewriting the same room logs would corrupt them.
+pub struct DataDirLock {
+    _file: fs::File,
+}
+
+pub fn lock_data_dir() -> io::Result<DataDirLock> {
+    let dir = data_dir()?;
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join("lock"))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(fs::TryLockError::WouldBlock) => {
+            let mut owner = String::new();
+            let _ = file.read_to_string(&mut owner);
+            let owner = match owner.trim() {
+                "" => String::new(),
+                pid => format!(" (pid {})", pid),
+            };
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!(
+                    "{} is in use by another marty instance{}; pass --force to override",
+                    dir.display(),
+                    owner
+                ),
+            ));
+        }
+        Err(fs::TryLockError::Error(err)) => return Err(err),
+    }
+    file.set_len(0)?;
+    write!(file, "{}", std::process::id())?;
+    Ok(DataDirLock { _file: file })
+}
+
 pub fn crypto_dir() -> io::Result<PathBuf> {
     let dir = data_dir()?.join("crypto");
     fs::create_dir_all(&dir)?;
@@ -62,7 +340,7 @@ pub fn attachments_dir() -> io::Result<PathBuf> {
 fn home_dir() -> io::Result<PathBuf> {
     std::env::var_os("HOME")
         .map(PathBuf::from)
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "HOME not set"))
+        .ok_or_else(|| io::Error::other("HOME not set"))
 }
 
 pub fn load_config(path: &Path) -> io::Result<AppConfig> {
@@ -81,7 +359,19 @@ pub fn save_config(path: &Path, cfg: &AppConfig) -> io::Result<()> {
     fs::write(path, raw)
 }
 
-pub fn decrypt_sessions(cfg: &mut AppConfig, passphrase: &str) -> io::Result<()> {
+/// Applies `change` to the config file on disk. Session blobs stay
+/// encrypted, so this needs no passphrase.
+pub fn update_config(change: impl FnOnce(&mut AppConfig)) -> io::Result<()> {
+    let path = config_path()?;
+    let mut cfg = load_config(&path)?;
+    change(&mut cfg);
+    save_config(&path, &cfg)
+}
+
+/// Decrypts the saved sessions; returns the time spent deriving keys from
+/// the passphrase.
+pub fn decrypt_sessions(cfg: &mut AppConfig, passphrase: &str) -> io::Result<Duration> {
+    let mut kdf = Duration::ZERO;
     for account in &mut cfg.accounts {
         if account.session.is_some() {
             continue;
@@ -89,7 +379,7 @@ pub fn decrypt_sessions(cfg: &mut AppConfig, passphrase: &str) -> io::Result<()>
         let Some(encrypted) = &account.session_encrypted else {
             continue;
         };
-        let raw = decrypt_value(passphrase, encrypted).map_err(|e| {
+        let (raw, elapsed) = decrypt_value(passphrase, encrypted).map_err(|e| {
             io::Error::new(
                 io::ErrorKind::InvalidData,
                 format!("failed to decrypt session: {}", e),
@@ -98,8 +388,9 @@ pub fn decrypt_sessions(cfg: &mut AppConfig, passphrase: &str) -> io::Result<()>
         let session = serde_json::from_slice(&raw)
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
         account.session = Some(session);
+        kdf += elapsed;
     }
-    Ok(())
+    Ok(kdf)
 }
 
 pub fn encrypt_account_session(account: &mut AccountConfig, passphrase: &str) -> io::Result<()> {
@@ -0,0 +1,765 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Result;
+use chrono::Local;
+use matrix_sdk::Client;
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+
+use crate::config::{config_path, data_dir, load_config, messages_dir, SenderFormat};
+use crate::matrix::{
+    scheduled_command, start_sync, EventRelation, MatrixCommand, MatrixEvent, MemberInfo,
+    RoomInfo, RoomListState, ServerInfo, SyncOptions,
+};
+use crate::metrics::{Counter, Metrics};
+use crate::storage::{
+    load_all_messages, load_scheduled, load_search_index_for, store_scheduled, store_search_index,
+    take_due_scheduled, ScheduledMessage, SearchIndex, SEARCH_INDEX_SAVE_INTERVAL,
+};
+use crate::notify::{
+    alert_severity, invite_notification, nickname, notify_message, notify_send, sender_label,
+    verification_notification, Severity,
+};
+
+const READY_TIMEOUT: Duration = Duration::from_secs(15);
+/// How long queued commands may take to drain after a shutdown request.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the `/schedule` queue is checked for due messages.
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// First descriptor passed by systemd socket activation.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+pub fn socket_path() -> io::Result<PathBuf> {
+    Ok(data_dir()?.join("marty.sock"))
+}
+
+/// True when a background instance is accepting connections.
+pub fn is_running() -> bool {
+    socket_path().is_ok_and(|path| std::os::unix::net::UnixStream::connect(path).is_ok())
+}
+
+/// What a newly attached UI needs to catch up; messages come from the
+/// encrypted store, which the daemon keeps writing while detached.
+#[derive(Default)]
+struct Snapshot {
+    rooms: Vec<RoomInfo>,
+    server_info: Option<ServerInfo>,
+    backfill_done: bool,
+    /// Member lists and recent senders by room, for naming senders in
+    /// notifications the way the UI does.
+    members: HashMap<String, Vec<MemberInfo>>,
+    senders: HashMap<String, HashSet<String>>,
+}
+
+impl Snapshot {
+    fn apply(&mut self, evt: &MatrixEvent) {
+        match evt {
+            MatrixEvent::Rooms(rooms) => self.rooms = rooms.clone(),
+            MatrixEvent::RoomAdded(room) => self.rooms.push(room.clone()),
+            MatrixEvent::RoomUpdated(room) => {
+                if let Some(existing) = self.rooms.iter_mut().find(|r| r.room_id == room.room_id) {
+                    *existing = room.clone();
+                }
+            }
+            MatrixEvent::RoomRemoved { room_id } => self.rooms.retain(|r| &r.room_id != room_id),
+            MatrixEvent::ServerInfo(info) => self.server_info = Some(info.clone()),
+            MatrixEvent::BackfillDone => self.backfill_done = true,
+            MatrixEvent::Members { room_id, members } => {
+                self.members.insert(room_id.clone(), members.clone());
+            }
+            MatrixEvent::MemberChanged {
+                room_id,
+                member,
+                joined,
+            } => {
+                let members = self.members.entry(room_id.clone()).or_default();
+                members.retain(|m| m.user_id != member.user_id);
+                if *joined {
+                    members.push(member.clone());
+                }
+            }
+            MatrixEvent::Message { room_id, sender, .. }
+            | MatrixEvent::Attachment { room_id, sender, .. } => {
+                self.senders
+                    .entry(room_id.clone())
+                    .or_default()
+                    .insert(sender.clone());
+            }
+            _ => {}
+        }
+    }
+
+    /// Member list requests for the joined rooms, so that notifications
+    /// can use display names without a UI having opened the room first.
+    fn member_fetches(&self, config: &NotifyConfig) -> Vec<MatrixCommand> {
+        if config.sender_format != SenderFormat::DisplayName {
+            return Vec::new();
+        }
+        self.rooms
+            .iter()
+            .filter(|room| room.state == RoomListState::Joined)
+            .map(|room| MatrixCommand::FetchMembers {
+                room_id: room.room_id.clone(),
+            })
+            .collect()
+    }
+
+    fn replay(&self) -> Vec<MatrixEvent> {
+        let mut rooms = self.rooms.clone();
+        rooms.sort_by_key(|room| room.state == RoomListState::Invited);
+        let mut events = vec![MatrixEvent::Rooms(rooms)];
+        if let Some(info) = &self.server_info {
+            events.push(MatrixEvent::ServerInfo(info.clone()));
+        }
+        if self.backfill_done {
+            events.push(MatrixEvent::BackfillDone);
+        }
+        events
+    }
+
+    fn room_name(&self, room_id: &str) -> String {
+        self.rooms
+            .iter()
+            .find(|room| room.room_id == room_id)
+            .map(|room| room.name.clone())
+            .unwrap_or_else(|| room_id.to_string())
+    }
+
+    fn sender_label(&self, config: &NotifyConfig, room_id: &str, sender: &str) -> String {
+        sender_label(
+            config.sender_format,
+            nickname(&config.nicknames, &config.room_nicknames, room_id, sender),
+            self.members.get(room_id).map_or(&[], Vec::as_slice),
+            self.senders.get(room_id).into_iter().flatten(),
+            sender,
+        )
+    }
+}
+
+/// The config behind notification names, thread mutes and alert rooms.
+/// Loaded once, then reloaded on SIGHUP or when the file changes, since an
+/// attached UI saves mutes and nicknames there.
+#[derive(Default)]
+struct NotifyConfig {
+    modified: Option<SystemTime>,
+    sender_format: SenderFormat,
+    nicknames: HashMap<String, String>,
+    room_nicknames: HashMap<String, HashMap<String, String>>,
+    muted_threads: HashSet<String>,
+    alert_rooms: HashSet<String>,
+}
+
+impl NotifyConfig {
+    fn load() -> Self {
+        let mut config = Self::default();
+        config.reload();
+        config
+    }
+
+    /// Keeps the previous settings when the file can't be read, e.g. while
+    /// it is being rewritten.
+    fn reload(&mut self) {
+        let Ok(path) = config_path() else {
+            return;
+        };
+        let modified = fs::metadata(&path).and_then(|meta| meta.modified()).ok();
+        let Ok(cfg) = load_config(&path) else {
+            return;
+        };
+        *self = Self {
+            modified,
+            sender_format: cfg.sender_format,
+            nicknames: cfg.nicknames,
+            room_nicknames: cfg.room_nicknames,
+            muted_threads: cfg.muted_threads.into_iter().collect(),
+            alert_rooms: cfg.alert_rooms.into_iter().collect(),
+        };
+    }
+
+    /// Reloads if the file's modification time moved since the last load.
+    fn refresh(&mut self) {
+        let modified = config_path()
+            .and_then(fs::metadata)
+            .and_then(|meta| meta.modified())
+            .ok();
+        if modified != self.modified {
+            self.reload();
+        }
+    }
+
+    fn thread_muted(&self, thread_root: Option<&str>) -> bool {
+        thread_root.is_some_and(|root| self.muted_threads.contains(root))
+    }
+}
+
+/// Messages queued with `/schedule`. The instance holding the data dir sends
+/// them, so each goes out once however many UIs are attached, and also
+/// while none is.
+struct Schedule {
+    messages: Vec<ScheduledMessage>,
+}
+
+impl Schedule {
+    fn load(passphrase: &str) -> Self {
+        let messages = messages_dir()
+            .and_then(|base| load_scheduled(&base, passphrase))
+            .unwrap_or_default();
+        Self { messages }
+    }
+
+    fn save(&self, passphrase: &str) {
+        if let Ok(base) = messages_dir() {
+            let _ = store_scheduled(&base, passphrase, &self.messages);
+        }
+    }
+
+    /// Sends the messages that are due; true when the queue changed.
+    fn send_due(&mut self, cmd_tx: &mpsc::UnboundedSender<MatrixCommand>) -> bool {
+        let due = take_due_scheduled(&mut self.messages, Local::now().timestamp_millis());
+        let changed = !due.is_empty();
+        for msg in due {
+            let _ = cmd_tx.send(scheduled_command(msg));
+        }
+        changed
+    }
+
+    /// Applies a `Schedule` or `TakeScheduled` command from an attached UI.
+    fn apply(&mut self, cmd: MatrixCommand, cmd_tx: &mpsc::UnboundedSender<MatrixCommand>) {
+        match cmd {
+            MatrixCommand::Schedule { message } => {
+                let idx = self
+                    .messages
+                    .partition_point(|msg| msg.send_at <= message.send_at);
+                self.messages.insert(idx, message);
+            }
+            MatrixCommand::TakeScheduled { message, send } => {
+                // Gone when it was sent or cancelled in the meantime.
+                let Some(idx) = self.messages.iter().position(|msg| *msg == message) else {
+                    return;
+                };
+                let msg = self.messages.remove(idx);
+                if send {
+                    let _ = cmd_tx.send(scheduled_command(msg));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn event(&self) -> MatrixEvent {
+        MatrixEvent::Scheduled(self.messages.clone())
+    }
+}
+
+/// Runs the sync loop headless and serves events/commands as JSON lines on
+/// the control socket until a `Shutdown` command or SIGTERM arrives. Desktop
+/// notifications are sent from here while no UI is attached, and the
+/// configured `startup_commands` once the first backfill is done. The
+/// `/schedule` queue is sent from here too.
+pub async fn serve(
+    client: Client,
+    passphrase: String,
+    options: SyncOptions,
+    own_user_id: Option<String>,
+    mut startup_commands: Vec<MatrixCommand>,
+) -> Result<()> {
+    let path = socket_path()?;
+    let activated = activated_listener()?;
+    let owns_socket = activated.is_none();
+    let listener = match activated {
+        Some(listener) => listener,
+        None => {
+            // The caller holds the data dir lock, so any existing socket is stale.
+            let _ = fs::remove_file(&path);
+            bind_private(&path)?
+        }
+    };
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+
+    let metrics = options.metrics.clone();
+    let mut notify_config = NotifyConfig::load();
+    let mut index = load_index(&passphrase);
+    let mut save_timer = tokio::time::interval(SEARCH_INDEX_SAVE_INTERVAL);
+    let mut schedule = Schedule::load(&passphrase);
+    let mut schedule_timer = tokio::time::interval(SCHEDULE_CHECK_INTERVAL);
+    let (evt_tx, mut evt_rx) = mpsc::unbounded_channel();
+    let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+    let (client_cmd_tx, mut client_cmd_rx) = mpsc::unbounded_channel();
+    let sync = tokio::spawn(start_sync(
+        client,
+        passphrase.clone(),
+        options,
+        cmd_rx,
+        evt_tx,
+    ));
+    sd_notify("READY=1");
+
+    let mut snapshot = Snapshot::default();
+    let mut clients: Vec<mpsc::UnboundedSender<String>> = Vec::new();
+    loop {
+        tokio::select! {
+            evt = evt_rx.recv() => {
+                let Some(evt) = evt else { break };
+                snapshot.apply(&evt);
+                index_event(&mut index, &evt);
+                if index.needs_purge() {
+                    save_index(&mut index, &passphrase);
+                }
+                if matches!(evt, MatrixEvent::BackfillDone) {
+                    for cmd in snapshot.member_fetches(&notify_config) {
+                        let _ = cmd_tx.send(cmd);
+                    }
+                    for cmd in startup_commands.drain(..) {
+                        let _ = cmd_tx.send(cmd);
+                    }
+                }
+                clients.retain(|client| !client.is_closed());
+                if clients.is_empty() && snapshot.backfill_done {
+                    notify(&snapshot, &mut notify_config, &evt, own_user_id.as_deref(), &metrics);
+                }
+                broadcast(&clients, &evt);
+            }
+            cmd = client_cmd_rx.recv() => {
+                let Some(cmd) = cmd else { continue };
+                match cmd {
+                    MatrixCommand::Shutdown => break,
+                    MatrixCommand::Schedule { .. } | MatrixCommand::TakeScheduled { .. } => {
+                        schedule.apply(cmd, &cmd_tx);
+                        schedule.save(&passphrase);
+                        broadcast(&clients, &schedule.event());
+                    }
+                    cmd => {
+                        let _ = cmd_tx.send(cmd);
+                    }
+                }
+            }
+            _ = schedule_timer.tick() => {
+                if schedule.send_due(&cmd_tx) {
+                    schedule.save(&passphrase);
+                    broadcast(&clients, &schedule.event());
+                }
+            }
+            _ = sigterm.recv() => break,
+            _ = sighup.recv() => notify_config.reload(),
+            _ = save_timer.tick() => save_index(&mut index, &passphrase),
+            conn = listener.accept() => {
+                let Ok((stream, _)) = conn else { continue };
+                let (tx, rx) = mpsc::unbounded_channel();
+                for evt in snapshot.replay().iter().chain([&schedule.event()]) {
+                    if let Some(line) = encode(evt) {
+                        let _ = tx.send(line);
+                    }
+                }
+                clients.push(tx);
+                tokio::spawn(serve_client(stream, rx, client_cmd_tx.clone()));
+            }
+        }
+    }
+    // Commands are handled in order, so sends queued before the shutdown
+    // request still go out; message storage is written as events arrive.
+    sd_notify("STOPPING=1");
+    let _ = cmd_tx.send(MatrixCommand::Shutdown);
+    let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, sync).await;
+    save_index(&mut index, &passphrase);
+    if owns_socket {
+        let _ = fs::remove_file(&path);
+    }
+    Ok(())
+}
+
+/// `--notify-only`: syncs, stores history and sends the `/schedule` queue
+/// like `serve`, but without a control socket, so there is no UI to defer
+/// to and every notification is sent from here. Stops on SIGINT or SIGTERM.
+pub async fn notify_only(
+    client: Client,
+    passphrase: String,
+    options: SyncOptions,
+    own_user_id: Option<String>,
+    mut startup_commands: Vec<MatrixCommand>,
+) -> Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+
+    let metrics = options.metrics.clone();
+    let mut notify_config = NotifyConfig::load();
+    let mut index = load_index(&passphrase);
+    let mut save_timer = tokio::time::interval(SEARCH_INDEX_SAVE_INTERVAL);
+    let mut schedule = Schedule::load(&passphrase);
+    let mut schedule_timer = tokio::time::interval(SCHEDULE_CHECK_INTERVAL);
+    let (evt_tx, mut evt_rx) = mpsc::unbounded_channel();
+    let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+    let sync = tokio::spawn(start_sync(
+        client,
+        passphrase.clone(),
+        options,
+        cmd_rx,
+        evt_tx,
+    ));
+    sd_notify("READY=1");
+
+    let mut snapshot = Snapshot::default();
+    loop {
+        tokio::select! {
+            evt = evt_rx.recv() => {
+                let Some(evt) = evt else { break };
+                snapshot.apply(&evt);
+                index_event(&mut index, &evt);
+                if index.needs_purge() {
+                    save_index(&mut index, &passphrase);
+                }
+                if matches!(evt, MatrixEvent::BackfillDone) {
+                    for cmd in snapshot.member_fetches(&notify_config) {
+                        let _ = cmd_tx.send(cmd);
+                    }
+                    for cmd in startup_commands.drain(..) {
+                        let _ = cmd_tx.send(cmd);
+                    }
+                }
+                if snapshot.backfill_done {
+                    notify(&snapshot, &mut notify_config, &evt, own_user_id.as_deref(), &metrics);
+                }
+            }
+            _ = schedule_timer.tick() => {
+                if schedule.send_due(&cmd_tx) {
+                    schedule.save(&passphrase);
+                }
+            }
+            _ = sigterm.recv() => break,
+            _ = sigint.recv() => break,
+            _ = sighup.recv() => notify_config.reload(),
+            _ = save_timer.tick() => save_index(&mut index, &passphrase),
+        }
+    }
+    sd_notify("STOPPING=1");
+    let _ = cmd_tx.send(MatrixCommand::Shutdown);
+    let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, sync).await;
+    save_index(&mut index, &passphrase);
+    Ok(())
+}
+
+/// The search index, caught up with the stored history. This instance holds
+/// the data dir, so it is the one that keeps the index on disk current.
+fn load_index(passphrase: &str) -> SearchIndex {
+    let Ok(base) = messages_dir() else {
+        return SearchIndex::default();
+    };
+    let rooms = load_all_messages(&base, passphrase)
+        .map(|loaded| loaded.rooms)
+        .unwrap_or_default();
+    load_search_index_for(&base, passphrase, &rooms)
+}
+
+fn save_index(index: &mut SearchIndex, passphrase: &str) {
+    if !index.take_changed() {
+        return;
+    }
+    if let Ok(base) = messages_dir() {
+        let _ = store_search_index(&base, passphrase, index);
+    }
+}
+
+/// Indexes what the UI would index for `evt`.
+fn index_event(index: &mut SearchIndex, evt: &MatrixEvent) {
+    match evt {
+        MatrixEvent::Message {
+            room_id,
+            event_id,
+            body,
+            timestamp,
+            ..
+        } => index.add_text(room_id, event_id, *timestamp, body),
+        MatrixEvent::Attachment {
+            room_id,
+            event_id,
+            name,
+            timestamp,
+            ..
+        } => index.add_text(room_id, event_id, *timestamp, name),
+        MatrixEvent::Poll {
+            room_id,
+            event_id,
+            timestamp,
+            poll,
+            ..
+        } => index.add_text(room_id, event_id, *timestamp, &poll.question),
+        MatrixEvent::Related { relation, .. } => match relation {
+            EventRelation::Edit { target, body, .. } => index.update_text(target, body),
+            EventRelation::Redaction { target } => index.remove(target),
+            _ => {}
+        },
+        _ => {}
+    }
+}
+
+/// Sends the notification the UI would send for `evt` if it were
+/// attached with another room open.
+fn notify(
+    snapshot: &Snapshot,
+    config: &mut NotifyConfig,
+    evt: &MatrixEvent,
+    own_user_id: Option<&str>,
+    metrics: &Metrics,
+) {
+    config.refresh();
+    let (room_id, sender, body) = match evt {
+        MatrixEvent::Message {
+            room_id,
+            sender,
+            body,
+            thread_root,
+            ..
+        } if !config.thread_muted(thread_root.as_deref()) => (room_id, sender, body.clone()),
+        MatrixEvent::Attachment {
+            room_id,
+            sender,
+            kind,
+            name,
+            thread_root,
+            ..
+        } if !config.thread_muted(thread_root.as_deref()) => {
+            (room_id, sender, format!("[{}] {}", kind, name))
+        }
+        _ => {
+            if let Some((title, body)) = notification(evt) {
+                notify_send(&title, &body);
+                metrics.incr(Counter::Notifications);
+            }
+            return;
+        }
+    };
+    if own_user_id == Some(sender.as_str()) {
+        return;
+    }
+    let critical = matches!(evt, MatrixEvent::Message { .. })
+        && alert_severity(&config.alert_rooms, snapshot.rooms.iter(), room_id, &body)
+            == Some(Severity::Critical);
+    let label = snapshot.sender_label(config, room_id, sender);
+    notify_message(&snapshot.room_name(room_id), &label, &body, critical);
+    metrics.incr(Counter::Notifications);
+}
+
+/// Binds the control socket inside a new owner-only directory and moves it
+/// to `path`, so other users can never reach it while it has the default
+/// permissions.
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let dir = path.with_extension(format!("{}.d", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let tmp = dir.join("marty.sock");
+    let bound = UnixListener::bind(&tmp).and_then(|listener| {
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&tmp, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&dir);
+    bound
+}
+
+/// The control socket handed over by systemd socket activation
+/// (`LISTEN_PID`/`LISTEN_FDS`), if this process was started that way.
+fn activated_listener() -> io::Result<Option<UnixListener>> {
+    let for_us = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<u32>().ok())
+        .unwrap_or(0);
+    if !for_us || count == 0 {
+        return Ok(None);
+    }
+    // SAFETY: systemd passes the listening socket as descriptor 3 and
+    // nothing else in this process owns it.
+    let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    listener.set_nonblocking(true)?;
+    UnixListener::from_std(listener).map(Some)
+}
+
+/// Reports a state change to systemd when running as a `Type=notify`
+/// unit; a no-op otherwise.
+fn sd_notify(state: &str) {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(target) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let Ok(socket) = UnixDatagram::unbound() else {
+        return;
+    };
+    match target.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            if let Ok(addr) = std::os::unix::net::SocketAddr::from_abstract_name(name) {
+                let _ = socket.send_to_addr(state.as_bytes(), &addr);
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {}
+        None => {
+            let _ = socket.send_to(state.as_bytes(), &target);
+        }
+    }
+}
+
+/// The passphrase from a systemd credential (`LoadCredential=passphrase:...`),
+/// if one was passed to the unit.
+pub fn credential_passphrase() -> Option<String> {
+    let dir = env::var_os("CREDENTIALS_DIRECTORY")?;
+    let raw = fs::read_to_string(PathBuf::from(dir).join("passphrase")).ok()?;
+    Some(raw.trim_end_matches(['\r', '\n']).to_string())
+}
+
+async fn serve_client(
+    stream: UnixStream,
+    mut outgoing: mpsc::UnboundedReceiver<String>,
+    cmd_tx: mpsc::UnboundedSender<MatrixCommand>,
+) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    loop {
+        tokio::select! {
+            line = lines.next_line() => match line {
+                Ok(Some(line)) => {
+                    if let Ok(cmd) = serde_json::from_str::<MatrixCommand>(&line) {
+                        let _ = cmd_tx.send(cmd);
+                    }
+                }
+                _ => break,
+            },
+            out = outgoing.recv() => match out {
+                Some(out) => {
+                    if write.write_all(out.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+                None => break,
+            },
+        }
+    }
+}
+
+/// Notifications for events other than messages and attachments.
+fn notification(evt: &MatrixEvent) -> Option<(String, String)> {
+    match evt {
+        MatrixEvent::ServerNotice { body, .. } => {
+            Some(("Server notice".to_string(), body.clone()))
+        }
+        MatrixEvent::RoomAdded(room) if room.state == RoomListState::Invited => {
+            Some(invite_notification(room))
+        }
+        MatrixEvent::VerificationRequest { user_id, device_id } => {
+            Some(verification_notification(user_id, device_id))
+        }
+        _ => None,
+    }
+}
+
+fn broadcast(clients: &[mpsc::UnboundedSender<String>], evt: &MatrixEvent) {
+    if let Some(line) = encode(evt) {
+        for client in clients {
+            let _ = client.send(line.clone());
+        }
+    }
+}
+
+fn encode<T: Serialize>(value: &T) -> Option<String> {
+    let mut line = serde_json::to_string(value).ok()?;
+    line.push('\n');
+    Some(line)
+}
+
+/// Connects to the background instance and bridges its socket onto the
+/// same channel pair the in-process sync loop uses.
+pub async fn connect() -> io::Result<(
+    mpsc::WeakUnboundedSender<MatrixEvent>,
+    mpsc::UnboundedReceiver<MatrixEvent>,
+    mpsc::UnboundedSender<MatrixCommand>,
+)> {
+    let stream = UnixStream::connect(socket_path()?).await?;
+    let (read, mut write) = stream.into_split();
+    let (evt_tx, evt_rx) = mpsc::unbounded_channel();
+    let ui_tx = evt_tx.downgrade();
+    let (cmd_tx, mut cmd_rx) = mpsc::unbounded_channel::<MatrixCommand>();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(read).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if let Ok(evt) = serde_json::from_str::<MatrixEvent>(&line) {
+                let _ = evt_tx.send(evt);
+            }
+        }
+        let _ = evt_tx.send(MatrixEvent::Status {
+            message: "Background marty stopped".to_string(),
+        });
+    });
+    tokio::spawn(async move {
+        while let Some(cmd) = cmd_rx.recv().await {
+            let Some(line) = encode(&cmd) else { continue };
+            if write.write_all(line.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    });
+    Ok((ui_tx, evt_rx, cmd_tx))
+}
+
+/// Starts `marty --daemon` in its own process group, handing it the
+/// passphrase on stdin, and waits until its socket accepts connections.
+pub async fn spawn_detached(passphrase: &str) -> io::Result<()> {
+    let log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(data_dir()?.join("daemon.log"))?;
+    // `mode` only applies to new files; an older log may be readable by
+    // others.
+    log.set_permissions(fs::Permissions::from_mode(0o600))?;
+    let mut child = Command::new(std::env::current_exe()?)
+        .arg("--daemon")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(log)
+        .process_group(0)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        writeln!(stdin, "{}", passphrase)?;
+    }
+    let started = Instant::now();
+    while started.elapsed() < READY_TIMEOUT {
+        if UnixStream::connect(socket_path()?).await.is_ok() {
+            return Ok(());
+        }
+        if let Some(status) = child.try_wait()? {
+            return Err(io::Error::other(format!(
+                "background marty exited ({}); see daemon.log in the data dir",
+                status
+            )));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    Err(io::Error::other("background marty did not start in time"))
+}
+
+/// Asks the background instance to shut down.
+pub fn stop() -> io::Result<()> {
+    let mut stream = std::os::unix::net::UnixStream::connect(socket_path()?)
+        .map_err(|_| io::Error::other("no background marty is running"))?;
+    let line = encode(&MatrixCommand::Shutdown).unwrap_or_default();
+    stream.write_all(line.as_bytes())
+}
@@ -0,0 +1,17 @@
+//! The client behind the `marty` binary, also built as a library so the
+//! benchmarks can drive the storage layer and the UI directly.
+
+mod clipboard;
+pub mod config;
+pub mod daemon;
+mod emoji;
+pub mod matrix;
+pub mod metrics;
+mod notify;
+mod paste;
+mod preview;
+pub mod profile;
+mod spell;
+pub mod storage;
+mod tts;
+pub mod ui;
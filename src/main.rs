@@ -1,767 +1,95 @@
-mod config;
-mod matrix;
-mod storage;
-
-use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs;
-use std::hash::{Hash, Hasher};
-use std::io::{self, Write};
-use std::path::Path;
-use std::process::{Command, Stdio};
+use std::io::{self, IsTerminal, Write};
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use anyhow::Result;
-use arboard::Clipboard;
-use chrono::{Local, TimeZone};
-use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
-use crossterm::execute;
-use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
-use ratatui::backend::CrosstermBackend;
-use ratatui::buffer::Buffer;
-use ratatui::layout::{Constraint, Direction, Layout, Rect};
-use ratatui::style::{Color, Modifier, Style};
-use ratatui::text::{Line, Span};
-use ratatui::widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph, Wrap};
-use ratatui::Terminal;
+use anyhow::{anyhow, Context, Result};
 use rpassword::read_password;
 use tokio::sync::mpsc;
 
-use crate::config::{
+use marty::{config, daemon};
+
+use marty::config::{
     config_path, crypto_dir, decrypt_sessions, encrypt_account_session, encrypt_missing_sessions,
-    load_config, messages_dir, save_config,
+    load_config, lock_data_dir, save_config, DataDirLock,
 };
-use crate::matrix::{
-    build_client, login_with_client, start_sync, MatrixCommand, MatrixEvent, RoomInfo, RoomListState,
-};
-use crate::storage::{load_all_messages, load_all_read_receipts, store_read_receipts};
-
-const TICK_RATE: Duration = Duration::from_millis(100);
-const SELECTED_BG: Color = Color::Rgb(160, 170, 210);
-const HELP_LINES: [&str; 26] = [
-    "App navigation",
-    "  Alt+Q\tQuit.",
-    "  F1\tToggle help panel showing shortcuts.",
-    "  Up\tOne Channel Up",
-    "  Down\tOne Channel Down",
-    "  Alt+A\tAdd chat (room or user).",
-    "  Alt+J\tJoin/add chat (room or user).",
-    "  Alt+D\tDelete chat (y/n confirm).",
-    "  Ctrl+A\tAccept invite.",
-    "  Ctrl+D\tDecline invite.",
-    "  Alt+V\tStart verification (SAS).",
-    "Message input",
-    "  Enter\tWhen input empty (single-line): open URL/attachment.",
-    "  Enter\tSend message (single-line) or insert newline (multi-line).",
-    "  file://<path>\tSend attachment from disk.",
-    "  Alt+Enter\tToggle multi-line input.",
-    "  Left/Right\tMove cursor in input.",
-    "  Alt+Left/Right\tJump word in input.",
-    "Message/channel selection",
-    "  Esc\tReset message selection or close help panel.",
-    "  Alt+Up\tSelect previous message.",
-    "  Alt+Down\tSelect next message.",
-    "Clipboard",
-    "  Alt+Y\tCopy selected message to clipboard.",
-    "Help menu",
-    "  Esc\tClose help panel. Up/Down/PageDown scroll.",
-];
-
-#[derive(Clone)]
-enum MessageItem {
-    Separator(String),
-    Message {
-        time: String,
-        sender_id: String,
-        name: String,
-        text: String,
-        event_id: Option<String>,
-        reply_to: Option<String>,
-    },
-    Attachment {
-        time: String,
-        sender_id: String,
-        name: String,
-        label: String,
-        filename: String,
-        path: String,
-        event_id: Option<String>,
-        reply_to: Option<String>,
-    },
-}
-
-enum PromptMode {
-    Add,
-    Delete { room_id: String, room_name: String },
-}
-
-struct PromptState {
-    mode: PromptMode,
-    input: String,
-}
-
-struct ReplyPreview {
-    sender: String,
-    text: String,
-}
-
-struct App {
-    rooms: Vec<RoomInfo>,
-    selected: usize,
-    messages_by_room: HashMap<String, Vec<MessageItem>>,
-    last_date_by_room: HashMap<String, String>,
-    seen_event_ids: HashMap<String, HashSet<String>>,
-    reply_index: HashMap<String, HashMap<String, ReplyPreview>>,
-    read_receipts: HashMap<String, HashSet<String>>,
-    last_message_ts: HashMap<String, i64>,
-    last_seen_ts: HashMap<String, i64>,
-    unread_counts: HashMap<String, usize>,
-    message_selected: Option<usize>,
-    input: String,
-    input_cursor: usize,
-    input_multiline: bool,
-    prompt: Option<PromptState>,
-    verification_emojis: Option<Vec<(String, String)>>,
-    verification_status: Option<String>,
-    verification_until: Option<Instant>,
-    help_open: bool,
-    help_scroll: u16,
-    is_syncing: bool,
-    notifications_ready: bool,
-    own_user_id: Option<String>,
-    should_quit: bool,
-}
-
-impl App {
-    fn new() -> Self {
-        Self {
-            rooms: Vec::new(),
-            selected: 0,
-            messages_by_room: HashMap::new(),
-            last_date_by_room: HashMap::new(),
-            seen_event_ids: HashMap::new(),
-            reply_index: HashMap::new(),
-            read_receipts: HashMap::new(),
-            last_message_ts: HashMap::new(),
-            last_seen_ts: HashMap::new(),
-            unread_counts: HashMap::new(),
-            message_selected: None,
-            input: String::new(),
-            input_cursor: 0,
-            input_multiline: false,
-            prompt: None,
-            verification_emojis: None,
-            verification_status: None,
-            verification_until: None,
-            help_open: false,
-            help_scroll: 0,
-            is_syncing: true,
-            notifications_ready: false,
-            own_user_id: None,
-            should_quit: false,
-        }
-    }
-
-    fn on_up(&mut self) {
-        if self.selected > 0 {
-            self.selected -= 1;
-            self.message_selected = None;
-            if let Some(room_id) = self.rooms.get(self.selected).map(|room| room.room_id.clone()) {
-                self.mark_room_read(&room_id);
-            }
-        }
-    }
-
-    fn on_down(&mut self) {
-        if self.selected + 1 < self.rooms.len() {
-            self.selected += 1;
-            self.message_selected = None;
-            if let Some(room_id) = self.rooms.get(self.selected).map(|room| room.room_id.clone()) {
-                self.mark_room_read(&room_id);
-            }
-        }
-    }
-
-    fn input_len_chars(&self) -> usize {
-        self.input.chars().count()
-    }
-
-    fn cursor_to_byte(input: &str, cursor: usize) -> usize {
-        input
-            .char_indices()
-            .nth(cursor)
-            .map(|(idx, _)| idx)
-            .unwrap_or_else(|| input.len())
-    }
-
-    fn input_move_left(&mut self) {
-        if self.input_cursor > 0 {
-            self.input_cursor -= 1;
-        }
-    }
-
-    fn input_move_right(&mut self) {
-        let len = self.input_len_chars();
-        if self.input_cursor < len {
-            self.input_cursor += 1;
-        }
-    }
-
-    fn input_move_word_left(&mut self) {
-        if self.input_cursor == 0 {
-            return;
-        }
-        let chars: Vec<char> = self.input.chars().collect();
-        let mut idx = self.input_cursor;
-        while idx > 0 && chars[idx - 1].is_whitespace() {
-            idx -= 1;
-        }
-        while idx > 0 && !chars[idx - 1].is_whitespace() {
-            idx -= 1;
-        }
-        self.input_cursor = idx;
-    }
-
-    fn input_move_word_right(&mut self) {
-        let chars: Vec<char> = self.input.chars().collect();
-        let len = chars.len();
-        let mut idx = self.input_cursor;
-        while idx < len && chars[idx].is_whitespace() {
-            idx += 1;
-        }
-        while idx < len && !chars[idx].is_whitespace() {
-            idx += 1;
-        }
-        self.input_cursor = idx;
-    }
-
-    fn input_insert_char(&mut self, c: char) {
-        let idx = Self::cursor_to_byte(&self.input, self.input_cursor);
-        self.input.insert(idx, c);
-        self.input_cursor += 1;
-    }
-
-    fn input_backspace(&mut self) {
-        if self.input_cursor == 0 {
-            return;
-        }
-        let end = Self::cursor_to_byte(&self.input, self.input_cursor);
-        let start = Self::cursor_to_byte(&self.input, self.input_cursor - 1);
-        self.input.replace_range(start..end, "");
-        self.input_cursor -= 1;
-    }
-
-    fn on_enter(&mut self) -> Option<String> {
-        if !self.input.trim().is_empty() {
-            let text = self.input.trim_end().to_string();
-            self.input.clear();
-            self.input_cursor = 0;
-            return Some(text);
-        }
-        None
-    }
-
-    fn toggle_help(&mut self) {
-        self.help_open = !self.help_open;
-        if self.help_open {
-            self.help_scroll = 0;
-        }
-    }
-
-    fn start_add_prompt(&mut self) {
-        self.prompt = Some(PromptState {
-            mode: PromptMode::Add,
-            input: String::new(),
-        });
-    }
-
-    fn start_delete_prompt(&mut self) {
-        if let Some(room) = self.rooms.get(self.selected) {
-            self.prompt = Some(PromptState {
-                mode: PromptMode::Delete {
-                    room_id: room.room_id.clone(),
-                    room_name: room.name.clone(),
-                },
-                input: String::new(),
-            });
-        }
-    }
-
-    fn cancel_prompt(&mut self) {
-        self.prompt = None;
-    }
-
-    fn prompt_backspace(&mut self) {
-        if let Some(state) = self.prompt.as_mut() {
-            state.input.pop();
-        }
-    }
-
-    fn prompt_push(&mut self, c: char) {
-        if let Some(state) = self.prompt.as_mut() {
-            state.input.push(c);
-        }
-    }
-
-    fn submit_prompt(&mut self) -> Option<MatrixCommand> {
-        let mut state = self.prompt.take()?;
-        let trimmed = state.input.trim();
-        if trimmed.is_empty() {
-            self.prompt = Some(state);
-            return None;
-        }
-        match &state.mode {
-            PromptMode::Add => {
-                if trimmed.starts_with('@') {
-                    return Some(MatrixCommand::CreateDirect {
-                        user_id: trimmed.to_string(),
-                    });
-                }
-                Some(MatrixCommand::JoinRoom {
-                    room: trimmed.to_string(),
-                })
-            }
-            PromptMode::Delete { room_id, .. } => {
-                if trimmed.eq_ignore_ascii_case("y") || trimmed.eq_ignore_ascii_case("yes") {
-                    let room_id = room_id.clone();
-                    Some(MatrixCommand::LeaveRoom { room_id })
-                } else if trimmed.eq_ignore_ascii_case("n") || trimmed.eq_ignore_ascii_case("no") {
-                    None
-                } else {
-                    state.input.clear();
-                    self.prompt = Some(state);
-                    None
-                }
-            }
-        }
-    }
-
-    fn show_verification_emojis(&mut self, emojis: Vec<(String, String)>) {
-        self.verification_emojis = Some(emojis);
-        self.verification_status =
-            Some("Match the emojis on your other device. Y=confirm, N=cancel".to_string());
-        self.verification_until = None;
-    }
-
-    fn show_verification_status(&mut self, status: &str) {
-        self.verification_emojis = None;
-        self.verification_status = Some(status.to_string());
-        self.verification_until = Some(Instant::now() + Duration::from_secs(3));
-    }
-
-    fn clear_verification(&mut self) {
-        self.verification_emojis = None;
-        self.verification_status = None;
-        self.verification_until = None;
-    }
-
-    fn on_escape(&mut self) {
-        if self.help_open {
-            self.help_open = false;
-        } else {
-            self.message_selected = None;
-        }
-    }
-
-    fn on_message_up(&mut self) {
-        let Some(messages) = self.current_messages() else {
-            return;
-        };
-        if messages.is_empty() {
-            return;
-        }
-        self.message_selected = match self.message_selected {
-            Some(idx) => Some(idx.saturating_sub(1)),
-            None => Some(messages.len() - 1),
-        };
-    }
-
-    fn on_message_down(&mut self) {
-        let Some(messages) = self.current_messages() else {
-            return;
-        };
-        if messages.is_empty() {
-            return;
-        }
-        self.message_selected = match self.message_selected {
-            Some(idx) => {
-                if idx + 1 < messages.len() {
-                    Some(idx + 1)
-                } else {
-                    Some(idx)
-                }
-            }
-            None => Some(0),
-        };
-    }
-
-    fn on_copy_message(&mut self) {
-        if let Some(idx) = self.message_selected {
-            if let Some(messages) = self.current_messages_mut() {
-                if let Some(msg) = messages.get(idx) {
-                    let text = msg_content(msg);
-                    let _ = copy_to_clipboard(&text);
-                }
-            }
-        }
-    }
-
-    fn on_open_url(&mut self) {
-        if let Some(idx) = self.message_selected {
-            if let Some(messages) = self.current_messages_mut() {
-                if let Some(msg) = messages.get(idx) {
-                    let msg_text = msg_string(msg);
-                    if let Some(url) = extract_url(&msg_text) {
-                        let _ = open_url(&url);
-                    }
-                }
-            }
-        }
-    }
-
-    fn on_help_up(&mut self) {
-        self.help_scroll = self.help_scroll.saturating_sub(1);
-    }
-
-    fn on_help_down(&mut self) {
-        let max = HELP_LINES.len().saturating_sub(1) as u16;
-        self.help_scroll = (self.help_scroll + 1).min(max);
-    }
-
-    fn selected_room_id(&self) -> Option<String> {
-        self.rooms.get(self.selected).map(|room| room.room_id.clone())
-    }
-
-    fn selected_room(&self) -> Option<&RoomInfo> {
-        self.rooms.get(self.selected)
-    }
-
-    fn selected_room_is_invited(&self) -> bool {
-        matches!(
-            self.selected_room().map(|room| room.state),
-            Some(RoomListState::Invited)
-        )
-    }
-
-    fn reply_preview(&self, room_id: &str, reply_to: &str) -> Option<&ReplyPreview> {
-        self.reply_index
-            .get(room_id)
-            .and_then(|map| map.get(reply_to))
-    }
-
-    fn mark_read_receipt(&mut self, room_id: &str, event_id: &str) {
-        self.read_receipts
-            .entry(room_id.to_string())
-            .or_default()
-            .insert(event_id.to_string());
-    }
-
-    fn has_read_receipt(&self, room_id: &str, event_id: &str) -> bool {
-        self.read_receipts
-            .get(room_id)
-            .map(|set| set.contains(event_id))
-            .unwrap_or(false)
-    }
-
-    fn read_receipt_for(
-        &self,
-        room_id: &str,
-        sender_id: &str,
-        event_id: Option<&str>,
-    ) -> Option<bool> {
-        if !is_own_sender(sender_id, self.own_user_id.as_deref()) {
-            return None;
-        }
-        let Some(event_id) = event_id else {
-            return Some(false);
-        };
-        Some(self.has_read_receipt(room_id, event_id))
-    }
-
-    fn selected_attachment_path(&self) -> Option<String> {
-        let idx = self.message_selected?;
-        let messages = self.current_messages()?;
-        match messages.get(idx) {
-            Some(MessageItem::Attachment { path, .. }) => Some(path.clone()),
-            _ => None,
-        }
-    }
-
-    fn selected_message_event_id(&self) -> Option<String> {
-        let idx = self.message_selected?;
-        let messages = self.current_messages()?;
-        match messages.get(idx) {
-            Some(MessageItem::Message { event_id, .. }) => event_id.clone(),
-            Some(MessageItem::Attachment { event_id, .. }) => event_id.clone(),
-            _ => None,
-        }
-    }
-
-    fn current_messages(&self) -> Option<&Vec<MessageItem>> {
-        let room_id = self.selected_room_id()?;
-        self.messages_by_room.get(&room_id)
-    }
-
-    fn current_messages_mut(&mut self) -> Option<&mut Vec<MessageItem>> {
-        let room_id = self.selected_room_id()?;
-        self.messages_by_room.get_mut(&room_id)
-    }
-
-    fn update_rooms(&mut self, rooms: Vec<RoomInfo>) {
-        for room in &rooms {
-            self.messages_by_room
-                .entry(room.room_id.clone())
-                .or_default();
-            self.seen_event_ids
-                .entry(room.room_id.clone())
-                .or_default();
-            self.reply_index
-                .entry(room.room_id.clone())
-                .or_default();
-            self.read_receipts
-                .entry(room.room_id.clone())
-                .or_default();
-            self.unread_counts.entry(room.room_id.clone()).or_default();
-            self.last_seen_ts.entry(room.room_id.clone()).or_default();
-            self.last_message_ts.entry(room.room_id.clone()).or_default();
-        }
-        self.rooms = rooms;
-        self.selected = 0;
-        self.message_selected = None;
-        self.is_syncing = false;
-        if let Some(room_id) = self.rooms.get(self.selected).map(|room| room.room_id.clone()) {
-            self.mark_room_read(&room_id);
-        }
-    }
-
-    fn handle_incoming_message(
-        &mut self,
-        room_id: &str,
-        event_id: Option<&str>,
-        ts: i64,
-        sender: &str,
-        body: &str,
-        reply_to: Option<&str>,
-    ) {
-        let is_selected = self
-            .selected_room_id()
-            .as_deref()
-            .map(|id| id == room_id)
-            .unwrap_or(false);
-        let last_seen = *self.last_seen_ts.get(room_id).unwrap_or(&0);
-        if !is_selected && ts > last_seen {
-            let entry = self.unread_counts.entry(room_id.to_string()).or_default();
-            *entry = entry.saturating_add(1);
-        }
-        self.push_message_with_time(room_id, event_id, ts, sender, body, reply_to);
-        if is_selected {
-            self.mark_room_read(room_id);
-        }
-    }
-
-    fn handle_incoming_attachment(
-        &mut self,
-        room_id: &str,
-        event_id: Option<&str>,
-        ts: i64,
-        sender: &str,
-        label: &str,
-        filename: &str,
-        path: &str,
-        reply_to: Option<&str>,
-    ) {
-        let is_selected = self
-            .selected_room_id()
-            .as_deref()
-            .map(|id| id == room_id)
-            .unwrap_or(false);
-        let last_seen = *self.last_seen_ts.get(room_id).unwrap_or(&0);
-        if !is_selected && ts > last_seen {
-            let entry = self.unread_counts.entry(room_id.to_string()).or_default();
-            *entry = entry.saturating_add(1);
-        }
-        self.push_attachment_with_time(
-            room_id,
-            event_id,
-            ts,
-            sender,
-            label,
-            filename,
-            path,
-            reply_to,
-        );
-        if is_selected {
-            self.mark_room_read(room_id);
-        }
-    }
-
-    fn room_name(&self, room_id: &str) -> String {
-        self.rooms
-            .iter()
-            .find(|room| room.room_id == room_id)
-            .map(|room| room.name.clone())
-            .unwrap_or_else(|| room_id.to_string())
-    }
-
-    fn should_notify(&self, room_id: &str, sender: &str) -> bool {
-        if !self.notifications_ready {
-            return false;
-        }
-        if self
-            .selected_room_id()
-            .as_deref()
-            .map(|id| id == room_id)
-            .unwrap_or(false)
-        {
-            return false;
-        }
-        if let Some(own) = self.own_user_id.as_deref() {
-            if sender == own {
-                return false;
-            }
-        }
-        true
-    }
-
-    fn mark_room_read(&mut self, room_id: &str) {
-        if let Some(ts) = self.last_message_ts.get(room_id).copied() {
-            self.last_seen_ts.insert(room_id.to_string(), ts);
-        }
-        self.unread_counts.insert(room_id.to_string(), 0);
-    }
-
-    fn push_message_with_time(
-        &mut self,
-        room_id: &str,
-        event_id: Option<&str>,
-        ts: i64,
-        sender: &str,
-        body: &str,
-        reply_to: Option<&str>,
-    ) {
-        let body = if reply_to.is_some() {
-            strip_reply_fallback(body)
-        } else {
-            body
-        };
-        if let Some(event_id) = event_id {
-            let seen = self.seen_event_ids.entry(room_id.to_string()).or_default();
-            if !seen.insert(event_id.to_string()) {
-                return;
-            }
-        }
-        let date = format_date(ts);
-        let entry = self.messages_by_room.entry(room_id.to_string()).or_default();
-        let last_date = self.last_date_by_room.entry(room_id.to_string()).or_default();
-        if last_date != &date {
-            entry.push(MessageItem::Separator(date.clone()));
-            *last_date = date;
-        }
-        entry.push(MessageItem::Message {
-            time: format_timestamp(ts),
-            sender_id: sender.to_string(),
-            name: format_sender(sender),
-            text: body.to_string(),
-            event_id: event_id.map(|id| id.to_string()),
-            reply_to: reply_to.map(|id| id.to_string()),
-        });
-        if let Some(event_id) = event_id {
-            let previews = self.reply_index.entry(room_id.to_string()).or_default();
-            previews.insert(
-                event_id.to_string(),
-                ReplyPreview {
-                    sender: format_sender(sender),
-                    text: body.to_string(),
-                },
-            );
-        }
-        self.last_message_ts
-            .insert(room_id.to_string(), ts);
-    }
-
-    fn push_attachment_with_time(
-        &mut self,
-        room_id: &str,
-        event_id: Option<&str>,
-        ts: i64,
-        sender: &str,
-        label: &str,
-        filename: &str,
-        path: &str,
-        reply_to: Option<&str>,
-    ) {
-        if let Some(event_id) = event_id {
-            let seen = self.seen_event_ids.entry(room_id.to_string()).or_default();
-            if !seen.insert(event_id.to_string()) {
-                return;
-            }
-        }
-        let date = format_date(ts);
-        let entry = self.messages_by_room.entry(room_id.to_string()).or_default();
-        let last_date = self.last_date_by_room.entry(room_id.to_string()).or_default();
-        if last_date != &date {
-            entry.push(MessageItem::Separator(date.clone()));
-            *last_date = date;
-        }
-        entry.push(MessageItem::Attachment {
-            time: format_timestamp(ts),
-            sender_id: sender.to_string(),
-            name: format_sender(sender),
-            label: label.to_string(),
-            filename: filename.to_string(),
-            path: path.to_string(),
-            event_id: event_id.map(|id| id.to_string()),
-            reply_to: reply_to.map(|id| id.to_string()),
-        });
-        if let Some(event_id) = event_id {
-            let previews = self.reply_index.entry(room_id.to_string()).or_default();
-            previews.insert(
-                event_id.to_string(),
-                ReplyPreview {
-                    sender: format_sender(sender),
-                    text: format!("[{}] {}", label, filename),
-                },
-            );
-        }
-        self.last_message_ts
-            .insert(room_id.to_string(), ts);
-    }
-}
-
-fn format_timestamp(ts: i64) -> String {
-    Local
-        .timestamp_millis_opt(ts)
-        .single()
-        .unwrap_or_else(Local::now)
-        .format("%H:%M")
-        .to_string()
-}
-
-fn format_date(ts: i64) -> String {
-    Local
-        .timestamp_millis_opt(ts)
-        .single()
-        .unwrap_or_else(Local::now)
-        .format("%A, %m/%d/%y")
-        .to_string()
-}
-
-fn format_sender(sender: &str) -> String {
-    let trimmed = sender.trim_start_matches('@');
-    trimmed.split(':').next().unwrap_or(trimmed).to_string()
-}
-
-fn parse_command(_text: &str) -> Option<MatrixCommand> {
-    None
-}
-
-fn parse_file_input(text: &str) -> Option<String> {
-    let trimmed = text.trim();
-    let path = trimmed.strip_prefix("file://")?;
-    if path.is_empty() {
-        return None;
-    }
-    Some(path.to_string())
+use marty::matrix::{build_client, login_with_client, start_sync, MatrixCommand, SyncOptions};
+use marty::metrics::Metrics;
+use marty::profile::StartupProfile;
+use marty::ui::{parse_command, run_ui, split_exec, UiOptions};
+
+#[derive(PartialEq, Eq)]
+enum RunMode {
+    Interactive,
+    Daemon,
+    NotifyOnly,
+    Attach,
+    Stop,
+}
+
+struct CliArgs {
+    mode: RunMode,
+    read_only: bool,
+    profile_startup: bool,
+    stats_file: Option<PathBuf>,
+    force: bool,
+    exec: Vec<String>,
+}
+
+fn parse_args() -> Result<CliArgs> {
+    let mut args = CliArgs {
+        mode: RunMode::Interactive,
+        read_only: false,
+        profile_startup: false,
+        stats_file: None,
+        force: false,
+        exec: Vec::new(),
+    };
+    let mut argv = env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--read-only" => args.read_only = true,
+            "--profile-startup" => args.profile_startup = true,
+            "--force" => args.force = true,
+            "--daemon" => args.mode = RunMode::Daemon,
+            "--notify-only" => args.mode = RunMode::NotifyOnly,
+            "attach" => args.mode = RunMode::Attach,
+            "stop" => args.mode = RunMode::Stop,
+            "--stats-file" => {
+                let path = argv.next().context("--stats-file requires a path")?;
+                args.stats_file = Some(PathBuf::from(path));
+            }
+            "--exec" => {
+                let script = argv.next().context("--exec requires commands")?;
+                args.exec.push(script);
+            }
+            "-h" | "--help" => {
+                println!(
+                    "Usage: marty [attach|stop] [--daemon] [--notify-only] [--read-only] [--profile-startup] [--stats-file <path>] [--exec <commands>] [--force]"
+                );
+                println!();
+                println!("  attach             Open the UI on a running background instance.");
+                println!("  stop               Shut down the background instance.");
+                println!("  --daemon           Sync headless in the foreground, serving `marty attach`;");
+                println!("                     reads the passphrase from stdin when it is not a terminal.");
+                println!("  --notify-only      Sync headless, storing history and sending desktop");
+                println!("                     notifications only; Ctrl+C stops.");
+                println!("  --read-only        Monitor mode: no sending, joining or leaving;");
+                println!("                     the input box filters the message view.");
+                println!("  --profile-startup  Print startup phase timings on exit.");
+                println!("  --stats-file PATH  Periodically write runtime counters to PATH as JSON.");
+                println!("  --exec COMMANDS    Run `;`-separated commands (e.g. \"/join #foo; /msg @bot hi\")");
+                println!("                     once the initial sync is done; `\\;` is a literal semicolon.");
+                println!("  --force            Start even if another instance holds the data dir lock.");
+                std::process::exit(0);
+            }
+            other => anyhow::bail!("unknown argument: {}", other),
+        }
+    }
+    Ok(args)
 }
 
 fn prompt(label: &str) -> io::Result<String> {
@@ -788,765 +116,63 @@ fn print_passphrase_status_line(label: &str, ok: bool) {
     let _ = io::stdout().flush();
 }
 
-fn msg_string(item: &MessageItem) -> String {
-    match item {
-        MessageItem::Separator(label) => format!("==== {} ====", label),
-        MessageItem::Message { time, name, text, .. } => {
-            format!("{} {}: {}", time, name, text)
-        }
-        MessageItem::Attachment {
-            time,
-            name,
-            label,
-            filename,
-            path,
-            ..
-        } => {
-            format!("{} {}: [{}] {} ({})", time, name, label, filename, path)
-        }
-    }
-}
-
-fn msg_content(item: &MessageItem) -> String {
-    match item {
-        MessageItem::Separator(label) => label.clone(),
-        MessageItem::Message { text, .. } => text.clone(),
-        MessageItem::Attachment { label, filename, .. } => {
-            format!("[{}] {}", label, filename)
-        }
-    }
-}
-
-fn strip_reply_fallback(body: &str) -> &str {
-    if !body.starts_with("> ") {
-        return body;
-    }
-    if let Some(pos) = body.find("\r\n\r\n") {
-        return &body[pos + 4..];
-    }
-    if let Some(pos) = body.find("\n\n") {
-        return &body[pos + 2..];
-    }
-    body
-}
-
-enum WrapToken {
-    Text(String),
-    Newline,
-}
-
-fn wrap_tokens(text: &str) -> Vec<WrapToken> {
-    let mut tokens = Vec::new();
-    let mut current = String::new();
-    let mut current_is_space = None;
-    for ch in text.chars() {
-        if ch == '\n' {
-            if !current.is_empty() {
-                tokens.push(WrapToken::Text(current));
-                current = String::new();
-                current_is_space = None;
-            }
-            tokens.push(WrapToken::Newline);
-            continue;
-        }
-        let is_space = ch.is_whitespace();
-        if current_is_space == Some(is_space) || current_is_space.is_none() {
-            current.push(ch);
-            current_is_space = Some(is_space);
-        } else {
-            tokens.push(WrapToken::Text(current));
-            current = String::new();
-            current.push(ch);
-            current_is_space = Some(is_space);
-        }
-    }
-    if !current.is_empty() {
-        tokens.push(WrapToken::Text(current));
-    }
-    tokens
-}
-
-fn wrap_text_lines(text: &str, width: u16) -> Vec<String> {
-    let width = width.max(1) as usize;
-    if text.is_empty() {
-        return vec![String::new()];
-    }
-    let mut lines = Vec::new();
-    let mut line = String::new();
-    let mut line_len = 0usize;
-    for token in wrap_tokens(text) {
-        match token {
-            WrapToken::Newline => {
-                lines.push(line);
-                line = String::new();
-                line_len = 0;
-            }
-            WrapToken::Text(chunk) => {
-                let chunk_len = chunk.chars().count();
-                if line_len > 0 && line_len + chunk_len > width {
-                    lines.push(line);
-                    line = String::new();
-                    line_len = 0;
-                }
-                if chunk_len > width {
-                    for ch in chunk.chars() {
-                        if line_len >= width {
-                            lines.push(line);
-                            line = String::new();
-                            line_len = 0;
-                        }
-                        line.push(ch);
-                        line_len += 1;
-                    }
-                } else {
-                    line.push_str(&chunk);
-                    line_len += chunk_len;
-                }
-            }
-        }
-    }
-    if lines.is_empty() || !line.is_empty() {
-        lines.push(line);
-    }
-    lines
-}
-
-fn reply_preview_text(app: &App, room_id: Option<&str>, reply_id: &str) -> String {
-    let Some(room_id) = room_id else {
-        return "> (unknown)".to_string();
-    };
-    app.reply_preview(room_id, reply_id)
-        .map(|p| format!("> ({}) {}", p.sender, p.text))
-        .unwrap_or_else(|| "> (unknown)".to_string())
-}
-
-fn message_prefix_spans(
-    time: &str,
-    name: &str,
-    sender_id: &str,
-    own_user_id: Option<&str>,
-    read_receipt: Option<bool>,
-) -> (Vec<Span<'static>>, usize) {
-    let receipt_prefix = if let Some(read) = read_receipt {
-        if read { "● " } else { "○ " }
-    } else {
-        "  "
-    };
-    let time_text = format!("{} ", time);
-    let name_text = format!("{}: ", name);
-    let mut spans = Vec::new();
-    spans.push(Span::styled(
-        receipt_prefix.to_string(),
-        Style::default().fg(Color::Rgb(160, 160, 160)),
-    ));
-    spans.push(Span::styled(
-        time_text.clone(),
-        Style::default().fg(Color::Rgb(238, 193, 99)),
-    ));
-    let name_color = color_for_sender(sender_id, own_user_id);
-    spans.push(Span::styled(
-        name_text.clone(),
-        Style::default()
-            .fg(name_color)
-            .add_modifier(Modifier::BOLD),
-    ));
-    let prefix_len = receipt_prefix.len() + time_text.len() + name_text.len();
-    (spans, prefix_len)
-}
-
-fn message_render_height(
-    app: &App,
-    room_id: Option<&str>,
-    item: &MessageItem,
-    width: u16,
-) -> u16 {
-    let width = width.max(1);
-    match item {
-        MessageItem::Separator(_) => 1,
-        MessageItem::Message {
-            time,
-            name,
-            sender_id,
-            text,
-            reply_to,
-            ..
-        } => {
-            let (_, prefix_len) =
-                message_prefix_spans(time, name, sender_id, app.own_user_id.as_deref(), None);
-            if let Some(reply_id) = reply_to.as_deref() {
-                let preview = reply_preview_text(app, room_id, reply_id);
-                let preview_lines =
-                    wrap_text_lines(&preview, width.saturating_sub(prefix_len as u16)).len();
-                let reply_prefix = reply_prefix(time, name, None);
-                let body_lines = wrap_text_lines(
-                    text,
-                    width.saturating_sub(reply_prefix.len() as u16),
-                )
-                .len();
-                (preview_lines + body_lines) as u16
-            } else {
-                wrap_text_lines(text, width.saturating_sub(prefix_len as u16)).len() as u16
-            }
-        }
-        MessageItem::Attachment {
-            time,
-            name,
-            sender_id,
-            label,
-            filename,
-            reply_to,
-            ..
-        } => {
-            let (_, prefix_len) =
-                message_prefix_spans(time, name, sender_id, app.own_user_id.as_deref(), None);
-            let text = format!("[{}] {}", label, filename);
-            if let Some(reply_id) = reply_to.as_deref() {
-                let preview = reply_preview_text(app, room_id, reply_id);
-                let preview_lines =
-                    wrap_text_lines(&preview, width.saturating_sub(prefix_len as u16)).len();
-                let reply_prefix = reply_prefix(time, name, None);
-                let body_lines = wrap_text_lines(
-                    &text,
-                    width.saturating_sub(reply_prefix.len() as u16),
-                )
-                .len();
-                (preview_lines + body_lines) as u16
-            } else {
-                wrap_text_lines(&text, width.saturating_sub(prefix_len as u16)).len() as u16
-            }
-        }
-    }
-}
-
-fn message_window_start(
-    app: &App,
-    room_id: Option<&str>,
-    messages: &[MessageItem],
-    height: u16,
-    width: u16,
-    selected: Option<usize>,
-) -> usize {
-    if messages.is_empty() || height == 0 {
-        return 0;
-    }
-    let target = selected.filter(|idx| *idx < messages.len());
-    let mut idx = target.unwrap_or_else(|| messages.len().saturating_sub(1));
-    let start_idx = idx;
-    let mut remaining = height as i32;
-    loop {
-        let item_height = message_render_height(app, room_id, &messages[idx], width) as i32;
-        if remaining - item_height < 0 {
-            return if idx == start_idx { idx } else { idx + 1 };
-        }
-        remaining -= item_height;
-        if idx == 0 {
-            return 0;
-        }
-        idx = idx.saturating_sub(1);
-    }
-}
-
-fn render_messages_area(
-    f: &mut ratatui::Frame,
-    area: Rect,
-    app: &mut App,
-) {
-    let block = Block::default().borders(Borders::ALL).title("Messages");
-    f.render_widget(&block, area);
-    let inner = block.inner(area);
-    if inner.width == 0 || inner.height == 0 {
-        return;
-    }
-    let room_id = app.selected_room_id();
-    if let Some(room) = app.selected_room() {
-        if room.state == RoomListState::Invited {
-            let inviter = room.inviter.as_deref().unwrap_or("Unknown user");
-            let lines = vec![
-                Line::from(format!("Invitation from {}", inviter)),
-                Line::from("Ctrl+A to accept, Ctrl+D to decline."),
-            ];
-            let text = Paragraph::new(lines).wrap(Wrap { trim: false });
-            f.render_widget(text, inner);
-            return;
-        }
-    }
-    let messages = app
-        .current_messages()
-        .map(|items| items.as_slice())
-        .unwrap_or(&[]);
-    let start = message_window_start(
-        app,
-        room_id.as_deref(),
-        messages,
-        inner.height,
-        inner.width,
-        app.message_selected,
-    );
-    let buf = f.buffer_mut();
-    let mut y = inner.y;
-    let max_y = inner.y + inner.height;
-    for (idx, item) in messages.iter().enumerate().skip(start) {
-        if y >= max_y {
-            break;
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = parse_args()?;
+    match args.mode {
+        RunMode::Stop => {
+            daemon::stop()?;
+            println!("Background marty stopped.");
+            return Ok(());
         }
-        let selected = app.message_selected == Some(idx);
-        match item {
-            MessageItem::Separator(label) => {
-                let line = format_separator(label, inner.width);
-                draw_plain_line(buf, inner, y, &line, selected);
-                y = y.saturating_add(1);
-            }
-            MessageItem::Message {
-                time,
-                name,
-                sender_id,
-                text,
-                reply_to,
-                event_id,
-                ..
-            } => {
-                if let (Some(reply_id), Some(room_id)) = (reply_to.as_deref(), room_id.as_deref())
-                {
-                    let reply_text = reply_preview_text(app, Some(room_id), reply_id);
-                    let read_receipt =
-                        app.read_receipt_for(room_id, sender_id, event_id.as_deref());
-                    let (prefix_spans, prefix_len) = message_prefix_spans(
-                        time,
-                        name,
-                        sender_id,
-                        app.own_user_id.as_deref(),
-                        read_receipt,
-                    );
-                    y = draw_wrapped_spans(
-                        buf,
-                        inner,
-                        y,
-                        max_y,
-                        &prefix_spans,
-                        prefix_len,
-                        &reply_text,
-                        Some(Style::default().fg(Color::Rgb(150, 150, 150))),
-                        selected,
-                    );
-                    if y >= max_y {
-                        break;
-                    }
-                    let prefix = reply_prefix(time, name, read_receipt);
-                    let prefix_len = prefix.len();
-                    let prefix_spans = vec![Span::raw(prefix)];
-                    y = draw_wrapped_spans(
-                        buf,
-                        inner,
-                        y,
-                        max_y,
-                        &prefix_spans,
-                        prefix_len,
-                        text,
-                        None,
-                        selected,
-                    );
-                } else {
-                    let read_receipt = room_id
-                        .as_deref()
-                        .and_then(|id| app.read_receipt_for(id, sender_id, event_id.as_deref()));
-                    let (prefix_spans, prefix_len) = message_prefix_spans(
-                        time,
-                        name,
-                        sender_id,
-                        app.own_user_id.as_deref(),
-                        read_receipt,
-                    );
-                    y = draw_wrapped_spans(
-                        buf,
-                        inner,
-                        y,
-                        max_y,
-                        &prefix_spans,
-                        prefix_len,
-                        text,
-                        None,
-                        selected,
-                    );
-                }
-            }
-            MessageItem::Attachment {
-                time,
-                name,
-                sender_id,
-                label,
-                filename,
-                reply_to,
-                event_id,
-                ..
-            } => {
-                let text = format!("[{}] {}", label, filename);
-                if let (Some(reply_id), Some(room_id)) = (reply_to.as_deref(), room_id.as_deref())
-                {
-                    let reply_text = reply_preview_text(app, Some(room_id), reply_id);
-                    let read_receipt =
-                        app.read_receipt_for(room_id, sender_id, event_id.as_deref());
-                    let (prefix_spans, prefix_len) = message_prefix_spans(
-                        time,
-                        name,
-                        sender_id,
-                        app.own_user_id.as_deref(),
-                        read_receipt,
-                    );
-                    y = draw_wrapped_spans(
-                        buf,
-                        inner,
-                        y,
-                        max_y,
-                        &prefix_spans,
-                        prefix_len,
-                        &reply_text,
-                        Some(Style::default().fg(Color::Rgb(150, 150, 150))),
-                        selected,
-                    );
-                    if y >= max_y {
-                        break;
-                    }
-                    let prefix = reply_prefix(time, name, read_receipt);
-                    let prefix_len = prefix.len();
-                    let prefix_spans = vec![Span::raw(prefix)];
-                    y = draw_wrapped_spans(
-                        buf,
-                        inner,
-                        y,
-                        max_y,
-                        &prefix_spans,
-                        prefix_len,
-                        &text,
-                        None,
-                        selected,
-                    );
-                } else {
-                    let read_receipt = room_id
-                        .as_deref()
-                        .and_then(|id| app.read_receipt_for(id, sender_id, event_id.as_deref()));
-                    let (prefix_spans, prefix_len) = message_prefix_spans(
-                        time,
-                        name,
-                        sender_id,
-                        app.own_user_id.as_deref(),
-                        read_receipt,
-                    );
-                    y = draw_wrapped_spans(
-                        buf,
-                        inner,
-                        y,
-                        max_y,
-                        &prefix_spans,
-                        prefix_len,
-                        &text,
-                        None,
-                        selected,
-                    );
-                }
-            }
+        RunMode::Daemon | RunMode::NotifyOnly => return run_daemon(&args).await,
+        RunMode::Attach if !daemon::is_running() => {
+            return Err(anyhow!("no background marty is running"));
         }
+        RunMode::Attach | RunMode::Interactive => {}
     }
-}
-
-fn format_help_line(line: &str) -> String {
-    const KEY_COL: usize = 18;
-    let Some((left, right)) = line.split_once('\t') else {
-        return line.to_string();
-    };
-    let left_len = left.len();
-    let pad = if left_len >= KEY_COL { 1 } else { KEY_COL - left_len };
-    format!("{}{}{}", left, " ".repeat(pad), right)
-}
-
-fn reply_prefix(time: &str, name: &str, read_receipt: Option<bool>) -> String {
-    let receipt_prefix = if let Some(read) = read_receipt {
-        if read { "● " } else { "○ " }
+    let profile = StartupProfile::new(args.profile_startup);
+    // A running background instance owns the data dir; attach to it instead.
+    let attach = daemon::is_running();
+    let lock = if attach {
+        None
     } else {
-        "  "
-    };
-    format!("{}{} {}: ", receipt_prefix, time, name)
-}
-
-fn cursor_position(input: &str, cursor: usize, width: u16) -> (u16, u16) {
-    let width = width.max(1) as usize;
-    let mut row = 0u16;
-    let mut col = 0usize;
-    let mut count = 0usize;
-    for token in wrap_tokens(input) {
-        if count >= cursor {
-            break;
-        }
-        match token {
-            WrapToken::Newline => {
-                if count == cursor {
-                    break;
-                }
-                count += 1;
-                row = row.saturating_add(1);
-                col = 0;
-            }
-            WrapToken::Text(chunk) => {
-                let chunk_len = chunk.chars().count();
-                if col > 0 && col + chunk_len > width {
-                    row = row.saturating_add(1);
-                    col = 0;
-                }
-                if chunk_len > width {
-                    for ch in chunk.chars() {
-                        if count >= cursor {
-                            break;
-                        }
-                        if col >= width {
-                            row = row.saturating_add(1);
-                            col = 0;
-                        }
-                        col += 1;
-                        count += 1;
-                        let _ = ch;
-                    }
-                } else {
-                    for ch in chunk.chars() {
-                        if count >= cursor {
-                            break;
-                        }
-                        col += 1;
-                        count += 1;
-                        let _ = ch;
-                    }
-                }
-            }
-        }
-    }
-    (row, col as u16)
-}
-
-fn format_separator(label: &str, width: u16) -> String {
-    let content_width = width as usize;
-    let label_width = label.len();
-    if content_width == 0 {
-        return String::new();
-    }
-    if label_width + 2 >= content_width {
-        return label.to_string();
-    }
-    let fill = content_width - label_width - 2;
-    let left = fill / 2;
-    let right = fill - left;
-    format!("{} {} {}", "=".repeat(left), label, "=".repeat(right))
-}
-
-fn color_for_sender(sender_id: &str, own_user_id: Option<&str>) -> Color {
-    if is_own_sender(sender_id, own_user_id) {
-        return Color::Rgb(180, 140, 210);
-    }
-    let palette = [
-        Color::Rgb(109, 188, 226),
-        Color::Rgb(140, 210, 180),
-        Color::Rgb(232, 182, 113),
-        Color::Rgb(198, 140, 210),
-        Color::Rgb(220, 150, 150),
-        Color::Rgb(120, 200, 140),
-    ];
-    let mut hasher = std::collections::hash_map::DefaultHasher::new();
-    sender_id.hash(&mut hasher);
-    let idx = (hasher.finish() as usize) % palette.len();
-    palette[idx]
-}
-
-fn is_own_sender(sender_id: &str, own_user_id: Option<&str>) -> bool {
-    let Some(own) = own_user_id else {
-        return false;
+        acquire_lock(&args)?
     };
-    if sender_id == own {
-        return true;
-    }
-    format_sender(sender_id) == format_sender(own)
-}
-
-fn draw_wrapped_spans(
-    buf: &mut Buffer,
-    area: Rect,
-    mut y: u16,
-    max_y: u16,
-    prefix_spans: &[Span<'static>],
-    prefix_len: usize,
-    text: &str,
-    text_style: Option<Style>,
-    selected: bool,
-) -> u16 {
-    if y >= max_y {
-        return y;
-    }
-    let width = area.width.max(1);
-    let text_width = width.saturating_sub(prefix_len as u16).max(1);
-    let lines = wrap_text_lines(text, text_width);
-    for (idx, line) in lines.iter().enumerate() {
-        if y >= max_y {
-            break;
-        }
-        let mut spans: Vec<Span<'static>> = Vec::new();
-        if idx == 0 {
-            spans.extend(prefix_spans.iter().cloned());
-        } else {
-            spans.push(Span::raw(" ".repeat(prefix_len)));
-        }
-        let line_span = match text_style {
-            Some(style) => Span::styled(line.clone(), style),
-            None => Span::raw(line.clone()),
-        };
-        spans.push(line_span);
-        draw_spans_line(buf, area, y, &spans, selected);
-        y = y.saturating_add(1);
-    }
-    y
-}
-
-fn draw_plain_line(buf: &mut Buffer, area: Rect, y: u16, text: &str, selected: bool) {
-    if y >= area.y + area.height {
-        return;
-    }
-    if selected {
-        fill_line(buf, area, y);
-        let style = Style::default().bg(SELECTED_BG).fg(Color::Black);
-        let _ = buf.set_stringn(area.x, y, text, area.width as usize, style);
-    } else {
-        let _ = buf.set_stringn(area.x, y, text, area.width as usize, Style::default());
-    }
-}
-
-fn draw_spans_line(buf: &mut Buffer, area: Rect, y: u16, spans: &[Span], selected: bool) {
-    if y >= area.y + area.height {
-        return;
-    }
-    let mut x = area.x;
-    let max_width = area.width as usize;
-    if selected {
-        fill_line(buf, area, y);
-    }
-    for span in spans {
-        if (x - area.x) as usize >= max_width {
-            break;
-        }
-        let remaining = max_width.saturating_sub((x - area.x) as usize);
-        let style = if selected {
-            Style::default()
-                .bg(SELECTED_BG)
-                .fg(Color::Black)
-                .add_modifier(Modifier::BOLD)
-        } else {
-            span.style
-        };
-        let (next_x, _) = buf.set_stringn(x, y, span.content.as_ref(), remaining, style);
-        x = next_x;
-    }
-}
-
-fn fill_line(buf: &mut Buffer, area: Rect, y: u16) {
-    for x in 0..area.width {
-        buf.get_mut(area.x + x, y)
-            .set_symbol(" ")
-            .set_bg(SELECTED_BG)
-            .set_fg(Color::Black);
-    }
-}
-
-fn copy_to_clipboard(text: &str) -> bool {
-    if env::var_os("WAYLAND_DISPLAY").is_some() {
-        return copy_with_wl_copy(text);
-    }
-    if Clipboard::new()
-        .and_then(|mut cb| cb.set_text(text.to_string()))
-        .is_ok()
-    {
-        return true;
-    }
-    copy_with_wl_copy(text)
-}
-
-fn copy_with_wl_copy(text: &str) -> bool {
-    if let Ok(mut child) = Command::new("wl-copy")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()
-    {
-        if let Some(mut stdin) = child.stdin.take() {
-            let _ = stdin.write_all(text.as_bytes());
-        }
-        return child.wait().is_ok();
-    }
-    false
-}
-
-fn extract_url(text: &str) -> Option<String> {
-    for part in text.split_whitespace() {
-        if part.starts_with("http://") || part.starts_with("https://") {
-            return Some(part.trim_end_matches(|c: char| c == ')' || c == ',' || c == '.').to_string());
-        }
-    }
-    None
-}
-
-fn open_url(url: &str) -> bool {
-    #[cfg(target_os = "windows")]
-    {
-        return Command::new("cmd")
-            .args(["/C", "start", "", url])
-            .spawn()
-            .is_ok();
-    }
-    #[cfg(target_os = "macos")]
-    {
-        return Command::new("open").arg(url).spawn().is_ok();
-    }
-    #[cfg(all(not(target_os = "windows"), not(target_os = "macos")))]
-    {
-        return Command::new("xdg-open").arg(url).spawn().is_ok();
-    }
-}
-
-fn open_path(path: &Path) -> bool {
-    #[cfg(target_os = "windows")]
-    {
-        return Command::new("cmd")
-            .args(["/C", "start", "", &path.display().to_string()])
-            .spawn()
-            .is_ok();
-    }
-    #[cfg(target_os = "macos")]
-    {
-        return Command::new("open").arg(path).spawn().is_ok();
-    }
-    #[cfg(all(not(target_os = "windows"), not(target_os = "macos")))]
-    {
-        return Command::new("xdg-open").arg(path).spawn().is_ok();
-    }
-}
-
-fn notify_send(title: &str, body: &str) {
-    let _ = Command::new("notify-send")
-        .arg(title)
-        .arg(body)
-        .spawn();
-}
-
-#[tokio::main]
-async fn main() -> Result<()> {
     let config_file = config_path()?;
     let mut cfg = load_config(&config_file)?;
+    let stats_file = args.stats_file.clone().or_else(|| cfg.stats_file.clone());
+    let metrics = Metrics::new(stats_file.is_some());
+    if let Some(path) = stats_file {
+        let interval = cfg.stats_interval_secs.unwrap_or(60).max(1);
+        metrics.spawn_stats_writer(path, Duration::from_secs(interval));
+    }
     let passphrase_prompt = if cfg.accounts.is_empty() {
         "Create passphrase: "
     } else {
         "Enter passphrase: "
     };
     let passphrase = prompt_password(passphrase_prompt)?;
-    match decrypt_sessions(&mut cfg, &passphrase) {
-        Ok(_) => print_passphrase_status_line(passphrase_prompt, true),
+    match profile.time("config decrypt", || decrypt_sessions(&mut cfg, &passphrase)) {
+        Ok(kdf) => {
+            profile.record("passphrase KDF", kdf);
+            print_passphrase_status_line(passphrase_prompt, true);
+        }
         Err(err) => {
             print_passphrase_status_line(passphrase_prompt, false);
             return Err(err.into());
         }
     }
+    if attach {
+        let own_user_id = cfg
+            .active
+            .and_then(|idx| cfg.accounts.get(idx))
+            .and_then(|account| account.user_id.clone());
+        let mut ui = UiOptions::new(args.read_only, &args.exec, &cfg, &profile, &metrics);
+        ui.attached = true;
+        let (evt_tx, evt_rx, cmd_tx) = daemon::connect().await?;
+        return run_ui(evt_tx, evt_rx, cmd_tx, passphrase, own_user_id, ui);
+    }
     if encrypt_missing_sessions(&mut cfg, &passphrase)? {
         save_config(&config_file, &cfg)?;
     }
@@ -1555,527 +181,193 @@ async fn main() -> Result<()> {
         let homeserver = prompt("Homeserver URL: ")?;
         let username = prompt("Username: ")?;
         let password = prompt_password("Password: ")?;
+        let device_name = cfg.device_display_name();
         let (client, account) =
-            login_with_recovery(&homeserver, &username, &password, &passphrase).await?;
+            login_with_recovery(&homeserver, &username, &password, &passphrase, &device_name)
+                .await?;
         let mut account = account.clone();
         encrypt_account_session(&mut account, &passphrase)?;
         let own_user_id = account.user_id.clone();
         cfg.accounts.push(account);
         cfg.active = Some(0);
         save_config(&config_file, &cfg)?;
-        return start_matrix(client, passphrase, own_user_id).await;
+        let options = sync_options(&cfg, &profile, &metrics);
+        let ui = UiOptions::new(args.read_only, &args.exec, &cfg, &profile, &metrics);
+        if cfg.background_on_quit {
+            return attach_background(client, lock, passphrase, own_user_id, ui).await;
+        }
+        return start_matrix(client, passphrase, own_user_id, ui, options).await;
     } else {
         let idx = cfg.active.unwrap_or(0).min(cfg.accounts.len().saturating_sub(1));
         cfg.accounts[idx].clone()
     };
 
     let client = if let Some(session) = account.session.clone() {
+        let started = Instant::now();
         let client = build_client_with_recovery(&account.homeserver, &passphrase).await?;
-        if client.restore_session(session).await.is_ok() {
+        let restored = client.restore_session(session).await.is_ok();
+        profile.record("client restore", started.elapsed());
+        if restored {
             client
         } else {
             let password = prompt_password("Password: ")?;
-            let (client, updated) =
-                login_with_recovery(&account.homeserver, &account.username, &password, &passphrase)
-                    .await?;
+            let (client, updated) = login_with_recovery(
+                &account.homeserver,
+                &account.username,
+                &password,
+                &passphrase,
+                &cfg.device_display_name(),
+            )
+            .await?;
             update_account_session(&mut cfg, &updated, &passphrase)?;
             save_config(&config_file, &cfg)?;
             client
         }
     } else {
         let password = prompt_password("Password: ")?;
-        let (client, updated) =
-            login_with_recovery(&account.homeserver, &account.username, &password, &passphrase)
-                .await?;
+        let (client, updated) = login_with_recovery(
+            &account.homeserver,
+            &account.username,
+            &password,
+            &passphrase,
+            &cfg.device_display_name(),
+        )
+        .await?;
         update_account_session(&mut cfg, &updated, &passphrase)?;
         save_config(&config_file, &cfg)?;
         client
     };
 
-    start_matrix(client, passphrase, account.user_id.clone()).await
+    let options = sync_options(&cfg, &profile, &metrics);
+    let ui = UiOptions::new(args.read_only, &args.exec, &cfg, &profile, &metrics);
+    if cfg.background_on_quit {
+        return attach_background(client, lock, passphrase, account.user_id.clone(), ui).await;
+    }
+    start_matrix(client, passphrase, account.user_id.clone(), ui, options).await
 }
 
-async fn start_matrix(
+fn acquire_lock(args: &CliArgs) -> Result<Option<DataDirLock>> {
+    match lock_data_dir() {
+        Ok(lock) => Ok(Some(lock)),
+        Err(err) if args.force => {
+            eprintln!("warning: {}; continuing because of --force", err);
+            Ok(None)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// `marty --daemon`: restore the saved session and sync headless, serving
+/// attached UIs over the control socket (or, with `--notify-only`, only
+/// sending notifications).
+async fn run_daemon(args: &CliArgs) -> Result<()> {
+    let _lock = acquire_lock(args)?;
+    let mut cfg = load_config(&config_path()?)?;
+    let passphrase = if let Some(passphrase) = daemon::credential_passphrase() {
+        passphrase
+    } else if io::stdin().is_terminal() {
+        prompt_password("Enter passphrase: ")?
+    } else {
+        let mut line = String::new();
+        io::stdin().read_line(&mut line)?;
+        line.trim_end_matches(['\r', '\n']).to_string()
+    };
+    decrypt_sessions(&mut cfg, &passphrase)?;
+    let account = cfg
+        .active
+        .and_then(|idx| cfg.accounts.get(idx))
+        .cloned()
+        .ok_or_else(|| anyhow!("no account configured; log in interactively first"))?;
+    let session = account
+        .session
+        .clone()
+        .ok_or_else(|| anyhow!("no saved session; log in interactively first"))?;
+    let client = build_client(&account.homeserver, &passphrase).await?;
+    client.restore_session(session).await?;
+
+    let stats_file = args.stats_file.clone().or_else(|| cfg.stats_file.clone());
+    let metrics = Metrics::new(stats_file.is_some());
+    if let Some(path) = stats_file {
+        let interval = cfg.stats_interval_secs.unwrap_or(60).max(1);
+        metrics.spawn_stats_writer(path, Duration::from_secs(interval));
+    }
+    let options = sync_options(&cfg, &StartupProfile::new(false), &metrics);
+    let lines: Vec<String> = cfg
+        .startup_commands
+        .iter()
+        .cloned()
+        .chain(args.exec.iter().flat_map(|script| split_exec(script)))
+        .collect();
+    let startup = daemon_startup_commands(&lines);
+    if args.mode == RunMode::NotifyOnly {
+        return daemon::notify_only(client, passphrase, options, account.user_id, startup).await;
+    }
+    daemon::serve(client, passphrase, options, account.user_id, startup).await
+}
+
+/// The configured startup commands, then the `--exec` ones, for a
+/// background instance. There is no selected room, so commands that need
+/// one are reported and dropped.
+fn daemon_startup_commands(lines: &[String]) -> Vec<MatrixCommand> {
+    lines
+        .iter()
+        .filter_map(|line| match parse_command(line, None) {
+            Some(Ok(cmd)) => Some(cmd),
+            Some(Err(err)) => {
+                eprintln!("startup command {}: {}", line, err);
+                None
+            }
+            None => {
+                eprintln!("startup command {}: not a command", line);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Hands the session over to a detached background instance and runs the
+/// UI against it, so quitting leaves sync running.
+async fn attach_background(
     client: matrix_sdk::Client,
+    lock: Option<DataDirLock>,
     passphrase: String,
     own_user_id: Option<String>,
+    mut ui: UiOptions,
 ) -> Result<()> {
-    let (evt_tx, evt_rx) = mpsc::unbounded_channel();
-    let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
-
-    tokio::spawn(start_sync(client, passphrase.clone(), cmd_rx, evt_tx));
-
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen)?;
-    let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
-
-    let res = run_app(&mut terminal, evt_rx, cmd_tx, passphrase, own_user_id);
-
-    disable_raw_mode()?;
-    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
-    terminal.show_cursor()?;
+    drop(client);
+    drop(lock);
+    daemon::spawn_detached(&passphrase).await?;
+    ui.attached = true;
+    let (evt_tx, evt_rx, cmd_tx) = daemon::connect().await?;
+    run_ui(evt_tx, evt_rx, cmd_tx, passphrase, own_user_id, ui)
+}
 
-    res?;
-    Ok(())
+fn sync_options(
+    cfg: &config::AppConfig,
+    profile: &StartupProfile,
+    metrics: &Metrics,
+) -> SyncOptions {
+    SyncOptions {
+        render_unknown_events: cfg.render_unknown_events,
+        profile: profile.clone(),
+        metrics: metrics.clone(),
+        bandwidth_saver: Arc::new(AtomicBool::new(cfg.bandwidth_saver)),
+    }
 }
 
-fn run_app(
-    terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
-    mut evt_rx: mpsc::UnboundedReceiver<MatrixEvent>,
-    cmd_tx: mpsc::UnboundedSender<MatrixCommand>,
+async fn start_matrix(
+    client: matrix_sdk::Client,
     passphrase: String,
     own_user_id: Option<String>,
-) -> io::Result<()> {
-    let mut app = App::new();
-    app.own_user_id = own_user_id;
-    let mut last_tick = Instant::now();
-    if let Ok(base) = messages_dir() {
-        if let Ok(persisted) = load_all_messages(&base, &passphrase) {
-            for (room_key, mut records) in persisted {
-                records.sort_by_key(|m| m.timestamp);
-                for record in records {
-                    let room_id = room_key.replace('_', ":");
-                    if let Some(path) = record.attachment_path.as_deref() {
-                        let label = record
-                            .attachment_kind
-                            .as_deref()
-                            .unwrap_or("file");
-                        let name = record
-                            .attachment_name
-                            .as_deref()
-                            .unwrap_or(&record.body);
-                        app.push_attachment_with_time(
-                            &room_id,
-                            record.event_id.as_deref(),
-                            record.timestamp,
-                            &record.sender,
-                            label,
-                            name,
-                            path,
-                            record.reply_to.as_deref(),
-                        );
-                    } else {
-                        app.push_message_with_time(
-                            &room_id,
-                            record.event_id.as_deref(),
-                            record.timestamp,
-                            &record.sender,
-                            &record.body,
-                            record.reply_to.as_deref(),
-                        );
-                    }
-                }
-            }
-            for (room_id, ts) in app.last_message_ts.clone() {
-                app.last_seen_ts.entry(room_id).or_insert(ts);
-            }
-        }
-        if let Ok(persisted) = load_all_read_receipts(&base, &passphrase) {
-            for (room_key, records) in persisted {
-                let room_id = room_key.replace('_', ":");
-                let set = app.read_receipts.entry(room_id).or_default();
-                for event_id in records {
-                    set.insert(event_id);
-                }
-            }
-        }
-    }
-
-    loop {
-        while let Ok(evt) = evt_rx.try_recv() {
-            match evt {
-                MatrixEvent::Rooms(rooms) => app.update_rooms(rooms),
-                MatrixEvent::Message {
-                    room_id,
-                    event_id,
-                    sender,
-                    body,
-                    timestamp,
-                    reply_to,
-                } => {
-                    app.handle_incoming_message(
-                        &room_id,
-                        Some(&event_id),
-                        timestamp,
-                        &sender,
-                        &body,
-                        reply_to.as_deref(),
-                    );
-                    if app.should_notify(&room_id, &sender) {
-                        let title = format!("{} — {}", app.room_name(&room_id), format_sender(&sender));
-                        notify_send(&title, &body);
-                    }
-                }
-                MatrixEvent::Attachment {
-                    room_id,
-                    event_id,
-                    sender,
-                    name,
-                    path,
-                    kind,
-                    timestamp,
-                    reply_to,
-                } => {
-                    app.handle_incoming_attachment(
-                        &room_id,
-                        Some(&event_id),
-                        timestamp,
-                        &sender,
-                        &kind,
-                        &name,
-                        &path,
-                        reply_to.as_deref(),
-                    );
-                    if app.should_notify(&room_id, &sender) {
-                        let title = format!("{} — {}", app.room_name(&room_id), format_sender(&sender));
-                        let body = format!("[{}] {}", kind, name);
-                        notify_send(&title, &body);
-                    }
-                }
-                MatrixEvent::Receipt { room_id, event_id } => {
-                    app.mark_read_receipt(&room_id, &event_id);
-                    if let Ok(base) = messages_dir() {
-                        if let Some(set) = app.read_receipts.get(&room_id) {
-                            let _ = store_read_receipts(&base, &passphrase, &room_id, set);
-                        }
-                    }
-                }
-                MatrixEvent::BackfillDone => {
-                    app.notifications_ready = true;
-                }
-                MatrixEvent::VerificationEmojis { emojis } => {
-                    app.show_verification_emojis(emojis);
-                }
-                MatrixEvent::VerificationStatus { message } => {
-                    app.show_verification_status(&message);
-                }
-                MatrixEvent::VerificationDone => {
-                    app.show_verification_status("Verification complete.");
-                }
-                MatrixEvent::VerificationCancelled { reason } => {
-                    app.show_verification_status(&format!("Verification cancelled: {}", reason));
-                }
-            }
-        }
-        if app.verification_emojis.is_none() {
-            if let Some(until) = app.verification_until {
-                if Instant::now() >= until {
-                    app.clear_verification();
-                }
-            }
-        }
-
-        terminal.draw(|f| {
-            let size = f.size();
-
-            if app.help_open {
-                let help_lines: Vec<Line> = HELP_LINES
-                    .iter()
-                    .map(|line| {
-                        let rendered = format_help_line(line);
-                        if line.starts_with(' ') || line.is_empty() {
-                            Line::from(Span::raw(rendered))
-                        } else {
-                            Line::from(Span::styled(
-                                rendered,
-                                Style::default()
-                                    .fg(Color::Rgb(140, 200, 220))
-                                    .add_modifier(Modifier::BOLD),
-                            ))
-                        }
-                    })
-                    .collect();
-                let help = Paragraph::new(help_lines)
-                    .block(Block::default().borders(Borders::ALL).title("Help"))
-                    .wrap(Wrap { trim: false })
-                    .scroll((app.help_scroll, 0));
-                f.render_widget(help, size);
-            } else {
-                let main_chunks = Layout::default()
-                    .direction(Direction::Horizontal)
-                    .constraints([Constraint::Length(28), Constraint::Min(1)])
-                    .split(size);
-
-                let right_chunks = Layout::default()
-                    .direction(Direction::Vertical)
-                    .constraints([
-                        Constraint::Min(3),
-                        Constraint::Length(if app.input_multiline { 5 } else { 3 }),
-                    ])
-                    .split(main_chunks[1]);
-
-                let channels: Vec<ListItem> = app
-                    .rooms
-                    .iter()
-                    .map(|room| {
-                        let label = if room.state == RoomListState::Invited {
-                            format!("[invite] {}", room.name)
-                        } else {
-                            room.name.clone()
-                        };
-                        let unread = *app.unread_counts.get(&room.room_id).unwrap_or(&0);
-                        let display = if unread > 0 {
-                            format!("{} [{}]", label, unread)
-                        } else {
-                            label
-                        };
-                        let style = if unread > 0 {
-                            Style::default().add_modifier(Modifier::BOLD)
-                        } else {
-                            Style::default()
-                        };
-                        ListItem::new(Line::from(Span::styled(display, style)))
-                    })
-                    .collect();
-
-                let mut list_state = ListState::default();
-                if !app.rooms.is_empty() {
-                    list_state.select(Some(app.selected));
-                }
-
-                let channels_list = List::new(channels)
-                    .block(Block::default().borders(Borders::ALL).title("Channels"))
-                    .highlight_style(
-                        Style::default()
-                            .bg(SELECTED_BG)
-                            .fg(Color::Black)
-                            .add_modifier(Modifier::BOLD),
-                    );
-
-                f.render_stateful_widget(channels_list, main_chunks[0], &mut list_state);
-
-                render_messages_area(f, right_chunks[0], &mut app);
-                let input_area = right_chunks[1];
-                let inner_width = input_area.width.saturating_sub(2);
-                let inner_height = input_area.height.saturating_sub(2);
-                let (row, col) = if app.input_multiline {
-                    cursor_position(&app.input, app.input_cursor, inner_width)
-                } else {
-                    (0, app.input_cursor as u16)
-                };
-                let (scroll_y, scroll_x) = if app.input_multiline {
-                    let scroll_y = row.saturating_sub(inner_height.saturating_sub(1));
-                    (scroll_y, 0)
-                } else {
-                    let scroll_x = col.saturating_sub(inner_width.saturating_sub(1));
-                    (0, scroll_x)
-                };
-                let input = if app.input_multiline {
-                    Paragraph::new(app.input.as_str())
-                        .block(Block::default().borders(Borders::ALL).title("Input"))
-                        .wrap(Wrap { trim: false })
-                        .scroll((scroll_y, 0))
-                } else {
-                    Paragraph::new(app.input.as_str())
-                        .block(Block::default().borders(Borders::ALL).title("Input"))
-                        .scroll((0, scroll_x))
-                };
-                f.render_widget(input, input_area);
-                let x = input_area.x + 1;
-                let y = input_area.y + 1;
-                let cursor_y = y
-                    .saturating_add(row.saturating_sub(scroll_y))
-                    .min(input_area.y + input_area.height - 2);
-                let cursor_x = x
-                    + col
-                        .saturating_sub(scroll_x)
-                        .min(inner_width.saturating_sub(1));
-                f.set_cursor(cursor_x, cursor_y);
-            }
-
-            if let Some(ref prompt) = app.prompt {
-                render_prompt(f, size, prompt);
-            }
-            if app.verification_emojis.is_some() || app.verification_status.is_some() {
-                render_verification_overlay(f, size, &app);
-            }
-            if app.is_syncing && !app.help_open {
-                render_sync_indicator(f, size);
-            }
-        })?;
-
-        let timeout = TICK_RATE
-            .checked_sub(last_tick.elapsed())
-            .unwrap_or_else(|| Duration::from_secs(0));
-        if event::poll(timeout)? {
-            if let Event::Key(key) = event::read()? {
-                if key.kind == KeyEventKind::Press {
-                    if app.prompt.is_some() {
-                        match key.code {
-                            KeyCode::Esc => app.cancel_prompt(),
-                            KeyCode::Enter => {
-                                if let Some(cmd) = app.submit_prompt() {
-                                    let _ = cmd_tx.send(cmd);
-                                }
-                            }
-                            KeyCode::Backspace => app.prompt_backspace(),
-                            KeyCode::Char(c) => app.prompt_push(c),
-                            _ => {}
-                        }
-                        continue;
-                    }
-                    match key.code {
-                        KeyCode::Char('q') if key.modifiers.contains(KeyModifiers::ALT) => {
-                            app.should_quit = true
-                        }
-                        KeyCode::F(1) => app.toggle_help(),
-                        KeyCode::Esc => {
-                            if app.verification_status.is_some()
-                                && app.verification_emojis.is_none()
-                            {
-                                app.clear_verification();
-                            } else {
-                                app.on_escape();
-                            }
-                        }
-                        KeyCode::Char('a') if key.modifiers.contains(KeyModifiers::ALT) => {
-                            app.start_add_prompt();
-                        }
-                        KeyCode::Char('j') if key.modifiers.contains(KeyModifiers::ALT) => {
-                            app.start_add_prompt();
-                        }
-                        KeyCode::Char('d') if key.modifiers.contains(KeyModifiers::ALT) => {
-                            app.start_delete_prompt();
-                        }
-                        KeyCode::Char('v') if key.modifiers.contains(KeyModifiers::ALT) => {
-                            let _ = cmd_tx.send(MatrixCommand::StartVerification);
-                            app.show_verification_status("Waiting for verification...");
-                        }
-                        KeyCode::Enter if key.modifiers.contains(KeyModifiers::ALT) => {
-                            app.input_multiline = !app.input_multiline;
-                        }
-                        KeyCode::Char('a') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                            if app.selected_room_is_invited() {
-                                if let Some(room_id) = app.selected_room_id() {
-                                    let _ = cmd_tx.send(MatrixCommand::AcceptInvite { room_id });
-                                }
-                            }
-                        }
-                        KeyCode::Char('d') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                            if app.selected_room_is_invited() {
-                                if let Some(room_id) = app.selected_room_id() {
-                                    let _ = cmd_tx.send(MatrixCommand::RejectInvite { room_id });
-                                }
-                            }
-                        }
-                        KeyCode::Char('y') if app.verification_emojis.is_some() => {
-                            let _ = cmd_tx.send(MatrixCommand::ConfirmVerification);
-                            app.show_verification_status("Verification confirmed.");
-                        }
-                        KeyCode::Char('n') if app.verification_emojis.is_some() => {
-                            let _ = cmd_tx.send(MatrixCommand::CancelVerification);
-                            app.show_verification_status("Verification cancelled.");
-                        }
-                        KeyCode::Up if key.modifiers.contains(KeyModifiers::ALT) => {
-                            app.on_message_up()
-                        }
-                        KeyCode::Down if key.modifiers.contains(KeyModifiers::ALT) => {
-                            app.on_message_down()
-                        }
-                        KeyCode::Up => {
-                            if app.help_open {
-                                app.on_help_up();
-                            } else {
-                                app.on_up();
-                            }
-                        }
-                        KeyCode::Down => {
-                            if app.help_open {
-                                app.on_help_down();
-                            } else {
-                                app.on_down();
-                            }
-                        }
-                        KeyCode::PageDown => {
-                            if app.help_open {
-                                app.on_help_down();
-                            }
-                        }
-                        KeyCode::PageUp => {
-                            if app.help_open {
-                                app.on_help_up();
-                            }
-                        }
-                        KeyCode::Enter => {
-                            if app.input_multiline {
-                                app.input_insert_char('\n');
-                            } else if app.input.trim().is_empty() {
-                                if let Some(path) = app.selected_attachment_path() {
-                                    let _ = open_path(Path::new(&path));
-                                } else {
-                                    app.on_open_url();
-                                }
-                            } else if let Some(path) = parse_file_input(&app.input) {
-                                if Path::new(&path).is_file() {
-                                    if let Some(room_id) = app.selected_room_id() {
-                                        if app.selected_room_is_invited() {
-                                            continue;
-                                        }
-                                        let reply_to = app.selected_message_event_id();
-                                        let _ = cmd_tx.send(MatrixCommand::SendAttachment {
-                                            room_id,
-                                            path,
-                                            reply_to,
-                                        });
-                                        app.input.clear();
-                                        app.input_cursor = 0;
-                                        app.message_selected = None;
-                                    }
-                                }
-                            } else if let Some(text) = app.on_enter() {
-                                if let Some(cmd) = parse_command(&text) {
-                                    let _ = cmd_tx.send(cmd);
-                                } else if let Some(room_id) = app.selected_room_id() {
-                                    if app.selected_room_is_invited() {
-                                        continue;
-                                    }
-                                    let reply_to = app.selected_message_event_id();
-                                    let _ = cmd_tx.send(MatrixCommand::SendMessage {
-                                        room_id,
-                                        body: text,
-                                        reply_to,
-                                    });
-                                    app.message_selected = None;
-                                }
-                            }
-                        }
-                        KeyCode::Char('y') if key.modifiers.contains(KeyModifiers::ALT) => {
-                            app.on_copy_message();
-                        }
-                        KeyCode::Backspace => {
-                            app.input_backspace();
-                        }
-                        KeyCode::Left if key.modifiers.contains(KeyModifiers::ALT) => {
-                            app.input_move_word_left();
-                        }
-                        KeyCode::Right if key.modifiers.contains(KeyModifiers::ALT) => {
-                            app.input_move_word_right();
-                        }
-                        KeyCode::Left => {
-                            app.input_move_left();
-                        }
-                        KeyCode::Right => {
-                            app.input_move_right();
-                        }
-                        KeyCode::Char(c) => {
-                            app.input_insert_char(c);
-                        }
-                        _ => {}
-                    }
-                }
-            }
-        }
-
-        if last_tick.elapsed() >= TICK_RATE {
-            last_tick = Instant::now();
-        }
+    ui: UiOptions,
+    options: SyncOptions,
+) -> Result<()> {
+    let (evt_tx, evt_rx) = mpsc::unbounded_channel();
+    let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
 
-        if app.should_quit {
-            return Ok(());
-        }
-    }
+    let ui_tx = evt_tx.downgrade();
+    tokio::spawn(start_sync(client, passphrase.clone(), options, cmd_rx, evt_tx));
+    run_ui(ui_tx, evt_rx, cmd_tx, passphrase, own_user_id, ui)
 }
 
 fn update_account_session(
@@ -2127,9 +419,10 @@ async fn login_with_recovery(
     username: &str,
     password: &str,
     passphrase: &str,
+    device_name: &str,
 ) -> Result<(matrix_sdk::Client, config::AccountConfig)> {
     let mut client = build_client_with_recovery(homeserver, passphrase).await?;
-    match login_with_client(&client, homeserver, username, password).await {
+    match login_with_client(&client, homeserver, username, password, device_name).await {
         Ok(account) => Ok((client, account)),
         Err(err) => {
             let err_str = format!("{:#}", err);
@@ -2142,7 +435,9 @@ async fn login_with_recovery(
                         fs::remove_dir_all(&dir)?;
                     }
                     client = build_client(homeserver, passphrase).await?;
-                    let account = login_with_client(&client, homeserver, username, password).await?;
+                    let account =
+                        login_with_client(&client, homeserver, username, password, device_name)
+                            .await?;
                     return Ok((client, account));
                 }
             }
@@ -2150,68 +445,3 @@ async fn login_with_recovery(
         }
     }
 }
-
-fn render_prompt(f: &mut ratatui::Frame, area: Rect, prompt: &PromptState) {
-    let popup = centered_rect(60, 3, area);
-    f.render_widget(Clear, popup);
-    let title = match &prompt.mode {
-        PromptMode::Add => "Add chat (@user or #room)".to_string(),
-        PromptMode::Delete { room_name, .. } => {
-            format!("Delete chat \"{}\"? (y/n)", room_name)
-        }
-    };
-    let block = Block::default().borders(Borders::ALL).title(title);
-    f.render_widget(&block, popup);
-    let inner = block.inner(popup);
-    let text = Paragraph::new(prompt.input.as_str());
-    f.render_widget(text, inner);
-    let x = inner.x + (prompt.input.len().min(inner.width as usize) as u16);
-    f.set_cursor(x, inner.y);
-}
-
-fn render_verification_overlay(f: &mut ratatui::Frame, area: Rect, app: &App) {
-    let popup = centered_rect(70, 7, area);
-    let block = Block::default().borders(Borders::ALL).title("Verification");
-    f.render_widget(&block, popup);
-    let inner = block.inner(popup);
-    let mut lines = Vec::new();
-    if let Some(ref emojis) = app.verification_emojis {
-        let symbols = emojis
-            .iter()
-            .map(|(symbol, _)| format!("{:^6}", symbol))
-            .collect::<Vec<_>>()
-            .join("");
-        let labels = emojis
-            .iter()
-            .map(|(_, desc)| format!("{:^6}", desc))
-            .collect::<Vec<_>>()
-            .join("");
-        lines.push(Line::from(symbols));
-        lines.push(Line::from(labels));
-    }
-    if let Some(ref status) = app.verification_status {
-        lines.push(Line::from(status.as_str()));
-    }
-    let content = Paragraph::new(lines).wrap(Wrap { trim: false });
-    f.render_widget(content, inner);
-}
-
-fn render_sync_indicator(f: &mut ratatui::Frame, area: Rect) {
-    let width = 18;
-    let height = 3;
-    let x = area.x + area.width.saturating_sub(width) - 1;
-    let y = area.y + 1;
-    let rect = Rect { x, y, width, height };
-    let block = Block::default().borders(Borders::ALL).title("Sync");
-    f.render_widget(&block, rect);
-    let inner = block.inner(rect);
-    let text = Paragraph::new("Syncing...");
-    f.render_widget(text, inner);
-}
-
-fn centered_rect(percent_x: u16, height: u16, area: Rect) -> Rect {
-    let width = area.width.saturating_mul(percent_x) / 100;
-    let x = area.x + (area.width.saturating_sub(width) / 2);
-    let y = area.y + (area.height.saturating_sub(height) / 2);
-    Rect { x, y, width, height }
-}
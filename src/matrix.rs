@@ -2,51 +2,201 @@
 use anyhow::{Context, Result};
 use futures_util::StreamExt;
 use matrix_sdk::config::SyncSettings;
-use matrix_sdk::ruma::events::relation::InReplyTo;
+use matrix_sdk::ruma::events::reaction::{OriginalSyncReactionEvent, ReactionEventContent};
+use matrix_sdk::ruma::events::relation::{Annotation, InReplyTo};
 use matrix_sdk::ruma::events::room::{
-    message::{MessageType, OriginalRoomMessageEvent, OriginalSyncRoomMessageEvent, Relation, RoomMessageEventContent},
-    MediaSource,
+    message::{
+        AudioInfo, AudioMessageEventContent, FileInfo, FileMessageEventContent,
+        ImageMessageEventContent, MessageType, OriginalRoomMessageEvent,
+        OriginalSyncRoomMessageEvent, Relation, ReplacementMetadata, RoomMessageEventContent,
+        ServerNoticeMessageEventContent, ServerNoticeType, VideoInfo, VideoMessageEventContent,
+    },
+    ImageInfo, MediaSource,
+};
+use matrix_sdk::ruma::events::room::canonical_alias::SyncRoomCanonicalAliasEvent;
+use matrix_sdk::ruma::events::room::encrypted::OriginalSyncRoomEncryptedEvent;
+use matrix_sdk::ruma::events::room::member::{
+    MembershipChange, MembershipState, OriginalRoomMemberEvent, OriginalSyncRoomMemberEvent,
+    StrippedRoomMemberEvent, SyncRoomMemberEvent,
+};
+use matrix_sdk::ruma::events::room::name::SyncRoomNameEvent;
+use matrix_sdk::ruma::events::room::power_levels::{
+    RoomPowerLevels, RoomPowerLevelsEventContent, SyncRoomPowerLevelsEvent,
+};
+use matrix_sdk::ruma::events::room::topic::SyncRoomTopicEvent;
+use matrix_sdk::ruma::events::room::redaction::{RoomRedactionEvent, SyncRoomRedactionEvent};
+use matrix_sdk::ruma::events::sticker::OriginalSyncStickerEvent;
+use matrix_sdk::ruma::events::poll::response::PollResponseEventContent;
+use matrix_sdk::ruma::events::poll::start::PollContentBlock;
+use matrix_sdk::ruma::events::poll::unstable_response::UnstablePollResponseEventContent;
+use matrix_sdk::ruma::events::poll::unstable_start::UnstablePollStartEventContent;
+use matrix_sdk::ruma::events::tag::TagName;
+use matrix_sdk::ruma::api::client::presence::set_presence;
+use matrix_sdk::ruma::api::client::receipt::create_receipt;
+use matrix_sdk::ruma::api::client::room::get_room_event;
+use matrix_sdk::ruma::api::client::state::get_state_events;
+use matrix_sdk::ruma::api::client::discovery::get_supported_versions;
+use matrix_sdk::ruma::api::client::media::get_media_preview;
+use matrix_sdk::ruma::presence::PresenceState;
+use matrix_sdk::ruma::events::{
+    AnyMessageLikeEvent, AnyStateEvent, AnySyncMessageLikeEvent, AnySyncTimelineEvent,
+    AnyTimelineEvent, MessageLikeEvent, MessageLikeEventType, StateEventType,
+    SyncMessageLikeEvent,
 };
-use matrix_sdk::ruma::events::receipt::{ReceiptEventContent, ReceiptType};
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::events::receipt::{ReceiptEventContent, ReceiptThread, ReceiptType};
+use matrix_sdk::ruma::events::typing::TypingEventContent;
 use matrix_sdk::ruma::events::SyncEphemeralRoomEvent;
-use matrix_sdk::ruma::{uint, RoomId};
+use matrix_sdk::ruma::events::Mentions;
+use matrix_sdk::ruma::{
+    assign, uint, EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedUserId, RoomAliasId, RoomId,
+    UserId,
+};
 use matrix_sdk::encryption::verification::{
-    AcceptSettings, SasState, SasVerification, VerificationRequestState,
+    AcceptSettings, SasState, SasVerification, VerificationRequest, VerificationRequestState,
 };
+use matrix_sdk::deserialized_responses::{AlgorithmInfo, TimelineEvent, VerificationState};
 use matrix_sdk::encryption::EncryptionSettings;
 use matrix_sdk::matrix_auth::MatrixSession;
 use matrix_sdk::attachment::AttachmentConfig;
 use matrix_sdk::room::{MessagesOptions, Room};
-use matrix_sdk::media::{MediaEventContent, MediaFormat, MediaRequest};
-use matrix_sdk::{Client, RoomState};
-use matrix_sdk::DisplayName;
+use matrix_sdk::media::{MediaEventContent, MediaFormat, MediaRequestParameters};
+use matrix_sdk::{Client, LoopCtrl, RoomMemberships, RoomState};
+use matrix_sdk::RoomDisplayName;
 use matrix_sdk::ruma::events::key::verification::{ShortAuthenticationString, VerificationMethod};
+use matrix_sdk::ruma::events::key::verification::request::ToDeviceKeyVerificationRequestEvent;
 use mime_guess::from_path;
+use serde::{Deserialize, Serialize};
 use tokio::sync::{mpsc, Mutex};
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::fs;
 
 use crate::config::AccountConfig;
-use crate::storage::{append_message, latest_room_timestamp, StoredMessage};
+use crate::metrics::{Counter, Metrics};
+use crate::notify::format_sender;
+use crate::profile::StartupProfile;
+use crate::spell::Misspelling;
+use crate::storage::{
+    append_message, export_sender, latest_room_timestamp, purge_messages, update_attachment_path, update_messages, ScheduledMessage, StoredMessage, StoredPoll, StoredPollAnswer,
+    StoredReaction, StoredVote,
+};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum RoomListState {
     Joined,
     Invited,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RoomInfo {
     pub room_id: String,
     pub name: String,
     pub state: RoomListState,
     pub inviter: Option<String>,
+    pub alias: Option<String>,
+    /// The other user of a direct chat.
+    #[serde(default)]
+    pub direct_user: Option<String>,
+    #[serde(default)]
+    pub topic: Option<String>,
+    /// Our power level is below what sending messages needs (usually the
+    /// room's `events_default`), as in announcement rooms.
+    #[serde(default)]
+    pub send_restricted: bool,
+    /// What our power level allows beyond posting; rooms without power
+    /// levels allow everything.
+    #[serde(default)]
+    pub can_invite: bool,
+    #[serde(default)]
+    pub can_set_topic: bool,
+    #[serde(default)]
+    pub can_redact_others: bool,
+    #[serde(default)]
+    pub member_count: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateEntry {
+    pub event_type: String,
+    pub state_key: String,
+    pub content: String,
+}
+
+/// A joined member of a room, for mention completion.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemberInfo {
+    pub user_id: String,
+    pub display_name: Option<String>,
+}
+
+/// A user mentioned in an outgoing message by `name`, rendered as a pill.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mention {
+    pub user_id: String,
+    pub name: String,
+}
+
+/// The send for a queued `/schedule` message.
+pub fn scheduled_command(msg: ScheduledMessage) -> MatrixCommand {
+    MatrixCommand::SendMessage {
+        room_id: msg.room_id,
+        body: msg.body,
+        reply_to: None,
+        mentions: msg
+            .mentions
+            .into_iter()
+            .map(|m| Mention {
+                user_id: m.user_id,
+                name: m.name,
+            })
+            .collect(),
+    }
 }
 
-#[derive(Debug)]
+/// A change to an earlier event in the same room.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum EventRelation {
+    /// `m.replace`; only applies when `sender` also sent the target.
+    Edit {
+        target: String,
+        sender: String,
+        body: String,
+    },
+    Reaction {
+        event_id: String,
+        target: String,
+        sender: String,
+        key: String,
+    },
+    /// A poll response; `answers` are the ids the sender picked.
+    PollVote {
+        event_id: String,
+        target: String,
+        sender: String,
+        answers: Vec<String>,
+        timestamp: i64,
+    },
+    /// `m.poll.end`; only applies when `sender` also started the poll.
+    PollEnd {
+        target: String,
+        sender: String,
+        timestamp: i64,
+    },
+    /// Redaction of a message, a reaction or a vote.
+    Redaction { target: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub enum MatrixEvent {
     Rooms(Vec<RoomInfo>),
+    RoomAdded(RoomInfo),
+    RoomUpdated(RoomInfo),
+    RoomRemoved {
+        room_id: String,
+    },
     Message {
         room_id: String,
         event_id: String,
@@ -54,6 +204,8 @@ pub enum MatrixEvent {
         body: String,
         timestamp: i64,
         reply_to: Option<String>,
+        /// Root event of the thread the message was posted in.
+        thread_root: Option<String>,
     },
     Attachment {
         room_id: String,
@@ -64,12 +216,116 @@ pub enum MatrixEvent {
         kind: String,
         timestamp: i64,
         reply_to: Option<String>,
+        thread_root: Option<String>,
     },
     Receipt {
         room_id: String,
         event_id: String,
     },
+    /// Everyone else currently typing in the room; empty when they stop.
+    Typing {
+        room_id: String,
+        user_ids: Vec<String>,
+    },
+    ServerNotice {
+        room_id: String,
+        event_id: String,
+        body: String,
+        timestamp: i64,
+    },
+    RoomNotice {
+        room_id: String,
+        body: String,
+        timestamp: i64,
+    },
+    /// A join, leave, invite, rename or topic change, as one line of text.
+    StateChange {
+        room_id: String,
+        body: String,
+        timestamp: i64,
+    },
+    Status {
+        message: String,
+    },
+    RoomState {
+        room_id: String,
+        entries: Vec<StateEntry>,
+    },
+    EventInfo {
+        event_id: String,
+        details: Vec<(String, String)>,
+    },
+    Members {
+        room_id: String,
+        members: Vec<MemberInfo>,
+    },
+    /// A member joined, left or changed their display name.
+    MemberChanged {
+        room_id: String,
+        member: MemberInfo,
+        joined: bool,
+    },
+    ServerInfo(ServerInfo),
+    AttachmentRestored {
+        room_id: String,
+        event_id: String,
+        path: String,
+    },
+    /// A live message that couldn't be decrypted; shown as a placeholder
+    /// until `RetryDecryption` succeeds.
+    Undecryptable {
+        room_id: String,
+        event_id: String,
+        sender: String,
+        timestamp: i64,
+    },
+    /// The text of a message that was undecryptable before.
+    Decrypted {
+        room_id: String,
+        event_id: String,
+        body: String,
+    },
+    /// A poll was started; votes arrive as `Related` poll votes.
+    Poll {
+        room_id: String,
+        event_id: String,
+        sender: String,
+        timestamp: i64,
+        poll: StoredPoll,
+    },
+    /// A replied-to message that wasn't loaded locally.
+    ReplyPreview {
+        room_id: String,
+        event_id: String,
+        sender: String,
+        body: String,
+    },
+    /// The page title the homeserver found for a URL; `None` when it has
+    /// none or the preview failed, so it isn't asked again.
+    LinkPreview {
+        url: String,
+        title: Option<String>,
+    },
+    Related {
+        room_id: String,
+        relation: EventRelation,
+    },
     BackfillDone,
+    /// A sync request returned (`ok`) or failed; the next response is due
+    /// within `timeout_secs`, the long-poll timeout.
+    SyncHealth {
+        ok: bool,
+        timeout_secs: u64,
+    },
+    /// How long a message or receipt took to reach the homeserver.
+    RoundTrip {
+        millis: u64,
+    },
+    /// Another device asked to verify; `StartVerification` accepts it.
+    VerificationRequest {
+        user_id: String,
+        device_id: String,
+    },
     VerificationStatus {
         message: String,
     },
@@ -80,28 +336,165 @@ pub enum MatrixEvent {
     VerificationCancelled {
         reason: String,
     },
+    /// The background instance's scheduled-message queue, sent to attached
+    /// UIs when they connect and whenever it changes.
+    Scheduled(Vec<ScheduledMessage>),
+    /// An image thumbnail finished decoding on the UI's preview worker.
+    PreviewReady,
+    /// The UI's own spell check of `text` finished on its worker thread.
+    Spelling {
+        text: String,
+        result: Result<Vec<Misspelling>, String>,
+    },
+}
+
+/// Delay before restarting the sync loop after it fails.
+const SYNC_RETRY_SECS: u64 = 5;
+
+/// Sync long-poll timeout, the SDK default.
+const SYNC_TIMEOUT_SECS: u64 = 30;
+
+/// Sync long-poll timeout while the bandwidth saver is on; the server
+/// holds each request this long when nothing happens.
+const SAVER_SYNC_TIMEOUT_SECS: u64 = 120;
+
+/// Minimum interval between batched room list updates.
+const ROOM_LIST_FLUSH_MS: u64 = 500;
+
+/// What the homeserver supports, from `/versions` and `/capabilities`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub server_name: String,
+    /// Newest spec version the server advertises, e.g. `v1.11`.
+    pub latest_version: Option<String>,
+    pub threads: bool,
+    pub authenticated_media: bool,
+    pub sliding_sync: bool,
+    pub set_displayname: bool,
+}
+
+/// Behaviour switches for the sync loop, derived from the app config.
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    pub render_unknown_events: bool,
+    pub profile: StartupProfile,
+    pub metrics: Metrics,
+    /// Skip attachment downloads and long-poll longer; toggled at runtime
+    /// with `SetBandwidthSaver`.
+    pub bandwidth_saver: Arc<AtomicBool>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub enum MatrixCommand {
     SendMessage {
         room_id: String,
         body: String,
         reply_to: Option<String>,
+        mentions: Vec<Mention>,
     },
     SendAttachment {
         room_id: String,
         path: String,
         reply_to: Option<String>,
+        /// The file was written for this upload and is deleted after it.
+        #[serde(default)]
+        temporary: bool,
+    },
+    RedactMessage {
+        room_id: String,
+        event_id: String,
+        reason: Option<String>,
+    },
+    /// Annotates a message with an emoji (`m.reaction`).
+    SendReaction {
+        room_id: String,
+        event_id: String,
+        key: String,
+    },
+    /// Replaces our vote on a poll with `answers`.
+    VotePoll {
+        room_id: String,
+        poll_id: String,
+        answers: Vec<String>,
+        stable: bool,
+    },
+    /// Replaces the body of one of our messages (`m.replace`).
+    EditMessage {
+        room_id: String,
+        event_id: String,
+        body: String,
+    },
+    /// Sends a plain message to a room ID, alias or user; a DM is created
+    /// when the user has none yet.
+    SendTo {
+        target: String,
+        body: String,
+    },
+    SendCustomEvent {
+        room_id: String,
+        event_type: String,
+        state_key: Option<String>,
+        content: serde_json::Value,
+    },
+    FetchRoomState { room_id: String },
+    FetchEventInfo { room_id: String, event_id: String },
+    FetchMembers { room_id: String },
+    /// Downloads an attachment again after its file went missing.
+    RedownloadAttachment { room_id: String, event_id: String },
+    /// Decrypts an undecryptable message again, requesting its key from our
+    /// other devices and the key backup.
+    RetryDecryption { room_id: String, event_id: String },
+    /// Fetches a replied-to message that isn't in the local archive.
+    FetchReplyPreview { room_id: String, event_id: String },
+    /// Asks the homeserver's `/preview_url` for a linked page's title.
+    FetchLinkPreview { url: String },
+    /// Turns the bandwidth saver on or off (see `SyncOptions`).
+    SetBandwidthSaver { enabled: bool },
+    SetPresence { away: bool },
+    /// Marks `event_id` read on the server; `private` keeps the receipt
+    /// from other members.
+    SendReceipt {
+        room_id: String,
+        event_id: String,
+        private: bool,
     },
     JoinRoom { room: String },
     CreateDirect { user_id: String },
     LeaveRoom { room_id: String },
+    InviteUser { room_id: String, user_id: String },
+    SetTopic { room_id: String, topic: String },
+    SetDisplayName { name: String },
+    RenameDevice { name: String },
+    /// Writes the megolm keys of one room to a passphrase-protected export
+    /// file in the standard format other clients import.
+    ExportRoomKeys {
+        room_id: String,
+        path: PathBuf,
+        passphrase: String,
+    },
+    ImportRoomKeys { path: PathBuf, passphrase: String },
+    /// Discards this device's outbound megolm session for an encrypted
+    /// room, so the next message goes out under a fresh one.
+    RotateRoomKey { room_id: String },
+    /// Writes everything the local archive holds about a user (messages
+    /// sent, reactions left) to a JSON file.
+    ExportSender {
+        user_id: String,
+        path: PathBuf,
+    },
     AcceptInvite { room_id: String },
     RejectInvite { room_id: String },
     StartVerification,
     ConfirmVerification,
     CancelVerification,
+    /// Queues a `/schedule` message with the background instance, which
+    /// holds the data dir and sends the queue; used by attached UIs.
+    Schedule { message: ScheduledMessage },
+    /// Drops a message from the background instance's queue; `send` sends
+    /// it right away instead.
+    TakeScheduled { message: ScheduledMessage, send: bool },
+    /// Stops the sync loop; sent by `marty stop` to a background instance.
+    Shutdown,
 }
 
 pub async fn build_client(homeserver: &str, passphrase: &str) -> Result<Client> {
@@ -125,11 +518,12 @@ pub async fn login_with_client(
     homeserver: &str,
     username: &str,
     password: &str,
+    device_name: &str,
 ) -> Result<AccountConfig> {
     let response = client
         .matrix_auth()
         .login_username(username, password)
-        .initial_device_display_name("marty")
+        .initial_device_display_name(device_name)
         .send()
         .await
         .context("matrix login")?;
@@ -148,34 +542,70 @@ pub async fn login_with_client(
 pub async fn start_sync(
     client: Client,
     passphrase: String,
+    options: SyncOptions,
     mut cmd_rx: mpsc::UnboundedReceiver<MatrixCommand>,
     evt_tx: mpsc::UnboundedSender<MatrixEvent>,
 ) -> Result<()> {
     let sas_state: Arc<Mutex<Option<SasVerification>>> = Arc::new(Mutex::new(None));
+    let incoming_request: Arc<Mutex<Option<VerificationRequest>>> = Arc::new(Mutex::new(None));
+    let started = std::time::Instant::now();
+    let _ = evt_tx.send(MatrixEvent::ServerInfo(server_info(&client).await));
+    options.profile.record("server info", started.elapsed());
+    let started = std::time::Instant::now();
     let _ = client.sync_once(SyncSettings::default()).await;
-    publish_rooms(&client, &evt_tx).await;
-    backfill_since_last_seen(&client, &passphrase, &evt_tx).await;
+    options.profile.record("initial sync", started.elapsed());
+    let room_list = RoomListTracker::default();
+    room_list.publish_all(&client, &evt_tx).await;
+    room_list.spawn_flusher(client.clone(), evt_tx.clone());
+    let started = std::time::Instant::now();
+    backfill_since_last_seen(&client, &passphrase, &options, &evt_tx).await;
+    options.profile.record("backfill", started.elapsed());
     let _ = evt_tx.send(MatrixEvent::BackfillDone);
 
     let evt_tx_clone = evt_tx.clone();
     let passphrase_clone = passphrase.clone();
     let own_user = client.user_id().map(|id| id.to_owned());
+    let metrics_clone = options.metrics.clone();
+    let saver_clone = options.bandwidth_saver.clone();
     client
         .add_event_handler(move |ev: OriginalSyncRoomMessageEvent, room: Room| {
             let evt_tx = evt_tx_clone.clone();
             let passphrase = passphrase_clone.clone();
+            let metrics = metrics_clone.clone();
+            let download = !saver_clone.load(Ordering::Relaxed);
             async move {
                 if room.state() != RoomState::Joined {
                     return;
                 }
+                metrics.incr(Counter::MessagesReceived);
                 let room_id = room.room_id().to_string();
+                if let Some(Relation::Replacement(replacement)) = &ev.content.relates_to {
+                    let relation = EventRelation::Edit {
+                        target: replacement.event_id.to_string(),
+                        sender: ev.sender.to_string(),
+                        body: replacement.new_content.msgtype.body().to_string(),
+                    };
+                    let _ = store_relation(&passphrase, &room_id, &relation);
+                    let _ = evt_tx.send(MatrixEvent::Related { room_id, relation });
+                    return;
+                }
                 let event_id = ev.event_id.to_string();
                 let sender = ev.sender.to_string();
                 let ts = i64::from(ev.origin_server_ts.0);
                 let reply_to = extract_reply_to(&ev.content);
+                let thread_root = extract_thread_root(&ev.content);
                 match &ev.content.msgtype {
-                    MessageType::Text(text) => {
-                        let body = text.body.clone();
+                    MessageType::Text(_)
+                    | MessageType::Notice(_)
+                    | MessageType::Emote(_)
+                    | MessageType::ServerNotice(_) => {
+                        let body = match &ev.content.msgtype {
+                            MessageType::ServerNotice(notice) => server_notice_text(notice),
+                            MessageType::Emote(emote) => emote_text(&sender, &emote.body),
+                            other => other.body().to_string(),
+                        };
+                        let is_notice = matches!(ev.content.msgtype, MessageType::ServerNotice(_))
+                            || is_server_notice_room(&room).await;
                         let _ = evt_tx.send(MatrixEvent::Message {
                             room_id: room_id.clone(),
                             event_id: event_id.clone(),
@@ -183,7 +613,16 @@ pub async fn start_sync(
                             body: body.clone(),
                             timestamp: ts,
                             reply_to: reply_to.clone(),
+                            thread_root: thread_root.clone(),
                         });
+                        if is_notice {
+                            let _ = evt_tx.send(MatrixEvent::ServerNotice {
+                                room_id: room_id.clone(),
+                                event_id: event_id.clone(),
+                                body: body.clone(),
+                                timestamp: ts,
+                            });
+                        }
                         let _ = store_message_encrypted(
                             &passphrase,
                             &room_id,
@@ -192,6 +631,7 @@ pub async fn start_sync(
                             &body,
                             Some(&event_id),
                             reply_to.as_deref(),
+                            thread_root.as_deref(),
                             None,
                         );
                     }
@@ -207,7 +647,9 @@ pub async fn start_sync(
                             "image",
                             &content.body,
                             reply_to.clone(),
+                            thread_root.clone(),
                             content,
+                            download,
                         )
                         .await;
                     }
@@ -223,7 +665,9 @@ pub async fn start_sync(
                             "file",
                             &content.body,
                             reply_to.clone(),
+                            thread_root.clone(),
                             content,
+                            download,
                         )
                         .await;
                     }
@@ -239,7 +683,9 @@ pub async fn start_sync(
                             "video",
                             &content.body,
                             reply_to.clone(),
+                            thread_root.clone(),
                             content,
+                            download,
                         )
                         .await;
                     }
@@ -255,7 +701,9 @@ pub async fn start_sync(
                             "audio",
                             &content.body,
                             reply_to.clone(),
+                            thread_root.clone(),
                             content,
+                            download,
                         )
                         .await;
                     }
@@ -264,6 +712,316 @@ pub async fn start_sync(
             }
         });
 
+    let evt_tx_sticker = evt_tx.clone();
+    let passphrase_sticker = passphrase.clone();
+    let metrics_sticker = options.metrics.clone();
+    let saver_sticker = options.bandwidth_saver.clone();
+    client.add_event_handler(move |ev: OriginalSyncStickerEvent, room: Room| {
+        let evt_tx = evt_tx_sticker.clone();
+        let passphrase = passphrase_sticker.clone();
+        let metrics = metrics_sticker.clone();
+        let download = !saver_sticker.load(Ordering::Relaxed);
+        async move {
+            if room.state() != RoomState::Joined {
+                return;
+            }
+            metrics.incr(Counter::MessagesReceived);
+            handle_attachment_event(
+                &room,
+                &passphrase,
+                &evt_tx,
+                room.room_id().as_str(),
+                ev.event_id.as_str(),
+                ev.sender.as_str(),
+                i64::from(ev.origin_server_ts.0),
+                "sticker",
+                &ev.content.body,
+                None,
+                None,
+                &ev.content,
+                download,
+            )
+            .await;
+        }
+    });
+
+    if options.render_unknown_events {
+        let evt_tx_unknown = evt_tx.clone();
+        let passphrase_unknown = passphrase.clone();
+        client.add_event_handler(move |ev: Raw<AnySyncTimelineEvent>, room: Room| {
+            let evt_tx = evt_tx_unknown.clone();
+            let passphrase = passphrase_unknown.clone();
+            async move {
+                if room.state() != RoomState::Joined {
+                    return;
+                }
+                let Some(unknown) = unknown_event(ev.cast_ref()) else {
+                    return;
+                };
+                let room_id = room.room_id().to_string();
+                let _ = evt_tx.send(MatrixEvent::Message {
+                    room_id: room_id.clone(),
+                    event_id: unknown.event_id.clone(),
+                    sender: unknown.sender.clone(),
+                    body: unknown.body.clone(),
+                    timestamp: unknown.timestamp,
+                    reply_to: None,
+                    thread_root: None,
+                });
+                let _ = store_message_encrypted(
+                    &passphrase,
+                    &room_id,
+                    unknown.timestamp,
+                    &unknown.sender,
+                    &unknown.body,
+                    Some(&unknown.event_id),
+                    None,
+                    None,
+                    None,
+                );
+            }
+        });
+    }
+
+    let evt_tx_reactions = evt_tx.clone();
+    let passphrase_reactions = passphrase.clone();
+    client.add_event_handler(move |ev: OriginalSyncReactionEvent, room: Room| {
+        let evt_tx = evt_tx_reactions.clone();
+        let passphrase = passphrase_reactions.clone();
+        async move {
+            if room.state() != RoomState::Joined {
+                return;
+            }
+            let room_id = room.room_id().to_string();
+            let relation = EventRelation::Reaction {
+                event_id: ev.event_id.to_string(),
+                target: ev.content.relates_to.event_id.to_string(),
+                sender: ev.sender.to_string(),
+                key: ev.content.relates_to.key,
+            };
+            let _ = store_relation(&passphrase, &room_id, &relation);
+            let _ = evt_tx.send(MatrixEvent::Related { room_id, relation });
+        }
+    });
+
+    // Polls come in a stable and an MSC3381 flavour of each event.
+    let evt_tx_polls = evt_tx.clone();
+    let passphrase_polls = passphrase.clone();
+    client.add_event_handler(move |ev: AnySyncMessageLikeEvent, room: Room| {
+        let evt_tx = evt_tx_polls.clone();
+        let passphrase = passphrase_polls.clone();
+        async move {
+            if room.state() != RoomState::Joined {
+                return;
+            }
+            let room_id = room.room_id().to_string();
+            let event_id = ev.event_id().to_string();
+            let sender = ev.sender().to_string();
+            let ts = i64::from(ev.origin_server_ts().0);
+            let relation = match &ev {
+                AnySyncMessageLikeEvent::PollStart(SyncMessageLikeEvent::Original(start)) => {
+                    let poll = stable_poll(&start.content.poll);
+                    publish_poll(&passphrase, &evt_tx, &room_id, &event_id, &sender, ts, poll);
+                    return;
+                }
+                AnySyncMessageLikeEvent::UnstablePollStart(SyncMessageLikeEvent::Original(
+                    start,
+                )) => {
+                    if let Some(poll) = unstable_poll(&start.content) {
+                        publish_poll(&passphrase, &evt_tx, &room_id, &event_id, &sender, ts, poll);
+                    }
+                    return;
+                }
+                AnySyncMessageLikeEvent::PollResponse(SyncMessageLikeEvent::Original(response)) => {
+                    EventRelation::PollVote {
+                        event_id,
+                        target: response.content.relates_to.event_id.to_string(),
+                        sender,
+                        answers: response.content.selections.to_vec(),
+                        timestamp: ts,
+                    }
+                }
+                AnySyncMessageLikeEvent::UnstablePollResponse(SyncMessageLikeEvent::Original(
+                    response,
+                )) => EventRelation::PollVote {
+                    event_id,
+                    target: response.content.relates_to.event_id.to_string(),
+                    sender,
+                    answers: response.content.poll_response.answers.clone(),
+                    timestamp: ts,
+                },
+                AnySyncMessageLikeEvent::PollEnd(SyncMessageLikeEvent::Original(end)) => {
+                    EventRelation::PollEnd {
+                        target: end.content.relates_to.event_id.to_string(),
+                        sender,
+                        timestamp: ts,
+                    }
+                }
+                AnySyncMessageLikeEvent::UnstablePollEnd(SyncMessageLikeEvent::Original(end)) => {
+                    EventRelation::PollEnd {
+                        target: end.content.relates_to.event_id.to_string(),
+                        sender,
+                        timestamp: ts,
+                    }
+                }
+                _ => return,
+            };
+            let _ = store_relation(&passphrase, &room_id, &relation);
+            let _ = evt_tx.send(MatrixEvent::Related { room_id, relation });
+        }
+    });
+
+    let evt_tx_redactions = evt_tx.clone();
+    let passphrase_redactions = passphrase.clone();
+    client.add_event_handler(move |ev: SyncRoomRedactionEvent, room: Room| {
+        let evt_tx = evt_tx_redactions.clone();
+        let passphrase = passphrase_redactions.clone();
+        async move {
+            if room.state() != RoomState::Joined {
+                return;
+            }
+            let SyncRoomRedactionEvent::Original(ev) = ev else {
+                return;
+            };
+            let Some(target) = ev.redacts.or(ev.content.redacts) else {
+                return;
+            };
+            let room_id = room.room_id().to_string();
+            let _ = purge_redacted(&passphrase, &room_id, target.as_str());
+            let relation = EventRelation::Redaction {
+                target: target.to_string(),
+            };
+            let _ = evt_tx.send(MatrixEvent::Related { room_id, relation });
+        }
+    });
+
+    let metrics_utd = options.metrics.clone();
+    let evt_tx_utd = evt_tx.clone();
+    client.add_event_handler(move |ev: OriginalSyncRoomEncryptedEvent, room: Room| {
+        let metrics = metrics_utd.clone();
+        let evt_tx = evt_tx_utd.clone();
+        async move {
+            metrics.incr(Counter::DecryptFailures);
+            if room.state() != RoomState::Joined {
+                return;
+            }
+            let _ = evt_tx.send(MatrixEvent::Undecryptable {
+                room_id: room.room_id().to_string(),
+                event_id: ev.event_id.to_string(),
+                sender: ev.sender.to_string(),
+                timestamp: i64::from(ev.origin_server_ts.0),
+            });
+        }
+    });
+
+    let room_list_members = room_list.clone();
+    let own_user_members = own_user.clone();
+    let evt_tx_members = evt_tx.clone();
+    client.add_event_handler(move |ev: SyncRoomMemberEvent, room: Room| {
+        let room_list = room_list_members.clone();
+        let own_user = own_user_members.clone();
+        let evt_tx = evt_tx_members.clone();
+        async move {
+            // Rooms without an explicit name are named after their members,
+            // so any display name or membership change may rename them.
+            if own_user.as_deref() == Some(ev.state_key()) || room.name().is_none() {
+                room_list.mark_dirty(room.room_id().as_str()).await;
+            }
+            if let Some(body) = ev.as_original().and_then(membership_notice) {
+                if room.state() == RoomState::Joined {
+                    let _ = evt_tx.send(MatrixEvent::StateChange {
+                        room_id: room.room_id().to_string(),
+                        body,
+                        timestamp: i64::from(ev.origin_server_ts().0),
+                    });
+                }
+            }
+            let display_name = ev
+                .as_original()
+                .and_then(|ev| ev.content.displayname.clone());
+            let _ = evt_tx.send(MatrixEvent::MemberChanged {
+                room_id: room.room_id().to_string(),
+                member: MemberInfo {
+                    user_id: ev.state_key().to_string(),
+                    display_name,
+                },
+                joined: *ev.membership() == MembershipState::Join,
+            });
+        }
+    });
+
+    let room_list_names = room_list.clone();
+    let evt_tx_names = evt_tx.clone();
+    client.add_event_handler(move |ev: SyncRoomNameEvent, room: Room| {
+        let room_list = room_list_names.clone();
+        let evt_tx = evt_tx_names.clone();
+        async move {
+            room_list.mark_dirty(room.room_id().as_str()).await;
+            if let (Some(ev), RoomState::Joined) = (ev.as_original(), room.state()) {
+                let sender = format_sender(ev.sender.as_str());
+                let body = match ev.content.name.trim() {
+                    "" => format!("{} removed the room name", sender),
+                    name => format!("{} renamed the room to \"{}\"", sender, name),
+                };
+                let _ = evt_tx.send(MatrixEvent::StateChange {
+                    room_id: room.room_id().to_string(),
+                    body,
+                    timestamp: i64::from(ev.origin_server_ts.0),
+                });
+            }
+        }
+    });
+
+    let room_list_topics = room_list.clone();
+    let evt_tx_topics = evt_tx.clone();
+    client.add_event_handler(move |ev: SyncRoomTopicEvent, room: Room| {
+        let room_list = room_list_topics.clone();
+        let evt_tx = evt_tx_topics.clone();
+        async move {
+            room_list.mark_dirty(room.room_id().as_str()).await;
+            if let (Some(ev), RoomState::Joined) = (ev.as_original(), room.state()) {
+                let sender = format_sender(ev.sender.as_str());
+                let body = match ev.content.topic.trim() {
+                    "" => format!("{} removed the topic", sender),
+                    topic => format!("{} changed the topic to \"{}\"", sender, topic),
+                };
+                let _ = evt_tx.send(MatrixEvent::StateChange {
+                    room_id: room.room_id().to_string(),
+                    body,
+                    timestamp: i64::from(ev.origin_server_ts.0),
+                });
+            }
+        }
+    });
+
+    let room_list_power = room_list.clone();
+    client.add_event_handler(move |_: SyncRoomPowerLevelsEvent, room: Room| {
+        let room_list = room_list_power.clone();
+        async move {
+            room_list.mark_dirty(room.room_id().as_str()).await;
+        }
+    });
+
+    let room_list_aliases = room_list.clone();
+    client.add_event_handler(move |_: SyncRoomCanonicalAliasEvent, room: Room| {
+        let room_list = room_list_aliases.clone();
+        async move {
+            room_list.mark_dirty(room.room_id().as_str()).await;
+        }
+    });
+
+    let room_list_invites = room_list.clone();
+    let own_user_invites = own_user.clone();
+    client.add_event_handler(move |ev: StrippedRoomMemberEvent, room: Room| {
+        let room_list = room_list_invites.clone();
+        let own_user = own_user_invites.clone();
+        async move {
+            if own_user.as_deref() == Some(ev.state_key.as_ref()) {
+                room_list.mark_dirty(room.room_id().as_str()).await;
+            }
+        }
+    });
+
     let evt_tx_receipts = evt_tx.clone();
     let own_user_receipts = own_user.clone();
     client.add_event_handler(move |ev: SyncEphemeralRoomEvent<ReceiptEventContent>, room: Room| {
@@ -279,7 +1037,7 @@ pub async fn start_sync(
                 let Some(users) = receipts.get(&ReceiptType::Read) else {
                     continue;
                 };
-                for (user_id, _) in users {
+                for user_id in users.keys() {
                     if own_user
                         .as_ref()
                         .is_some_and(|u| u.as_str() == user_id.as_str())
@@ -296,9 +1054,104 @@ pub async fn start_sync(
         }
     });
 
+    let evt_tx_typing = evt_tx.clone();
+    let own_user_typing = own_user.clone();
+    client.add_event_handler(move |ev: SyncEphemeralRoomEvent<TypingEventContent>, room: Room| {
+        let evt_tx = evt_tx_typing.clone();
+        let own_user = own_user_typing.clone();
+        async move {
+            if room.state() != RoomState::Joined {
+                return;
+            }
+            let user_ids = ev
+                .content
+                .user_ids
+                .iter()
+                .filter(|user_id| own_user.as_ref() != Some(*user_id))
+                .map(|user_id| user_id.to_string())
+                .collect();
+            let _ = evt_tx.send(MatrixEvent::Typing {
+                room_id: room.room_id().to_string(),
+                user_ids,
+            });
+        }
+    });
+
+    let evt_tx_verification = evt_tx.clone();
+    let incoming_verification = incoming_request.clone();
+    client.add_event_handler(move |ev: ToDeviceKeyVerificationRequestEvent, client: Client| {
+        let evt_tx = evt_tx_verification.clone();
+        let incoming = incoming_verification.clone();
+        async move {
+            let Some(request) = client
+                .encryption()
+                .get_verification_request(&ev.sender, &ev.content.transaction_id)
+                .await
+            else {
+                return;
+            };
+            *incoming.lock().await = Some(request);
+            let _ = evt_tx.send(MatrixEvent::VerificationRequest {
+                user_id: ev.sender.to_string(),
+                device_id: ev.content.from_device.to_string(),
+            });
+        }
+    });
+
     let sync_client = client.clone();
+    let sync_metrics = options.metrics.clone();
+    let sync_saver = options.bandwidth_saver.clone();
+    // Every /sync sets presence, so auto-away has to ride along with it or
+    // the next long-poll marks the user online again.
+    let away = Arc::new(AtomicBool::new(false));
+    let sync_away = away.clone();
+    let sync_tx = evt_tx.clone();
     let sync_task = tokio::spawn(async move {
-        let _ = sync_client.sync(SyncSettings::default()).await;
+        // Restarted with the other long-poll timeout once the bandwidth
+        // saver is toggled, or with the new presence once away changes,
+        // after the request in flight returns.
+        loop {
+            let saver = sync_saver.load(Ordering::Relaxed);
+            let is_away = sync_away.load(Ordering::Relaxed);
+            let timeout_secs = if saver {
+                SAVER_SYNC_TIMEOUT_SECS
+            } else {
+                SYNC_TIMEOUT_SECS
+            };
+            let presence = if is_away {
+                PresenceState::Unavailable
+            } else {
+                PresenceState::Online
+            };
+            let settings = SyncSettings::default()
+                .timeout(std::time::Duration::from_secs(timeout_secs))
+                .set_presence(presence);
+            let synced = sync_client
+                .sync_with_callback(settings, |_| {
+                    let toggled = sync_saver.load(Ordering::Relaxed) != saver
+                        || sync_away.load(Ordering::Relaxed) != is_away;
+                    let _ = sync_tx.send(MatrixEvent::SyncHealth {
+                        ok: true,
+                        timeout_secs,
+                    });
+                    async move {
+                        if toggled {
+                            LoopCtrl::Break
+                        } else {
+                            LoopCtrl::Continue
+                        }
+                    }
+                })
+                .await;
+            if synced.is_err() {
+                let _ = sync_tx.send(MatrixEvent::SyncHealth {
+                    ok: false,
+                    timeout_secs,
+                });
+                sync_metrics.incr(Counter::SyncRestarts);
+                tokio::time::sleep(std::time::Duration::from_secs(SYNC_RETRY_SECS)).await;
+            }
+        }
     });
 
     while let Some(cmd) = cmd_rx.recv().await {
@@ -307,10 +1160,19 @@ pub async fn start_sync(
                 room_id,
                 body,
                 reply_to,
+                mentions,
             } => {
                 if let Ok(room_id) = RoomId::parse(&room_id) {
                     if let Some(room) = client.get_room(&room_id) {
-                        let mut content = RoomMessageEventContent::text_plain(body.clone());
+                        let mut content = if mentions.is_empty() {
+                            RoomMessageEventContent::text_plain(body.clone())
+                        } else {
+                            let user_ids = mentions
+                                .iter()
+                                .filter_map(|mention| UserId::parse(&mention.user_id).ok());
+                            RoomMessageEventContent::text_html(body.clone(), mention_html(&body, &mentions))
+                                .add_mentions(Mentions::with_user_ids(user_ids))
+                        };
                         if let Some(reply_to) = reply_to {
                             if let Ok(event_id) = reply_to.parse() {
                                 content.relates_to = Some(Relation::Reply {
@@ -318,7 +1180,13 @@ pub async fn start_sync(
                                 });
                             }
                         }
-                        let _ = room.send(content).await;
+                        let started = std::time::Instant::now();
+                        if room.send(content).await.is_ok() {
+                            options.metrics.incr(Counter::MessagesSent);
+                            let _ = evt_tx.send(MatrixEvent::RoundTrip {
+                                millis: started.elapsed().as_millis() as u64,
+                            });
+                        }
                     }
                 }
             }
@@ -326,28 +1194,320 @@ pub async fn start_sync(
                 room_id,
                 path,
                 reply_to,
+                temporary,
             } => {
-                let _reply_to = reply_to;
-                if let Ok(room_id) = RoomId::parse(&room_id) {
-                    if let Some(room) = client.get_room(&room_id) {
-                        let Ok(data) = fs::read(&path) else {
-                            continue;
-                        };
-                        let body = Path::new(&path)
-                            .file_name()
-                            .and_then(|name| name.to_str())
-                            .unwrap_or("attachment");
-                        let mime = from_path(&path).first_or_octet_stream();
-                        let _ = room
+                let room = RoomId::parse(&room_id)
+                    .ok()
+                    .and_then(|room_id| client.get_room(&room_id));
+                if let (Some(room), Ok(data)) = (room, fs::read(&path)) {
+                    let body = Path::new(&path)
+                        .file_name()
+                        .and_then(|name| name.to_str())
+                        .unwrap_or("attachment");
+                    let mime = from_path(&path).first_or_octet_stream();
+                    let reply_to = reply_to.and_then(|event_id| EventId::parse(event_id).ok());
+                    let sent = match reply_to {
+                        Some(event_id) => {
+                            send_reply_attachment(&client, &room, body, &mime, data, event_id)
+                                .await
+                        }
+                        None => room
                             .send_attachment(body, &mime, data, AttachmentConfig::new())
-                            .await;
+                            .await
+                            .map(|_| ()),
+                    };
+                    match sent {
+                        Ok(()) => options.metrics.incr(Counter::MessagesSent),
+                        Err(err) => {
+                            let _ = evt_tx.send(MatrixEvent::Status {
+                                message: format!("Failed to send {}: {}", body, err),
+                            });
+                        }
+                    }
+                }
+                if temporary {
+                    let _ = fs::remove_file(&path);
+                }
+            }
+            MatrixCommand::RedactMessage {
+                room_id,
+                event_id,
+                reason,
+            } => {
+                let room = RoomId::parse(&room_id).ok().and_then(|id| client.get_room(&id));
+                let (Some(room), Ok(parsed)) = (room, EventId::parse(&event_id)) else {
+                    continue;
+                };
+                match room.redact(&parsed, reason.as_deref(), None).await {
+                    Ok(_) => {
+                        let _ = purge_redacted(&passphrase, &room_id, &event_id);
+                        let relation = EventRelation::Redaction { target: event_id };
+                        let _ = evt_tx.send(MatrixEvent::Related { room_id, relation });
                     }
+                    Err(err) => {
+                        let _ = evt_tx.send(MatrixEvent::Status {
+                            message: format!("Failed to delete message: {}", err),
+                        });
+                    }
+                }
+            }
+            MatrixCommand::SendReaction {
+                room_id,
+                event_id,
+                key,
+            } => {
+                let room = RoomId::parse(&room_id).ok().and_then(|id| client.get_room(&id));
+                let (Some(room), Ok(event_id)) = (room, EventId::parse(&event_id)) else {
+                    continue;
+                };
+                let content = ReactionEventContent::new(Annotation::new(event_id, key.clone()));
+                let message = match room.send(content).await {
+                    Ok(_) => format!("Reacted with {}", key),
+                    Err(err) => format!("Failed to send reaction: {}", err),
+                };
+                let _ = evt_tx.send(MatrixEvent::Status { message });
+            }
+            MatrixCommand::VotePoll {
+                room_id,
+                poll_id,
+                answers,
+                stable,
+            } => {
+                let room = RoomId::parse(&room_id).ok().and_then(|id| client.get_room(&id));
+                let (Some(room), Ok(poll_id)) = (room, EventId::parse(&poll_id)) else {
+                    continue;
+                };
+                let sent = if stable {
+                    let content = PollResponseEventContent::new(answers.into(), poll_id);
+                    room.send(content).await
+                } else {
+                    let content = UnstablePollResponseEventContent::new(answers, poll_id);
+                    room.send(content).await
+                };
+                let message = match sent {
+                    Ok(_) => "Vote sent".to_string(),
+                    Err(err) => format!("Failed to send vote: {}", err),
+                };
+                let _ = evt_tx.send(MatrixEvent::Status { message });
+            }
+            MatrixCommand::EditMessage {
+                room_id,
+                event_id,
+                body,
+            } => {
+                let room = RoomId::parse(&room_id).ok().and_then(|id| client.get_room(&id));
+                let (Some(room), Ok(event_id)) = (room, EventId::parse(&event_id)) else {
+                    continue;
+                };
+                let content = RoomMessageEventContent::text_plain(body)
+                    .make_replacement(ReplacementMetadata::new(event_id, None), None);
+                match room.send(content).await {
+                    Ok(_) => options.metrics.incr(Counter::MessagesSent),
+                    Err(err) => {
+                        let _ = evt_tx.send(MatrixEvent::Status {
+                            message: format!("Failed to edit message: {}", err),
+                        });
+                    }
+                }
+            }
+            MatrixCommand::SendTo { target, body } => {
+                let result = match resolve_target(&client, &target).await {
+                    Ok(room) => room
+                        .send(RoomMessageEventContent::text_plain(body))
+                        .await
+                        .map_err(|err| err.to_string()),
+                    Err(err) => Err(err),
+                };
+                match result {
+                    Ok(_) => options.metrics.incr(Counter::MessagesSent),
+                    Err(err) => {
+                        let _ = evt_tx.send(MatrixEvent::Status {
+                            message: format!("Failed to send to {}: {}", target, err),
+                        });
+                    }
+                }
+            }
+            MatrixCommand::SendCustomEvent {
+                room_id,
+                event_type,
+                state_key,
+                content,
+            } => {
+                let Some(room) = RoomId::parse(&room_id).ok().and_then(|id| client.get_room(&id))
+                else {
+                    continue;
+                };
+                let result = match state_key {
+                    Some(state_key) => room
+                        .send_state_event_raw(&event_type, &state_key, content)
+                        .await
+                        .map(|response| response.event_id),
+                    None => room
+                        .send_raw(&event_type, content)
+                        .await
+                        .map(|response| response.event_id),
+                };
+                let message = match result {
+                    Ok(event_id) => format!("Sent {} ({})", event_type, event_id),
+                    Err(err) => format!("Failed to send {}: {}", event_type, err),
+                };
+                let _ = evt_tx.send(MatrixEvent::Status { message });
+            }
+            MatrixCommand::FetchRoomState { room_id } => {
+                let Ok(parsed) = RoomId::parse(&room_id) else {
+                    continue;
+                };
+                let request = get_state_events::v3::Request::new(parsed);
+                match client.send(request, None).await {
+                    Ok(response) => {
+                        let entries = state_entries(&response.room_state);
+                        let _ = evt_tx.send(MatrixEvent::RoomState { room_id, entries });
+                    }
+                    Err(err) => {
+                        let _ = evt_tx.send(MatrixEvent::Status {
+                            message: format!("Failed to fetch room state: {}", err),
+                        });
+                    }
+                }
+            }
+            MatrixCommand::FetchEventInfo { room_id, event_id } => {
+                let room = RoomId::parse(&room_id).ok().and_then(|id| client.get_room(&id));
+                let (Some(room), Ok(parsed)) = (room, EventId::parse(&event_id)) else {
+                    continue;
+                };
+                let details = event_encryption_details(&client, &room, parsed).await;
+                let _ = evt_tx.send(MatrixEvent::EventInfo { event_id, details });
+            }
+            MatrixCommand::RedownloadAttachment { room_id, event_id } => {
+                match redownload_attachment(&client, &passphrase, &room_id, &event_id).await {
+                    Ok(path) => {
+                        let _ = evt_tx.send(MatrixEvent::AttachmentRestored {
+                            room_id,
+                            event_id,
+                            path: path.to_string_lossy().to_string(),
+                        });
+                    }
+                    Err(err) => {
+                        let _ = evt_tx.send(MatrixEvent::Status {
+                            message: format!("Re-download failed: {}", err),
+                        });
+                    }
+                }
+            }
+            MatrixCommand::RetryDecryption { room_id, event_id } => {
+                let message =
+                    match retry_decryption(&client, &passphrase, &room_id, &event_id).await {
+                        Ok(Some(body)) => {
+                            let _ = evt_tx.send(MatrixEvent::Decrypted {
+                                room_id,
+                                event_id,
+                                body,
+                            });
+                            "Message decrypted".to_string()
+                        }
+                        Ok(None) => "Still undecryptable: keys were requested from your other \
+                            devices and aren't in the key backup; try again once one is online"
+                            .to_string(),
+                        Err(err) => format!("Decryption retry failed: {}", err),
+                    };
+                let _ = evt_tx.send(MatrixEvent::Status { message });
+            }
+            MatrixCommand::FetchReplyPreview { room_id, event_id } => {
+                // Best effort: the quote keeps its placeholder on failure.
+                if let Ok((sender, body)) = fetch_reply_preview(&client, &room_id, &event_id).await
+                {
+                    let _ = evt_tx.send(MatrixEvent::ReplyPreview {
+                        room_id,
+                        event_id,
+                        sender,
+                        body,
+                    });
+                }
+            }
+            MatrixCommand::FetchLinkPreview { url } => {
+                // The homeserver may take a while to fetch the page.
+                let client = client.clone();
+                let evt_tx = evt_tx.clone();
+                tokio::spawn(async move {
+                    let title = fetch_link_title(&client, &url).await;
+                    let _ = evt_tx.send(MatrixEvent::LinkPreview { url, title });
+                });
+            }
+            MatrixCommand::SetBandwidthSaver { enabled } => {
+                options.bandwidth_saver.store(enabled, Ordering::Relaxed);
+            }
+            MatrixCommand::FetchMembers { room_id } => {
+                let Some(room) = RoomId::parse(&room_id).ok().and_then(|id| client.get_room(&id))
+                else {
+                    continue;
+                };
+                match room.members(RoomMemberships::JOIN).await {
+                    Ok(members) => {
+                        let members = members
+                            .iter()
+                            .map(|member| MemberInfo {
+                                user_id: member.user_id().to_string(),
+                                display_name: member.display_name().map(str::to_string),
+                            })
+                            .collect();
+                        let _ = evt_tx.send(MatrixEvent::Members { room_id, members });
+                    }
+                    Err(err) => {
+                        let _ = evt_tx.send(MatrixEvent::Status {
+                            message: format!("Failed to fetch members: {}", err),
+                        });
+                    }
+                }
+            }
+            MatrixCommand::SetPresence { away: is_away } => {
+                away.store(is_away, Ordering::Relaxed);
+                let Some(user_id) = client.user_id() else { continue };
+                let presence = if is_away {
+                    PresenceState::Unavailable
+                } else {
+                    PresenceState::Online
+                };
+                let request = set_presence::v3::Request::new(user_id.to_owned(), presence);
+                let _ = client.send(request, None).await;
+            }
+            MatrixCommand::SendReceipt {
+                room_id,
+                event_id,
+                private,
+            } => {
+                let room = RoomId::parse(&room_id).ok().and_then(|id| client.get_room(&id));
+                let (Some(room), Ok(event_id)) = (room, EventId::parse(&event_id)) else {
+                    continue;
+                };
+                let receipt_type = if private {
+                    create_receipt::v3::ReceiptType::ReadPrivate
+                } else {
+                    create_receipt::v3::ReceiptType::Read
+                };
+                let started = std::time::Instant::now();
+                if room
+                    .send_single_receipt(receipt_type, ReceiptThread::Unthreaded, event_id)
+                    .await
+                    .is_ok()
+                {
+                    let _ = evt_tx.send(MatrixEvent::RoundTrip {
+                        millis: started.elapsed().as_millis() as u64,
+                    });
                 }
             }
             MatrixCommand::JoinRoom { room } => {
                 if let Ok(room_or_alias) = matrix_sdk::ruma::RoomOrAliasId::parse(&room) {
-                    let _ = client.join_room_by_id_or_alias(&room_or_alias, &[]).await;
-                    publish_rooms(&client, &evt_tx).await;
+                    match client.join_room_by_id_or_alias(&room_or_alias, &[]).await {
+                        Ok(joined) => {
+                            room_list.refresh(&client, &evt_tx, joined.room_id().as_str()).await;
+                            backfill_room(&client, &joined, &passphrase, &options, &evt_tx).await;
+                        }
+                        Err(err) => {
+                            let _ = evt_tx.send(MatrixEvent::Status {
+                                message: format!("Failed to join {}: {}", room, err),
+                            });
+                        }
+                    }
                 }
             }
             MatrixCommand::CreateDirect { user_id } => {
@@ -356,23 +1516,131 @@ pub async fn start_sync(
                         matrix_sdk::ruma::api::client::room::create_room::v3::Request::new();
                     request.is_direct = true;
                     request.invite.push(user_id.to_owned());
-                    let _ = client.create_room(request).await;
-                    publish_rooms(&client, &evt_tx).await;
+                    if let Ok(room) = client.create_room(request).await {
+                        room_list.refresh(&client, &evt_tx, room.room_id().as_str()).await;
+                    }
                 }
             }
             MatrixCommand::LeaveRoom { room_id } => {
                 if let Ok(room_id) = RoomId::parse(&room_id) {
                     if let Some(room) = client.get_room(&room_id) {
                         let _ = room.leave().await;
-                        publish_rooms(&client, &evt_tx).await;
+                        room_list.refresh(&client, &evt_tx, room_id.as_str()).await;
                     }
                 }
             }
+            MatrixCommand::InviteUser { room_id, user_id } => {
+                let Some(room) = RoomId::parse(&room_id).ok().and_then(|id| client.get_room(&id))
+                else {
+                    continue;
+                };
+                let Ok(user) = matrix_sdk::ruma::UserId::parse(&user_id) else {
+                    continue;
+                };
+                let message = match room.invite_user_by_id(&user).await {
+                    Ok(()) => format!("Invited {}", user_id),
+                    Err(err) => format!("Failed to invite {}: {}", user_id, err),
+                };
+                let _ = evt_tx.send(MatrixEvent::Status { message });
+            }
+            MatrixCommand::SetTopic { room_id, topic } => {
+                let Some(room) = RoomId::parse(&room_id).ok().and_then(|id| client.get_room(&id))
+                else {
+                    continue;
+                };
+                let message = match room.set_room_topic(&topic).await {
+                    Ok(_) => "Topic updated".to_string(),
+                    Err(err) => format!("Failed to set topic: {}", err),
+                };
+                let _ = evt_tx.send(MatrixEvent::Status { message });
+            }
+            MatrixCommand::SetDisplayName { name } => {
+                let message = match client.account().set_display_name(Some(&name)).await {
+                    Ok(()) => format!("Display name set to {}", name),
+                    Err(err) => format!("Failed to set display name: {}", err),
+                };
+                let _ = evt_tx.send(MatrixEvent::Status { message });
+            }
+            MatrixCommand::RenameDevice { name } => {
+                let Some(device_id) = client.device_id() else { continue };
+                let message = match client.rename_device(device_id, &name).await {
+                    Ok(_) => format!("Device {} renamed to {}", device_id, name),
+                    Err(err) => format!("Failed to rename device: {}", err),
+                };
+                let _ = evt_tx.send(MatrixEvent::Status { message });
+            }
+            MatrixCommand::ExportRoomKeys {
+                room_id,
+                path,
+                passphrase,
+            } => {
+                let message = match export_room_keys(&client, &room_id, &path, &passphrase).await {
+                    Ok(count) => format!("Exported {} key(s) to {}", count, path.display()),
+                    Err(err) => format!("Key export failed: {}", err),
+                };
+                let _ = evt_tx.send(MatrixEvent::Status { message });
+            }
+            MatrixCommand::ImportRoomKeys { path, passphrase } => {
+                let message = match client
+                    .encryption()
+                    .import_room_keys(path.clone(), &passphrase)
+                    .await
+                {
+                    Ok(result) => format!(
+                        "Imported {} of {} key(s) for {} room(s) from {}",
+                        result.imported_count,
+                        result.total_count,
+                        result.keys.len(),
+                        path.display()
+                    ),
+                    Err(err) => format!("Key import failed: {}", err),
+                };
+                let _ = evt_tx.send(MatrixEvent::Status { message });
+            }
+            MatrixCommand::RotateRoomKey { room_id } => {
+                let Some(room) = RoomId::parse(&room_id).ok().and_then(|id| client.get_room(&id))
+                else {
+                    continue;
+                };
+                let message = if !room.is_encrypted().await.unwrap_or(false) {
+                    "This room isn't encrypted".to_string()
+                } else {
+                    match room.discard_room_key().await {
+                        Ok(()) => "Room key discarded; your next message here starts a new \
+                                   session, shared only with the current members' devices"
+                            .to_string(),
+                        Err(err) => format!("Failed to rotate room key: {}", err),
+                    }
+                };
+                let _ = evt_tx.send(MatrixEvent::Status { message });
+            }
+            MatrixCommand::ExportSender { user_id, path } => {
+                let exported = crate::config::messages_dir()
+                    .map_err(|err| err.to_string())
+                    .and_then(|base| {
+                        export_sender(&base, &passphrase, &user_id, &path)
+                            .map_err(|err| err.to_string())
+                    });
+                let message = match exported {
+                    Ok((messages, reactions)) => format!(
+                        "Exported {} message(s) and {} reaction(s) from {} to {}",
+                        messages,
+                        reactions,
+                        user_id,
+                        path.display()
+                    ),
+                    Err(err) => format!("Export failed: {}", err),
+                };
+                let _ = evt_tx.send(MatrixEvent::Status { message });
+            }
             MatrixCommand::AcceptInvite { room_id } => {
                 if let Ok(room_id) = RoomId::parse(&room_id) {
                     if let Some(room) = client.get_room(&room_id) {
-                        let _ = room.join().await;
-                        publish_rooms(&client, &evt_tx).await;
+                        let joined = room.join().await.is_ok();
+                        room_list.refresh(&client, &evt_tx, room_id.as_str()).await;
+                        if joined {
+                            backfill_room(&client, &room, &passphrase, &options, &evt_tx).await;
+                        }
                     }
                 }
             }
@@ -380,62 +1648,47 @@ pub async fn start_sync(
                 if let Ok(room_id) = RoomId::parse(&room_id) {
                     if let Some(room) = client.get_room(&room_id) {
                         let _ = room.leave().await;
-                        publish_rooms(&client, &evt_tx).await;
+                        room_list.refresh(&client, &evt_tx, room_id.as_str()).await;
                     }
                 }
             }
             MatrixCommand::StartVerification => {
+                let pending = incoming_request
+                    .lock()
+                    .await
+                    .take()
+                    .filter(|request| !request.is_done() && !request.is_cancelled());
+                if let Some(request) = pending {
+                    if request
+                        .accept_with_methods(vec![VerificationMethod::SasV1])
+                        .await
+                        .is_ok()
+                    {
+                        let _ = evt_tx.send(MatrixEvent::VerificationStatus {
+                            message: "Request accepted. Waiting for other device...".to_string(),
+                        });
+                        tokio::spawn(follow_verification_request(
+                            request,
+                            sas_state.clone(),
+                            evt_tx.clone(),
+                        ));
+                    }
+                    continue;
+                }
                 let Some(user_id) = client.user_id() else { continue };
                 if let Ok(Some(user)) = client.encryption().get_user_identity(user_id).await {
                     if let Ok(request) = user
                         .request_verification_with_methods(vec![VerificationMethod::SasV1])
                         .await
                     {
-                        let evt_tx = evt_tx.clone();
-                        let sas_state = sas_state.clone();
                         let _ = evt_tx.send(MatrixEvent::VerificationStatus {
                             message: "Waiting for other device...".to_string(),
                         });
-                        tokio::spawn(async move {
-                            let mut changes = request.changes();
-                            let mut started = false;
-                            while let Some(state) = changes.next().await {
-                                match state {
-                                    VerificationRequestState::Transitioned { verification } => {
-                                        if let Some(sas) = verification.sas() {
-                                            started = true;
-                                            let _ = evt_tx.send(MatrixEvent::VerificationStatus {
-                                                message: "SAS started. Waiting for emojis...".to_string(),
-                                            });
-                                            start_sas_flow(sas, &sas_state, &evt_tx).await;
-                                        }
-                                    }
-                                    VerificationRequestState::Ready { .. } => {
-                                        if started {
-                                            continue;
-                                        }
-                                        let _ = evt_tx.send(MatrixEvent::VerificationStatus {
-                                            message: "SAS requested. Waiting for emojis...".to_string(),
-                                        });
-                                        if let Ok(Some(sas)) = request.start_sas().await {
-                                            started = true;
-                                            start_sas_flow(sas, &sas_state, &evt_tx).await;
-                                        }
-                                    }
-                                    VerificationRequestState::Cancelled(cancel) => {
-                                        let _ = evt_tx.send(MatrixEvent::VerificationCancelled {
-                                            reason: cancel.reason().to_string(),
-                                        });
-                                        break;
-                                    }
-                                    VerificationRequestState::Done => {
-                                        let _ = evt_tx.send(MatrixEvent::VerificationDone);
-                                        break;
-                                    }
-                                    _ => {}
-                                }
-                            }
-                        });
+                        tokio::spawn(follow_verification_request(
+                            request,
+                            sas_state.clone(),
+                            evt_tx.clone(),
+                        ));
                     }
                 }
             }
@@ -449,6 +1702,13 @@ pub async fn start_sync(
                     let _ = sas.mismatch().await;
                 }
             }
+            // The background instance handles its queue before commands
+            // reach the sync loop.
+            MatrixCommand::Schedule { .. } | MatrixCommand::TakeScheduled { .. } => {}
+            MatrixCommand::Shutdown => {
+                sync_task.abort();
+                break;
+            }
         }
     }
 
@@ -456,55 +1716,165 @@ pub async fn start_sync(
     Ok(())
 }
 
-async fn publish_rooms(client: &Client, evt_tx: &mpsc::UnboundedSender<MatrixEvent>) {
-    let joined_rooms = client.joined_rooms();
-    let invited_rooms = client.invited_rooms();
-    let mut room_infos = Vec::new();
-    for room in joined_rooms {
-        let room_id = room.room_id().to_string();
-        let name = match room.display_name().await {
-            Ok(DisplayName::Empty) | Ok(DisplayName::EmptyWas(_)) => {
-                resolve_room_name(client, &room, &room_id).await
+/// Room list as last sent to the UI. The full list goes out once after the
+/// initial sync; afterwards rooms are marked dirty and recomputed in batches,
+/// and only the differences are published.
+#[derive(Clone, Default)]
+struct RoomListTracker {
+    published: Arc<Mutex<HashMap<String, RoomInfo>>>,
+    dirty: Arc<Mutex<HashSet<String>>>,
+}
+
+impl RoomListTracker {
+    async fn publish_all(&self, client: &Client, evt_tx: &mpsc::UnboundedSender<MatrixEvent>) {
+        let mut room_infos = Vec::new();
+        for room in client.joined_rooms().into_iter().chain(client.invited_rooms()) {
+            if let Some(info) = room_info(client, &room).await {
+                room_infos.push(info);
+            }
+        }
+        let mut published = self.published.lock().await;
+        *published = room_infos
+            .iter()
+            .map(|info| (info.room_id.clone(), info.clone()))
+            .collect();
+        let _ = evt_tx.send(MatrixEvent::Rooms(room_infos));
+    }
+
+    async fn mark_dirty(&self, room_id: &str) {
+        self.dirty.lock().await.insert(room_id.to_string());
+    }
+
+    /// Marks `room_id` dirty and publishes pending changes right away; used
+    /// after commands so the UI doesn't wait for the next batch.
+    async fn refresh(
+        &self,
+        client: &Client,
+        evt_tx: &mpsc::UnboundedSender<MatrixEvent>,
+        room_id: &str,
+    ) {
+        self.mark_dirty(room_id).await;
+        self.flush(client, evt_tx).await;
+    }
+
+    async fn flush(&self, client: &Client, evt_tx: &mpsc::UnboundedSender<MatrixEvent>) {
+        let dirty: Vec<String> = self.dirty.lock().await.drain().collect();
+        if dirty.is_empty() {
+            return;
+        }
+        let mut published = self.published.lock().await;
+        for room_id in dirty {
+            let room = RoomId::parse(&room_id)
+                .ok()
+                .and_then(|id| client.get_room(&id));
+            let info = match room {
+                Some(room) => room_info(client, &room).await,
+                None => None,
+            };
+            match (info, published.contains_key(&room_id)) {
+                (Some(info), false) => {
+                    published.insert(room_id, info.clone());
+                    let _ = evt_tx.send(MatrixEvent::RoomAdded(info));
+                }
+                (Some(info), true) => {
+                    if published.get(&room_id) != Some(&info) {
+                        published.insert(room_id, info.clone());
+                        let _ = evt_tx.send(MatrixEvent::RoomUpdated(info));
+                    }
+                }
+                (None, true) => {
+                    published.remove(&room_id);
+                    let _ = evt_tx.send(MatrixEvent::RoomRemoved { room_id });
+                }
+                (None, false) => {}
+            }
+        }
+    }
+
+    /// Publishes dirty rooms at most once per `ROOM_LIST_FLUSH_MS`.
+    fn spawn_flusher(&self, client: Client, evt_tx: mpsc::UnboundedSender<MatrixEvent>) {
+        let tracker = self.clone();
+        tokio::spawn(async move {
+            let mut ticker =
+                tokio::time::interval(std::time::Duration::from_millis(ROOM_LIST_FLUSH_MS));
+            loop {
+                ticker.tick().await;
+                if evt_tx.is_closed() {
+                    break;
+                }
+                tracker.flush(&client, &evt_tx).await;
             }
-            Ok(name) => name.to_string(),
-            Err(_) => resolve_room_name(client, &room, &room_id).await,
-        };
-        room_infos.push(RoomInfo {
-            room_id,
-            name,
-            state: RoomListState::Joined,
-            inviter: None,
         });
     }
-    for room in invited_rooms {
-        let room_id = room.room_id().to_string();
-        let inviter = room
-            .invite_details()
+}
+
+async fn room_info(client: &Client, room: &Room) -> Option<RoomInfo> {
+    let state = match room.state() {
+        RoomState::Joined => RoomListState::Joined,
+        RoomState::Invited => RoomListState::Invited,
+        RoomState::Left | RoomState::Knocked => return None,
+    };
+    let room_id = room.room_id().to_string();
+    let inviter = if state == RoomListState::Invited {
+        room.invite_details()
             .await
             .ok()
             .and_then(|invite| invite.inviter)
             .map(|inviter| inviter.name().to_string())
-            .filter(|name| !name.is_empty());
-        let name = match room.display_name().await {
-            Ok(DisplayName::Empty) | Ok(DisplayName::EmptyWas(_)) => {
-                resolve_room_name(client, &room, &room_id).await
-            }
-            Ok(name) => name.to_string(),
-            Err(_) => resolve_room_name(client, &room, &room_id).await,
-        };
-        let name = if (name == room_id || name == "Empty Room") && inviter.is_some() {
-            inviter.clone().unwrap_or(name)
-        } else {
-            name
-        };
-        room_infos.push(RoomInfo {
-            room_id,
-            name,
-            state: RoomListState::Invited,
-            inviter,
-        });
-    }
-    let _ = evt_tx.send(MatrixEvent::Rooms(room_infos));
+            .filter(|name| !name.is_empty())
+    } else {
+        None
+    };
+    let name = match room.compute_display_name().await {
+        Ok(RoomDisplayName::Empty) | Ok(RoomDisplayName::EmptyWas(_)) => {
+            resolve_room_name(client, room, &room_id).await
+        }
+        Ok(name) => name.to_string(),
+        Err(_) => resolve_room_name(client, room, &room_id).await,
+    };
+    let name = if (name == room_id || name == "Empty Room") && inviter.is_some() {
+        inviter.clone().unwrap_or(name)
+    } else {
+        name
+    };
+    let levels = match (state, client.user_id()) {
+        (RoomListState::Joined, Some(user_id)) => {
+            power_levels(room).await.map(|levels| (levels, user_id))
+        }
+        _ => None,
+    };
+    let can = |check: fn(&RoomPowerLevels, &UserId) -> bool| {
+        levels
+            .as_ref()
+            .is_none_or(|(levels, user_id)| check(levels, user_id))
+    };
+    let send_restricted = !can(|levels, user_id| {
+        levels.user_can_send_message(user_id, MessageLikeEventType::RoomMessage)
+    });
+    Some(RoomInfo {
+        room_id,
+        name,
+        state,
+        inviter,
+        alias: room.canonical_alias().map(|alias| alias.to_string()),
+        direct_user: direct_target(client, room).map(|user| user.to_string()),
+        topic: room.topic().filter(|topic| !topic.trim().is_empty()),
+        send_restricted,
+        can_invite: can(RoomPowerLevels::user_can_invite),
+        can_set_topic: can(|levels, user_id| {
+            levels.user_can_send_state(user_id, StateEventType::RoomTopic)
+        }),
+        can_redact_others: can(RoomPowerLevels::user_can_redact_event_of_other),
+        member_count: room.joined_members_count(),
+    })
+}
+
+async fn power_levels(room: &Room) -> Option<RoomPowerLevels> {
+    let raw = room
+        .get_state_event_static::<RoomPowerLevelsEventContent>()
+        .await
+        .ok()??;
+    Some(raw.deserialize().ok()?.power_levels())
 }
 
 enum BackfillItem {
@@ -514,6 +1884,8 @@ enum BackfillItem {
         body: String,
         timestamp: i64,
         reply_to: Option<String>,
+        thread_root: Option<String>,
+        server_notice: bool,
     },
     Attachment {
         event_id: String,
@@ -523,7 +1895,60 @@ enum BackfillItem {
         kind: String,
         timestamp: i64,
         reply_to: Option<String>,
+        thread_root: Option<String>,
     },
+    Notice {
+        body: String,
+        timestamp: i64,
+    },
+    Relation {
+        relation: EventRelation,
+        timestamp: i64,
+    },
+    Poll {
+        event_id: String,
+        sender: String,
+        timestamp: i64,
+        poll: StoredPoll,
+    },
+}
+
+/// An event marty has no dedicated rendering for, shown as type + JSON.
+struct UnknownEvent {
+    event_id: String,
+    sender: String,
+    body: String,
+    timestamp: i64,
+}
+
+const HANDLED_MSGTYPES: [&str; 8] = [
+    "m.text",
+    "m.notice",
+    "m.emote",
+    "m.image",
+    "m.file",
+    "m.video",
+    "m.audio",
+    "m.server_notice",
+];
+const HANDLED_EVENT_TYPES: [&str; 10] = [
+    "m.room.encrypted",
+    "m.room.redaction",
+    "m.reaction",
+    "m.sticker",
+    "m.poll.start",
+    "m.poll.response",
+    "m.poll.end",
+    "org.matrix.msc3381.poll.start",
+    "org.matrix.msc3381.poll.response",
+    "org.matrix.msc3381.poll.end",
+];
+const UNKNOWN_SNIPPET_LEN: usize = 200;
+
+/// Non-message events backfill cares about when explaining history gaps.
+enum HistoryMarker {
+    Undecryptable(i64),
+    OwnJoin(i64),
 }
 
 struct AttachmentInfo {
@@ -535,200 +1960,789 @@ struct AttachmentInfo {
 async fn backfill_since_last_seen(
     client: &Client,
     passphrase: &str,
+    sync_options: &SyncOptions,
+    evt_tx: &mpsc::UnboundedSender<MatrixEvent>,
+) {
+    for room in client.joined_rooms() {
+        backfill_room(client, &room, passphrase, sync_options, evt_tx).await;
+    }
+}
+
+/// Fetches what `room` got since its newest stored message. A room with no
+/// stored messages, e.g. one just joined, is a first visit and gets its
+/// latest page of history, with the pre-join marker if that reaches back to
+/// our join.
+async fn backfill_room(
+    client: &Client,
+    room: &Room,
+    passphrase: &str,
+    sync_options: &SyncOptions,
     evt_tx: &mpsc::UnboundedSender<MatrixEvent>,
 ) {
     let Ok(messages_dir) = crate::config::messages_dir() else {
         return;
     };
-    for room in client.joined_rooms() {
-        let room_id = room.room_id().to_string();
-        let last_ts = match latest_room_timestamp(&messages_dir, &room_id, passphrase) {
-            Ok(Some(ts)) => ts,
-            _ => continue,
+    let own_user = client.user_id().map(|id| id.to_string());
+    let room_id = room.room_id().to_string();
+    let (last_ts, first_visit) = match latest_room_timestamp(&messages_dir, &room_id, passphrase) {
+        Ok(Some(ts)) => (ts, false),
+        Ok(None) => (i64::MIN, true),
+        Err(_) => return,
+    };
+    let notice_room = is_server_notice_room(room).await;
+    let encrypted = room.is_encrypted().await.unwrap_or(false);
+    let download = !sync_options.bandwidth_saver.load(Ordering::Relaxed);
+    let mut undecryptable: Option<(usize, i64)> = None;
+    let mut joined_at: Option<i64> = None;
+    let mut from: Option<String> = None;
+    let mut collected: Vec<BackfillItem> = Vec::new();
+    loop {
+        let mut options = MessagesOptions::backward();
+        options.limit = uint!(50);
+        if let Some(token) = from.as_ref() {
+            options.from = Some(token.clone());
+        }
+        let Ok(messages) = room.messages(options).await else {
+            break;
         };
-        let mut from: Option<String> = None;
-        let mut collected: Vec<BackfillItem> = Vec::new();
-        loop {
-            let mut options = MessagesOptions::backward();
-            options.limit = uint!(50);
-            if let Some(token) = from.as_ref() {
-                options.from = Some(token.clone());
+        if messages.chunk.is_empty() {
+            break;
+        }
+        let mut stop = false;
+        for event in messages.chunk {
+            if sync_options.render_unknown_events {
+                if let Some(unknown) = unknown_event(timeline_raw(&event)) {
+                    if unknown.timestamp <= last_ts {
+                        stop = true;
+                        break;
+                    }
+                    collected.push(BackfillItem::Text {
+                        event_id: unknown.event_id,
+                        sender: unknown.sender,
+                        body: unknown.body,
+                        timestamp: unknown.timestamp,
+                        reply_to: None,
+                        thread_root: None,
+                        server_notice: false,
+                    });
+                    continue;
+                }
             }
-            let Ok(messages) = room.messages(options).await else {
-                break;
-            };
-            if messages.chunk.is_empty() {
-                break;
+            if let Some((ts, relation)) = event_relation(timeline_raw(&event)) {
+                if ts <= last_ts {
+                    stop = true;
+                    break;
+                }
+                collected.push(BackfillItem::Relation {
+                    relation,
+                    timestamp: ts,
+                });
+                continue;
             }
-            let mut stop = false;
-            for event in messages.chunk {
-                let Ok(message) = event.event.deserialize_as::<OriginalRoomMessageEvent>() else {
-                    continue;
-                };
-                let ts = i64::from(message.origin_server_ts.0);
+            if let Some((ts, item)) = backfill_poll(timeline_raw(&event)) {
                 if ts <= last_ts {
                     stop = true;
                     break;
                 }
-                match &message.content.msgtype {
-                    MessageType::Text(text) => {
-                        collected.push(BackfillItem::Text {
-                            event_id: message.event_id.to_string(),
-                            sender: message.sender.to_string(),
-                            body: text.body.clone(),
-                            timestamp: ts,
-                            reply_to: extract_reply_to(&message.content),
-                        });
+                collected.push(item);
+                continue;
+            }
+            if let Ok(AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::Sticker(
+                MessageLikeEvent::Original(sticker),
+            ))) = timeline_raw(&event).deserialize()
+            {
+                let ts = i64::from(sticker.origin_server_ts.0);
+                if ts <= last_ts {
+                    stop = true;
+                    break;
+                }
+                if let Some(item) = backfill_attachment(
+                    room,
+                    sticker.event_id.as_ref(),
+                    sticker.sender.as_ref(),
+                    ts,
+                    "sticker",
+                    &sticker.content.body,
+                    None,
+                    None,
+                    &sticker.content,
+                    download,
+                )
+                .await
+                {
+                    collected.push(item);
+                }
+                continue;
+            }
+            let Ok(message) = timeline_raw(&event).deserialize_as::<OriginalRoomMessageEvent>() else {
+                match history_marker(timeline_raw(&event), own_user.as_deref()) {
+                    Some(HistoryMarker::Undecryptable(ts)) if ts > last_ts => {
+                        let entry = undecryptable.get_or_insert((0, ts));
+                        entry.0 += 1;
+                        entry.1 = entry.1.min(ts);
                     }
-                    MessageType::Image(content) => {
-                        if let Some(item) = backfill_attachment(
-                            &room,
-                            &message.event_id.to_string(),
-                            &message.sender.to_string(),
-                            ts,
-                            "image",
-                            &content.body,
-                            extract_reply_to(&message.content),
-                            content,
-                        )
-                        .await
-                        {
-                            collected.push(item);
-                        }
+                    Some(HistoryMarker::OwnJoin(ts)) if ts > last_ts && encrypted => {
+                        joined_at = Some(ts);
+                        stop = true;
+                        break;
                     }
-                    MessageType::File(content) => {
-                        if let Some(item) = backfill_attachment(
-                            &room,
-                            &message.event_id.to_string(),
-                            &message.sender.to_string(),
-                            ts,
-                            "file",
-                            &content.body,
-                            extract_reply_to(&message.content),
-                            content,
-                        )
-                        .await
-                        {
-                            collected.push(item);
+                    _ => {}
+                }
+                continue;
+            };
+            let ts = i64::from(message.origin_server_ts.0);
+            if ts <= last_ts {
+                stop = true;
+                break;
+            }
+            match &message.content.msgtype {
+                MessageType::Text(_) | MessageType::Notice(_) | MessageType::Emote(_) => {
+                    let body = match &message.content.msgtype {
+                        MessageType::Emote(emote) => {
+                            emote_text(message.sender.as_str(), &emote.body)
                         }
+                        other => other.body().to_string(),
+                    };
+                    collected.push(BackfillItem::Text {
+                        event_id: message.event_id.to_string(),
+                        sender: message.sender.to_string(),
+                        body,
+                        timestamp: ts,
+                        reply_to: extract_reply_to(&message.content),
+                        thread_root: extract_thread_root(&message.content),
+                        server_notice: notice_room,
+                    });
+                }
+                MessageType::ServerNotice(notice) => {
+                    collected.push(BackfillItem::Text {
+                        event_id: message.event_id.to_string(),
+                        sender: message.sender.to_string(),
+                        body: server_notice_text(notice),
+                        timestamp: ts,
+                        reply_to: extract_reply_to(&message.content),
+                        thread_root: extract_thread_root(&message.content),
+                        server_notice: true,
+                    });
+                }
+                MessageType::Image(content) => {
+                    if let Some(item) = backfill_attachment(
+                        room,
+                        message.event_id.as_ref(),
+                        message.sender.as_ref(),
+                        ts,
+                        "image",
+                        &content.body,
+                        extract_reply_to(&message.content),
+                        extract_thread_root(&message.content),
+                        content,
+                        download,
+                    )
+                    .await
+                    {
+                        collected.push(item);
                     }
-                    MessageType::Video(content) => {
-                        if let Some(item) = backfill_attachment(
-                            &room,
-                            &message.event_id.to_string(),
-                            &message.sender.to_string(),
-                            ts,
-                            "video",
-                            &content.body,
-                            extract_reply_to(&message.content),
-                            content,
-                        )
-                        .await
-                        {
-                            collected.push(item);
-                        }
+                }
+                MessageType::File(content) => {
+                    if let Some(item) = backfill_attachment(
+                        room,
+                        message.event_id.as_ref(),
+                        message.sender.as_ref(),
+                        ts,
+                        "file",
+                        &content.body,
+                        extract_reply_to(&message.content),
+                        extract_thread_root(&message.content),
+                        content,
+                        download,
+                    )
+                    .await
+                    {
+                        collected.push(item);
                     }
-                    MessageType::Audio(content) => {
-                        if let Some(item) = backfill_attachment(
-                            &room,
-                            &message.event_id.to_string(),
-                            &message.sender.to_string(),
-                            ts,
-                            "audio",
-                            &content.body,
-                            extract_reply_to(&message.content),
-                            content,
-                        )
-                        .await
-                        {
-                            collected.push(item);
-                        }
+                }
+                MessageType::Video(content) => {
+                    if let Some(item) = backfill_attachment(
+                        room,
+                        message.event_id.as_ref(),
+                        message.sender.as_ref(),
+                        ts,
+                        "video",
+                        &content.body,
+                        extract_reply_to(&message.content),
+                        extract_thread_root(&message.content),
+                        content,
+                        download,
+                    )
+                    .await
+                    {
+                        collected.push(item);
                     }
-                    _ => {}
                 }
+                MessageType::Audio(content) => {
+                    if let Some(item) = backfill_attachment(
+                        room,
+                        message.event_id.as_ref(),
+                        message.sender.as_ref(),
+                        ts,
+                        "audio",
+                        &content.body,
+                        extract_reply_to(&message.content),
+                        extract_thread_root(&message.content),
+                        content,
+                        download,
+                    )
+                    .await
+                    {
+                        collected.push(item);
+                    }
+                }
+                _ => {}
+            }
+        }
+        if stop || first_visit {
+            break;
+        }
+        match messages.end {
+            Some(token) => from = Some(token),
+            None => break,
+        }
+    }
+    if let Some(ts) = joined_at {
+        collected.push(BackfillItem::Notice {
+            body: "History before you joined is unavailable.".to_string(),
+            timestamp: ts,
+        });
+    }
+    if let Some((count, ts)) = undecryptable {
+        let body = if count == 1 {
+            "1 message could not be decrypted.".to_string()
+        } else {
+            format!("{} messages could not be decrypted.", count)
+        };
+        collected.push(BackfillItem::Notice { body, timestamp: ts });
+    }
+    collected.sort_by_key(|msg| match msg {
+        BackfillItem::Text { timestamp, .. } => *timestamp,
+        BackfillItem::Attachment { timestamp, .. } => *timestamp,
+        BackfillItem::Notice { timestamp, .. } => *timestamp,
+        BackfillItem::Relation { timestamp, .. } => *timestamp,
+        BackfillItem::Poll { timestamp, .. } => *timestamp,
+    });
+    for msg in collected {
+        match msg {
+            BackfillItem::Text {
+                event_id,
+                sender,
+                body,
+                timestamp,
+                reply_to,
+                thread_root,
+                server_notice,
+            } => {
+                let _ = evt_tx.send(MatrixEvent::Message {
+                    room_id: room_id.clone(),
+                    event_id: event_id.clone(),
+                    sender: sender.clone(),
+                    body: body.clone(),
+                    timestamp,
+                    reply_to: reply_to.clone(),
+                    thread_root: thread_root.clone(),
+                });
+                if server_notice {
+                    let _ = evt_tx.send(MatrixEvent::ServerNotice {
+                        room_id: room_id.clone(),
+                        event_id: event_id.clone(),
+                        body: body.clone(),
+                        timestamp,
+                    });
+                }
+                let _ = store_message_encrypted(
+                    passphrase,
+                    &room_id,
+                    timestamp,
+                    &sender,
+                    &body,
+                    Some(&event_id),
+                    reply_to.as_deref(),
+                    thread_root.as_deref(),
+                    None,
+                );
+            }
+            BackfillItem::Attachment {
+                event_id,
+                sender,
+                name,
+                path,
+                kind,
+                timestamp,
+                reply_to,
+                thread_root,
+            } => {
+                let name_for_store = name.clone();
+                let name_for_attachment = name.clone();
+                let path_clone = path.clone();
+                let _ = evt_tx.send(MatrixEvent::Attachment {
+                    room_id: room_id.clone(),
+                    event_id: event_id.clone(),
+                    sender: sender.clone(),
+                    name: name.clone(),
+                    path: path.clone(),
+                    kind: kind.clone(),
+                    timestamp,
+                    reply_to: reply_to.clone(),
+                    thread_root: thread_root.clone(),
+                });
+                let _ = store_message_encrypted(
+                    passphrase,
+                    &room_id,
+                    timestamp,
+                    &sender,
+                    &name_for_store,
+                    Some(&event_id),
+                    reply_to.as_deref(),
+                    thread_root.as_deref(),
+                    Some(AttachmentInfo {
+                        kind,
+                        name: name_for_attachment,
+                        path: path_clone,
+                    }),
+                );
+            }
+            BackfillItem::Notice { body, timestamp } => {
+                let _ = evt_tx.send(MatrixEvent::RoomNotice {
+                    room_id: room_id.clone(),
+                    body,
+                    timestamp,
+                });
+            }
+            BackfillItem::Relation { relation, .. } => {
+                let _ = store_relation(passphrase, &room_id, &relation);
+                let _ = evt_tx.send(MatrixEvent::Related {
+                    room_id: room_id.clone(),
+                    relation,
+                });
+            }
+            BackfillItem::Poll {
+                event_id,
+                sender,
+                timestamp,
+                poll,
+            } => publish_poll(
+                passphrase, evt_tx, &room_id, &event_id, &sender, timestamp, poll,
+            ),
+        }
+    }
+}
+
+/// Queries `/versions` and `/capabilities`. Failed requests leave features
+/// at what a current server would offer, except unstable ones.
+async fn server_info(client: &Client) -> ServerInfo {
+    let server_name = client
+        .user_id()
+        .map(|id| id.server_name().to_string())
+        .or_else(|| client.homeserver().host_str().map(str::to_string))
+        .unwrap_or_default();
+    let (versions, unstable) = match client.send(get_supported_versions::Request::new(), None).await
+    {
+        Ok(response) => (response.versions, response.unstable_features),
+        Err(_) => Default::default(),
+    };
+    let minor = |version: &str| {
+        let (major, minor) = version.strip_prefix('v')?.split_once('.')?;
+        Some((major.parse::<u32>().ok()?, minor.parse::<u32>().ok()?))
+    };
+    let latest = versions.iter().filter_map(|v| minor(v).map(|m| (m, v))).max();
+    let at_least = |wanted: (u32, u32)| latest.is_some_and(|(version, _)| version >= wanted);
+    let unstable = |feature: &str| unstable.get(feature).copied().unwrap_or(false);
+    let set_displayname = client
+        .get_capabilities()
+        .await
+        .map(|caps| caps.set_displayname.enabled)
+        .unwrap_or(true);
+    ServerInfo {
+        server_name,
+        latest_version: latest.map(|(_, version)| version.clone()),
+        threads: at_least((1, 4)) || unstable("org.matrix.msc3440.stable"),
+        authenticated_media: at_least((1, 11)) || unstable("org.matrix.msc3916.stable"),
+        sliding_sync: unstable("org.matrix.simplified_msc3575") || unstable("org.matrix.msc3575"),
+        set_displayname,
+    }
+}
+
+/// A fetched event as sent by the server, room ID included.
+fn timeline_raw(event: &TimelineEvent) -> &Raw<AnyTimelineEvent> {
+    event.raw().cast_ref()
+}
+
+/// Edits, reactions and redactions, with their timestamp; `None` for
+/// anything else.
+fn event_relation(event: &Raw<AnyTimelineEvent>) -> Option<(i64, EventRelation)> {
+    let AnyTimelineEvent::MessageLike(event) = event.deserialize().ok()? else {
+        return None;
+    };
+    let ts = i64::from(event.origin_server_ts().0);
+    let relation = match event {
+        AnyMessageLikeEvent::RoomMessage(MessageLikeEvent::Original(message)) => {
+            let Some(Relation::Replacement(replacement)) = message.content.relates_to else {
+                return None;
+            };
+            EventRelation::Edit {
+                target: replacement.event_id.to_string(),
+                sender: message.sender.to_string(),
+                body: replacement.new_content.msgtype.body().to_string(),
+            }
+        }
+        AnyMessageLikeEvent::Reaction(MessageLikeEvent::Original(reaction)) => {
+            EventRelation::Reaction {
+                event_id: reaction.event_id.to_string(),
+                target: reaction.content.relates_to.event_id.to_string(),
+                sender: reaction.sender.to_string(),
+                key: reaction.content.relates_to.key,
+            }
+        }
+        AnyMessageLikeEvent::RoomRedaction(RoomRedactionEvent::Original(redaction)) => {
+            let target = redaction.redacts.or(redaction.content.redacts)?;
+            EventRelation::Redaction {
+                target: target.to_string(),
+            }
+        }
+        AnyMessageLikeEvent::PollResponse(MessageLikeEvent::Original(response)) => {
+            EventRelation::PollVote {
+                event_id: response.event_id.to_string(),
+                target: response.content.relates_to.event_id.to_string(),
+                sender: response.sender.to_string(),
+                answers: response.content.selections.to_vec(),
+                timestamp: ts,
             }
-            if stop {
-                break;
+        }
+        AnyMessageLikeEvent::UnstablePollResponse(MessageLikeEvent::Original(response)) => {
+            EventRelation::PollVote {
+                event_id: response.event_id.to_string(),
+                target: response.content.relates_to.event_id.to_string(),
+                sender: response.sender.to_string(),
+                answers: response.content.poll_response.answers,
+                timestamp: ts,
             }
-            match messages.end {
-                Some(token) => from = Some(token),
-                None => break,
+        }
+        AnyMessageLikeEvent::PollEnd(MessageLikeEvent::Original(end)) => EventRelation::PollEnd {
+            target: end.content.relates_to.event_id.to_string(),
+            sender: end.sender.to_string(),
+            timestamp: ts,
+        },
+        AnyMessageLikeEvent::UnstablePollEnd(MessageLikeEvent::Original(end)) => {
+            EventRelation::PollEnd {
+                target: end.content.relates_to.event_id.to_string(),
+                sender: end.sender.to_string(),
+                timestamp: ts,
             }
         }
-        collected.sort_by_key(|msg| match msg {
-            BackfillItem::Text { timestamp, .. } => *timestamp,
-            BackfillItem::Attachment { timestamp, .. } => *timestamp,
-        });
-        for msg in collected {
-            match msg {
-                BackfillItem::Text {
-                    event_id,
-                    sender,
-                    body,
-                    timestamp,
-                    reply_to,
-                } => {
-                    let _ = evt_tx.send(MatrixEvent::Message {
-                        room_id: room_id.clone(),
-                        event_id: event_id.clone(),
-                        sender: sender.clone(),
-                        body: body.clone(),
-                        timestamp,
-                        reply_to: reply_to.clone(),
-                    });
-                    let _ = store_message_encrypted(
-                        passphrase,
-                        &room_id,
-                        timestamp,
-                        &sender,
-                        &body,
-                        Some(&event_id),
-                        reply_to.as_deref(),
-                        None,
-                    );
-                }
-                BackfillItem::Attachment {
-                    event_id,
-                    sender,
-                    name,
-                    path,
-                    kind,
-                    timestamp,
-                    reply_to,
-                } => {
-                    let name_for_store = name.clone();
-                    let name_for_attachment = name.clone();
-                    let path_clone = path.clone();
-                    let _ = evt_tx.send(MatrixEvent::Attachment {
-                        room_id: room_id.clone(),
-                        event_id: event_id.clone(),
-                        sender: sender.clone(),
-                        name: name.clone(),
-                        path: path.clone(),
-                        kind: kind.clone(),
-                        timestamp,
-                        reply_to: reply_to.clone(),
-                    });
-                    let _ = store_message_encrypted(
-                        passphrase,
-                        &room_id,
-                        timestamp,
-                        &sender,
-                        &name_for_store,
-                        Some(&event_id),
-                        reply_to.as_deref(),
-                        Some(AttachmentInfo {
-                            kind,
-                            name: name_for_attachment,
-                            path: path_clone,
-                        }),
-                    );
+        _ => return None,
+    };
+    Some((ts, relation))
+}
+
+/// A poll start in backfilled history, with its timestamp.
+fn backfill_poll(event: &Raw<AnyTimelineEvent>) -> Option<(i64, BackfillItem)> {
+    let AnyTimelineEvent::MessageLike(event) = event.deserialize().ok()? else {
+        return None;
+    };
+    let poll = match &event {
+        AnyMessageLikeEvent::PollStart(MessageLikeEvent::Original(start)) => {
+            stable_poll(&start.content.poll)
+        }
+        AnyMessageLikeEvent::UnstablePollStart(MessageLikeEvent::Original(start)) => {
+            unstable_poll(&start.content)?
+        }
+        _ => return None,
+    };
+    let ts = i64::from(event.origin_server_ts().0);
+    let item = BackfillItem::Poll {
+        event_id: event.event_id().to_string(),
+        sender: event.sender().to_string(),
+        timestamp: ts,
+        poll,
+    };
+    Some((ts, item))
+}
+
+/// Applies a relation to the room log: edits replace the body, reactions
+/// and votes are recorded on their target, a poll end closes the poll from
+/// then on, and redactions go through `purge_redacted`.
+fn store_relation(passphrase: &str, room_id: &str, relation: &EventRelation) -> Result<()> {
+    if let EventRelation::Redaction { target } = relation {
+        return purge_redacted(passphrase, room_id, target);
+    }
+    let messages_dir = crate::config::messages_dir()?;
+    update_messages(&messages_dir, passphrase, room_id, |records| match relation {
+        EventRelation::Edit {
+            target,
+            sender,
+            body,
+        } => {
+            let Some(record) = records.iter_mut().find(|msg| {
+                msg.event_id.as_deref() == Some(target.as_str()) && &msg.sender == sender
+            }) else {
+                return false;
+            };
+            record.body = body.clone();
+            record.edited = true;
+            true
+        }
+        EventRelation::Reaction {
+            event_id,
+            target,
+            sender,
+            key,
+        } => {
+            let Some(record) = records
+                .iter_mut()
+                .find(|msg| msg.event_id.as_deref() == Some(target.as_str()))
+            else {
+                return false;
+            };
+            if record.reactions.iter().any(|r| &r.event_id == event_id) {
+                return false;
+            }
+            record.reactions.push(StoredReaction {
+                event_id: event_id.clone(),
+                sender: sender.clone(),
+                key: key.clone(),
+            });
+            true
+        }
+        EventRelation::PollVote {
+            event_id,
+            target,
+            sender,
+            answers,
+            timestamp,
+        } => {
+            let Some(record) = records.iter_mut().find(|msg| {
+                msg.event_id.as_deref() == Some(target.as_str()) && msg.poll.is_some()
+            }) else {
+                return false;
+            };
+            if record.votes.iter().any(|v| &v.event_id == event_id) {
+                return false;
+            }
+            record.votes.push(StoredVote {
+                event_id: event_id.clone(),
+                sender: sender.clone(),
+                answers: answers.clone(),
+                timestamp: *timestamp,
+            });
+            true
+        }
+        EventRelation::PollEnd {
+            target,
+            sender,
+            timestamp,
+        } => {
+            let Some(poll) = records
+                .iter_mut()
+                .find(|msg| {
+                    msg.event_id.as_deref() == Some(target.as_str()) && &msg.sender == sender
+                })
+                .and_then(|msg| msg.poll.as_mut())
+            else {
+                return false;
+            };
+            if poll.ended.is_some_and(|ended| ended <= *timestamp) {
+                return false;
+            }
+            poll.ended = Some(*timestamp);
+            true
+        }
+        EventRelation::Redaction { .. } => false,
+    })?;
+    Ok(())
+}
+
+/// Removes the local traces of a redacted event: a message's text,
+/// attachment and annotations are cleared from the log and its backup and
+/// the file marty downloaded for it is deleted; a redacted reaction or vote
+/// is dropped from its target. The caller drops it from the search index
+/// with `SearchIndex::remove`.
+fn purge_redacted(passphrase: &str, room_id: &str, target: &str) -> Result<()> {
+    let messages_dir = crate::config::messages_dir()?;
+    let mut download = None;
+    purge_messages(&messages_dir, passphrase, room_id, |records| {
+        for record in records.iter_mut() {
+            if record.event_id.as_deref() == Some(target) {
+                record.body.clear();
+                download = record.attachment_path.take();
+                record.attachment_name = None;
+                record.attachment_kind = None;
+                record.reactions.clear();
+                record.poll = None;
+                record.votes.clear();
+                record.redacted = true;
+                return true;
+            }
+            let before = record.reactions.len() + record.votes.len();
+            record.reactions.retain(|r| r.event_id != target);
+            record.votes.retain(|v| v.event_id != target);
+            if record.reactions.len() + record.votes.len() != before {
+                return true;
+            }
+        }
+        false
+    })?;
+    // Only files marty downloaded itself; never a path outside its data dir.
+    if let Some(path) = download {
+        let attachments = crate::config::data_dir()?.join("attachments");
+        if Path::new(&path).starts_with(&attachments) {
+            let _ = fs::remove_file(&path);
+        }
+    }
+    Ok(())
+}
+
+/// Exports the inbound group sessions of `room_id` only and returns how
+/// many were written.
+async fn export_room_keys(
+    client: &Client,
+    room_id: &str,
+    path: &Path,
+    passphrase: &str,
+) -> Result<usize> {
+    let room_id = RoomId::parse(room_id)?;
+    let mut count = 0;
+    client
+        .encryption()
+        .export_room_keys(path.to_path_buf(), passphrase, |session| {
+            let matches = session.room_id() == room_id;
+            count += usize::from(matches);
+            matches
+        })
+        .await?;
+    if count == 0 {
+        let _ = fs::remove_file(path);
+        anyhow::bail!("no keys stored for this room");
+    }
+    Ok(count)
+}
+
+/// Fetches an event from the server and describes how it was encrypted: the
+/// megolm session it belongs to and the sending device as seen on decryption.
+async fn event_encryption_details(
+    client: &Client,
+    room: &Room,
+    event_id: OwnedEventId,
+) -> Vec<(String, String)> {
+    let request = get_room_event::v3::Request::new(room.room_id().to_owned(), event_id);
+    let raw = match client.send(request, None).await {
+        Ok(response) => response.event,
+        Err(err) => return vec![("Encryption".to_string(), format!("unavailable: {}", err))],
+    };
+    let event_type = raw.get_field::<String>("type").ok().flatten().unwrap_or_default();
+    if event_type != "m.room.encrypted" {
+        return vec![("Encryption".to_string(), "not encrypted".to_string())];
+    }
+    let content = raw
+        .get_field::<serde_json::Value>("content")
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    let mut details = Vec::new();
+    for (label, key) in [("Algorithm", "algorithm"), ("Session ID", "session_id")] {
+        if let Some(value) = content.get(key).and_then(|v| v.as_str()) {
+            details.push((label.to_string(), value.to_string()));
+        }
+    }
+    match room.decrypt_event(raw.cast_ref()).await {
+        Ok(event) => {
+            if let Some(info) = event.encryption_info() {
+                if let Some(device) = &info.sender_device {
+                    details.push(("Sender device".to_string(), device.to_string()));
                 }
+                let AlgorithmInfo::MegolmV1AesSha2 { curve25519_key, .. } = &info.algorithm_info;
+                details.push(("Sender key".to_string(), curve25519_key.clone()));
+                let verification = match &info.verification_state {
+                    VerificationState::Verified => "verified".to_string(),
+                    VerificationState::Unverified(level) => format!("unverified ({:?})", level),
+                };
+                details.push(("Verification".to_string(), verification));
+            }
+        }
+        Err(err) => details.push(("Decryption".to_string(), format!("failed: {}", err))),
+    }
+    details
+}
+
+fn state_entries(events: &[Raw<AnyStateEvent>]) -> Vec<StateEntry> {
+    let mut entries: Vec<StateEntry> = events
+        .iter()
+        .filter_map(|raw| {
+            let content = raw
+                .get_field::<serde_json::Value>("content")
+                .ok()
+                .flatten()
+                .unwrap_or_default();
+            Some(StateEntry {
+                event_type: raw.get_field::<String>("type").ok().flatten()?,
+                state_key: raw.get_field::<String>("state_key").ok().flatten().unwrap_or_default(),
+                content: serde_json::to_string_pretty(&content).unwrap_or_default(),
+            })
+        })
+        .collect();
+    entries.sort_by(|a, b| (&a.event_type, &a.state_key).cmp(&(&b.event_type, &b.state_key)));
+    entries
+}
+
+fn unknown_event(event: &Raw<AnyTimelineEvent>) -> Option<UnknownEvent> {
+    if event.get_field::<String>("state_key").ok().flatten().is_some() {
+        return None;
+    }
+    let event_type = event.get_field::<String>("type").ok().flatten()?;
+    let content = event
+        .get_field::<serde_json::Value>("content")
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    let label = if event_type == "m.room.message" {
+        let msgtype = content.get("msgtype").and_then(|v| v.as_str()).unwrap_or_default();
+        if HANDLED_MSGTYPES.contains(&msgtype) {
+            return None;
+        }
+        format!("{} {}", event_type, msgtype)
+    } else if HANDLED_EVENT_TYPES.contains(&event_type.as_str()) {
+        return None;
+    } else {
+        event_type
+    };
+    let mut snippet = content.to_string();
+    if snippet.chars().count() > UNKNOWN_SNIPPET_LEN {
+        snippet = snippet.chars().take(UNKNOWN_SNIPPET_LEN).collect();
+        snippet.push('…');
+    }
+    Some(UnknownEvent {
+        event_id: event.get_field::<String>("event_id").ok().flatten()?,
+        sender: event.get_field::<String>("sender").ok().flatten()?,
+        body: format!("[{}] {}", label.trim(), snippet),
+        timestamp: event.get_field::<i64>("origin_server_ts").ok().flatten()?,
+    })
+}
+
+fn history_marker(event: &Raw<AnyTimelineEvent>, own_user: Option<&str>) -> Option<HistoryMarker> {
+    let event_type = event.get_field::<String>("type").ok().flatten()?;
+    let ts = event.get_field::<i64>("origin_server_ts").ok().flatten()?;
+    match event_type.as_str() {
+        "m.room.encrypted" => Some(HistoryMarker::Undecryptable(ts)),
+        "m.room.member" => {
+            let member = event.deserialize_as::<OriginalRoomMemberEvent>().ok()?;
+            let is_own = own_user.is_some_and(|own| member.state_key.as_str() == own);
+            if is_own && matches!(member.membership_change(), MembershipChange::Joined) {
+                Some(HistoryMarker::OwnJoin(ts))
+            } else {
+                None
             }
         }
+        _ => None,
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_attachment_event<T: MediaEventContent + ?Sized>(
     room: &Room,
     passphrase: &str,
@@ -740,13 +2754,15 @@ async fn handle_attachment_event<T: MediaEventContent + ?Sized>(
     kind: &str,
     body: &str,
     reply_to: Option<String>,
+    thread_root: Option<String>,
     content: &T,
+    download: bool,
 ) {
     let Some(source) = content.source() else {
         return;
     };
     let name = attachment_name(body, kind);
-    match download_attachment(room, &source, &name).await {
+    match fetch_or_skip(room, &source, &name, download).await {
         Ok(path) => {
             let path_str = path.to_string_lossy().to_string();
             let _ = evt_tx.send(MatrixEvent::Attachment {
@@ -758,6 +2774,7 @@ async fn handle_attachment_event<T: MediaEventContent + ?Sized>(
                 kind: kind.to_string(),
                 timestamp: ts,
                 reply_to: reply_to.clone(),
+                thread_root: thread_root.clone(),
             });
             let _ = store_message_encrypted(
                 passphrase,
@@ -767,6 +2784,7 @@ async fn handle_attachment_event<T: MediaEventContent + ?Sized>(
                 &name,
                 Some(event_id),
                 reply_to.as_deref(),
+                thread_root.as_deref(),
                 Some(AttachmentInfo {
                     kind: kind.to_string(),
                     name: name.clone(),
@@ -783,6 +2801,7 @@ async fn handle_attachment_event<T: MediaEventContent + ?Sized>(
                 body: fallback.clone(),
                 timestamp: ts,
                 reply_to: reply_to.clone(),
+                thread_root: thread_root.clone(),
             });
             let _ = store_message_encrypted(
                 passphrase,
@@ -792,12 +2811,14 @@ async fn handle_attachment_event<T: MediaEventContent + ?Sized>(
                 &fallback,
                 Some(event_id),
                 reply_to.as_deref(),
+                thread_root.as_deref(),
                 None,
             );
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn backfill_attachment<T: MediaEventContent + ?Sized>(
     room: &Room,
     event_id: &str,
@@ -806,13 +2827,13 @@ async fn backfill_attachment<T: MediaEventContent + ?Sized>(
     kind: &str,
     body: &str,
     reply_to: Option<String>,
+    thread_root: Option<String>,
     content: &T,
+    download: bool,
 ) -> Option<BackfillItem> {
-    let Some(source) = content.source() else {
-        return None;
-    };
+    let source = content.source()?;
     let name = attachment_name(body, kind);
-    match download_attachment(room, &source, &name).await {
+    match fetch_or_skip(room, &source, &name, download).await {
         Ok(path) => Some(BackfillItem::Attachment {
             event_id: event_id.to_string(),
             sender: sender.to_string(),
@@ -821,6 +2842,7 @@ async fn backfill_attachment<T: MediaEventContent + ?Sized>(
             kind: kind.to_string(),
             timestamp: ts,
             reply_to,
+            thread_root,
         }),
         Err(_) => Some(BackfillItem::Text {
             event_id: event_id.to_string(),
@@ -828,12 +2850,174 @@ async fn backfill_attachment<T: MediaEventContent + ?Sized>(
             body: format!("[{}] {}", kind, name),
             timestamp: ts,
             reply_to,
+            thread_root,
+            server_notice: false,
         }),
     }
 }
 
+/// Fetches an attachment event again and downloads its media, updating the
+/// stored record to point at the new file.
+async fn redownload_attachment(
+    client: &Client,
+    passphrase: &str,
+    room_id: &str,
+    event_id: &str,
+) -> Result<PathBuf> {
+    let room = RoomId::parse(room_id)
+        .ok()
+        .and_then(|id| client.get_room(&id))
+        .context("unknown room")?;
+    let event = room.event(&EventId::parse(event_id)?, None).await?;
+    let AnyTimelineEvent::MessageLike(event) = timeline_raw(&event).deserialize()? else {
+        anyhow::bail!("not an attachment");
+    };
+    let path = match &event {
+        AnyMessageLikeEvent::Sticker(MessageLikeEvent::Original(sticker)) => {
+            let content = &sticker.content;
+            fetch_media(&room, content, &content.body, "sticker").await?
+        }
+        AnyMessageLikeEvent::RoomMessage(MessageLikeEvent::Original(message)) => {
+            match &message.content.msgtype {
+                MessageType::Image(c) => fetch_media(&room, c, &c.body, "image").await?,
+                MessageType::File(c) => fetch_media(&room, c, &c.body, "file").await?,
+                MessageType::Video(c) => fetch_media(&room, c, &c.body, "video").await?,
+                MessageType::Audio(c) => fetch_media(&room, c, &c.body, "audio").await?,
+                _ => anyhow::bail!("not an attachment"),
+            }
+        }
+        _ => anyhow::bail!("not an attachment"),
+    };
+    let base = crate::config::messages_dir()?;
+    update_attachment_path(&base, passphrase, room_id, event_id, &path.to_string_lossy())?;
+    Ok(path)
+}
+
+/// Fetches an undecryptable event and decrypts it again. A failed attempt
+/// queues a key request to our other devices; the key backup, when this
+/// session has it enabled, is asked for the session before a second try.
+/// The decrypted text is stored and returned; `None` when the key is
+/// still missing.
+async fn retry_decryption(
+    client: &Client,
+    passphrase: &str,
+    room_id: &str,
+    event_id: &str,
+) -> Result<Option<String>> {
+    let room = RoomId::parse(room_id)
+        .ok()
+        .and_then(|id| client.get_room(&id))
+        .context("unknown room")?;
+    let request =
+        get_room_event::v3::Request::new(room.room_id().to_owned(), EventId::parse(event_id)?);
+    let raw = client.send(request, None).await?.event;
+    let session_id = raw
+        .get_field::<serde_json::Value>("content")
+        .ok()
+        .flatten()
+        .and_then(|content| Some(content.get("session_id")?.as_str()?.to_string()));
+    let mut decrypted = room.decrypt_event(raw.cast_ref()).await;
+    let backups = client.encryption().backups();
+    if let Some(session_id) = session_id.filter(|_| decrypted.is_err()) {
+        if backups.are_enabled().await
+            && backups
+                .download_room_key(room.room_id(), &session_id)
+                .await
+                .is_ok()
+        {
+            decrypted = room.decrypt_event(raw.cast_ref()).await;
+        }
+    }
+    let Ok(event) = decrypted else {
+        return Ok(None);
+    };
+    let AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(
+        MessageLikeEvent::Original(message),
+    )) = timeline_raw(&event).deserialize()?
+    else {
+        anyhow::bail!("not a message");
+    };
+    let body = message.content.msgtype.body().to_string();
+    let _ = store_message_encrypted(
+        passphrase,
+        room_id,
+        i64::from(message.origin_server_ts.0),
+        message.sender.as_str(),
+        &body,
+        Some(event_id),
+        extract_reply_to(&message.content).as_deref(),
+        extract_thread_root(&message.content).as_deref(),
+        None,
+    );
+    Ok(Some(body))
+}
+
+/// Sender and body of a message fetched by event ID, for reply quotes.
+async fn fetch_reply_preview(
+    client: &Client,
+    room_id: &str,
+    event_id: &str,
+) -> Result<(String, String)> {
+    let room = RoomId::parse(room_id)
+        .ok()
+        .and_then(|id| client.get_room(&id))
+        .context("unknown room")?;
+    let event = room.event(&EventId::parse(event_id)?, None).await?;
+    let AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(
+        MessageLikeEvent::Original(message),
+    )) = timeline_raw(&event).deserialize()?
+    else {
+        anyhow::bail!("not a message");
+    };
+    Ok((
+        message.sender.to_string(),
+        message.content.msgtype.body().to_string(),
+    ))
+}
+
+/// The OpenGraph title (or description) of `url`, on one line.
+// The unauthenticated endpoint: servers predating Matrix 1.11 lack the
+// authenticated one, and the rest still serve this.
+#[allow(deprecated)]
+async fn fetch_link_title(client: &Client, url: &str) -> Option<String> {
+    let mut request = get_media_preview::v3::Request::new(url.to_string());
+    request.ts = Some(MilliSecondsSinceUnixEpoch::now());
+    let data = client.send(request, None).await.ok()?.data?;
+    let data: serde_json::Value = serde_json::from_str(data.get()).ok()?;
+    ["og:title", "og:description"]
+        .iter()
+        .filter_map(|key| data.get(key)?.as_str())
+        .map(|text| text.split_whitespace().collect::<Vec<_>>().join(" "))
+        .find(|text| !text.is_empty())
+}
+
+async fn fetch_media<T: MediaEventContent + ?Sized>(
+    room: &Room,
+    content: &T,
+    body: &str,
+    kind: &str,
+) -> Result<PathBuf> {
+    let source = content.source().context("attachment has no media")?;
+    download_attachment(room, &source, &attachment_name(body, kind)).await
+}
+
+/// Downloads an attachment, or with `download` off leaves it for later:
+/// the empty path marks it as not downloaded, and opening it fetches it
+/// like a missing file.
+async fn fetch_or_skip(
+    room: &Room,
+    source: &MediaSource,
+    name: &str,
+    download: bool,
+) -> Result<PathBuf> {
+    if !download {
+        return Ok(PathBuf::new());
+    }
+    download_attachment(room, source, name).await
+}
+
 async fn download_attachment(room: &Room, source: &MediaSource, name: &str) -> Result<PathBuf> {
-    let request = MediaRequest {
+    let request = MediaRequestParameters {
         source: source.clone(),
         format: MediaFormat::File,
     };
@@ -855,6 +3039,52 @@ fn attachment_name(body: &str, fallback: &str) -> String {
     }
 }
 
+/// Uploads an attachment and sends it as a reply to `reply_to`, which
+/// `Room::send_attachment` has no way to express. Encrypted rooms get an
+/// encrypted upload like `send_attachment` would do.
+async fn send_reply_attachment(
+    client: &Client,
+    room: &Room,
+    body: &str,
+    mime: &mime_guess::Mime,
+    data: Vec<u8>,
+    reply_to: OwnedEventId,
+) -> matrix_sdk::Result<()> {
+    let source = if room.is_encrypted().await? {
+        let mut reader = std::io::Cursor::new(data);
+        let file = client.upload_encrypted_file(mime, &mut reader).await?;
+        MediaSource::Encrypted(Box::new(file))
+    } else {
+        MediaSource::Plain(client.media().upload(mime, data, None).await?.content_uri)
+    };
+    let body = body.to_string();
+    let mimetype = Some(mime.essence_str().to_string());
+    let msgtype = match mime.type_() {
+        mime_guess::mime::IMAGE => {
+            let info = assign!(ImageInfo::new(), { mimetype });
+            MessageType::Image(ImageMessageEventContent::new(body, source).info(Box::new(info)))
+        }
+        mime_guess::mime::VIDEO => {
+            let info = assign!(VideoInfo::new(), { mimetype });
+            MessageType::Video(VideoMessageEventContent::new(body, source).info(Box::new(info)))
+        }
+        mime_guess::mime::AUDIO => {
+            let info = assign!(AudioInfo::new(), { mimetype });
+            MessageType::Audio(AudioMessageEventContent::new(body, source).info(Box::new(info)))
+        }
+        _ => {
+            let info = assign!(FileInfo::new(), { mimetype });
+            MessageType::File(FileMessageEventContent::new(body, source).info(Box::new(info)))
+        }
+    };
+    let mut content = RoomMessageEventContent::new(msgtype);
+    content.relates_to = Some(Relation::Reply {
+        in_reply_to: InReplyTo::new(reply_to),
+    });
+    room.send(content).await?;
+    Ok(())
+}
+
 fn extract_reply_to(content: &RoomMessageEventContent) -> Option<String> {
     match content.relates_to.as_ref() {
         Some(Relation::Reply { in_reply_to }) => Some(in_reply_to.event_id.to_string()),
@@ -862,6 +3092,80 @@ fn extract_reply_to(content: &RoomMessageEventContent) -> Option<String> {
     }
 }
 
+fn extract_thread_root(content: &RoomMessageEventContent) -> Option<String> {
+    match content.relates_to.as_ref() {
+        Some(Relation::Thread(thread)) => Some(thread.event_id.to_string()),
+        _ => None,
+    }
+}
+
+async fn is_server_notice_room(room: &Room) -> bool {
+    room.tags()
+        .await
+        .ok()
+        .flatten()
+        .is_some_and(|tags| tags.contains_key(&TagName::ServerNotice))
+}
+
+/// A timeline line for a membership change, or `None` for changes not
+/// worth showing (avatar updates, no-ops).
+fn membership_notice(ev: &OriginalSyncRoomMemberEvent) -> Option<String> {
+    let sender = format_sender(ev.sender.as_str());
+    let target = ev
+        .content
+        .displayname
+        .clone()
+        .or_else(|| ev.unsigned.prev_content.as_ref()?.displayname.clone())
+        .unwrap_or_else(|| format_sender(ev.state_key.as_str()));
+    let body = match ev.membership_change() {
+        MembershipChange::Joined | MembershipChange::InvitationAccepted => {
+            format!("{} joined", target)
+        }
+        MembershipChange::Left => format!("{} left", target),
+        MembershipChange::Invited => format!("{} invited {}", sender, target),
+        MembershipChange::InvitationRejected => format!("{} declined the invitation", target),
+        MembershipChange::InvitationRevoked => {
+            format!("{} withdrew the invitation of {}", sender, target)
+        }
+        MembershipChange::Kicked => format!("{} removed {}", sender, target),
+        MembershipChange::Banned | MembershipChange::KickedAndBanned => {
+            format!("{} banned {}", sender, target)
+        }
+        MembershipChange::Unbanned => format!("{} unbanned {}", sender, target),
+        MembershipChange::ProfileChanged {
+            displayname_change: Some(change),
+            ..
+        } => {
+            let old = change.old.map_or_else(
+                || format_sender(ev.state_key.as_str()),
+                str::to_string,
+            );
+            match change.new {
+                Some(new) => format!("{} is now known as {}", old, new),
+                None => format!("{} removed their display name", old),
+            }
+        }
+        _ => return None,
+    };
+    Some(body)
+}
+
+/// A `/me` message, shown as "* sender body".
+fn emote_text(sender: &str, body: &str) -> String {
+    format!("* {} {}", format_sender(sender), body)
+}
+
+fn server_notice_text(notice: &ServerNoticeMessageEventContent) -> String {
+    let mut text = match notice.server_notice_type {
+        ServerNoticeType::UsageLimitReached => format!("Usage limit reached: {}", notice.body),
+        _ => notice.body.clone(),
+    };
+    if let Some(contact) = notice.admin_contact.as_deref() {
+        text.push_str(&format!(" (contact: {})", contact));
+    }
+    text
+}
+
 fn sanitize_filename(name: &str) -> String {
     let cleaned = name.replace(['/', '\\'], "_");
     let trimmed = cleaned.trim();
@@ -901,13 +3205,15 @@ fn uuid_suffix() -> String {
     format!("{:x}", rand::random::<u64>())
 }
 
-async fn resolve_room_name(client: &Client, room: &Room, fallback: &str) -> String {
-    let own_id = client.user_id().map(|id| id.as_str());
-    if let Some(target) = room
-        .direct_targets()
+fn direct_target(client: &Client, room: &Room) -> Option<OwnedUserId> {
+    let own_id = client.user_id();
+    room.direct_targets()
         .into_iter()
-        .find(|user| Some(user.as_str()) != own_id)
-    {
+        .find(|user| Some(user.as_ref()) != own_id)
+}
+
+async fn resolve_room_name(client: &Client, room: &Room, fallback: &str) -> String {
+    if let Some(target) = direct_target(client, room) {
         return format_user_id(target.as_str());
     }
     if let Some(name) = room.name() {
@@ -928,6 +3234,53 @@ fn format_user_id(user_id: &str) -> String {
         .to_string()
 }
 
+/// Drives a verification request, ours or accepted from another device,
+/// until SAS starts and then until it completes or is cancelled.
+async fn follow_verification_request(
+    request: VerificationRequest,
+    sas_state: Arc<Mutex<Option<SasVerification>>>,
+    evt_tx: mpsc::UnboundedSender<MatrixEvent>,
+) {
+    let mut changes = request.changes();
+    let mut started = false;
+    while let Some(state) = changes.next().await {
+        match state {
+            VerificationRequestState::Transitioned { verification } => {
+                if let Some(sas) = verification.sas() {
+                    started = true;
+                    let _ = evt_tx.send(MatrixEvent::VerificationStatus {
+                        message: "SAS started. Waiting for emojis...".to_string(),
+                    });
+                    start_sas_flow(sas, &sas_state, &evt_tx).await;
+                }
+            }
+            VerificationRequestState::Ready { .. } => {
+                if started {
+                    continue;
+                }
+                let _ = evt_tx.send(MatrixEvent::VerificationStatus {
+                    message: "SAS requested. Waiting for emojis...".to_string(),
+                });
+                if let Ok(Some(sas)) = request.start_sas().await {
+                    started = true;
+                    start_sas_flow(sas, &sas_state, &evt_tx).await;
+                }
+            }
+            VerificationRequestState::Cancelled(cancel) => {
+                let _ = evt_tx.send(MatrixEvent::VerificationCancelled {
+                    reason: cancel.reason().to_string(),
+                });
+                break;
+            }
+            VerificationRequestState::Done => {
+                let _ = evt_tx.send(MatrixEvent::VerificationDone);
+                break;
+            }
+            _ => {}
+        }
+    }
+}
+
 async fn start_sas_flow(
     sas: SasVerification,
     sas_state: &Arc<Mutex<Option<SasVerification>>>,
@@ -944,15 +3297,16 @@ async fn start_sas_flow(
         let mut sas_changes = sas.changes();
         while let Some(state) = sas_changes.next().await {
             match state {
-                SasState::KeysExchanged { emojis, .. } => {
-                    if let Some(emojis) = emojis {
-                        let pairs = emojis
-                            .emojis
-                            .iter()
-                            .map(|e| (e.symbol.to_string(), e.description.to_string()))
-                            .collect();
-                        let _ = evt_tx.send(MatrixEvent::VerificationEmojis { emojis: pairs });
-                    }
+                SasState::KeysExchanged {
+                    emojis: Some(emojis),
+                    ..
+                } => {
+                    let pairs = emojis
+                        .emojis
+                        .iter()
+                        .map(|e| (e.symbol.to_string(), e.description.to_string()))
+                        .collect();
+                    let _ = evt_tx.send(MatrixEvent::VerificationEmojis { emojis: pairs });
                 }
                 SasState::Done { .. } => {
                     let _ = evt_tx.send(MatrixEvent::VerificationDone);
@@ -971,6 +3325,82 @@ async fn start_sas_flow(
 }
 
 
+/// Finds the joined room behind a `!room_id`, `#alias` or `@user` target,
+/// creating a DM for users without one.
+async fn resolve_target(client: &Client, target: &str) -> std::result::Result<Room, String> {
+    let room_id = if target.starts_with('@') {
+        let user_id = UserId::parse(target).map_err(|err| err.to_string())?;
+        if let Some(room) = client.get_dm_room(&user_id) {
+            return Ok(room);
+        }
+        return client.create_dm(&user_id).await.map_err(|err| err.to_string());
+    } else if target.starts_with('#') {
+        let alias = RoomAliasId::parse(target).map_err(|err| err.to_string())?;
+        client
+            .resolve_room_alias(&alias)
+            .await
+            .map_err(|err| err.to_string())?
+            .room_id
+    } else {
+        RoomId::parse(target).map_err(|err| err.to_string())?
+    };
+    client
+        .get_room(&room_id)
+        .filter(|room| room.state() == RoomState::Joined)
+        .ok_or_else(|| "not joined".to_string())
+}
+
+/// HTML body for a message with mentions: each mentioned name becomes a
+/// `matrix.to` pill, everything else is escaped.
+fn mention_html(body: &str, mentions: &[Mention]) -> String {
+    let mut html = String::new();
+    let mut rest = body;
+    loop {
+        let next = mentions
+            .iter()
+            .filter_map(|mention| find_mention(rest, &mention.name).map(|idx| (idx, mention)))
+            .min_by_key(|(idx, mention)| (*idx, std::cmp::Reverse(mention.name.len())));
+        let Some((idx, mention)) = next else {
+            html.push_str(&escape_html(rest));
+            break;
+        };
+        html.push_str(&escape_html(&rest[..idx]));
+        html.push_str(&format!(
+            "<a href=\"https://matrix.to/#/{}\">{}</a>",
+            escape_html(&mention.user_id),
+            escape_html(&mention.name)
+        ));
+        rest = &rest[idx + mention.name.len()..];
+    }
+    html.replace('\n', "<br>")
+}
+
+/// Byte offset of the first place `name` stands as a whole word in `text`,
+/// so mentioning "Sam" leaves the start of "Samantha" alone.
+pub(crate) fn find_mention(text: &str, name: &str) -> Option<usize> {
+    if name.is_empty() {
+        return None;
+    }
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let starts_word = name.chars().next().is_some_and(is_word);
+    let ends_word = name.chars().next_back().is_some_and(is_word);
+    text.match_indices(name).map(|(idx, _)| idx).find(|&idx| {
+        let before = text[..idx].chars().next_back();
+        let after = text[idx + name.len()..].chars().next();
+        let joined_before = starts_word && before.is_some_and(is_word);
+        let joined_after = ends_word && after.is_some_and(is_word);
+        !joined_before && !joined_after
+    })
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[allow(clippy::too_many_arguments)]
 fn store_message_encrypted(
     passphrase: &str,
     room_id: &str,
@@ -979,6 +3409,7 @@ fn store_message_encrypted(
     body: &str,
     event_id: Option<&str>,
     reply_to: Option<&str>,
+    thread_root: Option<&str>,
     attachment: Option<AttachmentInfo>,
 ) -> Result<()> {
     let messages_dir = crate::config::messages_dir()?;
@@ -988,10 +3419,125 @@ fn store_message_encrypted(
         body: body.to_string(),
         event_id: event_id.map(|id| id.to_string()),
         reply_to: reply_to.map(|id| id.to_string()),
+        thread_root: thread_root.map(|id| id.to_string()),
         attachment_path: attachment.as_ref().map(|info| info.path.clone()),
         attachment_name: attachment.as_ref().map(|info| info.name.clone()),
         attachment_kind: attachment.map(|info| info.kind),
+        edited: false,
+        redacted: false,
+        reactions: Vec::new(),
+        poll: None,
+        votes: Vec::new(),
     };
     append_message(&messages_dir, passphrase, room_id, record)?;
     Ok(())
 }
+
+/// Stores a poll start with its question as the body, then shows it.
+fn publish_poll(
+    passphrase: &str,
+    evt_tx: &mpsc::UnboundedSender<MatrixEvent>,
+    room_id: &str,
+    event_id: &str,
+    sender: &str,
+    ts: i64,
+    poll: StoredPoll,
+) {
+    if let Ok(messages_dir) = crate::config::messages_dir() {
+        let record = StoredMessage {
+            timestamp: ts,
+            sender: sender.to_string(),
+            body: poll.question.clone(),
+            event_id: Some(event_id.to_string()),
+            reply_to: None,
+            thread_root: None,
+            attachment_path: None,
+            attachment_name: None,
+            attachment_kind: None,
+            edited: false,
+            redacted: false,
+            reactions: Vec::new(),
+            poll: Some(poll.clone()),
+            votes: Vec::new(),
+        };
+        let _ = append_message(&messages_dir, passphrase, room_id, record);
+    }
+    let _ = evt_tx.send(MatrixEvent::Poll {
+        room_id: room_id.to_string(),
+        event_id: event_id.to_string(),
+        sender: sender.to_string(),
+        timestamp: ts,
+        poll,
+    });
+}
+
+fn stable_poll(poll: &PollContentBlock) -> StoredPoll {
+    StoredPoll {
+        question: poll.question.text.find_plain().unwrap_or_default().to_string(),
+        answers: poll
+            .answers
+            .iter()
+            .map(|answer| StoredPollAnswer {
+                id: answer.id.clone(),
+                text: answer.text.find_plain().unwrap_or_default().to_string(),
+            })
+            .collect(),
+        max_selections: poll.max_selections.into(),
+        stable: true,
+        ended: None,
+    }
+}
+
+/// `None` for an edit of an MSC3381 poll, which marty doesn't apply.
+fn unstable_poll(content: &UnstablePollStartEventContent) -> Option<StoredPoll> {
+    let UnstablePollStartEventContent::New(content) = content else {
+        return None;
+    };
+    let poll = &content.poll_start;
+    Some(StoredPoll {
+        question: poll.question.text.clone(),
+        answers: poll
+            .answers
+            .iter()
+            .map(|answer| StoredPollAnswer {
+                id: answer.id.clone(),
+                text: answer.text.clone(),
+            })
+            .collect(),
+        max_selections: poll.max_selections.into(),
+        stable: false,
+        ended: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mention(user_id: &str, name: &str) -> Mention {
+        Mention {
+            user_id: user_id.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn mention_html_links_whole_names_only() {
+        let sam = mention("@sam:example.org", "Sam");
+        assert_eq!(
+            mention_html("Samantha and Sam", &[sam]),
+            "Samantha and <a href=\"https://matrix.to/#/@sam:example.org\">Sam</a>"
+        );
+        assert_eq!(find_mention("Sam_2 Sam.", "Sam"), Some(6));
+        assert_eq!(find_mention("Samantha", "Sam"), None);
+    }
+
+    #[test]
+    fn mention_html_escapes_the_user_id() {
+        let odd = mention("@a\"b<c>:example.org", "ab");
+        assert_eq!(
+            mention_html("hi ab", &[odd]),
+            "hi <a href=\"https://matrix.to/#/@a&quot;b&lt;c&gt;:example.org\">ab</a>"
+        );
+    }
+}
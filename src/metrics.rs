@@ -0,0 +1,106 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Counter {
+    MessagesReceived,
+    MessagesSent,
+    SyncRestarts,
+    DecryptFailures,
+    Notifications,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    messages_received: AtomicU64,
+    messages_sent: AtomicU64,
+    sync_restarts: AtomicU64,
+    decrypt_failures: AtomicU64,
+    notifications: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    pub updated: i64,
+    pub uptime_secs: u64,
+    pub messages_received: u64,
+    pub messages_sent: u64,
+    pub sync_restarts: u64,
+    pub decrypt_failures: u64,
+    pub notifications: u64,
+}
+
+/// Runtime counters for long-lived sessions, written to a stats file when
+/// `stats_file` is configured. Like `StartupProfile`, a disabled instance
+/// ignores increments.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    counters: Option<Arc<Counters>>,
+}
+
+impl Metrics {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            counters: enabled.then(|| Arc::new(Counters::default())),
+        }
+    }
+
+    pub fn incr(&self, counter: Counter) {
+        let Some(counters) = &self.counters else {
+            return;
+        };
+        let slot = match counter {
+            Counter::MessagesReceived => &counters.messages_received,
+            Counter::MessagesSent => &counters.messages_sent,
+            Counter::SyncRestarts => &counters.sync_restarts,
+            Counter::DecryptFailures => &counters.decrypt_failures,
+            Counter::Notifications => &counters.notifications,
+        };
+        slot.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self, uptime: Duration) -> Option<Snapshot> {
+        let counters = self.counters.as_ref()?;
+        Some(Snapshot {
+            updated: chrono::Utc::now().timestamp(),
+            uptime_secs: uptime.as_secs(),
+            messages_received: counters.messages_received.load(Ordering::Relaxed),
+            messages_sent: counters.messages_sent.load(Ordering::Relaxed),
+            sync_restarts: counters.sync_restarts.load(Ordering::Relaxed),
+            decrypt_failures: counters.decrypt_failures.load(Ordering::Relaxed),
+            notifications: counters.notifications.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Rewrites `path` with the current counters every `interval` until the
+    /// runtime shuts down.
+    pub fn spawn_stats_writer(&self, path: PathBuf, interval: Duration) {
+        if self.counters.is_none() {
+            return;
+        }
+        let metrics = self.clone();
+        let started = std::time::Instant::now();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Some(snapshot) = metrics.snapshot(started.elapsed()) {
+                    let _ = write_stats(&path, &snapshot);
+                }
+            }
+        });
+    }
+}
+
+fn write_stats(path: &Path, snapshot: &Snapshot) -> io::Result<()> {
+    let json = serde_json::to_string_pretty(snapshot).map_err(io::Error::other)?;
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, json)?;
+    fs::rename(&tmp, path)
+}
@@ -0,0 +1,235 @@
+use std::collections::{HashMap, HashSet};
+use std::process::Command;
+
+use crate::config::SenderFormat;
+use crate::matrix::{MemberInfo, RoomInfo};
+
+/// Severity of a message in an alert room, from keywords such as
+/// Alertmanager's `[FIRING]`/`[RESOLVED]` or Grafana's `[Alerting]`/`[OK]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Severity {
+    Critical,
+    Warning,
+    Resolved,
+}
+
+impl Severity {
+    /// A bot's status tag (`[RESOLVED]`, `[OK]`, `status=resolved`) wins, so
+    /// `[RESOLVED] critical: ...` reads as recovered. Then an explicit
+    /// `severity=` label, then loose keywords with critical before warning.
+    /// Loose words such as "ok" never resolve a message on their own.
+    pub(crate) fn parse(text: &str) -> Option<Self> {
+        const RESOLVED: &[&str] = &["resolved", "ok", "recovered", "cleared"];
+        const CRITICAL: &[&str] = &["critical", "crit", "fatal", "emergency", "sev1", "p1"];
+        const WARNING: &[&str] = &["warning", "warn", "firing", "alerting", "sev2", "p2"];
+        let lower = text.to_lowercase();
+        let tags: Vec<&str> = lower
+            .split('[')
+            .skip(1)
+            .filter_map(|rest| rest.split_once(']'))
+            .filter_map(|(tag, _)| tag.split(|c: char| !c.is_alphanumeric()).next())
+            .collect();
+        let status = label_value(&lower, "status");
+        if tags.iter().chain(status.iter()).any(|tag| RESOLVED.contains(tag)) {
+            return Some(Severity::Resolved);
+        }
+        match label_value(&lower, "severity") {
+            Some(value) if CRITICAL.contains(&value) => return Some(Severity::Critical),
+            Some(value) if WARNING.contains(&value) => return Some(Severity::Warning),
+            _ => {}
+        }
+        let words: Vec<&str> = lower
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect();
+        let has = |keywords: &[&str]| words.iter().any(|word| keywords.contains(word));
+        if has(CRITICAL) {
+            Some(Severity::Critical)
+        } else if has(WARNING) {
+            Some(Severity::Warning)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Severity::Critical => "critical",
+            Severity::Warning => "warning",
+            Severity::Resolved => "resolved",
+        }
+    }
+}
+
+/// Value of a `key=value` or `key: value` label in lowercased `text`.
+fn label_value<'a>(text: &'a str, key: &str) -> Option<&'a str> {
+    text.match_indices(key).find_map(|(at, _)| {
+        let before = text[..at].chars().next_back();
+        if before.is_some_and(|c| c.is_alphanumeric() || c == '_') {
+            return None;
+        }
+        let rest = text[at + key.len()..].trim_start();
+        let rest = rest.strip_prefix(['=', ':'])?;
+        let rest = rest.trim_start().trim_start_matches(['"', '\'']);
+        let end = rest
+            .find(|c: char| !c.is_alphanumeric())
+            .unwrap_or(rest.len());
+        (end > 0).then(|| &rest[..end])
+    })
+}
+
+pub(crate) fn format_sender(sender: &str) -> String {
+    let trimmed = sender.trim_start_matches('@');
+    trimmed.split(':').next().unwrap_or(trimmed).to_string()
+}
+
+/// The local nickname for `user_id` in `room_id`, room-scoped ones first.
+pub(crate) fn nickname<'a>(
+    nicknames: &'a HashMap<String, String>,
+    room_nicknames: &'a HashMap<String, HashMap<String, String>>,
+    room_id: &str,
+    user_id: &str,
+) -> Option<&'a str> {
+    room_nicknames
+        .get(room_id)
+        .and_then(|nicknames| nicknames.get(user_id))
+        .or_else(|| nicknames.get(user_id))
+        .map(String::as_str)
+}
+
+fn member_display_name<'a>(members: &'a [MemberInfo], user_id: &str) -> Option<&'a str> {
+    members
+        .iter()
+        .find(|member| member.user_id == user_id)?
+        .display_name
+        .as_deref()
+        .filter(|name| !name.is_empty())
+}
+
+/// Label for `sender` under `format`, unless it has a nickname. Localparts
+/// and display names gain the server / MXID only when another of `senders`
+/// or `members` would otherwise look the same.
+pub(crate) fn sender_label<'a>(
+    format: SenderFormat,
+    nickname: Option<&str>,
+    members: &'a [MemberInfo],
+    senders: impl Iterator<Item = &'a String>,
+    sender: &str,
+) -> String {
+    if let Some(nickname) = nickname {
+        return nickname.to_string();
+    }
+    let base = |user_id: &str| match format {
+        SenderFormat::Localpart => format_sender(user_id),
+        SenderFormat::Mxid => user_id.to_string(),
+        SenderFormat::DisplayName => member_display_name(members, user_id)
+            .map(str::to_string)
+            .unwrap_or_else(|| format_sender(user_id)),
+    };
+    let label = base(sender);
+    if format == SenderFormat::Mxid {
+        return label;
+    }
+    let ambiguous = senders
+        .chain(members.iter().map(|member| &member.user_id))
+        .any(|other| other != sender && base(other) == label);
+    match (ambiguous, format) {
+        (false, _) => label,
+        (true, SenderFormat::DisplayName) => format!("{} ({})", label, sender),
+        (true, _) => sender.trim_start_matches('@').to_string(),
+    }
+}
+
+/// Whether `room_id`, or its alias among `rooms`, is in a configured room
+/// list.
+pub(crate) fn room_listed<'a>(
+    list: &HashSet<String>,
+    mut rooms: impl Iterator<Item = &'a RoomInfo>,
+    room_id: &str,
+) -> bool {
+    list.contains(room_id)
+        || rooms.any(|room| {
+            room.room_id == room_id
+                && room.alias.as_ref().is_some_and(|alias| list.contains(alias))
+        })
+}
+
+/// Severity of `text` when `room_id` is one of `alert_rooms`.
+pub(crate) fn alert_severity<'a>(
+    alert_rooms: &HashSet<String>,
+    rooms: impl Iterator<Item = &'a RoomInfo>,
+    room_id: &str,
+    text: &str,
+) -> Option<Severity> {
+    if alert_rooms.is_empty() || !room_listed(alert_rooms, rooms, room_id) {
+        return None;
+    }
+    Severity::parse(text)
+}
+
+pub(crate) fn notify_send(title: &str, body: &str) {
+    let _ = Command::new("notify-send")
+        .arg(title)
+        .arg(body)
+        .spawn();
+}
+
+fn notify_send_urgent(title: &str, body: &str) {
+    let _ = Command::new("notify-send")
+        .args(["--urgency", "critical"])
+        .arg(title)
+        .arg(body)
+        .spawn();
+}
+
+/// Notification for a message: criticals from an alert room go out with
+/// critical urgency under the room name alone, others under
+/// "room — sender".
+pub(crate) fn notify_message(room_name: &str, sender_label: &str, body: &str, critical: bool) {
+    if critical {
+        notify_send_urgent(&format!("CRITICAL — {}", room_name), body);
+    } else {
+        notify_send(&format!("{} — {}", room_name, sender_label), body);
+    }
+}
+
+pub(crate) fn invite_notification(room: &RoomInfo) -> (String, String) {
+    let body = match &room.inviter {
+        Some(inviter) => format!("{} invited you to {}", inviter, room.name),
+        None => format!("You were invited to {}", room.name),
+    };
+    ("Invitation".to_string(), body)
+}
+
+pub(crate) fn verification_notification(user_id: &str, device_id: &str) -> (String, String) {
+    (
+        "Verification request".to_string(),
+        format!("{} wants to verify from device {}", user_id, device_id),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn severity_prefers_bot_tags_over_loose_words() {
+        let parse = Severity::parse;
+        assert_eq!(parse("CRITICAL: db01 down, last ok 12:00"), Some(Severity::Critical));
+        assert_eq!(parse("[RESOLVED] critical: db01 down"), Some(Severity::Resolved));
+        assert_eq!(parse("[OK] disk usage normal"), Some(Severity::Resolved));
+        assert_eq!(parse("alert cleared, status=resolved"), Some(Severity::Resolved));
+        assert_eq!(parse("the backup recovered fine, warning: slow"), Some(Severity::Warning));
+    }
+
+    #[test]
+    fn severity_reads_firing_tags_and_labels() {
+        let parse = Severity::parse;
+        assert_eq!(parse("[FIRING:2] HighLatency"), Some(Severity::Warning));
+        assert_eq!(parse("[FIRING:1] DiskFull severity=critical"), Some(Severity::Critical));
+        assert_eq!(parse("[Alerting] cpu severity: \"warning\""), Some(Severity::Warning));
+        assert_eq!(parse("sev1 incident opened"), Some(Severity::Critical));
+        assert_eq!(parse("lunch is ready"), None);
+        assert_eq!(parse("ok see you tomorrow"), None);
+    }
+}
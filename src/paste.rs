@@ -0,0 +1,91 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+/// A finished upload: the link to send in place of the text, or why the
+/// command failed.
+pub struct Paste {
+    pub room_id: String,
+    pub reply_to: Option<String>,
+    pub text: String,
+    pub result: Result<String, String>,
+}
+
+/// Uploads long messages with a pastebin command such as
+/// `pastebinit -b dpaste.com` or `curl -F file=@- https://0x0.st`. The
+/// text goes to its stdin and the first line it prints is the link.
+pub struct Paster {
+    program: String,
+    args: Vec<String>,
+    done_tx: Sender<Paste>,
+    done_rx: Receiver<Paste>,
+}
+
+impl Paster {
+    /// `None` when the command is empty.
+    pub fn new(command: &str) -> Option<Self> {
+        let mut words = command.split_whitespace().map(str::to_string);
+        let program = words.next()?;
+        let (done_tx, done_rx) = mpsc::channel();
+        Some(Self {
+            program,
+            args: words.collect(),
+            done_tx,
+            done_rx,
+        })
+    }
+
+    /// Runs the command in the background; see `finished`.
+    pub fn upload(&self, room_id: String, reply_to: Option<String>, text: String) {
+        let program = self.program.clone();
+        let args = self.args.clone();
+        let done_tx = self.done_tx.clone();
+        thread::spawn(move || {
+            let result = run(&program, &args, &text);
+            let _ = done_tx.send(Paste {
+                room_id,
+                reply_to,
+                text,
+                result,
+            });
+        });
+    }
+
+    pub fn finished(&self) -> Option<Paste> {
+        self.done_rx.try_recv().ok()
+    }
+}
+
+fn run(program: &str, args: &[String], text: &str) -> Result<String, String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|err| format!("{}: {}", program, err))?;
+    // Written from its own thread while the output is read, so a command
+    // that prints as it reads can't fill both pipes and stall.
+    let input = text.to_string();
+    let writer = child
+        .stdin
+        .take()
+        .map(|mut stdin| thread::spawn(move || stdin.write_all(input.as_bytes())));
+    let output = child
+        .wait_with_output()
+        .map_err(|err| format!("{}: {}", program, err))?;
+    let written = writer.map_or(Ok(Ok(())), |writer| writer.join());
+    if !output.status.success() {
+        return Err(format!("{} exited with {}", program, output.status));
+    }
+    written
+        .map_err(|_| format!("{}: input writer panicked", program))?
+        .map_err(|err| format!("{}: {}", program, err))?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| format!("{} printed no link", program))
+}
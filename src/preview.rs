@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, Cursor, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+use base64::Engine;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, ImageReader, Limits, RgbImage};
+
+/// Largest thumbnail, in terminal cells.
+const MAX_COLS: u32 = 40;
+const MAX_ROWS: u32 = 10;
+/// Cell size assumed when the terminal doesn't report its pixel size.
+const FALLBACK_CELL: (u32, u32) = (8, 16);
+/// Base64 bytes per kitty graphics chunk.
+const KITTY_CHUNK: usize = 4096;
+/// Largest image side and decoder allocation accepted for a thumbnail, so
+/// a huge or malicious file is skipped instead of exhausting memory.
+const MAX_SOURCE_SIDE: u32 = 16_384;
+const MAX_DECODE_ALLOC: u64 = 256 * 1024 * 1024;
+
+/// Terminal graphics protocols thumbnails can be drawn with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Kitty,
+    Iterm2,
+    Sixel,
+}
+
+impl Protocol {
+    /// `image_previews` from the config: `auto` (the default) guesses from
+    /// the environment; `off` or an unsupported terminal keeps text labels.
+    pub fn from_config(setting: Option<&str>) -> Option<Self> {
+        match setting.unwrap_or("auto") {
+            "kitty" => Some(Self::Kitty),
+            "iterm2" => Some(Self::Iterm2),
+            "sixel" => Some(Self::Sixel),
+            "auto" => Self::detect(),
+            _ => None,
+        }
+    }
+
+    fn detect() -> Option<Self> {
+        // Multiplexers swallow or misplace graphics escapes.
+        if env::var_os("TMUX").is_some() || env::var_os("STY").is_some() {
+            return None;
+        }
+        let term = env::var("TERM").unwrap_or_default();
+        let program = env::var("TERM_PROGRAM").unwrap_or_default();
+        if env::var_os("KITTY_WINDOW_ID").is_some()
+            || term.contains("kitty")
+            || term.contains("ghostty")
+            || program == "ghostty"
+        {
+            Some(Self::Kitty)
+        } else if program == "iTerm.app" || program == "WezTerm" {
+            Some(Self::Iterm2)
+        } else if term.starts_with("foot") || term.contains("mlterm") || term.contains("sixel") {
+            Some(Self::Sixel)
+        } else {
+            None
+        }
+    }
+}
+
+/// A thumbnail ready to draw: its size in cells and the escape sequence
+/// that draws it, encoded once.
+struct Thumbnail {
+    cols: u16,
+    rows: u16,
+    sequence: Vec<u8>,
+}
+
+enum Slot {
+    Decoding,
+    Ready(Thumbnail),
+    Failed,
+}
+
+/// Decodes image attachments into cell-sized thumbnails on worker threads
+/// (cached per file) and draws them over the area the timeline leaves
+/// blank for them.
+pub struct Previews {
+    protocol: Protocol,
+    cell: (u32, u32),
+    thumbnails: Arc<Mutex<HashMap<String, Slot>>>,
+    /// Called from a worker when a thumbnail is ready, so the UI redraws
+    /// and reserves its rows.
+    on_ready: Option<Arc<dyn Fn() + Send + Sync>>,
+}
+
+impl Previews {
+    pub fn new(protocol: Protocol) -> Self {
+        let cell = crossterm::terminal::window_size()
+            .ok()
+            .filter(|size| size.width > 0 && size.height > 0 && size.columns > 0 && size.rows > 0)
+            .map(|size| {
+                (
+                    (size.width / size.columns) as u32,
+                    (size.height / size.rows) as u32,
+                )
+            })
+            .unwrap_or(FALLBACK_CELL);
+        Self {
+            protocol,
+            cell,
+            thumbnails: Arc::new(Mutex::new(HashMap::new())),
+            on_ready: None,
+        }
+    }
+
+    pub fn on_ready(&mut self, notify: impl Fn() + Send + Sync + 'static) {
+        self.on_ready = Some(Arc::new(notify));
+    }
+
+    /// Columns and rows the thumbnail of `path` takes; `None` while it is
+    /// still decoding or when the file isn't a readable image. The first
+    /// call starts decoding it in the background.
+    pub fn size(&self, path: &str) -> Option<(u16, u16)> {
+        let mut thumbnails = self.thumbnails.lock().ok()?;
+        match thumbnails.get(path) {
+            Some(Slot::Ready(thumb)) => return Some((thumb.cols, thumb.rows)),
+            Some(_) => return None,
+            None => {}
+        }
+        thumbnails.insert(path.to_string(), Slot::Decoding);
+        let (protocol, cell) = (self.protocol, self.cell);
+        let thumbnails = Arc::clone(&self.thumbnails);
+        let on_ready = self.on_ready.clone();
+        let path = path.to_string();
+        thread::spawn(move || {
+            let slot = thumbnail(protocol, cell, &path).map_or(Slot::Failed, Slot::Ready);
+            let ready = matches!(slot, Slot::Ready(_));
+            if let Ok(mut thumbnails) = thumbnails.lock() {
+                thumbnails.insert(path, slot);
+            }
+            if let (true, Some(on_ready)) = (ready, on_ready) {
+                on_ready();
+            }
+        });
+        None
+    }
+
+    /// Draws the thumbnail of `path` with its top-left corner at cell
+    /// (`x`, `y`), leaving the cursor where it was.
+    pub fn draw(&self, out: &mut impl Write, path: &str, x: u16, y: u16) -> io::Result<()> {
+        let Ok(thumbnails) = self.thumbnails.lock() else {
+            return Ok(());
+        };
+        let Some(Slot::Ready(thumb)) = thumbnails.get(path) else {
+            return Ok(());
+        };
+        write!(out, "\x1b7\x1b[{};{}H", y + 1, x + 1)?;
+        out.write_all(&thumb.sequence)?;
+        out.write_all(b"\x1b8")
+    }
+
+    /// Removes drawn thumbnails where the terminal keeps them apart from the
+    /// text. Returns false when the screen has to be repainted instead.
+    pub fn clear(&self, out: &mut impl Write) -> io::Result<bool> {
+        if self.protocol == Protocol::Kitty {
+            out.write_all(b"\x1b_Ga=d,d=A,q=2\x1b\\")?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+/// Decodes `path` within the size limits, scales it to fit `MAX_COLS` by
+/// `MAX_ROWS` cells and encodes it for `protocol`.
+fn thumbnail(protocol: Protocol, cell: (u32, u32), path: &str) -> Option<Thumbnail> {
+    let mut reader = ImageReader::open(path).ok()?.with_guessed_format().ok()?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_SOURCE_SIDE);
+    limits.max_image_height = Some(MAX_SOURCE_SIDE);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    reader.limits(limits);
+    let image = reader.decode().ok()?;
+    let (cell_w, cell_h) = cell;
+    let (width, height) = (image.width().max(1), image.height().max(1));
+    let scale = (MAX_COLS * cell_w) as f64 / width as f64;
+    let scale = scale
+        .min((MAX_ROWS * cell_h) as f64 / height as f64)
+        .min(1.0);
+    let width = ((width as f64 * scale) as u32).max(1);
+    let height = ((height as f64 * scale) as u32).max(1);
+    let cols = width.div_ceil(cell_w) as u16;
+    let rows = height.div_ceil(cell_h) as u16;
+    let image = image.resize_exact(width, height, FilterType::Triangle);
+    let sequence = encode(protocol, &image, cols, rows).ok()?;
+    Some(Thumbnail {
+        cols,
+        rows,
+        sequence,
+    })
+}
+
+/// The escape sequence that draws `image` over `cols` by `rows` cells at
+/// the cursor.
+fn encode(protocol: Protocol, image: &DynamicImage, cols: u16, rows: u16) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match protocol {
+        Protocol::Kitty => {
+            let data = BASE64_STANDARD.encode(png_bytes(image)?);
+            let mut chunks = data.as_bytes().chunks(KITTY_CHUNK).peekable();
+            let mut first = true;
+            while let Some(chunk) = chunks.next() {
+                let more = u8::from(chunks.peek().is_some());
+                if first {
+                    write!(
+                        out,
+                        "\x1b_Ga=T,f=100,q=2,C=1,c={},r={},m={};",
+                        cols, rows, more
+                    )?;
+                    first = false;
+                } else {
+                    write!(out, "\x1b_Gm={};", more)?;
+                }
+                out.write_all(chunk)?;
+                out.write_all(b"\x1b\\")?;
+            }
+        }
+        Protocol::Iterm2 => {
+            let data = BASE64_STANDARD.encode(png_bytes(image)?);
+            write!(
+                out,
+                "\x1b]1337;File=inline=1;width={};height={};preserveAspectRatio=1:{}\x07",
+                cols, rows, data
+            )?;
+        }
+        Protocol::Sixel => out = sixel(&image.to_rgb8()),
+    }
+    Ok(out)
+}
+
+fn png_bytes(image: &DynamicImage) -> io::Result<Vec<u8>> {
+    let mut data = Cursor::new(Vec::new());
+    image
+        .write_to(&mut data, ImageFormat::Png)
+        .map_err(io::Error::other)?;
+    Ok(data.into_inner())
+}
+
+/// Encodes `image` as sixels with a 6×6×6 color cube palette.
+fn sixel(image: &RgbImage) -> Vec<u8> {
+    let level = |v: u8| (v as u32 * 5 + 127) / 255;
+    let (width, height) = image.dimensions();
+    let mut out = Vec::new();
+    let _ = write!(out, "\x1bPq\"1;1;{};{}", width, height);
+    for idx in 0..216u32 {
+        let (r, g, b) = (idx / 36, idx / 6 % 6, idx % 6);
+        let _ = write!(out, "#{};2;{};{};{}", idx, r * 20, g * 20, b * 20);
+    }
+    let colors: Vec<u8> = image
+        .pixels()
+        .map(|p| (level(p[0]) * 36 + level(p[1]) * 6 + level(p[2])) as u8)
+        .collect();
+    for band in (0..height).step_by(6) {
+        let rows = (height - band).min(6);
+        let mut used = [false; 216];
+        for y in band..band + rows {
+            for x in 0..width {
+                used[colors[(y * width + x) as usize] as usize] = true;
+            }
+        }
+        for color in (0..216).filter(|color| used[*color]) {
+            let _ = write!(out, "#{}", color);
+            let mut run: Option<(u8, u32)> = None;
+            for x in 0..width {
+                let mut bits = 0u8;
+                for row in 0..rows {
+                    if colors[((band + row) * width + x) as usize] as usize == color {
+                        bits |= 1 << row;
+                    }
+                }
+                let ch = b'?' + bits;
+                run = match run {
+                    Some((prev, count)) if prev == ch => Some((prev, count + 1)),
+                    Some((prev, count)) => {
+                        push_run(&mut out, prev, count);
+                        Some((ch, 1))
+                    }
+                    None => Some((ch, 1)),
+                };
+            }
+            if let Some((prev, count)) = run {
+                push_run(&mut out, prev, count);
+            }
+            out.push(b'$');
+        }
+        out.push(b'-');
+    }
+    out.extend_from_slice(b"\x1b\\");
+    out
+}
+
+fn push_run(out: &mut Vec<u8>, ch: u8, count: u32) {
+    if count > 3 {
+        let _ = write!(out, "!{}{}", count, ch as char);
+    } else {
+        out.extend(std::iter::repeat_n(ch, count as usize));
+    }
+}
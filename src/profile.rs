@@ -0,0 +1,49 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+type Phases = Arc<Mutex<Vec<(String, Duration)>>>;
+
+/// Collects named startup phase timings when `--profile-startup` is given.
+/// A disabled profile records nothing, so call sites need no extra checks.
+#[derive(Debug, Clone, Default)]
+pub struct StartupProfile {
+    phases: Option<Phases>,
+}
+
+impl StartupProfile {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            phases: enabled.then(|| Arc::new(Mutex::new(Vec::new()))),
+        }
+    }
+
+    pub fn record(&self, label: &str, elapsed: Duration) {
+        if let Some(phases) = &self.phases {
+            if let Ok(mut phases) = phases.lock() {
+                phases.push((label.to_string(), elapsed));
+            }
+        }
+    }
+
+    pub fn time<T>(&self, label: &str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let out = f();
+        self.record(label, start.elapsed());
+        out
+    }
+
+    pub fn report(&self) -> Option<String> {
+        let phases = self.phases.as_ref()?.lock().ok()?;
+        let width = phases.iter().map(|(label, _)| label.len()).max().unwrap_or(0);
+        let mut out = String::from("Startup profile:\n");
+        for (label, elapsed) in phases.iter() {
+            out.push_str(&format!(
+                "  {:width$}  {:>9.1} ms\n",
+                label,
+                elapsed.as_secs_f64() * 1000.0,
+                width = width
+            ));
+        }
+        Some(out)
+    }
+}
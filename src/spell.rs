@@ -0,0 +1,166 @@
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+
+/// A misspelled word in the checked text, by char offset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Misspelling {
+    pub start: usize,
+    pub word: String,
+    pub suggestions: Vec<String>,
+}
+
+impl Misspelling {
+    pub fn end(&self) -> usize {
+        self.start + self.word.chars().count()
+    }
+}
+
+/// Checks text with `hunspell` in ispell pipe mode (`-a`).
+#[derive(Clone)]
+pub struct SpellChecker {
+    dictionary: Option<String>,
+}
+
+impl SpellChecker {
+    /// `dictionary` is passed to `hunspell -d`: a language such as `en_US`
+    /// or a path to a `.dic`/`.aff` pair without the extension.
+    pub fn new(dictionary: Option<String>) -> Self {
+        Self { dictionary }
+    }
+
+    /// Checks `text` on a worker thread and hands it back to `done` with
+    /// the result, so a slow start of hunspell never stalls the caller.
+    pub fn check_in_background(
+        &self,
+        text: String,
+        done: impl FnOnce(String, io::Result<Vec<Misspelling>>) + Send + 'static,
+    ) {
+        let checker = self.clone();
+        thread::spawn(move || {
+            let result = checker.check(&text);
+            done(text, result);
+        });
+    }
+
+    pub fn check(&self, text: &str) -> io::Result<Vec<Misspelling>> {
+        let mut cmd = Command::new("hunspell");
+        cmd.arg("-a");
+        if let Some(dictionary) = &self.dictionary {
+            cmd.args(["-d", dictionary]);
+        }
+        let mut child = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        // `^` keeps lines starting with pipe-mode commands from being taken
+        // as commands. Written from its own thread while the output is read,
+        // so a long input can't fill both pipes and stall the two processes.
+        let input: String = text.split('\n').map(|line| format!("^{}\n", line)).collect();
+        let writer = child.stdin.take().map(|mut stdin| {
+            thread::spawn(move || stdin.write_all(input.as_bytes()))
+        });
+        let output = child.wait_with_output()?;
+        let written = writer.map_or(Ok(Ok(())), |writer| writer.join());
+        if !output.status.success() {
+            return Err(io::Error::other(format!("hunspell exited with {}", output.status)));
+        }
+        written.map_err(|_| io::Error::other("hunspell input writer panicked"))??;
+        Ok(parse_pipe_output(text, &String::from_utf8_lossy(&output.stdout)))
+    }
+}
+
+/// Maps `&`/`#` result lines back onto `text`. Words are located by
+/// searching forward in their input line, which sidesteps hunspell's
+/// byte-vs-char offset ambiguity.
+fn parse_pipe_output(text: &str, output: &str) -> Vec<Misspelling> {
+    let mut found = Vec::new();
+    let mut lines = text.split('\n');
+    let mut line_start = 0usize;
+    let mut current = lines.next().unwrap_or_default();
+    let mut search_from = 0usize;
+    // The first output line is the version banner.
+    for result in output.lines().skip(1) {
+        if result.is_empty() {
+            line_start += current.chars().count() + 1;
+            current = lines.next().unwrap_or_default();
+            search_from = 0;
+            continue;
+        }
+        let (word, suggestions) = match result.chars().next() {
+            Some('&') => {
+                let Some((head, list)) = result.split_once(": ") else {
+                    continue;
+                };
+                let word = head.split_whitespace().nth(1).unwrap_or_default();
+                (word, list.split(", ").map(str::to_string).collect())
+            }
+            Some('#') => (result.split_whitespace().nth(1).unwrap_or_default(), Vec::new()),
+            _ => continue,
+        };
+        let Some(offset) = current[search_from..].find(word).map(|idx| idx + search_from) else {
+            continue;
+        };
+        search_from = offset + word.len();
+        found.push(Misspelling {
+            start: line_start + current[..offset].chars().count(),
+            word: word.to_string(),
+            suggestions,
+        });
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BANNER: &str = "@(#) International Ispell Version 3.2.06 (but really Hunspell 1.7.2)";
+
+    fn spans(found: &[Misspelling]) -> Vec<(usize, &str)> {
+        found.iter().map(|m| (m.start, m.word.as_str())).collect()
+    }
+
+    #[test]
+    fn reads_suggestions_and_unknown_words() {
+        let text = "helo wrld ok";
+        let output = format!("{}\n& helo 2 0: hello, help\n# wrld 5\n*\n\n", BANNER);
+        let found = parse_pipe_output(text, &output);
+        assert_eq!(spans(&found), [(0, "helo"), (5, "wrld")]);
+        assert_eq!(found[0].suggestions, ["hello", "help"]);
+        assert!(found[1].suggestions.is_empty());
+    }
+
+    #[test]
+    fn repeated_words_map_to_each_occurrence() {
+        let text = "teh cat and teh dog";
+        let output = format!("{}\n& teh 1 0: the\n*\n*\n& teh 1 12: the\n*\n\n", BANNER);
+        let found = parse_pipe_output(text, &output);
+        assert_eq!(spans(&found), [(0, "teh"), (12, "teh")]);
+    }
+
+    #[test]
+    fn offsets_span_lines_and_count_chars() {
+        let text = "grüße wrold\nsecnd line\n\nlast tpyo";
+        let output = format!(
+            "{}\n*\n& wrold 1 6: world\n\n& secnd 1 0: second\n*\n\n\n*\n# tpyo 5\n\n",
+            BANNER
+        );
+        let found = parse_pipe_output(text, &output);
+        assert_eq!(spans(&found), [(6, "wrold"), (12, "secnd"), (29, "tpyo")]);
+        let chars: Vec<char> = text.chars().collect();
+        for m in &found {
+            let word: String = chars[m.start..m.end()].iter().collect();
+            assert_eq!(word, m.word);
+        }
+    }
+
+    #[test]
+    fn ignores_the_banner_and_malformed_lines() {
+        let output = "& helo 1 0: hello\n& broken\n+ root\n-\n\n";
+        assert!(parse_pipe_output("helo", output).is_empty());
+    }
+}
@@ -1,6 +1,11 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+use std::ops::Bound;
 use std::fs;
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 use aes_gcm::aead::{Aead, KeyInit, OsRng};
 use aes_gcm::{Aes256Gcm, Nonce};
@@ -9,11 +14,21 @@ use base64::Engine;
 use pbkdf2::pbkdf2_hmac;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
 
 const SALT_LEN: usize = 16;
 const NONCE_LEN: usize = 12;
 const PBKDF2_ITERS: u32 = 100_000;
+const TAG_LEN: usize = 16;
+/// Encrypted files start with `MAGIC` and a format version byte. Files from
+/// before the header existed are read as version 0; version 2 payloads are
+/// zstd-compressed before encryption.
+const MAGIC: &[u8; 4] = b"MRTY";
+const FORMAT_PLAIN: u8 = 1;
+const FORMAT_ZSTD: u8 = 2;
+const FORMAT_VERSION: u8 = FORMAT_ZSTD;
+const ZSTD_LEVEL: i32 = 3;
+const HEADER_LEN: usize = MAGIC.len() + 1;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EncryptedValue {
@@ -22,38 +37,188 @@ pub struct EncryptedValue {
     pub data: String,
 }
 
+pub fn derive_key(passphrase: &str, salt: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, PBKDF2_ITERS, &mut key);
+    key
+}
+
 pub fn write_encrypted(path: &Path, passphrase: &str, plaintext: &[u8]) -> std::io::Result<()> {
+    let compressed = zstd::bulk::compress(plaintext, ZSTD_LEVEL)?;
+    let (version, plaintext) = if compressed.len() < plaintext.len() {
+        (FORMAT_ZSTD, compressed.as_slice())
+    } else {
+        (FORMAT_PLAIN, plaintext)
+    };
     let mut salt = [0u8; SALT_LEN];
     let mut nonce_bytes = [0u8; NONCE_LEN];
     OsRng.fill_bytes(&mut salt);
     OsRng.fill_bytes(&mut nonce_bytes);
 
-    let mut key = [0u8; 32];
-    pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), &salt, PBKDF2_ITERS, &mut key);
+    let key = derive_key(passphrase, &salt);
     let cipher = Aes256Gcm::new_from_slice(&key).expect("key size");
     let nonce = Nonce::from_slice(&nonce_bytes);
     let ciphertext = cipher
         .encrypt(nonce, plaintext)
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "encrypt failed"))?;
+        .map_err(|_| std::io::Error::other("encrypt failed"))?;
 
-    let mut out = Vec::with_capacity(SALT_LEN + NONCE_LEN + ciphertext.len());
+    let mut out = Vec::with_capacity(HEADER_LEN + SALT_LEN + NONCE_LEN + ciphertext.len());
+    out.extend_from_slice(MAGIC);
+    out.push(version);
     out.extend_from_slice(&salt);
     out.extend_from_slice(&nonce_bytes);
     out.extend_from_slice(&ciphertext);
-    fs::write(path, out)
+    write_atomic(path, &out)
+}
+
+/// Writes through a synced temp file and renames it into place, keeping the
+/// previous version as `<path>.bak`, so a crash never leaves a half-written file.
+/// The temp name is unique per write, so concurrent writers never share it.
+/// The directory is synced last so the renames survive a crash too.
+fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let tmp = tmp_path(path);
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp)?;
+    if let Err(err) = file.write_all(data).and_then(|_| file.sync_all()) {
+        let _ = fs::remove_file(&tmp);
+        return Err(err);
+    }
+    drop(file);
+    if path.exists() {
+        fs::rename(path, backup_path(path))?;
+    }
+    fs::rename(&tmp, path)?;
+    sync_parent(path)
+}
+
+/// Flushes the directory entry changes (renames) under `path`'s parent.
+fn sync_parent(path: &Path) -> std::io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+    let seq = NEXT_TMP.fetch_add(1, Ordering::Relaxed);
+    sibling_path(path, &format!("{}.{}.tmp", std::process::id(), seq))
+}
+
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+pub fn backup_path(path: &Path) -> PathBuf {
+    sibling_path(path, "bak")
+}
+
+/// Replaces the `.bak` of `path` with a copy of the current file, so
+/// content just purged from it doesn't survive in the previous generation.
+pub fn scrub_backup(path: &Path) -> std::io::Result<()> {
+    let backup = backup_path(path);
+    if !path.exists() {
+        return match fs::remove_file(&backup) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        };
+    }
+    let tmp = tmp_path(&backup);
+    if let Err(err) = fs::copy(path, &tmp).and_then(|_| fs::File::open(&tmp)?.sync_all()) {
+        let _ = fs::remove_file(&tmp);
+        return Err(err);
+    }
+    fs::rename(&tmp, &backup)?;
+    sync_parent(&backup)
+}
+
+/// True when either the file or its backup exists.
+pub fn encrypted_exists(path: &Path) -> bool {
+    path.exists() || backup_path(path).exists()
 }
 
 pub fn read_encrypted(path: &Path, passphrase: &str) -> std::io::Result<Vec<u8>> {
+    read_encrypted_with_backup(path, passphrase).map(|(data, _)| data)
+}
+
+/// Reads `path`, falling back to the `.bak` copy when the file is missing,
+/// truncated or fails authentication. The flag is set when the backup was used.
+pub fn read_encrypted_with_backup(
+    path: &Path,
+    passphrase: &str,
+) -> std::io::Result<(Vec<u8>, bool)> {
+    match read_encrypted_file(path, passphrase) {
+        Ok(data) => Ok((data, false)),
+        Err(err) => match read_encrypted_file(&backup_path(path), passphrase) {
+            Ok(data) => Ok((data, true)),
+            Err(_) => Err(err),
+        },
+    }
+}
+
+fn read_encrypted_file(path: &Path, passphrase: &str) -> std::io::Result<Vec<u8>> {
     let data = fs::read(path)?;
-    if data.len() < SALT_LEN + NONCE_LEN {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "ciphertext too short",
-        ));
+    let (version, body) = split_header(&data);
+    let result = if version > FORMAT_VERSION {
+        None
+    } else {
+        decrypt_body(passphrase, body)
+    };
+    if let Some(Ok(plaintext)) = result {
+        return match version {
+            FORMAT_ZSTD => zstd::stream::decode_all(plaintext.as_slice()).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{} failed to decompress: {}", path.display(), e),
+                )
+            }),
+            _ => Ok(plaintext),
+        };
     }
-    let (salt, rest) = data.split_at(SALT_LEN);
+    // A legacy file whose random salt happens to start with MAGIC.
+    if version > 0 {
+        if let Some(Ok(plaintext)) = decrypt_body(passphrase, &data) {
+            return Ok(plaintext);
+        }
+    }
+    let message = match result {
+        _ if version > FORMAT_VERSION => format!(
+            "{} uses storage format v{}, newer than the supported v{}; upgrade marty",
+            path.display(),
+            version,
+            FORMAT_VERSION
+        ),
+        None => format!("{} is truncated", path.display()),
+        _ => format!(
+            "{} (format v{}) could not be decrypted: wrong passphrase or corrupted file",
+            path.display(),
+            version
+        ),
+    };
+    Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message))
+}
+
+/// Returns the format version and the remaining bytes (salt, nonce, ciphertext).
+fn split_header(data: &[u8]) -> (u8, &[u8]) {
+    match data.strip_prefix(MAGIC.as_slice()) {
+        Some([version, rest @ ..]) => (*version, rest),
+        _ => (0, data),
+    }
+}
+
+fn decrypt_body(passphrase: &str, body: &[u8]) -> Option<std::io::Result<Vec<u8>>> {
+    if body.len() < SALT_LEN + NONCE_LEN + TAG_LEN {
+        return None;
+    }
+    let (salt, rest) = body.split_at(SALT_LEN);
     let (nonce_bytes, ciphertext) = rest.split_at(NONCE_LEN);
-    decrypt_bytes(passphrase, salt, nonce_bytes, ciphertext)
+    Some(decrypt_bytes(passphrase, salt, nonce_bytes, ciphertext))
 }
 
 pub fn encrypt_value(passphrase: &str, plaintext: &[u8]) -> std::io::Result<EncryptedValue> {
@@ -69,7 +234,11 @@ pub fn encrypt_value(passphrase: &str, plaintext: &[u8]) -> std::io::Result<Encr
     })
 }
 
-pub fn decrypt_value(passphrase: &str, value: &EncryptedValue) -> std::io::Result<Vec<u8>> {
+/// Decrypts `value`, also returning how long deriving the key took.
+pub fn decrypt_value(
+    passphrase: &str,
+    value: &EncryptedValue,
+) -> std::io::Result<(Vec<u8>, Duration)> {
     let salt = BASE64_STANDARD
         .decode(&value.salt)
         .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid salt"))?;
@@ -85,7 +254,10 @@ pub fn decrypt_value(passphrase: &str, value: &EncryptedValue) -> std::io::Resul
             "invalid encrypted value",
         ));
     }
-    decrypt_bytes(passphrase, &salt, &nonce, &data)
+    let started = Instant::now();
+    let key = derive_key(passphrase, &salt);
+    let kdf = started.elapsed();
+    decrypt_with_key(&key, &nonce, &data).map(|plaintext| (plaintext, kdf))
 }
 
 fn encrypt_bytes(
@@ -94,13 +266,12 @@ fn encrypt_bytes(
     nonce_bytes: &[u8],
     plaintext: &[u8],
 ) -> std::io::Result<Vec<u8>> {
-    let mut key = [0u8; 32];
-    pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, PBKDF2_ITERS, &mut key);
+    let key = derive_key(passphrase, salt);
     let cipher = Aes256Gcm::new_from_slice(&key).expect("key size");
     let nonce = Nonce::from_slice(nonce_bytes);
     let ciphertext = cipher
         .encrypt(nonce, plaintext)
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "encrypt failed"))?;
+        .map_err(|_| std::io::Error::other("encrypt failed"))?;
     Ok(ciphertext)
 }
 
@@ -110,13 +281,19 @@ fn decrypt_bytes(
     nonce_bytes: &[u8],
     ciphertext: &[u8],
 ) -> std::io::Result<Vec<u8>> {
-    let mut key = [0u8; 32];
-    pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, PBKDF2_ITERS, &mut key);
-    let cipher = Aes256Gcm::new_from_slice(&key).expect("key size");
+    decrypt_with_key(&derive_key(passphrase, salt), nonce_bytes, ciphertext)
+}
+
+fn decrypt_with_key(
+    key: &[u8; 32],
+    nonce_bytes: &[u8],
+    ciphertext: &[u8],
+) -> std::io::Result<Vec<u8>> {
+    let cipher = Aes256Gcm::new_from_slice(key).expect("key size");
     let nonce = Nonce::from_slice(nonce_bytes);
     let plaintext = cipher
         .decrypt(nonce, ciphertext)
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "decrypt failed"))?;
+        .map_err(|_| std::io::Error::other("decrypt failed"))?;
     Ok(plaintext)
 }
 
@@ -128,6 +305,18 @@ pub fn room_receipts_path(base: &Path, room_id: &str) -> PathBuf {
     base.join(room_id.replace(':', "_")).join("read_receipts.json.enc")
 }
 
+pub fn search_index_path(base: &Path) -> PathBuf {
+    base.join("search_index.json.enc")
+}
+
+pub fn drafts_path(base: &Path) -> PathBuf {
+    base.join("drafts.json.enc")
+}
+
+pub fn scheduled_path(base: &Path) -> PathBuf {
+    base.join("scheduled.json.enc")
+}
+
 pub fn ensure_room_dir(base: &Path, room_id: &str) -> std::io::Result<PathBuf> {
     let dir = base.join(room_id.replace(':', "_"));
     fs::create_dir_all(&dir)?;
@@ -143,12 +332,83 @@ pub struct StoredMessage {
     pub event_id: Option<String>,
     #[serde(default)]
     pub reply_to: Option<String>,
+    /// Root event of the thread the message was posted in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread_root: Option<String>,
     #[serde(default)]
     pub attachment_path: Option<String>,
     #[serde(default)]
     pub attachment_name: Option<String>,
     #[serde(default)]
     pub attachment_kind: Option<String>,
+    /// Body was replaced by an `m.replace` edit.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub edited: bool,
+    /// Redacted; body and attachment fields have been cleared.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub redacted: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<StoredReaction>,
+    /// Set for poll start events; `body` holds the question.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poll: Option<StoredPoll>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub votes: Vec<StoredVote>,
+}
+
+/// An `m.reaction` annotation on a stored message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredReaction {
+    pub event_id: String,
+    pub sender: String,
+    pub key: String,
+}
+
+/// The question and answers of a poll.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredPoll {
+    pub question: String,
+    pub answers: Vec<StoredPollAnswer>,
+    pub max_selections: u64,
+    /// Started as `m.poll.start` rather than the MSC3381 event most
+    /// clients still send; votes go out in the same flavour.
+    #[serde(default)]
+    pub stable: bool,
+    /// When its creator ended it (ms); later votes don't count.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ended: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredPollAnswer {
+    pub id: String,
+    pub text: String,
+}
+
+/// A poll response; a sender's latest one replaces their earlier votes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredVote {
+    pub event_id: String,
+    pub sender: String,
+    pub answers: Vec<String>,
+    pub timestamp: i64,
+}
+
+/// A message queued with `/schedule`, sent once `send_at` (ms) passes.
+/// The body is stored already formatted for sending.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduledMessage {
+    pub room_id: String,
+    pub body: String,
+    #[serde(default)]
+    pub mentions: Vec<StoredMention>,
+    pub send_at: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredMention {
+    pub user_id: String,
+    pub name: String,
 }
 
 pub fn append_message(
@@ -159,7 +419,7 @@ pub fn append_message(
 ) -> std::io::Result<()> {
     let _ = ensure_room_dir(base, room_id)?;
     let path = room_log_path(base, room_id);
-    let mut records = if path.exists() {
+    let mut records = if encrypted_exists(&path) {
         let raw = read_encrypted(&path, passphrase)?;
         serde_json::from_slice::<Vec<StoredMessage>>(&raw).unwrap_or_default()
     } else {
@@ -176,11 +436,77 @@ pub fn append_message(
     write_encrypted(&path, passphrase, &data)
 }
 
-pub fn load_all_messages(
+/// Rewrites a room log in place; `update` returns whether it changed
+/// anything, so untouched logs aren't re-encrypted.
+pub fn update_messages(
     base: &Path,
     passphrase: &str,
-) -> std::io::Result<Vec<(String, Vec<StoredMessage>)>> {
-    let mut out = Vec::new();
+    room_id: &str,
+    update: impl FnOnce(&mut Vec<StoredMessage>) -> bool,
+) -> std::io::Result<()> {
+    let path = room_log_path(base, room_id);
+    if !encrypted_exists(&path) {
+        return Ok(());
+    }
+    let raw = read_encrypted(&path, passphrase)?;
+    let mut records = serde_json::from_slice::<Vec<StoredMessage>>(&raw).unwrap_or_default();
+    if !update(&mut records) {
+        return Ok(());
+    }
+    let data = serde_json::to_vec(&records)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+    write_encrypted(&path, passphrase, &data)
+}
+
+/// Like `update_messages`, for changes that remove content: the backup the
+/// write rotates out still holds it, so it is replaced as well.
+pub fn purge_messages(
+    base: &Path,
+    passphrase: &str,
+    room_id: &str,
+    update: impl FnOnce(&mut Vec<StoredMessage>) -> bool,
+) -> std::io::Result<()> {
+    let mut changed = false;
+    update_messages(base, passphrase, room_id, |records| {
+        changed = update(records);
+        changed
+    })?;
+    if changed {
+        scrub_backup(&room_log_path(base, room_id))?;
+    }
+    Ok(())
+}
+
+/// Points the stored attachment for `event_id` at a freshly downloaded
+/// file; used when the original was deleted from the attachments dir.
+pub fn update_attachment_path(
+    base: &Path,
+    passphrase: &str,
+    room_id: &str,
+    event_id: &str,
+    attachment_path: &str,
+) -> std::io::Result<()> {
+    update_messages(base, passphrase, room_id, |records| {
+        let Some(record) = records
+            .iter_mut()
+            .find(|msg| msg.event_id.as_deref() == Some(event_id))
+        else {
+            return false;
+        };
+        record.attachment_path = Some(attachment_path.to_string());
+        true
+    })
+}
+
+#[derive(Debug, Default)]
+pub struct LoadedMessages {
+    pub rooms: Vec<(String, Vec<StoredMessage>)>,
+    /// Damaged room logs, either restored from backup or skipped.
+    pub problems: Vec<String>,
+}
+
+pub fn load_all_messages(base: &Path, passphrase: &str) -> std::io::Result<LoadedMessages> {
+    let mut out = LoadedMessages::default();
     if !base.exists() {
         return Ok(out);
     }
@@ -191,13 +517,25 @@ pub fn load_all_messages(
         }
         let room_key = entry.file_name().to_string_lossy().to_string();
         let path = entry.path().join("messages.jsonl.enc");
-        if !path.exists() {
+        if !encrypted_exists(&path) {
             continue;
         }
-        let raw = read_encrypted(&path, passphrase)?;
+        let raw = match read_encrypted_with_backup(&path, passphrase) {
+            Ok((raw, false)) => raw,
+            Ok((raw, true)) => {
+                out.problems
+                    .push(format!("{} restored from backup", room_key.replace('_', ":")));
+                raw
+            }
+            Err(err) => {
+                out.problems
+                    .push(format!("{} unreadable: {}", room_key.replace('_', ":"), err));
+                continue;
+            }
+        };
         let records = serde_json::from_slice::<Vec<StoredMessage>>(&raw)
             .unwrap_or_default();
-        out.push((room_key, records));
+        out.rooms.push((room_key, records));
     }
     Ok(out)
 }
@@ -217,7 +555,7 @@ pub fn load_all_read_receipts(
         }
         let room_key = entry.file_name().to_string_lossy().to_string();
         let path = entry.path().join("read_receipts.json.enc");
-        if !path.exists() {
+        if !encrypted_exists(&path) {
             continue;
         }
         let raw = read_encrypted(&path, passphrase)?;
@@ -248,7 +586,7 @@ pub fn latest_room_timestamp(
     passphrase: &str,
 ) -> std::io::Result<Option<i64>> {
     let path = room_log_path(base, room_id);
-    if !path.exists() {
+    if !encrypted_exists(&path) {
         return Ok(None);
     }
     let raw = read_encrypted(&path, passphrase)?;
@@ -256,3 +594,661 @@ pub fn latest_room_timestamp(
     let max_ts = records.iter().map(|msg| msg.timestamp).max();
     Ok(max_ts)
 }
+
+/// How often the owner of the data dir writes a changed search index back.
+pub const SEARCH_INDEX_SAVE_INTERVAL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexRef {
+    pub room_id: String,
+    pub event_id: String,
+    pub timestamp: i64,
+    /// Hash of the indexed text, so edits and redactions stored while the
+    /// index wasn't loaded are noticed by `catch_up`.
+    #[serde(default)]
+    pub text_hash: u64,
+}
+
+/// Inverted index from lowercase word tokens to message references, stored
+/// encrypted next to the room logs so search doesn't decrypt every log.
+///
+/// It is kept in memory and updated as messages arrive; only the process
+/// holding the data dir lock writes it back, every
+/// `SEARCH_INDEX_SAVE_INTERVAL` and on exit.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SearchIndex {
+    refs: Vec<IndexRef>,
+    /// Sorted by token so a query word finds its prefix matches with a range
+    /// lookup; each list of positions in `refs` is kept ascending.
+    tokens: BTreeMap<String, Vec<u32>>,
+    /// Position in `refs` per event, so a message seen twice (by live sync
+    /// and by backfill) is indexed once.
+    #[serde(skip)]
+    events: HashMap<String, u32>,
+    /// The tokens of each entry of `refs`, so an edit or redaction only
+    /// touches the lists its message is in.
+    #[serde(skip)]
+    ref_tokens: Vec<Vec<String>>,
+    /// Set when the index differs from what was last saved.
+    #[serde(skip)]
+    changed: bool,
+    /// Set when a redaction dropped words that the saved index and its
+    /// backup may still hold.
+    #[serde(skip)]
+    purged: bool,
+}
+
+impl SearchIndex {
+    /// Indexes a message unless its event is already in the index.
+    pub fn add_text(&mut self, room_id: &str, event_id: &str, timestamp: i64, text: &str) {
+        if self.events.contains_key(event_id) {
+            return;
+        }
+        let id = self.refs.len() as u32;
+        self.refs.push(IndexRef {
+            room_id: room_id.to_string(),
+            event_id: event_id.to_string(),
+            timestamp,
+            text_hash: text_hash(text),
+        });
+        self.events.insert(event_id.to_string(), id);
+        self.insert_tokens(id, text);
+        self.changed = true;
+    }
+
+    /// Re-indexes an edited message under its new text.
+    pub fn update_text(&mut self, event_id: &str, text: &str) {
+        let Some(&id) = self.events.get(event_id) else {
+            return;
+        };
+        let hash = text_hash(text);
+        if self.refs[id as usize].text_hash == hash {
+            return;
+        }
+        self.drop_tokens(&HashSet::from([id]));
+        self.insert_tokens(id, text);
+        self.refs[id as usize].text_hash = hash;
+        self.changed = true;
+    }
+
+    /// Forgets the words of a message, e.g. once it has been redacted. The
+    /// event stays known so a late copy of it isn't indexed again.
+    pub fn remove(&mut self, event_id: &str) {
+        let Some(&id) = self.events.get(event_id) else {
+            return;
+        };
+        if self.refs[id as usize].text_hash != text_hash("") {
+            self.update_text(event_id, "");
+            self.purged = true;
+        }
+    }
+
+    /// Whether words were removed since the index was last stored; such a
+    /// change is written right away instead of waiting for the next save.
+    pub fn needs_purge(&self) -> bool {
+        self.purged
+    }
+
+    /// Brings the index up to date with the output of `load_all_messages`:
+    /// adds messages it doesn't know yet and re-indexes those edited or
+    /// redacted since it was saved.
+    pub fn catch_up(&mut self, rooms: &[(String, Vec<StoredMessage>)]) {
+        let mut stale = Vec::new();
+        for (room_key, records) in rooms {
+            let room_id = room_key.replace('_', ":");
+            for record in records {
+                let Some(event_id) = record.event_id.as_deref() else {
+                    continue;
+                };
+                let text = record_text(record);
+                match self.events.get(event_id) {
+                    Some(&id) if self.refs[id as usize].text_hash != text_hash(text) => {
+                        stale.push((id, text));
+                    }
+                    Some(_) => {}
+                    None => self.add_text(&room_id, event_id, record.timestamp, text),
+                }
+            }
+        }
+        if stale.is_empty() {
+            return;
+        }
+        self.drop_tokens(&stale.iter().map(|(id, _)| *id).collect());
+        for (id, text) in stale {
+            self.insert_tokens(id, text);
+            self.refs[id as usize].text_hash = text_hash(text);
+        }
+        self.changed = true;
+    }
+
+    /// Whether the index changed since the last call.
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::take(&mut self.changed)
+    }
+
+    /// Fills `events` and `ref_tokens` after loading. Indexes written before
+    /// events were deduplicated can hold an event twice; the later copies
+    /// are dropped.
+    fn link_events(&mut self) {
+        self.ref_tokens = vec![Vec::new(); self.refs.len()];
+        for (token, ids) in &mut self.tokens {
+            // Older indexes appended re-indexed messages out of order.
+            ids.sort_unstable();
+            ids.dedup();
+            ids.retain(|&id| (id as usize) < self.ref_tokens.len());
+            for &id in ids.iter() {
+                self.ref_tokens[id as usize].push(token.clone());
+            }
+        }
+        self.tokens.retain(|_, ids| !ids.is_empty());
+        let mut duplicates = HashSet::new();
+        for (id, r) in self.refs.iter().enumerate() {
+            if self.events.contains_key(&r.event_id) {
+                duplicates.insert(id as u32);
+            } else {
+                self.events.insert(r.event_id.clone(), id as u32);
+            }
+        }
+        if !duplicates.is_empty() {
+            self.drop_tokens(&duplicates);
+            self.changed = true;
+        }
+    }
+
+    fn insert_tokens(&mut self, id: u32, text: &str) {
+        let tokens = tokenize(text);
+        for token in &tokens {
+            let ids = self.tokens.entry(token.clone()).or_default();
+            // New messages take the highest position, so this is a push
+            // unless an edit re-indexes an older one.
+            let pos = ids.partition_point(|&other| other < id);
+            if ids.get(pos) != Some(&id) {
+                ids.insert(pos, id);
+            }
+        }
+        if self.ref_tokens.len() <= id as usize {
+            self.ref_tokens.resize(id as usize + 1, Vec::new());
+        }
+        self.ref_tokens[id as usize] = tokens;
+    }
+
+    fn drop_tokens(&mut self, ids: &HashSet<u32>) {
+        for &id in ids {
+            let Some(tokens) = self.ref_tokens.get_mut(id as usize) else {
+                continue;
+            };
+            for token in std::mem::take(tokens) {
+                let Some(list) = self.tokens.get_mut(&token) else {
+                    continue;
+                };
+                if let Ok(pos) = list.binary_search(&id) {
+                    list.remove(pos);
+                }
+                if list.is_empty() {
+                    self.tokens.remove(&token);
+                }
+            }
+        }
+    }
+
+    /// Returns messages containing every query word (as a word prefix),
+    /// newest first.
+    pub fn search(&self, query: &str) -> Vec<&IndexRef> {
+        let mut matched: Option<HashSet<u32>> = None;
+        for word in tokenize(query) {
+            let ids: HashSet<u32> = self
+                .tokens
+                .range::<str, _>((Bound::Included(word.as_str()), Bound::Unbounded))
+                .take_while(|(token, _)| token.starts_with(&word))
+                .flat_map(|(_, ids)| ids.iter().copied())
+                .collect();
+            matched = Some(match matched {
+                Some(prev) => prev.intersection(&ids).copied().collect(),
+                None => ids,
+            });
+        }
+        let mut hits: Vec<&IndexRef> = matched
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|id| self.refs.get(id as usize))
+            .collect();
+        hits.sort_by_key(|hit| std::cmp::Reverse(hit.timestamp));
+        hits
+    }
+}
+
+/// The text a stored message is searchable by.
+fn record_text(record: &StoredMessage) -> &str {
+    record.attachment_name.as_deref().unwrap_or(&record.body)
+}
+
+fn text_hash(text: &str) -> u64 {
+    let digest = Sha256::digest(text.as_bytes());
+    u64::from_le_bytes(digest[..8].try_into().expect("digest length"))
+}
+
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= 2)
+        .map(|word| word.to_lowercase())
+        .filter(|word| seen.insert(word.clone()))
+        .collect()
+}
+
+pub fn load_search_index(base: &Path, passphrase: &str) -> std::io::Result<Option<SearchIndex>> {
+    let path = search_index_path(base);
+    if !encrypted_exists(&path) {
+        return Ok(None);
+    }
+    let raw = read_encrypted(&path, passphrase)?;
+    let mut index = serde_json::from_slice::<SearchIndex>(&raw)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+    index.link_events();
+    Ok(Some(index))
+}
+
+/// Loads the search index and catches it up with `rooms`, the output of
+/// `load_all_messages`; a missing or unreadable index is rebuilt.
+pub fn load_search_index_for(
+    base: &Path,
+    passphrase: &str,
+    rooms: &[(String, Vec<StoredMessage>)],
+) -> SearchIndex {
+    let mut index = load_search_index(base, passphrase)
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    index.catch_up(rooms);
+    index
+}
+
+/// Writes the index; after a redaction its backup is replaced too, so the
+/// removed words don't survive there.
+pub fn store_search_index(
+    base: &Path,
+    passphrase: &str,
+    index: &mut SearchIndex,
+) -> std::io::Result<()> {
+    fs::create_dir_all(base)?;
+    let data = serde_json::to_vec(index)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+    let path = search_index_path(base);
+    write_encrypted(&path, passphrase, &data)?;
+    if std::mem::take(&mut index.purged) {
+        scrub_backup(&path)?;
+    }
+    Ok(())
+}
+
+/// Unsent input per room ID.
+pub fn load_drafts(base: &Path, passphrase: &str) -> std::io::Result<HashMap<String, String>> {
+    let path = drafts_path(base);
+    if !encrypted_exists(&path) {
+        return Ok(HashMap::new());
+    }
+    let raw = read_encrypted(&path, passphrase)?;
+    serde_json::from_slice(&raw)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))
+}
+
+pub fn store_drafts(
+    base: &Path,
+    passphrase: &str,
+    drafts: &HashMap<String, String>,
+) -> std::io::Result<()> {
+    fs::create_dir_all(base)?;
+    let data = serde_json::to_vec(drafts)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+    write_encrypted(&drafts_path(base), passphrase, &data)
+}
+
+pub fn load_scheduled(base: &Path, passphrase: &str) -> std::io::Result<Vec<ScheduledMessage>> {
+    let path = scheduled_path(base);
+    if !encrypted_exists(&path) {
+        return Ok(Vec::new());
+    }
+    let raw = read_encrypted(&path, passphrase)?;
+    serde_json::from_slice(&raw)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))
+}
+
+pub fn store_scheduled(
+    base: &Path,
+    passphrase: &str,
+    scheduled: &[ScheduledMessage],
+) -> std::io::Result<()> {
+    fs::create_dir_all(base)?;
+    let data = serde_json::to_vec(scheduled)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+    write_encrypted(&scheduled_path(base), passphrase, &data)
+}
+
+/// Scheduled messages later than this when marty gets to them (it wasn't
+/// running) are held back as missed instead of sent.
+pub const SCHEDULE_GRACE_MS: i64 = 5 * 60 * 1000;
+
+/// Removes the queued messages that are due at `now` from `scheduled`,
+/// which is sorted by `send_at`. Messages later than `SCHEDULE_GRACE_MS`
+/// (nothing was running to send them) stay queued as missed.
+pub fn take_due_scheduled(
+    scheduled: &mut Vec<ScheduledMessage>,
+    now: i64,
+) -> Vec<ScheduledMessage> {
+    let mut due = Vec::new();
+    let mut idx = 0;
+    while let Some(msg) = scheduled.get(idx) {
+        if msg.send_at > now {
+            break;
+        }
+        if now - msg.send_at > SCHEDULE_GRACE_MS {
+            idx += 1;
+            continue;
+        }
+        due.push(scheduled.remove(idx));
+    }
+    due
+}
+
+/// Everything the local archive holds about one user: the messages they
+/// sent and the reactions they left, across all rooms.
+#[derive(Debug, Serialize)]
+pub struct SenderExport {
+    pub user_id: String,
+    pub messages: Vec<ExportedMessage>,
+    pub reactions: Vec<ExportedReaction>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportedMessage {
+    pub room_id: String,
+    #[serde(flatten)]
+    pub message: StoredMessage,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportedReaction {
+    pub room_id: String,
+    /// The message reacted to.
+    pub target: Option<String>,
+    pub event_id: String,
+    pub key: String,
+}
+
+/// Collects `user_id`'s messages and reactions from the output of
+/// `load_all_messages`, messages oldest first.
+pub fn collect_sender(rooms: &[(String, Vec<StoredMessage>)], user_id: &str) -> SenderExport {
+    let mut export = SenderExport {
+        user_id: user_id.to_string(),
+        messages: Vec::new(),
+        reactions: Vec::new(),
+    };
+    for (room_key, records) in rooms {
+        let room_id = room_key.replace('_', ":");
+        for record in records {
+            for reaction in record.reactions.iter().filter(|r| r.sender == user_id) {
+                export.reactions.push(ExportedReaction {
+                    room_id: room_id.clone(),
+                    target: record.event_id.clone(),
+                    event_id: reaction.event_id.clone(),
+                    key: reaction.key.clone(),
+                });
+            }
+            if record.sender == user_id {
+                export.messages.push(ExportedMessage {
+                    room_id: room_id.clone(),
+                    message: record.clone(),
+                });
+            }
+        }
+    }
+    export.messages.sort_by_key(|m| m.message.timestamp);
+    export
+}
+
+/// Writes `user_id`'s stored messages and reactions to `path` as
+/// unencrypted JSON readable only by the owner. Returns how many messages
+/// and reactions were exported.
+pub fn export_sender(
+    base: &Path,
+    passphrase: &str,
+    user_id: &str,
+    path: &Path,
+) -> std::io::Result<(usize, usize)> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let loaded = load_all_messages(base, passphrase)?;
+    let export = collect_sender(&loaded.rooms, user_id);
+    let data = serde_json::to_vec_pretty(&export)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // `mode` only applies to new files; an existing one may be readable by
+    // others.
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(&data)?;
+    Ok((export.messages.len(), export.reactions.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSPHRASE: &str = "test-passphrase";
+    const ROOM_ID: &str = "!room:example.org";
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("marty-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("temp dir");
+        dir
+    }
+
+    fn message(event_id: &str, timestamp: i64, body: &str) -> StoredMessage {
+        StoredMessage {
+            timestamp,
+            sender: "@alice:example.org".to_string(),
+            body: body.to_string(),
+            event_id: Some(event_id.to_string()),
+            reply_to: None,
+            thread_root: None,
+            attachment_path: None,
+            attachment_name: None,
+            attachment_kind: None,
+            edited: false,
+            redacted: false,
+            reactions: Vec::new(),
+            poll: None,
+            votes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn take_due_scheduled_keeps_future_and_missed_messages() {
+        let queued = |send_at| ScheduledMessage {
+            room_id: ROOM_ID.to_string(),
+            body: format!("at {}", send_at),
+            mentions: Vec::new(),
+            send_at,
+        };
+        let now = 10 * SCHEDULE_GRACE_MS;
+        let missed = queued(now - SCHEDULE_GRACE_MS - 1);
+        let due = queued(now - 1000);
+        let later = queued(now + 1000);
+        let mut scheduled = vec![missed.clone(), due.clone(), later.clone()];
+        assert_eq!(take_due_scheduled(&mut scheduled, now), [due]);
+        assert_eq!(scheduled, [missed, later]);
+    }
+
+    fn holds(path: &Path, text: &str) -> bool {
+        let raw = read_encrypted_file(path, PASSPHRASE).expect("readable");
+        String::from_utf8_lossy(&raw).contains(text)
+    }
+
+    #[test]
+    fn purged_message_is_gone_from_log_index_and_backups() {
+        let dir = temp_dir("purge");
+        append_message(&dir, PASSPHRASE, ROOM_ID, message("$secret", 1, "launch codes")).unwrap();
+        append_message(&dir, PASSPHRASE, ROOM_ID, message("$other", 2, "hello")).unwrap();
+        let log = room_log_path(&dir, ROOM_ID);
+        assert!(holds(&backup_path(&log), "launch codes"));
+
+        purge_messages(&dir, PASSPHRASE, ROOM_ID, |records| {
+            records[0].body.clear();
+            records[0].redacted = true;
+            true
+        })
+        .unwrap();
+        assert!(!holds(&log, "launch codes"));
+        assert!(!holds(&backup_path(&log), "launch codes"));
+
+        let mut index = SearchIndex::default();
+        index.add_text(ROOM_ID, "$secret", 1, "launch codes");
+        store_search_index(&dir, PASSPHRASE, &mut index).unwrap();
+        index.add_text(ROOM_ID, "$other", 2, "hello");
+        store_search_index(&dir, PASSPHRASE, &mut index).unwrap();
+        let path = search_index_path(&dir);
+        assert!(holds(&backup_path(&path), "launch"));
+
+        index.remove("$secret");
+        assert!(index.needs_purge());
+        store_search_index(&dir, PASSPHRASE, &mut index).unwrap();
+        assert!(!index.needs_purge());
+        assert!(!holds(&path, "launch"));
+        assert!(!holds(&backup_path(&path), "launch"));
+        assert!(index.search("launch").is_empty());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn event_ids(hits: Vec<&IndexRef>) -> Vec<&str> {
+        hits.into_iter().map(|hit| hit.event_id.as_str()).collect()
+    }
+
+    #[test]
+    fn search_index_follows_edits_and_matches_prefixes() {
+        let mut index = SearchIndex::default();
+        index.add_text(ROOM_ID, "$a", 1, "deploy the release");
+        index.add_text(ROOM_ID, "$b", 2, "release notes");
+        index.add_text(ROOM_ID, "$c", 3, "relax");
+        assert_eq!(event_ids(index.search("rel")), ["$c", "$b", "$a"]);
+        assert_eq!(event_ids(index.search("release")), ["$b", "$a"]);
+        assert_eq!(event_ids(index.search("rel dep")), ["$a"]);
+
+        index.update_text("$a", "rollback");
+        assert_eq!(event_ids(index.search("release")), ["$b"]);
+        assert_eq!(event_ids(index.search("roll")), ["$a"]);
+        index.update_text("$a", "deploy the release");
+        assert_eq!(event_ids(index.search("release")), ["$b", "$a"]);
+        assert!(index.search("rollback").is_empty());
+
+        index.remove("$b");
+        assert_eq!(event_ids(index.search("release")), ["$a"]);
+        assert!(index.search("notes").is_empty());
+        assert!(!index.tokens.contains_key("notes"));
+    }
+
+    #[test]
+    fn reloaded_search_index_forgets_dropped_words() {
+        let dir = temp_dir("search-reload");
+        let mut index = SearchIndex::default();
+        index.add_text(ROOM_ID, "$a", 1, "old words");
+        index.add_text(ROOM_ID, "$b", 2, "other words");
+        store_search_index(&dir, PASSPHRASE, &mut index).unwrap();
+
+        let mut index = load_search_index(&dir, PASSPHRASE).unwrap().expect("index");
+        assert_eq!(event_ids(index.search("words")), ["$b", "$a"]);
+        index.update_text("$a", "new text");
+        assert!(index.search("old").is_empty());
+        assert_eq!(event_ids(index.search("words")), ["$b"]);
+        assert_eq!(event_ids(index.search("new")), ["$a"]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn read_error(path: &Path, passphrase: &str) -> String {
+        read_encrypted_file(path, passphrase)
+            .expect_err("unreadable")
+            .to_string()
+    }
+
+    #[test]
+    fn header_round_trips_plain_and_compressed() {
+        let dir = temp_dir("header");
+        let path = dir.join("data");
+
+        let mut noise = vec![0u8; 4096];
+        OsRng.fill_bytes(&mut noise);
+        write_encrypted(&path, PASSPHRASE, &noise).unwrap();
+        let raw = fs::read(&path).unwrap();
+        assert_eq!(&raw[..MAGIC.len()], MAGIC);
+        assert_eq!(raw[MAGIC.len()], FORMAT_PLAIN);
+        assert_eq!(read_encrypted_file(&path, PASSPHRASE).unwrap(), noise);
+
+        let text = "hello again ".repeat(1000);
+        write_encrypted(&path, PASSPHRASE, text.as_bytes()).unwrap();
+        let raw = fs::read(&path).unwrap();
+        assert_eq!(raw[MAGIC.len()], FORMAT_ZSTD);
+        assert!(raw.len() < text.len());
+        assert_eq!(read_encrypted_file(&path, PASSPHRASE).unwrap(), text.as_bytes());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reads_legacy_files_without_header() {
+        let dir = temp_dir("legacy");
+        let path = dir.join("data");
+        let salt = [7u8; SALT_LEN];
+        let nonce = [9u8; NONCE_LEN];
+        let mut raw = salt.to_vec();
+        raw.extend_from_slice(&nonce);
+        raw.extend(encrypt_bytes(PASSPHRASE, &salt, &nonce, b"from v0").unwrap());
+        fs::write(&path, raw).unwrap();
+        assert_eq!(read_encrypted_file(&path, PASSPHRASE).unwrap(), b"from v0");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn header_errors_name_the_problem() {
+        let dir = temp_dir("header-errors");
+        let path = dir.join("data");
+        write_encrypted(&path, PASSPHRASE, b"secret").unwrap();
+        let raw = fs::read(&path).unwrap();
+
+        assert!(read_error(&path, "not the passphrase").contains("wrong passphrase"));
+
+        let mut newer = raw.clone();
+        newer[MAGIC.len()] = FORMAT_VERSION + 1;
+        fs::write(&path, &newer).unwrap();
+        assert!(read_error(&path, PASSPHRASE).contains("newer than the supported"));
+
+        fs::write(&path, &raw[..HEADER_LEN + SALT_LEN]).unwrap();
+        assert!(read_error(&path, PASSPHRASE).contains("truncated"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn corrupt_file_is_read_from_backup() {
+        let dir = temp_dir("backup");
+        let path = dir.join("data");
+        write_encrypted(&path, PASSPHRASE, b"first").unwrap();
+        write_encrypted(&path, PASSPHRASE, b"second").unwrap();
+
+        let mut raw = fs::read(&path).unwrap();
+        *raw.last_mut().unwrap() ^= 0xff;
+        fs::write(&path, raw).unwrap();
+        assert!(read_encrypted_file(&path, PASSPHRASE).is_err());
+        let (data, from_backup) = read_encrypted_with_backup(&path, PASSPHRASE).unwrap();
+        assert_eq!(data, b"first");
+        assert!(from_backup);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
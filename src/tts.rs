@@ -0,0 +1,38 @@
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+/// Speaks text with a command such as `espeak` or `say -v Alex`, one
+/// utterance at a time so announcements don't talk over each other.
+pub struct Speaker {
+    queue: Sender<String>,
+}
+
+impl Speaker {
+    /// The text is passed as the last argument of `command`, after `--` so
+    /// that a sender named `-w file` can't pass options. `None` when the
+    /// command is empty.
+    pub fn new(command: &str) -> Option<Self> {
+        let mut words = command.split_whitespace().map(str::to_string);
+        let program = words.next()?;
+        let args: Vec<String> = words.collect();
+        let (queue, texts) = mpsc::channel::<String>();
+        thread::spawn(move || {
+            for text in texts {
+                let _ = Command::new(&program)
+                    .args(&args)
+                    .arg("--")
+                    .arg(text)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status();
+            }
+        });
+        Some(Self { queue })
+    }
+
+    pub fn say(&self, text: String) {
+        let _ = self.queue.send(text);
+    }
+}
@@ -0,0 +1,8715 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::ops::{Bound, Index, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use chrono::{DateTime, Local, NaiveTime, TimeZone};
+use crossterm::event::{
+    self, DisableBracketedPaste, EnableBracketedPaste, Event, EventStream, KeyCode, KeyEvent,
+    KeyEventKind, KeyModifiers,
+};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen, SetTitle,
+};
+use futures_util::StreamExt;
+use ratatui::backend::CrosstermBackend;
+use ratatui::buffer::Buffer;
+use ratatui::layout::{Alignment, Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::Terminal;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use crate::{clipboard, config, emoji};
+use crate::clipboard::ClipboardProvider;
+use crate::config::{
+    data_dir, messages_dir, update_config, JumpModifier, RoomSort, SenderFormat, Workspace,
+};
+use crate::matrix::{
+    find_mention, scheduled_command, EventRelation, MatrixCommand, MatrixEvent, MemberInfo,
+    Mention, RoomInfo, RoomListState, ServerInfo, StateEntry,
+};
+use crate::metrics::{Counter, Metrics};
+use crate::notify::{
+    alert_severity, format_sender, invite_notification, nickname, notify_message, notify_send,
+    room_listed, sender_label, verification_notification, Severity,
+};
+use crate::paste::Paster;
+use crate::preview::{Previews, Protocol};
+use crate::profile::StartupProfile;
+use crate::spell::{Misspelling, SpellChecker};
+use crate::tts::Speaker;
+use crate::storage::{
+    load_all_messages, load_all_read_receipts, load_drafts, load_scheduled,
+    load_search_index_for, room_log_path, store_drafts, store_read_receipts, store_scheduled,
+    store_search_index, take_due_scheduled, ScheduledMessage, SearchIndex, StoredMention,
+    StoredPoll, StoredReaction, StoredVote, SCHEDULE_GRACE_MS, SEARCH_INDEX_SAVE_INTERVAL,
+};
+
+/// Event loop tick when `tick_rate_ms` is unset.
+const DEFAULT_TICK_RATE: Duration = Duration::from_millis(100);
+/// After this long without input the loop stops ticking and blocks until
+/// input, a Matrix event or the next timer (`App::next_wake`).
+const IDLE_AFTER: Duration = Duration::from_secs(30);
+/// How long a room has to stay open before its read receipt is sent.
+const RECEIPT_DWELL: Duration = Duration::from_secs(1);
+/// Least time between two read receipts for the same room.
+const RECEIPT_INTERVAL: Duration = Duration::from_secs(2);
+/// How long a confirmed leave waits, so Ctrl+Z can still take it back.
+const LEAVE_GRACE: Duration = Duration::from_secs(10);
+/// How far past its long-poll timeout a sync may run before the health
+/// line under the input flags it.
+const SYNC_GRACE: Duration = Duration::from_secs(15);
+/// Typing notices are dropped after this in case the stop never arrives.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(30);
+/// Typing pause before the input is spell checked again.
+const SPELL_DELAY: Duration = Duration::from_millis(400);
+/// Line count above which sending asks about a file or paste link, when
+/// `long_message_lines` is unset.
+const DEFAULT_LONG_MESSAGE_LINES: usize = 20;
+/// Text rows the input area grows to when `input_max_lines` is unset.
+const DEFAULT_INPUT_MAX_LINES: u16 = 8;
+/// Longest gap between messages of one sender shown as a group in the
+/// compact layout.
+const GROUP_WINDOW_MS: i64 = 5 * 60 * 1000;
+const SELECTED_BG: Color = Color::Rgb(160, 170, 210);
+const NOTICE_BG: Color = Color::Rgb(200, 80, 60);
+const NOTICE_FG: Color = Color::Rgb(130, 130, 130);
+const OWN_BG: Color = Color::Rgb(38, 34, 46);
+/// Columns the reaction line under a message is indented by.
+const REACTION_INDENT: usize = 8;
+/// Shown in place of redacted messages.
+const REDACTED_TEXT: &str = "(message deleted)";
+/// Shown in place of messages that couldn't be decrypted.
+const UNDECRYPTABLE_TEXT: &str = "(unable to decrypt; Alt+K requests the keys)";
+const HELP_LINES: [&str; 82] = [
+    "App navigation",
+    "  Alt+Q\tQuit.",
+    "  F1\tToggle help panel showing shortcuts.",
+    "  Up\tOne Channel Up",
+    "  Down\tOne Channel Down",
+    "  F2\tSwitch to the next workspace (then back to all rooms).",
+    HELP_JUMP_ALT,
+    "  F3\tShow or hide channel numbers.",
+    "  F4\tToggle the bandwidth saver (no media downloads or link previews).",
+    "  F5\tToggle the compact layout (one name for a run of messages).",
+    "  F6\tShow or hide joins, leaves, renames and topic changes.",
+    "  Alt+P\tPin the channel to the top of the list (again unpins).",
+    "  Shift+Up/Down\tMove a pinned channel up/down among the pinned ones.",
+    "  Alt+A\tAdd chat (room or user); Tab completes rooms/aliases.",
+    "  Alt+J\tJoin/add chat (room or user).",
+    "  Alt+D\tDelete chat (y/n confirm).",
+    "  Ctrl+A\tAccept invite.",
+    "  Ctrl+D\tDecline invite.",
+    "  Alt+V\tStart or accept verification (SAS).",
+    "  Alt+S\tBrowse state events of the current room.",
+    "  Ctrl+N\tAcknowledge server notice banner.",
+    "  Ctrl+Z\tUndo leaving a room (within 10 seconds).",
+    "  Ctrl+L\tLock the input to this room while browsing others (again releases).",
+    "Message input",
+    "  Enter\tWhen input empty (single-line): open URL/attachment.",
+    "  Enter\tSend message (single-line) or insert newline (multi-line).",
+    "  file://<path>\tSend attachment from disk.",
+    "  Alt+Enter\tToggle multi-line input.",
+    "  Ctrl+E\tCompose in $EDITOR; the saved text is sent.",
+    "  Tab\tExpand the snippet before the cursor, or complete a /command name.",
+    "  :code\tEmoji completion: Up/Down pick, Tab/Enter insert, Esc dismiss.",
+    "  @name\tMention a room member (same keys as emoji completion).",
+    "  /join\t<#alias|!room_id>: join a room.",
+    "  /leave\tLeave the current room.",
+    "  /msg\t<@user|#alias|!room> <text>: message another room or user.",
+    "  /invite\t<@user:server>: invite a user to the current room.",
+    "  /topic\t<text>: set the current room's topic.",
+    "  /nick\t<name>: set your display name.",
+    "  /device-name\t<name>: rename this session's device.",
+    "  /nickname\t[--global] <@user> [name]: local nickname (no name clears).",
+    "  /upload\t<path>: send a file to the current room.",
+    "  /plain\t<text>: send without emoji or mention formatting.",
+    "  /format\tplain|rich: set this room's default formatting.",
+    "  /mute-thread\tMute (or unmute) the selected message's thread.",
+    "  /workspace\t[name]: switch workspace; no name lists all rooms.",
+    "  /template\t[name]: fill in a message template (no name lists them).",
+    "  /ack\tAcknowledge the selected (or latest) alert in an alert room.",
+    "  /vote\t<n>: vote for answer n of the selected poll; toggles on multi-choice polls.",
+    "  /schedule\t<HH:MM|+30m> <text>: send later; no arguments lists the queue.",
+    "  /bridge\t<status|relink|action> [bridge]: command this room's bridge bot.",
+    "  /rotate-key\tStart a new encryption session for this room with your next message.",
+    "  /send-event\t[--state] <type> [state_key] <json>: send a raw event.",
+    "  /search\t<words>: search messages across all rooms.",
+    "  /export-keys\t<path>: export this room's encryption keys (asks a passphrase).",
+    "  /import-keys\t<path>: import keys from an export file.",
+    "  /export-user\t<@user> <path>: save all stored messages from a user as JSON.",
+    "  Left/Right\tMove cursor in input.",
+    "  Alt/Ctrl+Left/Right\tJump word in input.",
+    "  Home/End\tJump to start/end of the input line.",
+    "  Delete\tDelete the character under the cursor.",
+    "  Ctrl+Up/Down\tRecall earlier/later input sent in this room.",
+    "  Ctrl+S\tCycle spelling suggestions for the word before the cursor.",
+    "Message/channel selection",
+    "  Esc\tReset message selection or close help panel.",
+    "  Alt+Up\tSelect previous message.",
+    "  Alt+Down\tSelect next message.",
+    "  PageUp/PageDown\tScroll the messages a page; Esc returns to the newest.",
+    "  Alt+Shift+Up/Down\tJump to your previous/next own message.",
+    "  Alt+R\tReply to the selected message; Esc cancels.",
+    "  Alt+C\tShow the reply chain of the selected message.",
+    "  Alt+T\tShow the thread of the selected message.",
+    "  Alt+K\tRequest the keys of the selected undecryptable message and retry.",
+    "  1-9\tVote for that answer of the selected poll (input empty); toggles on multi-choice polls.",
+    "  Alt+I\tShow details of the selected message (or the room and its topic).",
+    "  Alt+E\tEdit the selected (or your last) message; Esc cancels.",
+    "  Alt+X\tDelete (redact) the selected message (own, or any as moderator).",
+    "  Alt+L\tReact to the selected message with an emoji.",
+    "Clipboard",
+    "  Alt+Y\tCopy selected message to clipboard.",
+    "Help menu",
+    "  Type\tFilter the help, e.g. \"reply\"; Backspace edits.",
+    "  Esc\tClear the filter, then close the help panel. Up/Down/PageDown scroll.",
+];
+
+/// The channel jump line of `HELP_LINES` for each `room_jump_modifier`.
+const HELP_JUMP_ALT: &str = "  Alt+1..9\tJump to the Nth channel (see room_jump_modifier).";
+const HELP_JUMP_CTRL: &str = "  Ctrl+1..9\tJump to the Nth channel (see room_jump_modifier).";
+
+/// Bridge names and the bot localparts their bridges use by default.
+const BRIDGE_BOTS: [(&str, &str); 12] = [
+    ("telegram", "telegrambot"),
+    ("whatsapp", "whatsappbot"),
+    ("signal", "signalbot"),
+    ("discord", "discordbot"),
+    ("slack", "slackbot"),
+    ("meta", "metabot"),
+    ("facebook", "facebookbot"),
+    ("instagram", "instagrambot"),
+    ("gmessages", "gmessagesbot"),
+    ("googlechat", "googlechatbot"),
+    ("twitter", "twitterbot"),
+    ("irc", "heisenbridge"),
+];
+
+/// `/bridge` actions that work without a `bridge_commands` entry.
+const BRIDGE_DEFAULT_COMMANDS: [(&str, &str); 2] = [("status", "ping"), ("relink", "login")];
+
+/// Words dropped from help searches so questions like "how do I reply" work.
+const HELP_STOP_WORDS: [&str; 8] = ["how", "can", "the", "and", "what", "with", "for", "does"];
+
+#[derive(Clone)]
+pub enum MessageItem {
+    Separator(String),
+    Notice {
+        time: String,
+        text: String,
+        /// A membership, name or topic change; hidden with F6.
+        state_change: bool,
+    },
+    Message {
+        time: String,
+        timestamp: i64,
+        sender_id: String,
+        name: String,
+        text: String,
+        event_id: Option<String>,
+        reply_to: Option<String>,
+        /// Replaced by an `m.replace` edit; shown as `(edited)`.
+        edited: bool,
+    },
+    Attachment {
+        time: String,
+        timestamp: i64,
+        sender_id: String,
+        name: String,
+        label: String,
+        filename: String,
+        path: String,
+        /// The file is gone from disk; Enter downloads it again.
+        missing: bool,
+        event_id: Option<String>,
+        reply_to: Option<String>,
+    },
+    /// Votes are kept apart in `App::poll_votes`, like reactions.
+    Poll {
+        time: String,
+        timestamp: i64,
+        sender_id: String,
+        name: String,
+        poll: StoredPoll,
+        event_id: Option<String>,
+    },
+}
+
+enum PromptMode {
+    Add,
+    /// `details` is the member count and last activity, to tell apart
+    /// rooms with similar names.
+    Delete {
+        room_id: String,
+        room_name: String,
+        details: String,
+    },
+    /// Confirmation (or a reason) before redacting one of our messages.
+    Redact { room_id: String, event_id: String },
+    /// Passphrase for `/export-keys` (with the room) or `/import-keys`.
+    KeyPassphrase { room_id: Option<String>, path: PathBuf },
+    /// Emoji (or `:shortcode:`) to react to a message with.
+    React { room_id: String, event_id: String },
+    /// How to send a message longer than `long_message_lines`.
+    LongMessage { room_id: String, text: String },
+    /// A `{?Field}` of `/template`; `values` holds the answers so far.
+    TemplateField {
+        name: String,
+        text: String,
+        fields: Vec<String>,
+        values: Vec<String>,
+    },
+}
+
+/// Offered in the reaction prompt before anything is typed.
+const QUICK_REACTIONS: [(&str, &str); 8] = [
+    ("+1", "👍"),
+    ("heart", "❤️"),
+    ("joy", "😂"),
+    ("tada", "🎉"),
+    ("open_mouth", "😮"),
+    ("cry", "😢"),
+    ("eyes", "👀"),
+    ("pray", "🙏"),
+];
+
+struct PromptState {
+    mode: PromptMode,
+    input: String,
+    suggestions: Vec<PromptSuggestion>,
+    suggestion: usize,
+    error: Option<String>,
+}
+
+/// Position while recalling sent input with Ctrl+Up/Down; `draft` is the
+/// unsent text to return to past the newest entry.
+struct HistoryCursor {
+    room_id: String,
+    index: usize,
+    draft: String,
+}
+
+/// Own message whose body the input replaces on Enter (Alt+E).
+struct EditTarget {
+    room_id: String,
+    event_id: String,
+}
+
+/// Message the next send replies to (Alt+R), shown above the input.
+struct ReplyTarget {
+    room_id: String,
+    event_id: String,
+    sender: String,
+    text: String,
+}
+
+/// Ctrl+S cycling through the suggestions for one misspelled word; option 0
+/// is the word as typed.
+struct SpellCycle {
+    start: usize,
+    options: Vec<String>,
+    index: usize,
+}
+
+/// Add-chat prompt completion: a joined room or recently seen alias.
+struct PromptSuggestion {
+    label: String,
+    value: String,
+}
+
+struct ReplyPreview {
+    sender_id: String,
+    sender: String,
+    text: String,
+}
+
+struct StateView {
+    room_id: String,
+    entries: Option<Vec<StateEntry>>,
+    scroll: u16,
+}
+
+struct MessageInfoView {
+    title: &'static str,
+    event_id: Option<String>,
+    local: Vec<(String, String)>,
+    remote: Option<Vec<(String, String)>>,
+}
+
+struct ChainEntry {
+    time: String,
+    sender: String,
+    text: String,
+    depth: usize,
+    focused: bool,
+}
+
+/// A confirmed leave, sent once `at` passes unless undone.
+struct PendingLeave {
+    room_id: String,
+    room_name: String,
+    at: Instant,
+}
+
+/// A popup of related messages: a reply chain (Alt+C) or a thread (Alt+T).
+struct ReplyChainView {
+    title: String,
+    entries: Vec<ChainEntry>,
+    scroll: u16,
+}
+
+struct SearchHit {
+    room_id: String,
+    time: String,
+    sender: String,
+    text: String,
+}
+
+struct SearchView {
+    query: String,
+    hits: Vec<SearchHit>,
+    scroll: u16,
+}
+
+/// The `/schedule` list of queued messages.
+struct ScheduledView {
+    selected: usize,
+}
+
+/// Autocomplete popup above the input. Accepting an item replaces the
+/// input from char index `start` (the trigger character) up to the cursor.
+struct Completion {
+    start: usize,
+    items: Vec<CompletionItem>,
+    selected: usize,
+}
+
+struct CompletionItem {
+    label: String,
+    replacement: String,
+    mention: Option<Mention>,
+}
+
+struct ServerNotice {
+    event_id: String,
+    room_id: String,
+    time: String,
+    text: String,
+}
+
+/// Where the unread messages of the room just switched to begin: the
+/// first message from someone else after `since`, the newest timestamp
+/// read before. Cleared once it has been shown and scrolled past.
+struct UnreadDivider {
+    room_id: String,
+    since: i64,
+    event_id: Option<String>,
+    shown: bool,
+}
+
+/// The selected room's messages as the timeline lists them: positions
+/// into the room's messages, so handing it out doesn't walk the history.
+#[derive(Clone, Default)]
+struct Timeline<'a> {
+    items: &'a [MessageItem],
+    shown: Rc<[usize]>,
+}
+
+impl<'a> Timeline<'a> {
+    fn len(&self) -> usize {
+        self.shown.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.shown.is_empty()
+    }
+
+    fn get(&self, idx: usize) -> Option<&'a MessageItem> {
+        self.shown.get(idx).map(|pos| &self.items[*pos])
+    }
+
+    fn iter(&self) -> impl DoubleEndedIterator<Item = &'a MessageItem> + ExactSizeIterator + '_ {
+        self.range(..)
+    }
+
+    fn range(
+        &self,
+        range: impl RangeBounds<usize>,
+    ) -> impl DoubleEndedIterator<Item = &'a MessageItem> + ExactSizeIterator + '_ {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        let items = self.items;
+        self.shown[bounds].iter().map(move |pos| &items[*pos])
+    }
+}
+
+impl Index<usize> for Timeline<'_> {
+    type Output = MessageItem;
+
+    fn index(&self, idx: usize) -> &MessageItem {
+        &self.items[self.shown[idx]]
+    }
+}
+
+/// What decided the cached `Timeline` positions, besides the room: any
+/// change to its messages, any thread root noted, and F6.
+#[derive(PartialEq)]
+struct TimelineKey {
+    generation: u64,
+    thread_generation: u64,
+    hide_state_events: bool,
+}
+
+/// The shown positions of the last `Timeline` built.
+struct TimelineCache {
+    room_id: String,
+    key: TimelineKey,
+    shown: Rc<[usize]>,
+}
+
+/// Messages by room ID. Mutable access goes through these methods so that
+/// each one moves the room to a new generation.
+#[derive(Default)]
+pub struct RoomMessages {
+    rooms: HashMap<String, Vec<MessageItem>>,
+    generations: HashMap<String, u64>,
+    next_generation: u64,
+}
+
+impl RoomMessages {
+    fn get(&self, room_id: &str) -> Option<&Vec<MessageItem>> {
+        self.rooms.get(room_id)
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &String> {
+        self.rooms.keys()
+    }
+
+    fn values(&self) -> impl Iterator<Item = &Vec<MessageItem>> {
+        self.rooms.values()
+    }
+
+    fn generation(&self, room_id: &str) -> u64 {
+        self.generations.get(room_id).copied().unwrap_or(0)
+    }
+
+    fn get_mut(&mut self, room_id: &str) -> Option<&mut Vec<MessageItem>> {
+        if !self.rooms.contains_key(room_id) {
+            return None;
+        }
+        self.touch(room_id);
+        self.rooms.get_mut(room_id)
+    }
+
+    fn get_or_default(&mut self, room_id: &str) -> &mut Vec<MessageItem> {
+        self.touch(room_id);
+        self.rooms.entry(room_id.to_string()).or_default()
+    }
+
+    pub fn insert(&mut self, room_id: String, items: Vec<MessageItem>) {
+        self.touch(&room_id);
+        self.rooms.insert(room_id, items);
+    }
+
+    fn remove(&mut self, room_id: &str) -> Option<Vec<MessageItem>> {
+        self.touch(room_id);
+        self.rooms.remove(room_id)
+    }
+
+    fn touch(&mut self, room_id: &str) {
+        self.next_generation += 1;
+        self.generations
+            .insert(room_id.to_string(), self.next_generation);
+    }
+}
+
+pub struct App {
+    pub rooms: Vec<RoomInfo>,
+    selected: usize,
+    pub messages_by_room: RoomMessages,
+    last_date_by_room: HashMap<String, String>,
+    seen_event_ids: HashMap<String, HashSet<String>>,
+    reply_index: HashMap<String, HashMap<String, ReplyPreview>>,
+    /// Reactions by the event they annotate.
+    reactions: HashMap<String, Vec<StoredReaction>>,
+    /// Annotated event of each reaction, for redactions.
+    reaction_targets: HashMap<String, String>,
+    /// Responses by the poll they answer.
+    poll_votes: HashMap<String, Vec<StoredVote>>,
+    /// Replied-to events asked from the server, so each is fetched once.
+    reply_fetches: HashSet<String>,
+    /// Name colors for other senders; empty means `SENDER_COLORS`.
+    sender_colors: Vec<Color>,
+    link_previews: bool,
+    /// Page titles by URL; `None` once a preview came back empty.
+    link_titles: HashMap<String, Option<String>>,
+    link_fetches: HashSet<String>,
+    /// URLs of drawn messages without a title yet.
+    pending_link_fetches: Vec<String>,
+    /// Missing reply quotes noticed while drawing, to fetch next.
+    pending_reply_fetches: Vec<(String, String)>,
+    previews: Option<Previews>,
+    /// Thumbnails laid out in the last frame (path and top-left cell), and
+    /// the ones currently on screen.
+    preview_placements: Vec<(String, u16, u16)>,
+    drawn_previews: Vec<(String, u16, u16)>,
+    read_receipts: HashMap<String, HashSet<String>>,
+    /// Users typing per room, and when that was last reported.
+    typing: HashMap<String, (Vec<String>, Instant)>,
+    /// Own message in the open DM the other party has read up to, marked
+    /// with a `✓ read` line.
+    read_marker: Option<String>,
+    /// `── new messages ──` line in the open room.
+    unread_divider: Option<UnreadDivider>,
+    timeline_cache: RefCell<Option<TimelineCache>>,
+    last_message_ts: HashMap<String, i64>,
+    last_seen_ts: HashMap<String, i64>,
+    pub unread_counts: HashMap<String, usize>,
+    /// Unread messages that mention us or arrived in a DM.
+    highlight_counts: HashMap<String, usize>,
+    hotlist: bool,
+    room_sort: RoomSort,
+    room_jump_modifier: JumpModifier,
+    room_numbers: bool,
+    private_read_receipts: bool,
+    compact_layout: bool,
+    hide_state_events: bool,
+    bandwidth_saver: bool,
+    pinned_rooms: Vec<String>,
+    speaker: Option<Speaker>,
+    long_message_lines: usize,
+    paster: Option<Paster>,
+    /// Room IDs or aliases announced through `speaker`.
+    tts_rooms: HashSet<String>,
+    /// Room IDs or aliases in alert mode.
+    alert_rooms: HashSet<String>,
+    alert_ack_template: Option<String>,
+    workspaces: BTreeMap<String, Workspace>,
+    workspace: Option<String>,
+    /// Rooms the active workspace leaves out of `rooms`.
+    hidden_rooms: Vec<RoomInfo>,
+    message_selected: Option<usize>,
+    /// Messages below the bottom of the viewport; 0 follows the newest.
+    message_scroll: usize,
+    /// Messages that fit the viewport as last drawn, for paging.
+    message_page: usize,
+    /// Room the scroll position belongs to.
+    scroll_room: Option<String>,
+    input: String,
+    input_cursor: usize,
+    input_multiline: bool,
+    prompt: Option<PromptState>,
+    verification_emojis: Option<Vec<(String, String)>>,
+    verification_status: Option<String>,
+    verification_until: Option<Instant>,
+    status: Option<String>,
+    status_until: Option<Instant>,
+    server_notices: Vec<ServerNotice>,
+    state_view: Option<StateView>,
+    search_index: SearchIndex,
+    search_view: Option<SearchView>,
+    /// Messages queued with `/schedule`, ordered by send time.
+    scheduled: Vec<ScheduledMessage>,
+    scheduled_view: Option<ScheduledView>,
+    scheduled_changed: bool,
+    /// Whether this instance sends the queue. An attached UI mirrors the
+    /// background instance's queue and changes it over the socket instead.
+    owns_schedule: bool,
+    reply_chain: Option<ReplyChainView>,
+    message_info: Option<MessageInfoView>,
+    completion: Option<Completion>,
+    room_members: HashMap<String, Vec<MemberInfo>>,
+    mentions: Vec<Mention>,
+    startup_commands: Vec<String>,
+    plain_text_rooms: HashSet<String>,
+    /// Thread roots whose messages neither notify nor count as unread.
+    muted_threads: HashSet<String>,
+    /// Thread root of each threaded message seen, by event ID.
+    thread_roots: HashMap<String, String>,
+    /// Bumped whenever `thread_roots` changes.
+    thread_generation: u64,
+    /// Number of replies seen per thread root.
+    thread_counts: HashMap<String, usize>,
+    recent_aliases: VecDeque<String>,
+    sender_format: SenderFormat,
+    room_senders: HashMap<String, HashSet<String>>,
+    input_history: HashMap<String, VecDeque<String>>,
+    history_cursor: Option<HistoryCursor>,
+    editing: Option<EditTarget>,
+    replying: Option<ReplyTarget>,
+    server_info: Option<ServerInfo>,
+    drafts: HashMap<String, String>,
+    draft_room: Option<String>,
+    /// Room the input is locked to with Ctrl+L while browsing others.
+    compose_lock: Option<String>,
+    pending_leave: Option<PendingLeave>,
+    /// The open room and since when, for the read receipt dwell time.
+    receipt_focus: Option<(String, Instant)>,
+    /// The last read receipt sent per room: event ID and when.
+    receipts_sent: HashMap<String, (String, Instant)>,
+    /// When the last sync response arrived, and whether a sync failed
+    /// since.
+    last_sync: Option<Instant>,
+    sync_failing: bool,
+    /// Long-poll timeout of the running sync.
+    sync_timeout: Duration,
+    /// Round trip of the latest message or receipt send, in ms.
+    round_trip_ms: Option<u64>,
+    clipboard: ClipboardProvider,
+    spell: Option<SpellChecker>,
+    /// Text rows the input area grows to before scrolling.
+    input_max_lines: u16,
+    /// Misspellings in `spell_checked`, the input as last checked.
+    misspellings: Vec<Misspelling>,
+    spell_checked: String,
+    /// Input being checked on the spell checker's worker thread.
+    spell_running: Option<String>,
+    /// Ctrl+S was pressed before the running check finished.
+    spell_cycle_pending: bool,
+    /// Where checks report back, as `MatrixEvent::Spelling`.
+    spell_events: Option<mpsc::WeakUnboundedSender<MatrixEvent>>,
+    spell_cycle: Option<SpellCycle>,
+    nicknames: HashMap<String, String>,
+    room_nicknames: HashMap<String, HashMap<String, String>>,
+    snippets: HashMap<String, String>,
+    bridge_commands: HashMap<String, String>,
+    templates: HashMap<String, String>,
+    room_templates: HashMap<String, HashMap<String, String>>,
+    help_open: bool,
+    help_scroll: u16,
+    help_filter: String,
+    read_only: bool,
+    auto_away: Option<Duration>,
+    last_activity: Instant,
+    is_away: bool,
+    is_syncing: bool,
+    notifications_ready: bool,
+    own_user_id: Option<String>,
+    should_quit: bool,
+}
+
+impl Default for App {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl App {
+    pub fn new() -> Self {
+        Self {
+            rooms: Vec::new(),
+            selected: 0,
+            messages_by_room: RoomMessages::default(),
+            last_date_by_room: HashMap::new(),
+            seen_event_ids: HashMap::new(),
+            reply_index: HashMap::new(),
+            reactions: HashMap::new(),
+            reaction_targets: HashMap::new(),
+            poll_votes: HashMap::new(),
+            reply_fetches: HashSet::new(),
+            sender_colors: Vec::new(),
+            link_previews: false,
+            link_titles: HashMap::new(),
+            link_fetches: HashSet::new(),
+            pending_link_fetches: Vec::new(),
+            pending_reply_fetches: Vec::new(),
+            previews: None,
+            preview_placements: Vec::new(),
+            drawn_previews: Vec::new(),
+            read_receipts: HashMap::new(),
+            typing: HashMap::new(),
+            read_marker: None,
+            unread_divider: None,
+            timeline_cache: RefCell::new(None),
+            last_message_ts: HashMap::new(),
+            last_seen_ts: HashMap::new(),
+            unread_counts: HashMap::new(),
+            highlight_counts: HashMap::new(),
+            hotlist: false,
+            room_sort: RoomSort::default(),
+            room_jump_modifier: JumpModifier::default(),
+            room_numbers: false,
+            private_read_receipts: false,
+            compact_layout: false,
+            hide_state_events: false,
+            bandwidth_saver: false,
+            pinned_rooms: Vec::new(),
+            speaker: None,
+            long_message_lines: DEFAULT_LONG_MESSAGE_LINES,
+            paster: None,
+            tts_rooms: HashSet::new(),
+            alert_rooms: HashSet::new(),
+            alert_ack_template: None,
+            workspaces: BTreeMap::new(),
+            workspace: None,
+            hidden_rooms: Vec::new(),
+            message_selected: None,
+            message_scroll: 0,
+            message_page: 1,
+            scroll_room: None,
+            input: String::new(),
+            input_cursor: 0,
+            input_multiline: false,
+            prompt: None,
+            verification_emojis: None,
+            verification_status: None,
+            verification_until: None,
+            status: None,
+            status_until: None,
+            server_notices: Vec::new(),
+            state_view: None,
+            search_index: SearchIndex::default(),
+            search_view: None,
+            scheduled: Vec::new(),
+            scheduled_view: None,
+            scheduled_changed: false,
+            owns_schedule: true,
+            reply_chain: None,
+            message_info: None,
+            completion: None,
+            room_members: HashMap::new(),
+            mentions: Vec::new(),
+            startup_commands: Vec::new(),
+            plain_text_rooms: HashSet::new(),
+            muted_threads: HashSet::new(),
+            thread_roots: HashMap::new(),
+            thread_generation: 0,
+            thread_counts: HashMap::new(),
+            recent_aliases: VecDeque::new(),
+            sender_format: SenderFormat::default(),
+            room_senders: HashMap::new(),
+            input_history: HashMap::new(),
+            history_cursor: None,
+            editing: None,
+            replying: None,
+            server_info: None,
+            drafts: HashMap::new(),
+            draft_room: None,
+            compose_lock: None,
+            pending_leave: None,
+            receipt_focus: None,
+            receipts_sent: HashMap::new(),
+            last_sync: None,
+            sync_failing: false,
+            sync_timeout: Duration::from_secs(30),
+            round_trip_ms: None,
+            clipboard: ClipboardProvider::default(),
+            spell: None,
+            input_max_lines: DEFAULT_INPUT_MAX_LINES,
+            misspellings: Vec::new(),
+            spell_checked: String::new(),
+            spell_running: None,
+            spell_cycle_pending: false,
+            spell_events: None,
+            spell_cycle: None,
+            nicknames: HashMap::new(),
+            snippets: HashMap::new(),
+            room_nicknames: HashMap::new(),
+            bridge_commands: HashMap::new(),
+            templates: HashMap::new(),
+            room_templates: HashMap::new(),
+            help_open: false,
+            help_scroll: 0,
+            help_filter: String::new(),
+            read_only: false,
+            auto_away: None,
+            last_activity: Instant::now(),
+            is_away: false,
+            is_syncing: true,
+            notifications_ready: false,
+            own_user_id: None,
+            should_quit: false,
+        }
+    }
+
+    fn on_up(&mut self) {
+        if self.selected > 0 {
+            self.selected -= 1;
+            self.message_selected = None;
+            if let Some(room_id) = self.rooms.get(self.selected).map(|room| room.room_id.clone()) {
+                self.mark_room_read(&room_id);
+            }
+        }
+    }
+
+    fn on_down(&mut self) {
+        if self.selected + 1 < self.rooms.len() {
+            self.selected += 1;
+            self.message_selected = None;
+            if let Some(room_id) = self.rooms.get(self.selected).map(|room| room.room_id.clone()) {
+                self.mark_room_read(&room_id);
+            }
+        }
+    }
+
+    fn input_len_chars(&self) -> usize {
+        self.input.chars().count()
+    }
+
+    fn cursor_to_byte(input: &str, cursor: usize) -> usize {
+        input
+            .char_indices()
+            .nth(cursor)
+            .map(|(idx, _)| idx)
+            .unwrap_or_else(|| input.len())
+    }
+
+    /// Char index of the grapheme cluster boundary before `cursor`.
+    fn prev_grapheme(input: &str, cursor: usize) -> usize {
+        let byte = Self::cursor_to_byte(input, cursor);
+        let start = input[..byte]
+            .grapheme_indices(true)
+            .next_back()
+            .map_or(0, |(idx, _)| idx);
+        input[..start].chars().count()
+    }
+
+    /// Char index of the grapheme cluster boundary after `cursor`.
+    fn next_grapheme(input: &str, cursor: usize) -> usize {
+        let byte = Self::cursor_to_byte(input, cursor);
+        input[byte..]
+            .graphemes(true)
+            .next()
+            .map_or(cursor, |grapheme| cursor + grapheme.chars().count())
+    }
+
+    fn input_move_left(&mut self) {
+        self.input_cursor = Self::prev_grapheme(&self.input, self.input_cursor);
+    }
+
+    fn input_move_right(&mut self) {
+        self.input_cursor = Self::next_grapheme(&self.input, self.input_cursor);
+    }
+
+    fn input_move_word_left(&mut self) {
+        if self.input_cursor == 0 {
+            return;
+        }
+        let chars: Vec<char> = self.input.chars().collect();
+        let mut idx = self.input_cursor;
+        while idx > 0 && chars[idx - 1].is_whitespace() {
+            idx -= 1;
+        }
+        while idx > 0 && !chars[idx - 1].is_whitespace() {
+            idx -= 1;
+        }
+        self.input_cursor = idx;
+    }
+
+    fn input_move_word_right(&mut self) {
+        let chars: Vec<char> = self.input.chars().collect();
+        let len = chars.len();
+        let mut idx = self.input_cursor;
+        while idx < len && chars[idx].is_whitespace() {
+            idx += 1;
+        }
+        while idx < len && !chars[idx].is_whitespace() {
+            idx += 1;
+        }
+        self.input_cursor = idx;
+    }
+
+    /// Moves to the start of the current line (multi-line input keeps its lines).
+    fn input_move_home(&mut self) {
+        let chars: Vec<char> = self.input.chars().collect();
+        let mut idx = self.input_cursor.min(chars.len());
+        while idx > 0 && chars[idx - 1] != '\n' {
+            idx -= 1;
+        }
+        self.input_cursor = idx;
+    }
+
+    fn input_move_end(&mut self) {
+        let chars: Vec<char> = self.input.chars().collect();
+        let mut idx = self.input_cursor;
+        while idx < chars.len() && chars[idx] != '\n' {
+            idx += 1;
+        }
+        self.input_cursor = idx;
+    }
+
+    fn input_insert_char(&mut self, c: char) {
+        let idx = Self::cursor_to_byte(&self.input, self.input_cursor);
+        self.input.insert(idx, c);
+        self.input_cursor += 1;
+        if self.read_only {
+            self.message_selected = None;
+        }
+    }
+
+    fn input_backspace(&mut self) {
+        if self.input_cursor == 0 {
+            return;
+        }
+        let prev = Self::prev_grapheme(&self.input, self.input_cursor);
+        let end = Self::cursor_to_byte(&self.input, self.input_cursor);
+        let start = Self::cursor_to_byte(&self.input, prev);
+        self.input.replace_range(start..end, "");
+        self.input_cursor = prev;
+        if self.read_only {
+            self.message_selected = None;
+        }
+    }
+
+    fn input_delete(&mut self) {
+        if self.input_cursor >= self.input_len_chars() {
+            return;
+        }
+        let next = Self::next_grapheme(&self.input, self.input_cursor);
+        let start = Self::cursor_to_byte(&self.input, self.input_cursor);
+        let end = Self::cursor_to_byte(&self.input, next);
+        self.input.replace_range(start..end, "");
+        if self.read_only {
+            self.message_selected = None;
+        }
+    }
+
+    fn on_enter(&mut self) -> Option<String> {
+        if !self.input.trim().is_empty() {
+            let text = self.input.trim_end().to_string();
+            self.input.clear();
+            self.input_cursor = 0;
+            return Some(text);
+        }
+        None
+    }
+
+    fn toggle_help(&mut self) {
+        self.help_open = !self.help_open;
+        if self.help_open {
+            self.help_scroll = 0;
+            self.help_filter.clear();
+        }
+    }
+
+    /// Help lines matching the filter, under their category headings. A
+    /// line matches when any filter word starts one of its words; matching
+    /// a heading keeps its whole category.
+    fn help_lines(&self) -> Vec<&'static str> {
+        let query = self.help_filter.to_lowercase();
+        let mut terms: Vec<&str> = query
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|term| !term.is_empty() && !HELP_STOP_WORDS.contains(term))
+            .collect();
+        // Short words ("do", "I") only count while nothing longer is typed.
+        if terms.iter().any(|term| term.len() > 2) {
+            terms.retain(|term| term.len() > 2);
+        }
+        let all = HELP_LINES.iter().filter_map(|&line| match (line, self.room_jump_modifier) {
+            (HELP_JUMP_ALT, JumpModifier::Ctrl) => Some(HELP_JUMP_CTRL),
+            (HELP_JUMP_ALT, JumpModifier::Off) => None,
+            _ => Some(line),
+        });
+        if terms.is_empty() {
+            return all.collect();
+        }
+        let matches = |line: &str| {
+            let line = line.to_lowercase();
+            let mut words = line.split(|c: char| !c.is_alphanumeric());
+            words.any(|word| terms.iter().any(|term| word.starts_with(term)))
+        };
+        let mut lines = Vec::new();
+        let mut heading: Option<&'static str> = None;
+        let mut heading_matched = false;
+        for line in all {
+            if !line.starts_with(' ') {
+                heading = Some(line);
+                heading_matched = matches(line);
+                if heading_matched {
+                    lines.push(line);
+                }
+                continue;
+            }
+            if heading_matched {
+                lines.push(line);
+            } else if matches(line) {
+                if let Some(heading) = heading.take() {
+                    lines.push(heading);
+                }
+                lines.push(line);
+            }
+        }
+        lines
+    }
+
+    fn help_filter_push(&mut self, c: char) {
+        self.help_filter.push(c);
+        self.help_scroll = 0;
+    }
+
+    fn help_filter_pop(&mut self) {
+        self.help_filter.pop();
+        self.help_scroll = 0;
+    }
+
+    /// Esc in the help panel: clears the filter first, then closes.
+    fn help_escape(&mut self) {
+        if self.help_filter.is_empty() {
+            self.help_open = false;
+        } else {
+            self.help_filter.clear();
+            self.help_scroll = 0;
+        }
+    }
+
+    fn start_add_prompt(&mut self) {
+        self.prompt = Some(PromptState {
+            mode: PromptMode::Add,
+            input: String::new(),
+            suggestions: Vec::new(),
+            suggestion: 0,
+            error: None,
+        });
+    }
+
+    fn start_delete_prompt(&mut self) {
+        if let Some(room) = self.rooms.get(self.selected) {
+            let members = match room.member_count {
+                1 => "1 member".to_string(),
+                count => format!("{} members", count),
+            };
+            let now = Local::now().timestamp_millis();
+            let activity = match self.last_message_ts.get(&room.room_id) {
+                Some(ts) if *ts > 0 => format!("last message {}", format_age(*ts, now)),
+                _ => "no messages stored".to_string(),
+            };
+            self.prompt = Some(PromptState {
+                mode: PromptMode::Delete {
+                    room_id: room.room_id.clone(),
+                    room_name: room.name.clone(),
+                    details: format!("{}, {}", members, activity),
+                },
+                input: String::new(),
+                suggestions: Vec::new(),
+                suggestion: 0,
+                error: None,
+            });
+        }
+    }
+
+    /// Asks before deleting the selected message: our own, or anyone's
+    /// when our power level allows redacting others.
+    fn start_redact_prompt(&mut self) {
+        let Some(room_id) = self.selected_room_id().map(str::to_string) else {
+            return;
+        };
+        let can_redact = |sender_id: &str| self.can_redact(sender_id);
+        let event_id = match self.selected_message() {
+            Some(MessageItem::Message {
+                sender_id,
+                event_id: Some(event_id),
+                text,
+                ..
+            }) if can_redact(sender_id) && text != REDACTED_TEXT => event_id.clone(),
+            Some(
+                MessageItem::Attachment {
+                    sender_id,
+                    event_id: Some(event_id),
+                    ..
+                }
+                | MessageItem::Poll {
+                    sender_id,
+                    event_id: Some(event_id),
+                    ..
+                },
+            ) if can_redact(sender_id) => event_id.clone(),
+            _ => {
+                self.set_status("Select one of your own messages to delete");
+                return;
+            }
+        };
+        self.prompt = Some(PromptState {
+            mode: PromptMode::Redact { room_id, event_id },
+            input: String::new(),
+            suggestions: Vec::new(),
+            suggestion: 0,
+            error: None,
+        });
+    }
+
+    fn can_redact(&self, sender_id: &str) -> bool {
+        self.own_user_id.as_deref() == Some(sender_id)
+            || self
+                .selected_room()
+                .is_some_and(|room| room.can_redact_others)
+    }
+
+    /// Opens the reaction picker for the selected message.
+    fn start_react_prompt(&mut self) {
+        let Some(room_id) = self.selected_room_id().map(str::to_string) else {
+            return;
+        };
+        let Some(event_id) = self.selected_message_event_id() else {
+            self.set_status("Select a message to react to");
+            return;
+        };
+        self.prompt = Some(PromptState {
+            mode: PromptMode::React { room_id, event_id },
+            input: String::new(),
+            suggestions: Vec::new(),
+            suggestion: 0,
+            error: None,
+        });
+        self.update_prompt_suggestions();
+    }
+
+    /// Asks for the export file passphrase; `export` needs a joined room.
+    fn start_key_prompt(&mut self, export: bool, path: PathBuf) {
+        let room_id = if export {
+            let Some(room_id) = self.selected_room_id().map(str::to_string) else {
+                self.set_status("No room selected");
+                return;
+            };
+            if self.selected_room_is_invited() {
+                self.set_status("Join the room before exporting its keys");
+                return;
+            }
+            Some(room_id)
+        } else {
+            None
+        };
+        self.prompt = Some(PromptState {
+            mode: PromptMode::KeyPassphrase { room_id, path },
+            input: String::new(),
+            suggestions: Vec::new(),
+            suggestion: 0,
+            error: None,
+        });
+    }
+
+    /// `/template <name>`: fills in the date and room, asks for each
+    /// `{?Field}`, then leaves the message in the input to review and send.
+    fn start_template(&mut self, name: &str) {
+        let Some(room_id) = self.compose_room_id() else {
+            self.set_status("No room selected");
+            return;
+        };
+        let room_templates = self.room_templates.get(&room_id);
+        if name.is_empty() {
+            let mut names: Vec<&str> = self
+                .templates
+                .keys()
+                .chain(room_templates.into_iter().flat_map(|t| t.keys()))
+                .map(String::as_str)
+                .collect();
+            names.sort_unstable();
+            names.dedup();
+            self.set_status(&if names.is_empty() {
+                "No templates configured".to_string()
+            } else {
+                format!("Templates: {}", names.join(", "))
+            });
+            return;
+        }
+        let Some(template) = room_templates
+            .and_then(|templates| templates.get(name))
+            .or_else(|| self.templates.get(name))
+        else {
+            self.set_status(&format!("No template named {}", name));
+            return;
+        };
+        let now = Local::now();
+        let text = template
+            .replace("{date}", &now.format("%Y-%m-%d").to_string())
+            .replace("{weekday}", &now.format("%A").to_string())
+            .replace("{time}", &now.format("%H:%M").to_string())
+            .replace("{room}", &self.room_name(&room_id));
+        let mut fields: Vec<String> = Vec::new();
+        let mut rest = text.as_str();
+        while let Some(start) = rest.find("{?") {
+            let Some(len) = rest[start..].find('}') else {
+                break;
+            };
+            let field = &rest[start + 2..start + len];
+            if !fields.iter().any(|known| known == field) {
+                fields.push(field.to_string());
+            }
+            rest = &rest[start + len + 1..];
+        }
+        if fields.is_empty() {
+            self.restore_input(text);
+            return;
+        }
+        self.prompt = Some(PromptState {
+            mode: PromptMode::TemplateField {
+                name: name.to_string(),
+                text,
+                fields,
+                values: Vec::new(),
+            },
+            input: String::new(),
+            suggestions: Vec::new(),
+            suggestion: 0,
+            error: None,
+        });
+    }
+
+    fn cancel_prompt(&mut self) {
+        // A held-back long message goes back into the input for editing.
+        if let Some(PromptMode::LongMessage { text, .. }) = self.prompt.take().map(|p| p.mode) {
+            self.restore_input(text);
+        }
+    }
+
+    /// Asks how to send `text` if it has more than `long_message_lines`
+    /// lines; returns false when it can be sent as is.
+    fn hold_long_message(&mut self, room_id: &str, text: &str) -> bool {
+        if self.long_message_lines == 0 || text.lines().count() <= self.long_message_lines {
+            return false;
+        }
+        self.prompt = Some(PromptState {
+            mode: PromptMode::LongMessage {
+                room_id: room_id.to_string(),
+                text: text.to_string(),
+            },
+            input: String::new(),
+            suggestions: Vec::new(),
+            suggestion: 0,
+            error: None,
+        });
+        true
+    }
+
+    /// `f` sends a held-back long message as a text file, `p` as a paste
+    /// link, `s` as is.
+    fn send_long_message(
+        &mut self,
+        room_id: String,
+        text: String,
+        choice: &str,
+    ) -> Option<MatrixCommand> {
+        let reply_to = self.take_reply_to(&room_id);
+        self.message_selected = None;
+        match choice {
+            "f" => match write_paste_file(&text) {
+                Ok(path) => Some(MatrixCommand::SendAttachment {
+                    room_id,
+                    path: path.to_string_lossy().into_owned(),
+                    reply_to,
+                    temporary: true,
+                }),
+                Err(err) => {
+                    self.set_status(&format!("Failed to write paste file: {}", err));
+                    self.restore_input(text);
+                    None
+                }
+            },
+            "p" => {
+                self.paster.as_ref()?.upload(room_id, reply_to, text);
+                self.set_status("Uploading paste...");
+                None
+            }
+            _ => {
+                let (body, mentions) = self.format_outgoing(&room_id, unescape_slash(text));
+                Some(MatrixCommand::SendMessage {
+                    room_id,
+                    body,
+                    reply_to,
+                    mentions,
+                })
+            }
+        }
+    }
+
+    /// Link messages for finished paste uploads. A failed upload puts the
+    /// text back into the input.
+    fn take_finished_pastes(&mut self) -> Vec<MatrixCommand> {
+        let mut sends = Vec::new();
+        while let Some(paste) = self.paster.as_ref().and_then(Paster::finished) {
+            match paste.result {
+                Ok(link) => sends.push(MatrixCommand::SendMessage {
+                    room_id: paste.room_id,
+                    body: link,
+                    reply_to: paste.reply_to,
+                    mentions: Vec::new(),
+                }),
+                Err(err) => {
+                    self.set_status(&format!("Paste failed: {}", err));
+                    if self.input.is_empty() {
+                        self.restore_input(paste.text);
+                    }
+                }
+            }
+        }
+        sends
+    }
+
+    /// Bracketed paste: inserted as typed, except that newlines switch the
+    /// input to multi-line instead of sending. Prompts take a single line.
+    fn on_paste(&mut self, text: &str) {
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        if let Some(state) = self.prompt.as_mut() {
+            state.input.push_str(&text.replace('\n', " "));
+            self.update_prompt_suggestions();
+            return;
+        }
+        if self.input_blocked(&text) {
+            self.set_status("You can't post in this room");
+            return;
+        }
+        if text.contains('\n') {
+            self.input_multiline = true;
+        }
+        let idx = Self::cursor_to_byte(&self.input, self.input_cursor);
+        self.input.insert_str(idx, &text);
+        self.input_cursor += text.chars().count();
+        if self.read_only {
+            self.message_selected = None;
+        }
+    }
+
+    fn prompt_backspace(&mut self) {
+        if let Some(state) = self.prompt.as_mut() {
+            state.input.pop();
+        }
+        self.update_prompt_suggestions();
+    }
+
+    fn prompt_push(&mut self, c: char) {
+        if let Some(state) = self.prompt.as_mut() {
+            state.input.push(c);
+        }
+        self.update_prompt_suggestions();
+    }
+
+    /// Matches the add-chat input against joined rooms (name, alias or ID)
+    /// and recently seen aliases, or the reaction input against shortcodes.
+    fn update_prompt_suggestions(&mut self) {
+        const MAX_SUGGESTIONS: usize = 6;
+        let Some(state) = self.prompt.as_ref() else {
+            return;
+        };
+        if let PromptMode::React { .. } = state.mode {
+            let code = state.input.trim().trim_matches(':');
+            let matches: Vec<(&str, &str)> = if code.is_empty() {
+                QUICK_REACTIONS.to_vec()
+            } else if code.chars().all(emoji::is_shortcode_char) {
+                emoji::search(code, QUICK_REACTIONS.len())
+            } else {
+                Vec::new()
+            };
+            if let Some(state) = self.prompt.as_mut() {
+                state.suggestions = matches
+                    .into_iter()
+                    .map(|(name, emoji)| PromptSuggestion {
+                        label: format!("{}  :{}:", emoji, name),
+                        value: emoji.to_string(),
+                    })
+                    .collect();
+                state.suggestion = 0;
+                state.error = None;
+            }
+            return;
+        }
+        if !matches!(state.mode, PromptMode::Add) {
+            return;
+        }
+        let query = state.input.trim().to_lowercase();
+        let mut suggestions = Vec::new();
+        if !query.is_empty() && !query.starts_with('@') {
+            let joined = self
+                .rooms
+                .iter()
+                .filter(|room| room.state == RoomListState::Joined);
+            for room in joined {
+                let value = room.alias.clone().unwrap_or_else(|| room.room_id.clone());
+                let matched = [Some(&room.name), room.alias.as_ref(), Some(&room.room_id)]
+                    .into_iter()
+                    .flatten()
+                    .any(|field| field.to_lowercase().contains(&query));
+                if matched {
+                    suggestions.push(PromptSuggestion {
+                        label: format!("{}  {} (joined)", room.name, value),
+                        value,
+                    });
+                }
+            }
+            let known: HashSet<&str> = self.rooms.iter().filter_map(|r| r.alias.as_deref()).collect();
+            for alias in self.recent_aliases.iter().rev() {
+                if !known.contains(alias.as_str()) && alias.to_lowercase().contains(&query) {
+                    suggestions.push(PromptSuggestion {
+                        label: alias.clone(),
+                        value: alias.clone(),
+                    });
+                }
+            }
+        }
+        suggestions.truncate(MAX_SUGGESTIONS);
+        if let Some(state) = self.prompt.as_mut() {
+            state.suggestions = suggestions;
+            state.suggestion = 0;
+            state.error = None;
+        }
+    }
+
+    fn move_prompt_suggestion(&mut self, delta: isize) {
+        if let Some(state) = self.prompt.as_mut() {
+            let len = state.suggestions.len() as isize;
+            if len > 0 {
+                state.suggestion = (state.suggestion as isize + delta).rem_euclid(len) as usize;
+            }
+        }
+    }
+
+    fn accept_prompt_suggestion(&mut self) {
+        if let Some(state) = self.prompt.as_mut() {
+            if let Some(suggestion) = state.suggestions.get(state.suggestion) {
+                state.input = suggestion.value.clone();
+                state.suggestions.clear();
+            }
+        }
+    }
+
+    /// Remembers `#alias:server` mentions from message bodies for the
+    /// add-chat prompt.
+    fn note_aliases(&mut self, body: &str) {
+        const MAX_RECENT_ALIASES: usize = 50;
+        for word in body.split_whitespace() {
+            let word = word.trim_end_matches(['.', ',', ')', '!', '?']);
+            if !word.starts_with('#') || matrix_sdk::ruma::RoomAliasId::parse(word).is_err() {
+                continue;
+            }
+            self.recent_aliases.retain(|alias| alias != word);
+            self.recent_aliases.push_back(word.to_string());
+            if self.recent_aliases.len() > MAX_RECENT_ALIASES {
+                self.recent_aliases.pop_front();
+            }
+        }
+    }
+
+    fn active_workspace(&self) -> Option<&Workspace> {
+        self.workspace.as_ref().and_then(|name| self.workspaces.get(name))
+    }
+
+    pub fn channels_width(&self) -> u16 {
+        self.active_workspace()
+            .and_then(|workspace| workspace.channels_width)
+            .unwrap_or(28)
+            .max(8)
+    }
+
+    /// Splits the rooms into listed and hidden ones for the active
+    /// workspace and sorts the listed ones, keeping the selection on the
+    /// same room while it stays listed. Invites are always listed.
+    fn apply_workspace(&mut self) {
+        let selected = self.selected_room_id().map(str::to_string);
+        let mut rooms = std::mem::take(&mut self.rooms);
+        rooms.append(&mut self.hidden_rooms);
+        let workspace = self.active_workspace();
+        let sort = self.list_sort();
+        let (mut shown, hidden): (Vec<_>, Vec<_>) = rooms.into_iter().partition(|room| {
+            let Some(workspace) = workspace else {
+                return true;
+            };
+            let listed = workspace.rooms.is_empty()
+                || workspace
+                    .rooms
+                    .iter()
+                    .any(|id| *id == room.room_id || room.alias.as_ref() == Some(id));
+            let matches = workspace.filter.as_ref().is_none_or(|filter| {
+                room.name.to_lowercase().contains(&filter.to_lowercase())
+            });
+            room.state == RoomListState::Invited || (listed && matches)
+        });
+        match sort {
+            RoomSort::Default => {}
+            RoomSort::Name => shown.sort_by_cached_key(|room| room.name.to_lowercase()),
+            RoomSort::Activity => shown.sort_by_key(|room| {
+                Reverse(
+                    self.last_message_ts
+                        .get(&room.room_id)
+                        .copied()
+                        .unwrap_or_default(),
+                )
+            }),
+        }
+        // Pinned rooms go first in their own order, whatever the sort.
+        shown.sort_by_key(|room| {
+            let pin = self.pinned_rooms.iter().position(|id| *id == room.room_id);
+            (
+                room.state == RoomListState::Invited,
+                pin.unwrap_or(usize::MAX),
+            )
+        });
+        self.rooms = shown;
+        self.hidden_rooms = hidden;
+        match selected.and_then(|id| self.rooms.iter().position(|room| room.room_id == id)) {
+            Some(idx) => self.selected = idx,
+            None => {
+                self.selected = 0;
+                self.message_selected = None;
+                if let Some(room_id) = self.selected_room_id().map(str::to_string) {
+                    self.mark_room_read(&room_id);
+                }
+            }
+        }
+    }
+
+    fn list_sort(&self) -> RoomSort {
+        self.active_workspace()
+            .and_then(|workspace| workspace.sort)
+            .unwrap_or(self.room_sort)
+    }
+
+    /// Moves a room a message just arrived in up the list when sorting by
+    /// activity.
+    fn note_activity(&mut self) {
+        if self.list_sort() == RoomSort::Activity {
+            self.apply_workspace();
+        }
+    }
+
+    fn is_pinned(&self, room_id: &str) -> bool {
+        self.pinned_rooms.iter().any(|id| id == room_id)
+    }
+
+    /// Pins the selected room below the other pinned ones, or unpins it.
+    fn toggle_pin(&mut self) {
+        let Some(room_id) = self.selected_room_id().map(str::to_string) else {
+            self.set_status("No room selected");
+            return;
+        };
+        let pinned = !self.is_pinned(&room_id);
+        if pinned {
+            self.pinned_rooms.push(room_id);
+        } else {
+            self.pinned_rooms.retain(|id| *id != room_id);
+        }
+        self.apply_workspace();
+        self.save_pins(if pinned { "Pinned" } else { "Unpinned" });
+    }
+
+    /// Moves the selected pinned room `delta` places among the pinned ones.
+    fn move_pin(&mut self, delta: isize) {
+        let Some(room_id) = self.selected_room_id() else {
+            return;
+        };
+        let Some(idx) = self.pinned_rooms.iter().position(|id| *id == room_id) else {
+            self.set_status("Only pinned rooms can be moved (Alt+P pins)");
+            return;
+        };
+        // Pins of rooms not in the list (hidden by the workspace or left)
+        // are stepped over so every keypress visibly moves the room.
+        let listed = |id: &String| self.rooms.iter().any(|room| room.room_id == *id);
+        let target = if delta < 0 {
+            self.pinned_rooms[..idx].iter().rposition(listed)
+        } else {
+            self.pinned_rooms[idx + 1..]
+                .iter()
+                .position(listed)
+                .map(|pos| idx + 1 + pos)
+        };
+        let Some(target) = target else {
+            return;
+        };
+        let pin = self.pinned_rooms.remove(idx);
+        self.pinned_rooms.insert(target, pin);
+        self.apply_workspace();
+        self.save_pins("Pin order changed");
+    }
+
+    fn save_pins(&mut self, label: &str) {
+        let pinned = self.pinned_rooms.clone();
+        match update_config(|cfg| cfg.pinned_rooms = pinned) {
+            Ok(()) => self.set_status(label),
+            Err(err) => self.set_status(&format!("{} (not saved: {})", label, err)),
+        }
+    }
+
+    /// Shows the workspace `name`, or every room for `None`, and saves it
+    /// as the one to start with.
+    fn switch_workspace(&mut self, name: Option<String>) {
+        if let Some(name) = &name {
+            if !self.workspaces.contains_key(name) {
+                self.set_status(&format!("No workspace named {}", name));
+                return;
+            }
+        }
+        self.workspace = name.clone();
+        self.apply_workspace();
+        let saved = update_config(|cfg| cfg.active_workspace = name.clone());
+        let label = match &name {
+            Some(name) => format!("Workspace {}", name),
+            None => "All rooms".to_string(),
+        };
+        match saved {
+            Ok(()) => self.set_status(&label),
+            Err(err) => self.set_status(&format!("{} (not saved: {})", label, err)),
+        }
+    }
+
+    /// Moves to the next workspace by name; after the last comes the full
+    /// room list.
+    fn cycle_workspace(&mut self) {
+        if self.workspaces.is_empty() {
+            self.set_status("No workspaces configured");
+            return;
+        }
+        let next = match &self.workspace {
+            Some(current) => self
+                .workspaces
+                .range::<String, _>((Bound::Excluded(current), Bound::Unbounded))
+                .next(),
+            None => self.workspaces.iter().next(),
+        };
+        self.switch_workspace(next.map(|(name, _)| name.clone()));
+    }
+
+    /// Selects the room at 1-based `index` in the channel list.
+    /// The digit 1-9 of a channel jump key under `room_jump_modifier`.
+    fn room_jump_digit(&self, key: &KeyEvent) -> Option<usize> {
+        let modifier = match self.room_jump_modifier {
+            JumpModifier::Alt => KeyModifiers::ALT,
+            JumpModifier::Ctrl => KeyModifiers::CONTROL,
+            JumpModifier::Off => return None,
+        };
+        match key.code {
+            KeyCode::Char(c @ '1'..='9') if key.modifiers.contains(modifier) => {
+                Some(c as usize - '0' as usize)
+            }
+            _ => None,
+        }
+    }
+
+    fn toggle_compact_layout(&mut self) {
+        self.compact_layout = !self.compact_layout;
+        let state = if self.compact_layout { "on" } else { "off" };
+        self.set_status(&format!("Compact layout {}", state));
+    }
+
+    fn toggle_state_events(&mut self) {
+        self.hide_state_events = !self.hide_state_events;
+        let state = if self.hide_state_events {
+            "hidden"
+        } else {
+            "shown"
+        };
+        self.set_status(&format!("Membership and room changes {}", state));
+    }
+
+    fn toggle_bandwidth_saver(&mut self) -> MatrixCommand {
+        self.bandwidth_saver = !self.bandwidth_saver;
+        let state = if self.bandwidth_saver { "on" } else { "off" };
+        self.set_status(&format!("Bandwidth saver {}", state));
+        MatrixCommand::SetBandwidthSaver {
+            enabled: self.bandwidth_saver,
+        }
+    }
+
+    fn toggle_room_numbers(&mut self) {
+        self.room_numbers = !self.room_numbers;
+        let state = if self.room_numbers { "shown" } else { "hidden" };
+        self.set_status(&format!("Channel numbers {}", state));
+    }
+
+    fn jump_to_room(&mut self, index: usize) {
+        if let Some(room_id) = index
+            .checked_sub(1)
+            .and_then(|idx| self.rooms.get(idx))
+            .map(|room| room.room_id.clone())
+        {
+            self.select_room(&room_id);
+        }
+    }
+
+    /// Whether `body` names our user ID or its localpart.
+    fn mentions_me(&self, body: &str) -> bool {
+        let Some(own) = self.own_user_id.as_deref() else {
+            return false;
+        };
+        let localpart = own.trim_start_matches('@').split(':').next().unwrap_or(own);
+        let body = body.to_lowercase();
+        let localpart = localpart.to_lowercase();
+        body.contains(&own.to_lowercase())
+            || body
+                .split(|c: char| !c.is_alphanumeric() && c != '_' && c != '-' && c != '.')
+                .any(|word| word.trim_end_matches('.') == localpart)
+    }
+
+    /// Counts an unread message, and a highlight for mentions and DMs.
+    fn bump_unread(&mut self, room_id: &str, mention: bool) {
+        let entry = self.unread_counts.entry(room_id.to_string()).or_default();
+        *entry = entry.saturating_add(1);
+        let direct = self
+            .rooms
+            .iter()
+            .any(|room| room.room_id == room_id && room.direct_user.is_some());
+        if mention || direct {
+            let entry = self.highlight_counts.entry(room_id.to_string()).or_default();
+            *entry = entry.saturating_add(1);
+        }
+    }
+
+    fn select_room(&mut self, room_id: &str) {
+        if let Some(idx) = self.rooms.iter().position(|room| room.room_id == room_id) {
+            self.selected = idx;
+            self.message_selected = None;
+            self.mark_room_read(room_id);
+        }
+    }
+
+    fn submit_prompt(&mut self) -> Option<MatrixCommand> {
+        let mut state = self.prompt.take()?;
+        if let PromptMode::TemplateField { text, fields, values, .. } = &mut state.mode {
+            values.push(state.input.trim().to_string());
+            if values.len() < fields.len() {
+                state.input.clear();
+                self.prompt = Some(state);
+                return None;
+            }
+            let mut filled = std::mem::take(text);
+            for (field, value) in fields.iter().zip(values.iter()) {
+                filled = filled.replace(&format!("{{?{}}}", field), value);
+            }
+            self.restore_input(filled);
+            return None;
+        }
+        if let PromptMode::LongMessage { room_id, text } = &state.mode {
+            let choice = state.input.trim().to_lowercase();
+            let paste = choice == "p" && self.paster.is_some();
+            if !(choice == "f" || choice == "s" || paste) {
+                state.input.clear();
+                self.prompt = Some(state);
+                return None;
+            }
+            let (room_id, text) = (room_id.clone(), text.clone());
+            return self.send_long_message(room_id, text, &choice);
+        }
+        if let PromptMode::React { room_id, event_id } = &state.mode {
+            let typed = state.input.trim();
+            let code = typed.trim_matches(':');
+            let key = emoji::lookup(code)
+                .map(str::to_string)
+                .or_else(|| {
+                    // A pasted emoji is sent as is.
+                    (!typed.is_empty() && !typed.chars().any(|c| c.is_ascii_alphanumeric()))
+                        .then(|| typed.to_string())
+                })
+                .or_else(|| {
+                    let highlighted = state.suggestions.get(state.suggestion)?;
+                    Some(highlighted.value.clone())
+                });
+            let Some(key) = key else {
+                state.error = Some(format!("unknown shortcode :{}:", code));
+                self.prompt = Some(state);
+                return None;
+            };
+            self.message_selected = None;
+            return Some(MatrixCommand::SendReaction {
+                room_id: room_id.clone(),
+                event_id: event_id.clone(),
+                key,
+            });
+        }
+        let trimmed = state.input.trim();
+        if trimmed.is_empty() {
+            self.prompt = Some(state);
+            return None;
+        }
+        match &state.mode {
+            PromptMode::Add => {
+                if trimmed.starts_with('@') {
+                    if matrix_sdk::ruma::UserId::parse(trimmed).is_err() {
+                        state.error = Some("not a valid user ID (@user:server)".to_string());
+                        self.prompt = Some(state);
+                        return None;
+                    }
+                    return Some(MatrixCommand::CreateDirect {
+                        user_id: trimmed.to_string(),
+                    });
+                }
+                if matrix_sdk::ruma::RoomOrAliasId::parse(trimmed).is_err() {
+                    state.error =
+                        Some("not a valid room alias or ID (#alias:server, !id:server)".to_string());
+                    self.prompt = Some(state);
+                    return None;
+                }
+                let joined = self
+                    .rooms
+                    .iter()
+                    .find(|room| {
+                        room.state == RoomListState::Joined
+                            && (room.room_id == trimmed || room.alias.as_deref() == Some(trimmed))
+                    })
+                    .map(|room| room.room_id.clone());
+                if let Some(room_id) = joined {
+                    self.select_room(&room_id);
+                    self.set_status("Already joined; switched to the room");
+                    return None;
+                }
+                Some(MatrixCommand::JoinRoom {
+                    room: trimmed.to_string(),
+                })
+            }
+            PromptMode::Redact { room_id, event_id } => {
+                if trimmed.eq_ignore_ascii_case("n") || trimmed.eq_ignore_ascii_case("no") {
+                    return None;
+                }
+                let reason = (!trimmed.eq_ignore_ascii_case("y")
+                    && !trimmed.eq_ignore_ascii_case("yes"))
+                .then(|| trimmed.to_string());
+                self.message_selected = None;
+                Some(MatrixCommand::RedactMessage {
+                    room_id: room_id.clone(),
+                    event_id: event_id.clone(),
+                    reason,
+                })
+            }
+            PromptMode::React { .. }
+            | PromptMode::TemplateField { .. }
+            | PromptMode::LongMessage { .. } => None,
+            PromptMode::KeyPassphrase { room_id, path } => {
+                let passphrase = state.input.clone();
+                let path = path.clone();
+                Some(match room_id {
+                    Some(room_id) => MatrixCommand::ExportRoomKeys {
+                        room_id: room_id.clone(),
+                        path,
+                        passphrase,
+                    },
+                    None => MatrixCommand::ImportRoomKeys { path, passphrase },
+                })
+            }
+            PromptMode::Delete { room_id, .. } => {
+                if trimmed.eq_ignore_ascii_case("y") || trimmed.eq_ignore_ascii_case("yes") {
+                    let room_id = room_id.clone();
+                    self.schedule_leave(room_id)
+                } else if trimmed.eq_ignore_ascii_case("n") || trimmed.eq_ignore_ascii_case("no") {
+                    None
+                } else {
+                    state.input.clear();
+                    self.prompt = Some(state);
+                    None
+                }
+            }
+        }
+    }
+
+    /// Holds a leave for `LEAVE_GRACE` so it can be undone. A leave still
+    /// waiting is returned to send right away.
+    fn schedule_leave(&mut self, room_id: String) -> Option<MatrixCommand> {
+        let previous = self.pending_leave.take();
+        self.pending_leave = Some(PendingLeave {
+            room_name: self.room_name(&room_id).into_owned(),
+            room_id,
+            at: Instant::now() + LEAVE_GRACE,
+        });
+        previous.map(|leave| MatrixCommand::LeaveRoom {
+            room_id: leave.room_id,
+        })
+    }
+
+    /// A read receipt for the newest message of the open room, once it has
+    /// been open for `RECEIPT_DWELL` and at most every `RECEIPT_INTERVAL`
+    /// per room, so flicking through rooms doesn't send one for each.
+    fn take_due_receipt(&mut self, now: Instant) -> Option<MatrixCommand> {
+        if self.read_only || self.selected_room_is_invited() {
+            return None;
+        }
+        // Borrowed from `rooms` alone so the checks below, which run every
+        // tick, don't allocate.
+        let room_id = self.rooms.get(self.selected)?.room_id.as_str();
+        match &self.receipt_focus {
+            Some((focused, since)) if focused == room_id => {
+                if now.duration_since(*since) < RECEIPT_DWELL {
+                    return None;
+                }
+            }
+            _ => {
+                self.receipt_focus = Some((room_id.to_string(), now));
+                return None;
+            }
+        }
+        let event_id = self
+            .messages_by_room
+            .get(room_id)?
+            .iter()
+            .rev()
+            .find_map(|item| message_ids(item).0)?;
+        if let Some((sent, at)) = self.receipts_sent.get(room_id) {
+            if sent == event_id || now.duration_since(*at) < RECEIPT_INTERVAL {
+                return None;
+            }
+        }
+        let event_id = event_id.to_string();
+        self.receipts_sent
+            .insert(room_id.to_string(), (event_id.clone(), now));
+        Some(MatrixCommand::SendReceipt {
+            room_id: room_id.to_string(),
+            event_id,
+            private: self.private_read_receipts,
+        })
+    }
+
+    /// The health line under the input: the latest send's round trip and
+    /// how long ago the last sync response came in, flagged (`true`) while
+    /// syncing fails or falls `SYNC_GRACE` behind its long-poll timeout.
+    fn sync_health(&self, now: Instant) -> Option<(String, bool)> {
+        let since = now.duration_since(self.last_sync?);
+        let age = since.as_secs();
+        let age = if age < 60 {
+            format!("{}s", age)
+        } else {
+            format!("{}m", age / 60)
+        };
+        let sync = if self.sync_failing {
+            format!("sync failing, last {} ago", age)
+        } else {
+            format!("synced {} ago", age)
+        };
+        let text = match self.round_trip_ms {
+            Some(millis) => format!("{} ms · {}", millis, sync),
+            None => sync,
+        };
+        let stale = self.sync_failing || since > self.sync_timeout + SYNC_GRACE;
+        Some((text, stale))
+    }
+
+    /// The labels on screen that change with time alone; an otherwise
+    /// unchanged frame is redrawn when one of them does.
+    fn clock_labels(
+        &self,
+        now: Instant,
+    ) -> (Option<(String, bool)>, Option<String>, Option<String>) {
+        (
+            self.sync_health(now),
+            self.leave_notice(),
+            self.typing_label(),
+        )
+    }
+
+    /// When the idle loop next has something to do: a clock label ticking
+    /// over, a notice expiring or a timer coming due. `None` when nothing
+    /// but input or a Matrix event can change anything.
+    fn next_wake(&self, now: Instant) -> Option<Instant> {
+        let sync_age = self.last_sync.map(|last| {
+            let age = now.duration_since(last).as_secs();
+            let step = if age < 60 { 1 } else { 60 };
+            last + Duration::from_secs((age / step + 1) * step)
+        });
+        let sync_stale = self.last_sync.map(|last| last + self.sync_timeout + SYNC_GRACE);
+        let leave = self.pending_leave.as_ref().map(|leave| {
+            let left = leave.at.saturating_duration_since(now);
+            leave.at - Duration::from_secs(left.as_secs())
+        });
+        let verification = self
+            .verification_until
+            .filter(|_| self.verification_emojis.is_none());
+        let away = self
+            .auto_away
+            .filter(|_| !self.is_away)
+            .map(|timeout| self.last_activity + timeout);
+        let typing = self
+            .selected_room_id()
+            .and_then(|room_id| self.typing.get(room_id))
+            .map(|(_, since)| *since + TYPING_TIMEOUT);
+        let receipt = self
+            .selected_room_id()
+            .and_then(|room_id| self.receipts_sent.get(room_id))
+            .map(|(_, at)| *at + RECEIPT_INTERVAL);
+        let now_ms = Local::now().timestamp_millis();
+        let scheduled = self
+            .scheduled
+            .iter()
+            .find(|msg| msg.send_at > now_ms)
+            .map(|msg| now + Duration::from_millis((msg.send_at - now_ms) as u64));
+        [
+            sync_age,
+            sync_stale,
+            leave,
+            verification,
+            self.status_until,
+            away,
+            typing,
+            receipt,
+            self.receipt_focus.as_ref().map(|(_, since)| *since + RECEIPT_DWELL),
+            scheduled,
+        ]
+        .into_iter()
+        .flatten()
+        .filter(|at| *at > now)
+        .min()
+    }
+
+    /// The pending leave once its grace period is over.
+    fn take_due_leave(&mut self, now: Instant) -> Option<MatrixCommand> {
+        if self.pending_leave.as_ref()?.at > now {
+            return None;
+        }
+        let leave = self.pending_leave.take()?;
+        Some(MatrixCommand::LeaveRoom {
+            room_id: leave.room_id,
+        })
+    }
+
+    fn undo_leave(&mut self) {
+        match self.pending_leave.take() {
+            Some(leave) => self.set_status(&format!("Stayed in {}", leave.room_name)),
+            None => self.set_status("Nothing to undo"),
+        }
+    }
+
+    /// Countdown shown in place of the status while a leave is pending.
+    fn leave_notice(&self) -> Option<String> {
+        let leave = self.pending_leave.as_ref()?;
+        let left = leave.at.saturating_duration_since(Instant::now());
+        Some(format!(
+            "Leaving {} in {}s · Ctrl+Z undoes",
+            leave.room_name,
+            left.as_secs() + 1
+        ))
+    }
+
+    fn show_verification_emojis(&mut self, emojis: Vec<(String, String)>) {
+        self.verification_emojis = Some(emojis);
+        self.verification_status =
+            Some("Match the emojis on your other device. Y=confirm, N=cancel".to_string());
+        self.verification_until = None;
+    }
+
+    fn show_verification_status(&mut self, status: &str) {
+        self.verification_emojis = None;
+        self.verification_status = Some(status.to_string());
+        self.verification_until = Some(Instant::now() + Duration::from_secs(3));
+    }
+
+    fn clear_verification(&mut self) {
+        self.verification_emojis = None;
+        self.verification_status = None;
+        self.verification_until = None;
+    }
+
+    fn set_status(&mut self, message: &str) {
+        self.status = Some(message.to_string());
+        self.status_until = Some(Instant::now() + Duration::from_secs(4));
+    }
+
+    /// Parses the configured startup commands, once per session. Failures
+    /// are reported in the status line and skipped.
+    fn run_startup_commands(&mut self) -> Vec<MatrixCommand> {
+        let lines = std::mem::take(&mut self.startup_commands);
+        if self.read_only {
+            if !lines.is_empty() {
+                self.set_status("Startup commands skipped in read-only mode");
+            }
+            return Vec::new();
+        }
+        let room_id = self.selected_room_id();
+        let mut errors = Vec::new();
+        let cmds = lines
+            .iter()
+            .filter_map(|line| match parse_command(line, room_id) {
+                Some(Ok(cmd)) => Some(cmd),
+                Some(Err(err)) => {
+                    errors.push(format!("{}: {}", line, err));
+                    None
+                }
+                None => {
+                    errors.push(format!("{}: not a command", line));
+                    None
+                }
+            })
+            .collect();
+        if !errors.is_empty() {
+            self.set_status(&format!("Startup: {}", errors.join("; ")));
+        }
+        cmds
+    }
+
+    /// Keeps the input bound to the compose room: when it changes, the text
+    /// is parked as the old room's draft and the new room's draft is
+    /// restored. Returns true when the drafts changed.
+    fn sync_draft(&mut self) -> bool {
+        if self.read_only {
+            return false;
+        }
+        let current = self.compose_room_id();
+        if current == self.draft_room {
+            return false;
+        }
+        let mut changed = false;
+        if let Some(room_id) = self.draft_room.take() {
+            let text = std::mem::take(&mut self.input);
+            changed = if text.trim().is_empty() {
+                self.drafts.remove(&room_id).is_some()
+            } else {
+                self.drafts.insert(room_id, text.clone()).as_ref() != Some(&text)
+            };
+        }
+        let draft = current
+            .as_ref()
+            .and_then(|room_id| self.drafts.get(room_id))
+            .cloned()
+            .unwrap_or_default();
+        self.restore_input(draft);
+        self.completion = None;
+        self.mentions.clear();
+        self.history_cursor = None;
+        self.editing = None;
+        self.replying = None;
+        self.draft_room = current;
+        changed
+    }
+
+    /// Stored drafts plus the text currently in the input.
+    fn all_drafts(&self) -> HashMap<String, String> {
+        let mut drafts = self.drafts.clone();
+        if let Some(room_id) = &self.draft_room {
+            if self.input.trim().is_empty() {
+                drafts.remove(room_id);
+            } else {
+                drafts.insert(room_id.clone(), self.input.clone());
+            }
+        }
+        drafts
+    }
+
+    /// Adds sent text to the room's input history, oldest entries first out.
+    fn remember_input(&mut self, room_id: &str, text: &str) {
+        const INPUT_HISTORY_LEN: usize = 50;
+        let history = self.input_history.entry(room_id.to_string()).or_default();
+        if history.back().map(String::as_str) != Some(text) {
+            history.push_back(text.to_string());
+        }
+        if history.len() > INPUT_HISTORY_LEN {
+            history.pop_front();
+        }
+        self.history_cursor = None;
+    }
+
+    /// Steps through the room's sent input. Editing a recalled entry ends
+    /// the recall; stepping past the newest entry restores the draft.
+    fn recall_input(&mut self, older: bool) {
+        let Some(room_id) = self.compose_room_id() else {
+            return;
+        };
+        let Some(history) = self.input_history.get(&room_id) else {
+            return;
+        };
+        let browsing = self
+            .history_cursor
+            .as_ref()
+            .filter(|cursor| cursor.room_id == room_id && history.get(cursor.index) == Some(&self.input));
+        let (index, draft) = match browsing {
+            Some(cursor) => (Some(cursor.index), cursor.draft.clone()),
+            None => (None, self.input.clone()),
+        };
+        let next = match (index, older) {
+            (None, true) => history.len().checked_sub(1),
+            (None, false) => return,
+            (Some(index), true) => Some(index.saturating_sub(1)),
+            (Some(index), false) => (index + 1 < history.len()).then_some(index + 1),
+        };
+        match next {
+            Some(index) => {
+                let text = history[index].clone();
+                self.restore_input(text);
+                self.history_cursor = Some(HistoryCursor {
+                    room_id,
+                    index,
+                    draft,
+                });
+            }
+            None => {
+                self.restore_input(draft);
+                self.history_cursor = None;
+            }
+        }
+    }
+
+    /// Loads the selected own message, or else our latest one in the room,
+    /// into the input for editing.
+    fn start_edit(&mut self) {
+        let Some(room_id) = self.selected_room_id().map(str::to_string) else {
+            return;
+        };
+        if self.locked_elsewhere() {
+            return;
+        }
+        let own = self.own_user_id.as_deref();
+        let is_own_text = |item: &&MessageItem| match item {
+            MessageItem::Message {
+                sender_id,
+                event_id: Some(_),
+                text,
+                ..
+            } => own == Some(sender_id.as_str()) && text != REDACTED_TEXT,
+            _ => false,
+        };
+        let target = match self.selected_message() {
+            Some(item) => Some(item).filter(is_own_text),
+            None => self.visible_messages().iter().rev().find(is_own_text),
+        };
+        let Some(MessageItem::Message {
+            event_id: Some(event_id),
+            text,
+            ..
+        }) = target
+        else {
+            self.set_status("Select one of your own messages to edit");
+            return;
+        };
+        let (event_id, text) = (event_id.clone(), text.clone());
+        self.editing = Some(EditTarget { room_id, event_id });
+        self.replying = None;
+        self.restore_input(text);
+        self.message_selected = None;
+        self.set_status("Editing message; Enter saves, Esc cancels");
+    }
+
+    /// Makes the selected message the target of the next send.
+    fn start_reply(&mut self) {
+        let Some(room_id) = self.selected_room_id().map(str::to_string) else {
+            return;
+        };
+        if self.locked_elsewhere() {
+            return;
+        }
+        let target = match self.selected_message() {
+            Some(MessageItem::Message {
+                name,
+                text,
+                event_id: Some(event_id),
+                ..
+            }) if text != REDACTED_TEXT => (event_id.clone(), name.clone(), text.clone()),
+            Some(MessageItem::Attachment {
+                name,
+                filename,
+                event_id: Some(event_id),
+                ..
+            }) => (event_id.clone(), name.clone(), filename.clone()),
+            Some(MessageItem::Poll {
+                name,
+                poll,
+                event_id: Some(event_id),
+                ..
+            }) => (
+                event_id.clone(),
+                name.clone(),
+                format!("[poll] {}", poll.question),
+            ),
+            _ => {
+                self.set_status("Select a message to reply to");
+                return;
+            }
+        };
+        let (event_id, sender, text) = target;
+        self.replying = Some(ReplyTarget {
+            room_id,
+            event_id,
+            sender,
+            text,
+        });
+        self.editing = None;
+        self.message_selected = None;
+        self.set_status("Replying; Enter sends, Esc cancels");
+    }
+
+    /// The event a message sent to `room_id` now replies to: the reply
+    /// target if one is set, else the selected message.
+    fn take_reply_to(&mut self, room_id: &str) -> Option<String> {
+        match self.replying.take() {
+            Some(target) if target.room_id == room_id => Some(target.event_id),
+            _ if self.selected_room_id() == Some(room_id) => self.selected_message_event_id(),
+            _ => None,
+        }
+    }
+
+    /// Why the homeserver would refuse `cmd`, going by its capabilities.
+    fn unsupported_reason(&self, cmd: &MatrixCommand) -> Option<&'static str> {
+        let room = |room_id: &str| self.rooms.iter().find(|room| room.room_id == room_id);
+        match cmd {
+            MatrixCommand::InviteUser { room_id, .. }
+                if room(room_id).is_some_and(|room| !room.can_invite) =>
+            {
+                return Some("Your power level is too low to invite here");
+            }
+            MatrixCommand::SetTopic { room_id, .. }
+                if room(room_id).is_some_and(|room| !room.can_set_topic) =>
+            {
+                return Some("Your power level is too low to change the topic here");
+            }
+            _ => {}
+        }
+        let info = self.server_info.as_ref()?;
+        match cmd {
+            MatrixCommand::SetDisplayName { .. } if !info.set_displayname => {
+                Some("This server does not allow changing display names")
+            }
+            _ => None,
+        }
+    }
+
+    /// The edit command for `text` when an edit is in progress.
+    fn take_edit(&mut self, text: &str) -> Option<MatrixCommand> {
+        let target = self.editing.take()?;
+        Some(MatrixCommand::EditMessage {
+            room_id: target.room_id,
+            event_id: target.event_id,
+            body: text.to_string(),
+        })
+    }
+
+    fn restore_input(&mut self, text: String) {
+        self.input_cursor = text.chars().count();
+        self.input = text;
+    }
+
+    /// The word being typed: its start as a char index and its text up to
+    /// the cursor.
+    fn word_before_cursor(&self) -> (usize, String) {
+        let before: Vec<char> = self.input.chars().take(self.input_cursor).collect();
+        let start = before
+            .iter()
+            .rposition(|c| c.is_whitespace())
+            .map(|idx| idx + 1)
+            .unwrap_or(0);
+        (start, before[start..].iter().collect())
+    }
+
+    /// Replaces every word of `text` that is a snippet trigger with its
+    /// expansion.
+    fn expand_snippets(&self, text: String) -> String {
+        if self.snippets.is_empty() {
+            return text;
+        }
+        let mut out = String::with_capacity(text.len());
+        for piece in text.split_inclusive(char::is_whitespace) {
+            let word = piece.trim_end_matches(char::is_whitespace);
+            match self.snippets.get(word) {
+                Some(expansion) => {
+                    out.push_str(expansion);
+                    out.push_str(&piece[word.len()..]);
+                }
+                None => out.push_str(piece),
+            }
+        }
+        out
+    }
+
+    /// Tab: expands the snippet trigger just before the cursor, or else
+    /// completes a command name.
+    fn on_tab(&mut self) {
+        let (start, word) = self.word_before_cursor();
+        match self.snippets.get(&word).cloned() {
+            Some(expansion) => self.replace_input_range(start, self.input_cursor, &expansion),
+            None => self.complete_command(),
+        }
+    }
+
+    /// Re-evaluates the autocomplete popup after the input changed. Returns
+    /// a member list fetch when a mention is started with a bare `@`.
+    fn update_completion(&mut self) -> Option<MatrixCommand> {
+        const MAX_ITEMS: usize = 8;
+        let (start, word) = self.word_before_cursor();
+        let mut fetch = None;
+        let items: Vec<CompletionItem> = if let Some(code) = word.strip_prefix(':') {
+            if !code.is_empty() && code.chars().all(emoji::is_shortcode_char) {
+                emoji::search(code, MAX_ITEMS)
+                    .into_iter()
+                    .map(|(name, emoji)| CompletionItem {
+                        label: format!("{} :{}:", emoji, name),
+                        replacement: emoji.to_string(),
+                        mention: None,
+                    })
+                    .collect()
+            } else {
+                Vec::new()
+            }
+        } else if let (Some(query), Some(room_id)) =
+            (word.strip_prefix('@'), self.compose_room_id())
+        {
+            if query.is_empty() {
+                fetch = Some(MatrixCommand::FetchMembers {
+                    room_id: room_id.clone(),
+                });
+            }
+            self.member_items(&room_id, query, MAX_ITEMS)
+        } else {
+            Vec::new()
+        };
+        self.completion = (!items.is_empty()).then_some(Completion {
+            start,
+            items,
+            selected: 0,
+        });
+        fetch
+    }
+
+    /// Room members whose display name or user ID matches `query`, prefix
+    /// matches first; the own user is left out.
+    fn member_items(&self, room_id: &str, query: &str, limit: usize) -> Vec<CompletionItem> {
+        let Some(members) = self.room_members.get(room_id) else {
+            return Vec::new();
+        };
+        let query = query.to_lowercase();
+        let mut matches: Vec<(bool, &MemberInfo)> = members
+            .iter()
+            .filter(|member| self.own_user_id.as_deref() != Some(member.user_id.as_str()))
+            .filter_map(|member| {
+                let names = [
+                    member.display_name.as_deref().unwrap_or_default().to_lowercase(),
+                    member.user_id.trim_start_matches('@').to_lowercase(),
+                    self.nickname(room_id, &member.user_id)
+                        .unwrap_or_default()
+                        .to_lowercase(),
+                ];
+                let names = names.iter().filter(|name| !name.is_empty());
+                let prefix = names.clone().any(|name| name.starts_with(&query));
+                (prefix || names.clone().any(|name| name.contains(&query))).then_some((prefix, member))
+            })
+            .collect();
+        matches.sort_by_key(|(prefix, _)| !prefix);
+        matches
+            .into_iter()
+            .take(limit)
+            .map(|(_, member)| {
+                let name = member
+                    .display_name
+                    .clone()
+                    .unwrap_or_else(|| member.user_id.clone());
+                let shown = self.nickname(room_id, &member.user_id).unwrap_or(&name);
+                CompletionItem {
+                    label: if *shown == member.user_id {
+                        shown.to_string()
+                    } else {
+                        format!("{} ({})", shown, member.user_id)
+                    },
+                    replacement: format!("{} ", name),
+                    mention: Some(Mention {
+                        user_id: member.user_id.clone(),
+                        name,
+                    }),
+                }
+            })
+            .collect()
+    }
+
+    fn set_room_members(&mut self, room_id: String, members: Vec<MemberInfo>) {
+        let refresh = self.selected_room_id() == Some(room_id.as_str());
+        self.room_members.insert(room_id.clone(), members);
+        if self.sender_format != SenderFormat::Mxid {
+            self.relabel_room(&room_id);
+        }
+        if refresh && self.word_before_cursor().1.starts_with('@') {
+            self.update_completion();
+        }
+    }
+
+    /// Keeps a fetched member list current as people join, leave or
+    /// rename themselves, relabelling the room's senders when someone new
+    /// or renamed appears. Those who left keep the label they had, and
+    /// rooms not fetched yet are left alone.
+    fn update_room_member(&mut self, room_id: &str, member: MemberInfo, joined: bool) {
+        let Some(members) = self.room_members.get_mut(room_id) else {
+            return;
+        };
+        let existing = members.iter().position(|m| m.user_id == member.user_id);
+        let changed = match (existing, joined) {
+            (Some(idx), true) if members[idx].display_name != member.display_name => {
+                members[idx] = member;
+                true
+            }
+            (Some(idx), false) => {
+                members.remove(idx);
+                false
+            }
+            (None, true) => {
+                members.push(member);
+                true
+            }
+            _ => false,
+        };
+        if changed && self.sender_format != SenderFormat::Mxid {
+            self.relabel_room(room_id);
+        }
+    }
+
+    /// Mentions picked from the popup whose name is still in `text`.
+    fn take_mentions(&mut self, text: &str) -> Vec<Mention> {
+        let mut mentions = std::mem::take(&mut self.mentions);
+        mentions.retain(|mention| find_mention(text, &mention.name).is_some());
+        mentions
+    }
+
+    /// Applies emoji shortcodes and mention pills to an outgoing message,
+    /// unless the room is switched to plain text.
+    fn format_outgoing(&mut self, room_id: &str, text: String) -> (String, Vec<Mention>) {
+        let mentions = self.take_mentions(&text);
+        if self.plain_text_rooms.contains(room_id) {
+            return (text, Vec::new());
+        }
+        (emoji::replace_shortcodes(&text), mentions)
+    }
+
+    /// `/format`: switches the room the input goes to between plain and
+    /// rich sending and saves the choice to the config file.
+    fn set_room_plain_text(&mut self, plain: bool) {
+        let Some(room_id) = self.compose_room_id() else {
+            self.set_status("No room selected");
+            return;
+        };
+        if plain {
+            self.plain_text_rooms.insert(room_id.clone());
+        } else {
+            self.plain_text_rooms.remove(&room_id);
+        }
+        let saved = update_config(|cfg| {
+            cfg.plain_text_rooms.retain(|id| *id != room_id);
+            if plain {
+                cfg.plain_text_rooms.push(room_id.clone());
+            }
+        });
+        let mode = if plain { "plain text" } else { "rich text" };
+        let name = self.room_name(&room_id).into_owned();
+        match saved {
+            Ok(()) => self.set_status(&format!("Sending {} in {}", mode, name)),
+            Err(err) => self.set_status(&format!("Sending {} in {} (not saved: {})", mode, name, err)),
+        }
+    }
+
+    /// Mutes or unmutes the thread of the selected message; a message
+    /// outside any thread is taken as the root of its own.
+    fn toggle_thread_mute(&mut self) {
+        let Some(event_id) = self.selected_message_event_id() else {
+            self.set_status("Select a message in the thread to mute");
+            return;
+        };
+        let root = self.thread_roots.get(&event_id).cloned().unwrap_or(event_id);
+        let muted = !self.muted_threads.remove(&root);
+        if muted {
+            self.muted_threads.insert(root.clone());
+        }
+        let saved = update_config(|cfg| {
+            cfg.muted_threads.retain(|id| *id != root);
+            if muted {
+                cfg.muted_threads.push(root.clone());
+            }
+        });
+        let state = if muted { "muted" } else { "unmuted" };
+        match saved {
+            Ok(()) => self.set_status(&format!("Thread {}", state)),
+            Err(err) => self.set_status(&format!("Thread {} (not saved: {})", state, err)),
+        }
+    }
+
+    /// Bridge bots in `room_id` by bridge name, found among the members and
+    /// the senders of loaded messages.
+    fn room_bridges(&self, room_id: &str) -> Vec<(&'static str, String)> {
+        let members = self
+            .room_members
+            .get(room_id)
+            .into_iter()
+            .flatten()
+            .map(|member| member.user_id.as_str());
+        let senders = self
+            .messages_by_room
+            .get(room_id)
+            .into_iter()
+            .flatten()
+            .filter_map(message_sender);
+        let mut bridges: Vec<(&'static str, String)> = Vec::new();
+        for user_id in members.chain(senders) {
+            let localpart = user_id.trim_start_matches('@').split(':').next().unwrap_or_default();
+            let Some((name, _)) = BRIDGE_BOTS.iter().find(|(_, bot)| *bot == localpart) else {
+                continue;
+            };
+            if !bridges.iter().any(|(_, bot)| bot == user_id) {
+                bridges.push((name, user_id.to_string()));
+            }
+        }
+        bridges
+    }
+
+    /// `/bridge <action> [bridge]`: sends the action's bot command to the
+    /// bridge bot of the room the input goes to, in a direct chat with the
+    /// bot.
+    fn bridge_command(&mut self, args: &str) -> Option<MatrixCommand> {
+        let room_id = self.compose_room_id()?;
+        let mut args = args.split_whitespace();
+        let Some(action) = args.next() else {
+            self.set_status(&command_usage("/bridge"));
+            return None;
+        };
+        let wanted = args.next();
+        let bridges = self.room_bridges(&room_id);
+        let bridge = match wanted {
+            Some(wanted) => bridges.iter().find(|(name, _)| *name == wanted),
+            None if bridges.len() == 1 => bridges.first(),
+            None => None,
+        };
+        let Some((name, bot)) = bridge else {
+            let fetch = !self.room_members.contains_key(&room_id);
+            let names: Vec<&str> = bridges.iter().map(|(name, _)| *name).collect();
+            self.set_status(&match (wanted, names.is_empty()) {
+                (_, true) if fetch => "No bridge bot seen yet; loading members, try again".to_string(),
+                (_, true) => "No bridge bot in this room".to_string(),
+                (Some(wanted), false) => {
+                    format!("No {} bridge here (found {})", wanted, names.join(", "))
+                }
+                (None, false) => {
+                    format!("Several bridges here ({}); add the bridge name", names.join(", "))
+                }
+            });
+            return fetch.then_some(MatrixCommand::FetchMembers { room_id });
+        };
+        let template = self
+            .bridge_commands
+            .get(&format!("{}.{}", name, action))
+            .or_else(|| self.bridge_commands.get(action))
+            .map(String::as_str)
+            .or_else(|| {
+                BRIDGE_DEFAULT_COMMANDS
+                    .iter()
+                    .find(|(known, _)| *known == action)
+                    .map(|(_, command)| *command)
+            });
+        let Some(template) = template else {
+            self.set_status(&format!("No bridge_commands entry for {}", action));
+            return None;
+        };
+        let body = template.replace("{room}", &room_id);
+        self.set_status(&format!("Sent \"{}\" to {}", body, bot));
+        Some(MatrixCommand::SendTo {
+            target: bot.clone(),
+            body,
+        })
+    }
+
+    /// Notes the thread of `event_id` and tells whether it is muted.
+    fn note_thread(&mut self, event_id: &str, thread_root: Option<&str>) -> bool {
+        let Some(root) = thread_root else {
+            return false;
+        };
+        let previous = self
+            .thread_roots
+            .insert(event_id.to_string(), root.to_string());
+        if previous.as_deref() != Some(root) {
+            self.thread_generation += 1;
+        }
+        if previous.is_none() {
+            *self.thread_counts.entry(root.to_string()).or_default() += 1;
+        }
+        self.muted_threads.contains(root)
+    }
+
+    /// Starts a spell check of the input once typing pauses; true when the
+    /// shown misspellings changed.
+    fn refresh_spelling(&mut self) -> bool {
+        if self.input != self.spell_checked
+            && self.spell_running.as_deref() != Some(self.input.as_str())
+            && self.last_activity.elapsed() >= SPELL_DELAY
+        {
+            return self.run_spellcheck();
+        }
+        false
+    }
+
+    /// Checks the input in the background; commands and read-only filters
+    /// are cleared right away, which is the only case returning true.
+    fn run_spellcheck(&mut self) -> bool {
+        let Some(spell) = self.spell.as_ref() else {
+            return false;
+        };
+        if self.read_only || self.input.starts_with('/') {
+            self.spell_checked = self.input.clone();
+            self.misspellings.clear();
+            return true;
+        }
+        let Some(events) = self.spell_events.clone() else {
+            return false;
+        };
+        self.spell_running = Some(self.input.clone());
+        spell.check_in_background(self.input.clone(), move |text, result| {
+            if let Some(events) = events.upgrade() {
+                let result = result.map_err(|err| err.to_string());
+                let _ = events.send(MatrixEvent::Spelling { text, result });
+            }
+        });
+        false
+    }
+
+    fn finish_spellcheck(&mut self, text: String, result: Result<Vec<Misspelling>, String>) {
+        if self.spell_running.as_deref() == Some(text.as_str()) {
+            self.spell_running = None;
+        }
+        match result {
+            Ok(found) => {
+                self.spell_checked = text;
+                self.misspellings = found;
+                if self.input == self.spell_checked
+                    && std::mem::take(&mut self.spell_cycle_pending)
+                {
+                    self.cycle_spelling();
+                }
+            }
+            Err(err) => {
+                self.spell = None;
+                self.spell_running = None;
+                self.spell_cycle_pending = false;
+                self.misspellings.clear();
+                self.set_status(&format!("Spell check disabled: {}", err));
+            }
+        }
+    }
+
+    /// Replaces the misspelled word at (or before) the cursor with its next
+    /// suggestion; repeated presses cycle back to the original.
+    fn cycle_spelling(&mut self) {
+        if self.spell.is_none() {
+            return;
+        }
+        if let Some(cycle) = self.spell_cycle.as_mut() {
+            let current = &cycle.options[cycle.index];
+            let end = cycle.start + current.chars().count();
+            let in_place = self
+                .input
+                .chars()
+                .skip(cycle.start)
+                .take(end - cycle.start)
+                .eq(current.chars());
+            if in_place {
+                cycle.index = (cycle.index + 1) % cycle.options.len();
+                let start = cycle.start;
+                let replacement = cycle.options[cycle.index].clone();
+                self.replace_input_range(start, end, &replacement);
+                return;
+            }
+        }
+        self.spell_cycle = None;
+        if self.input != self.spell_checked {
+            // Continued by `finish_spellcheck` once the input is checked.
+            self.spell_cycle_pending = true;
+            if self.spell_running.as_deref() != Some(self.input.as_str()) {
+                self.run_spellcheck();
+            }
+            if self.input != self.spell_checked {
+                return;
+            }
+            self.spell_cycle_pending = false;
+        }
+        let cursor = self.input_cursor;
+        let Some(word) = self
+            .misspellings
+            .iter()
+            .rev()
+            .find(|word| word.start <= cursor)
+            .cloned()
+        else {
+            self.set_status("No misspelled word before the cursor");
+            return;
+        };
+        if word.suggestions.is_empty() {
+            self.set_status(&format!("No suggestions for \"{}\"", word.word));
+            return;
+        }
+        let mut options = vec![word.word.clone()];
+        options.extend(word.suggestions.iter().cloned());
+        let replacement = options[1].clone();
+        self.spell_cycle = Some(SpellCycle {
+            start: word.start,
+            options,
+            index: 1,
+        });
+        self.replace_input_range(word.start, word.end(), &replacement);
+    }
+
+    /// Replaces chars `start..end` of the input, leaving the cursor after the
+    /// replacement.
+    fn replace_input_range(&mut self, start: usize, end: usize, replacement: &str) {
+        let from = Self::cursor_to_byte(&self.input, start);
+        let to = Self::cursor_to_byte(&self.input, end);
+        self.input.replace_range(from..to, replacement);
+        self.input_cursor = start + replacement.chars().count();
+    }
+
+    fn move_completion(&mut self, delta: isize) {
+        if let Some(completion) = self.completion.as_mut() {
+            let len = completion.items.len() as isize;
+            completion.selected = (completion.selected as isize + delta).rem_euclid(len) as usize;
+        }
+    }
+
+    fn accept_completion(&mut self) {
+        let Some(completion) = self.completion.take() else {
+            return;
+        };
+        let Some(item) = completion.items.get(completion.selected) else {
+            return;
+        };
+        let start = Self::cursor_to_byte(&self.input, completion.start);
+        let end = Self::cursor_to_byte(&self.input, self.input_cursor);
+        self.input.replace_range(start..end, &item.replacement);
+        self.input_cursor = completion.start + item.replacement.chars().count();
+        if let Some(mention) = &item.mention {
+            if !self.mentions.contains(mention) {
+                self.mentions.push(mention.clone());
+            }
+        }
+    }
+
+    /// Whether the selected room's power levels allow `command`; commands
+    /// we lack permission for aren't offered.
+    fn command_permitted(&self, command: &str) -> bool {
+        let Some(room) = self.selected_room() else {
+            return true;
+        };
+        match command {
+            "/invite" => room.can_invite,
+            "/topic" => room.can_set_topic,
+            _ => true,
+        }
+    }
+
+    /// Completes a slash command name typed at the start of the input. An
+    /// ambiguous prefix is extended as far as possible and the candidates
+    /// are listed in the status line.
+    fn complete_command(&mut self) {
+        if !self.input.starts_with('/') || self.input.contains(char::is_whitespace) {
+            return;
+        }
+        let candidates: Vec<&str> = COMMANDS
+            .iter()
+            .map(|(name, _)| *name)
+            .filter(|name| name.starts_with(self.input.as_str()))
+            .filter(|name| self.command_permitted(name))
+            .collect();
+        match candidates.as_slice() {
+            [] => self.set_status(&format!("Unknown command: {}", self.input)),
+            [only] => self.restore_input(format!("{} ", only)),
+            [first, rest @ ..] => {
+                let common = rest.iter().fold(first.len(), |len, name| {
+                    first
+                        .bytes()
+                        .zip(name.bytes())
+                        .take(len)
+                        .take_while(|(a, b)| a == b)
+                        .count()
+                });
+                self.restore_input(first[..common].to_string());
+                self.set_status(&candidates.join("  "));
+            }
+        }
+    }
+
+    fn open_state_view(&mut self) -> Option<MatrixCommand> {
+        let room = self.selected_room()?;
+        if room.state != RoomListState::Joined {
+            return None;
+        }
+        let room_id = room.room_id.clone();
+        self.state_view = Some(StateView {
+            room_id: room_id.clone(),
+            entries: None,
+            scroll: 0,
+        });
+        Some(MatrixCommand::FetchRoomState { room_id })
+    }
+
+    fn show_room_state(&mut self, room_id: &str, entries: Vec<StateEntry>) {
+        if let Some(view) = self.state_view.as_mut().filter(|v| v.room_id == room_id) {
+            view.entries = Some(entries);
+        }
+    }
+
+    fn scroll_state_view(&mut self, delta: i32) {
+        if let Some(view) = self.state_view.as_mut() {
+            view.scroll = (view.scroll as i32 + delta).max(0) as u16;
+        }
+    }
+
+    /// Shows the selected message with the messages it replies to (up the
+    /// chain) and every reply below it, indented by depth.
+    fn open_reply_chain(&mut self) {
+        let Some(event_id) = self.selected_message_event_id() else {
+            return;
+        };
+        let Some(messages) = self.current_messages() else {
+            return;
+        };
+        let mut by_id: HashMap<&str, &MessageItem> = HashMap::new();
+        let mut children: HashMap<&str, Vec<&str>> = HashMap::new();
+        for item in messages {
+            let (Some(id), reply_to) = message_ids(item) else {
+                continue;
+            };
+            by_id.insert(id, item);
+            if let Some(parent) = reply_to {
+                children.entry(parent).or_default().push(id);
+            }
+        }
+        let mut ancestors = Vec::new();
+        let mut seen = HashSet::from([event_id.as_str()]);
+        let mut current = by_id.get(event_id.as_str()).and_then(|item| message_ids(item).1);
+        while let Some(parent) = current.filter(|id| seen.insert(id)) {
+            let Some(item) = by_id.get(parent) else { break };
+            ancestors.push(*item);
+            current = message_ids(item).1;
+        }
+        let mut entries: Vec<ChainEntry> = ancestors
+            .iter()
+            .rev()
+            .enumerate()
+            .filter_map(|(depth, item)| chain_entry(item, depth, false))
+            .collect();
+        let mut stack = vec![(event_id.as_str(), ancestors.len())];
+        while let Some((id, depth)) = stack.pop() {
+            let Some(item) = by_id.get(id) else { continue };
+            entries.extend(chain_entry(item, depth, id == event_id));
+            if let Some(replies) = children.get(id) {
+                for reply in replies.iter().rev().filter(|reply| seen.insert(reply)) {
+                    stack.push((reply, depth + 1));
+                }
+            }
+        }
+        self.reply_chain = Some(ReplyChainView {
+            title: "Reply chain".to_string(),
+            entries,
+            scroll: 0,
+        });
+    }
+
+    /// Shows the thread the selected message belongs to (or starts): its
+    /// root followed by every reply, oldest first.
+    fn open_thread(&mut self) {
+        let Some(event_id) = self.selected_message_event_id() else {
+            return;
+        };
+        let root = match self.thread_roots.get(&event_id) {
+            Some(root) => root.clone(),
+            None if self.thread_counts.contains_key(&event_id) => event_id.clone(),
+            None => {
+                self.set_status("The selected message has no thread");
+                return;
+            }
+        };
+        let Some(messages) = self.current_messages() else {
+            return;
+        };
+        let entries: Vec<ChainEntry> = messages
+            .iter()
+            .filter_map(|item| {
+                let id = message_ids(item).0?;
+                let depth = if id == root {
+                    0
+                } else if self.thread_roots.get(id) == Some(&root) {
+                    1
+                } else {
+                    return None;
+                };
+                chain_entry(item, depth, id == event_id)
+            })
+            .collect();
+        let replies = self.thread_counts.get(&root).copied().unwrap_or_default();
+        self.reply_chain = Some(ReplyChainView {
+            title: format!("Thread ({})", reply_count(replies)),
+            entries,
+            scroll: 0,
+        });
+    }
+
+    /// Opens the info popup for the selected message; the returned command
+    /// fetches its encryption details from the server.
+    /// Details of the selected message, or of the room (with its full
+    /// topic) when no message is selected.
+    fn open_message_info(&mut self) -> Option<MatrixCommand> {
+        let room_id = self.selected_room_id().map(str::to_string)?;
+        let Some(item) = self.selected_message() else {
+            self.open_room_info();
+            return None;
+        };
+        let (timestamp, sender_id, event_id, reply_to, attachment) = match item {
+            MessageItem::Message {
+                timestamp,
+                sender_id,
+                event_id,
+                reply_to,
+                ..
+            } => (*timestamp, sender_id, event_id, reply_to, None),
+            MessageItem::Attachment {
+                timestamp,
+                sender_id,
+                event_id,
+                reply_to,
+                path,
+                ..
+            } => (*timestamp, sender_id, event_id, reply_to, Some(path)),
+            MessageItem::Poll {
+                timestamp,
+                sender_id,
+                event_id,
+                ..
+            } => (*timestamp, sender_id, event_id, &None, None),
+            _ => return None,
+        };
+        let mut local = vec![
+            ("Time".to_string(), format_full_timestamp(timestamp)),
+            ("Sender".to_string(), sender_id.clone()),
+            (
+                "Event ID".to_string(),
+                event_id.clone().unwrap_or_else(|| "(none)".to_string()),
+            ),
+            (
+                "Room".to_string(),
+                format!("{} ({})", self.room_name(&room_id), room_id),
+            ),
+        ];
+        if let Some(reply_to) = reply_to {
+            local.push(("Reply to".to_string(), reply_to.clone()));
+        }
+        if let Ok(base) = messages_dir() {
+            local.push((
+                "Stored in".to_string(),
+                room_log_path(&base, &room_id).display().to_string(),
+            ));
+        }
+        if let Some(path) = attachment {
+            local.push(("Attachment".to_string(), path.clone()));
+        }
+        let event_id = event_id.clone();
+        self.message_info = Some(MessageInfoView {
+            title: "Message info",
+            event_id: event_id.clone(),
+            local,
+            remote: None,
+        });
+        event_id.map(|event_id| MatrixCommand::FetchEventInfo { room_id, event_id })
+    }
+
+    fn open_room_info(&mut self) {
+        let Some(room) = self.selected_room() else {
+            return;
+        };
+        let mut local = vec![
+            ("Name".to_string(), self.room_label(room).into_owned()),
+            ("Room ID".to_string(), room.room_id.clone()),
+        ];
+        if let Some(alias) = &room.alias {
+            local.push(("Alias".to_string(), alias.clone()));
+        }
+        if let Some(user) = &room.direct_user {
+            local.push(("Direct chat".to_string(), user.clone()));
+        }
+        local.push((
+            "Topic".to_string(),
+            room.topic.clone().unwrap_or_else(|| "(none)".to_string()),
+        ));
+        self.message_info = Some(MessageInfoView {
+            title: "Room info",
+            event_id: None,
+            local,
+            remote: None,
+        });
+    }
+
+    fn show_event_info(&mut self, event_id: &str, details: Vec<(String, String)>) {
+        if let Some(view) = self
+            .message_info
+            .as_mut()
+            .filter(|v| v.event_id.as_deref() == Some(event_id))
+        {
+            view.remote = Some(details);
+        }
+    }
+
+    fn scroll_reply_chain(&mut self, delta: i32) {
+        if let Some(view) = self.reply_chain.as_mut() {
+            view.scroll = (view.scroll as i32 + delta).max(0) as u16;
+        }
+    }
+
+    fn open_search(&mut self, query: &str) {
+        let hits = self
+            .search_index
+            .search(query)
+            .into_iter()
+            .filter_map(|hit| {
+                let preview = self.reply_index.get(&hit.room_id)?.get(&hit.event_id)?;
+                Some(SearchHit {
+                    room_id: hit.room_id.clone(),
+                    time: format!("{} {}", format_date(hit.timestamp), format_timestamp(hit.timestamp)),
+                    sender: preview.sender.clone(),
+                    text: preview.text.clone(),
+                })
+            })
+            .collect();
+        self.search_view = Some(SearchView {
+            query: query.to_string(),
+            hits,
+            scroll: 0,
+        });
+    }
+
+    fn scroll_search_view(&mut self, delta: i32) {
+        if let Some(view) = self.search_view.as_mut() {
+            let max = view.hits.len().saturating_sub(1) as i32;
+            view.scroll = (view.scroll as i32 + delta).clamp(0, max) as u16;
+        }
+    }
+
+    /// Queues `text` for the room the input goes to, formatted now so
+    /// mentions picked from the popup are kept. Returns the command that
+    /// queues it with the background instance when that owns the queue.
+    fn schedule_message(&mut self, send_at: i64, text: String) -> Option<MatrixCommand> {
+        let Some(room_id) = self.compose_room_id() else {
+            self.set_status("No room selected");
+            return None;
+        };
+        let (body, mentions) = self.format_outgoing(&room_id, text);
+        let message = ScheduledMessage {
+            room_id,
+            body,
+            mentions: mentions
+                .into_iter()
+                .map(|m| StoredMention {
+                    user_id: m.user_id,
+                    name: m.name,
+                })
+                .collect(),
+            send_at,
+        };
+        let idx = self.scheduled.partition_point(|msg| msg.send_at <= send_at);
+        self.scheduled.insert(idx, message.clone());
+        self.scheduled_changed = true;
+        self.set_status(&format!(
+            "Scheduled for {} {} (/schedule to list)",
+            format_date(send_at),
+            format_timestamp(send_at)
+        ));
+        (!self.owns_schedule).then_some(MatrixCommand::Schedule { message })
+    }
+
+    /// Sends the scheduled messages that are due; missed ones stay queued
+    /// until sent or cancelled from the list.
+    fn take_due_scheduled(&mut self, now: i64) -> Vec<MatrixCommand> {
+        if !self.owns_schedule {
+            return Vec::new();
+        }
+        let due = take_due_scheduled(&mut self.scheduled, now);
+        if !due.is_empty() {
+            self.scheduled_changed = true;
+            self.clamp_scheduled_selection();
+        }
+        due.into_iter().map(scheduled_command).collect()
+    }
+
+    /// The background instance's queue after a change.
+    fn set_scheduled(&mut self, scheduled: Vec<ScheduledMessage>) {
+        self.scheduled = scheduled;
+        self.clamp_scheduled_selection();
+    }
+
+    fn clamp_scheduled_selection(&mut self) {
+        if let Some(view) = self.scheduled_view.as_mut() {
+            view.selected = view.selected.min(self.scheduled.len().saturating_sub(1));
+        }
+    }
+
+    fn open_scheduled(&mut self) {
+        self.scheduled_view = Some(ScheduledView { selected: 0 });
+    }
+
+    fn move_scheduled_selection(&mut self, delta: i32) {
+        let max = self.scheduled.len().saturating_sub(1) as i32;
+        if let Some(view) = self.scheduled_view.as_mut() {
+            view.selected = (view.selected as i32 + delta).clamp(0, max) as usize;
+        }
+    }
+
+    /// Drops the selected scheduled message from the queue; `send` sends it
+    /// right away instead.
+    fn take_selected_scheduled(&mut self, send: bool) -> Option<MatrixCommand> {
+        let view = self.scheduled_view.as_mut()?;
+        if view.selected >= self.scheduled.len() {
+            return None;
+        }
+        let msg = self.scheduled.remove(view.selected);
+        view.selected = view.selected.min(self.scheduled.len().saturating_sub(1));
+        self.scheduled_changed = true;
+        if send {
+            self.set_status("Sent");
+        } else {
+            self.set_status("Scheduled message cancelled");
+        }
+        if !self.owns_schedule {
+            Some(MatrixCommand::TakeScheduled { message: msg, send })
+        } else {
+            send.then(|| scheduled_command(msg))
+        }
+    }
+
+    /// Records a keypress; returns a presence update when coming back from away.
+    fn register_activity(&mut self) -> Option<MatrixCommand> {
+        self.last_activity = Instant::now();
+        if self.is_away {
+            self.is_away = false;
+            return Some(MatrixCommand::SetPresence { away: false });
+        }
+        None
+    }
+
+    fn check_idle(&mut self) -> Option<MatrixCommand> {
+        let timeout = self.auto_away?;
+        if !self.is_away && self.last_activity.elapsed() >= timeout {
+            self.is_away = true;
+            return Some(MatrixCommand::SetPresence { away: true });
+        }
+        None
+    }
+
+    fn push_server_notice(&mut self, room_id: &str, event_id: &str, ts: i64, text: &str) {
+        if self.server_notices.iter().any(|n| n.event_id == event_id) {
+            return;
+        }
+        self.server_notices.push(ServerNotice {
+            event_id: event_id.to_string(),
+            room_id: room_id.to_string(),
+            time: format_timestamp(ts),
+            text: text.to_string(),
+        });
+    }
+
+    fn acknowledge_server_notice(&mut self) {
+        if !self.server_notices.is_empty() {
+            self.server_notices.remove(0);
+        }
+    }
+
+    fn on_escape(&mut self) {
+        if self.editing.take().is_some() {
+            self.input.clear();
+            self.input_cursor = 0;
+            self.set_status("Edit cancelled");
+        } else if self.replying.take().is_some() {
+            self.set_status("Reply cancelled");
+        } else {
+            self.message_selected = None;
+            self.message_scroll = 0;
+        }
+    }
+
+    fn on_message_up(&mut self) {
+        let messages = self.visible_messages();
+        if messages.is_empty() {
+            return;
+        }
+        let bottom = messages.len() - 1 - self.message_scroll.min(messages.len() - 1);
+        self.message_selected = match self.message_selected {
+            Some(idx) => Some(idx.saturating_sub(1)),
+            None => Some(bottom),
+        };
+    }
+
+    /// Scrolls the timeline a page towards older (`up`) or newer messages,
+    /// moving the selection along if there is one.
+    fn scroll_messages_page(&mut self, up: bool) {
+        let len = self.visible_messages().len();
+        if len == 0 {
+            return;
+        }
+        let page = self.message_page.saturating_sub(1).max(1);
+        if let Some(idx) = self.message_selected {
+            self.message_selected = Some(if up {
+                idx.saturating_sub(page)
+            } else {
+                (idx + page).min(len - 1)
+            });
+        }
+        self.message_scroll = if up {
+            (self.message_scroll + page).min(len - 1)
+        } else {
+            self.message_scroll.saturating_sub(page)
+        };
+    }
+
+    fn on_message_down(&mut self) {
+        let messages = self.visible_messages();
+        if messages.is_empty() {
+            return;
+        }
+        self.message_selected = match self.message_selected {
+            Some(idx) => {
+                if idx + 1 < messages.len() {
+                    Some(idx + 1)
+                } else {
+                    Some(idx)
+                }
+            }
+            None => Some(0),
+        };
+    }
+
+    /// Selects our previous (`older`) or next own message, starting from the
+    /// selection or the bottom of the timeline.
+    fn select_own_message(&mut self, older: bool) {
+        let Some(own) = self.own_user_id.clone() else {
+            return;
+        };
+        let messages = self.visible_messages();
+        let is_own = |idx: &usize| message_sender(&messages[*idx]) == Some(own.as_str());
+        let found = match (self.message_selected, older) {
+            (Some(current), true) => (0..current).rev().find(is_own),
+            (None, true) => (0..messages.len()).rev().find(is_own),
+            (Some(current), false) => (current + 1..messages.len()).find(is_own),
+            (None, false) => None,
+        };
+        match found {
+            Some(idx) => self.message_selected = Some(idx),
+            None if older => self.set_status("No earlier message of yours"),
+            None => self.set_status("No later message of yours"),
+        }
+    }
+
+    fn on_copy_message(&mut self) {
+        if let Some(msg) = self.selected_message() {
+            let text = msg_content(msg);
+            self.copy_text(&text);
+        }
+    }
+
+    fn copy_text(&mut self, text: &str) {
+        match clipboard::copy(self.clipboard, text) {
+            Ok(provider) => self.set_status(&format!("Copied ({})", provider.name())),
+            Err(err) => self.set_status(&format!("Copy failed: {}", err)),
+        }
+    }
+
+    fn on_open_url(&mut self) {
+        if let Some(msg) = self.selected_message() {
+            let msg_text = msg_string(msg);
+            if let Some(url) = extract_url(&msg_text) {
+                let _ = open_url(url);
+            }
+        }
+    }
+
+    /// Hint shown in the empty input: what Enter and the message keys do in
+    /// the current state.
+    /// Whether typing `next` would start a message in a room we can't post
+    /// in; commands are still allowed.
+    fn input_blocked(&self, next: &str) -> bool {
+        let restricted =
+            !self.read_only && self.compose_room().is_some_and(|room| room.send_restricted);
+        restricted
+            && !self.input.starts_with('/')
+            && !(self.input.is_empty() && next.starts_with('/'))
+    }
+
+    fn input_placeholder(&self) -> String {
+        if self.read_only {
+            return "Type to filter messages · Alt+Up/Down select · Enter opens links".to_string();
+        }
+        let Some(room) = self.compose_room() else {
+            return "Alt+A to add a chat · F1 for help".to_string();
+        };
+        if room.state == RoomListState::Invited {
+            return "Ctrl+A accepts the invite, Ctrl+D declines".to_string();
+        }
+        if room.send_restricted {
+            return "Read-only room: your power level is too low to post · /commands still work"
+                .to_string();
+        }
+        if let Some(target) = &self.replying {
+            return format!("Reply to {} · Enter sends, Esc cancels", target.sender);
+        }
+        let own = |sender: &str| self.own_user_id.as_deref() == Some(sender);
+        match self.selected_message() {
+            Some(MessageItem::Attachment {
+                filename, sender_id, ..
+            }) => {
+                let mut hint = format!("Enter opens {} · Alt+R reply · Alt+L react", filename);
+                if self.can_redact(sender_id) {
+                    hint.push_str(" · Alt+X delete");
+                }
+                hint
+            }
+            Some(MessageItem::Poll { sender_id, .. }) => {
+                let mut hint = "1-9 vote · Alt+R reply · Alt+L react".to_string();
+                if self.can_redact(sender_id) {
+                    hint.push_str(" · Alt+X delete");
+                }
+                hint
+            }
+            Some(item @ MessageItem::Message { sender_id, .. }) => {
+                let mut hint = if extract_url(&msg_string(item)).is_some() {
+                    "Enter opens link · Alt+R reply · Alt+L react".to_string()
+                } else {
+                    "Alt+R reply · Alt+L react · Alt+Y copy".to_string()
+                };
+                if own(sender_id) {
+                    hint.push_str(" · Alt+E edit");
+                }
+                if self.can_redact(sender_id) {
+                    hint.push_str(" · Alt+X delete");
+                }
+                hint
+            }
+            _ => format!(
+                "Message {} · Alt+Up selects a message · F1 for help",
+                self.room_label(room)
+            ),
+        }
+    }
+
+    fn on_help_up(&mut self) {
+        self.help_scroll = self.help_scroll.saturating_sub(1);
+    }
+
+    fn on_help_down(&mut self) {
+        let max = self.help_lines().len().saturating_sub(1) as u16;
+        self.help_scroll = (self.help_scroll + 1).min(max);
+    }
+
+    fn selected_room_id(&self) -> Option<&str> {
+        self.rooms
+            .get(self.selected)
+            .map(|room| room.room_id.as_str())
+    }
+
+    fn selected_room(&self) -> Option<&RoomInfo> {
+        self.rooms.get(self.selected)
+    }
+
+    fn selected_room_is_invited(&self) -> bool {
+        matches!(
+            self.selected_room().map(|room| room.state),
+            Some(RoomListState::Invited)
+        )
+    }
+
+    /// Where typed messages go: the locked room, else the selected one.
+    fn compose_room_id(&self) -> Option<String> {
+        self.compose_lock
+            .clone()
+            .or_else(|| self.selected_room_id().map(str::to_string))
+    }
+
+    fn compose_room(&self) -> Option<&RoomInfo> {
+        match self.compose_lock.as_deref() {
+            Some(room_id) => self
+                .rooms
+                .iter()
+                .chain(&self.hidden_rooms)
+                .find(|room| room.room_id == room_id),
+            None => self.selected_room(),
+        }
+    }
+
+    fn compose_room_is_invited(&self) -> bool {
+        matches!(
+            self.compose_room().map(|room| room.state),
+            Some(RoomListState::Invited)
+        )
+    }
+
+    /// Locks the input to the selected room, so browsing other rooms can't
+    /// change where messages go, or releases the lock.
+    fn toggle_compose_lock(&mut self) {
+        if let Some(room_id) = self.compose_lock.take() {
+            let name = self.room_name(&room_id);
+            self.set_status(&format!("Input no longer locked to {}", name));
+            return;
+        }
+        match self.selected_room() {
+            Some(room) if room.state == RoomListState::Joined => {
+                let room_id = room.room_id.clone();
+                let name = self.room_name(&room_id);
+                self.set_status(&format!("Input locked to {} (Ctrl+L releases)", name));
+                self.compose_lock = Some(room_id);
+            }
+            _ => self.set_status("Select a joined room to lock the input to"),
+        }
+    }
+
+    /// Whether the input is locked to a room other than the selected one;
+    /// says so, since replies and edits act on the selected room.
+    fn locked_elsewhere(&mut self) -> bool {
+        let Some(room_id) = self.compose_lock.clone() else {
+            return false;
+        };
+        if self.selected_room_id() == Some(room_id.as_str()) {
+            return false;
+        }
+        let name = self.room_name(&room_id);
+        self.set_status(&format!("Input is locked to {} (Ctrl+L releases)", name));
+        true
+    }
+
+    fn reply_preview(&self, room_id: &str, reply_to: &str) -> Option<&ReplyPreview> {
+        self.reply_index
+            .get(room_id)
+            .and_then(|map| map.get(reply_to))
+    }
+
+    /// Whether a popup covers the timeline, so thumbnails must go.
+    fn overlay_open(&self) -> bool {
+        self.help_open
+            || self.prompt.is_some()
+            || self.state_view.is_some()
+            || self.search_view.is_some()
+            || self.scheduled_view.is_some()
+            || self.reply_chain.is_some()
+            || self.message_info.is_some()
+            || self.verification_emojis.is_some()
+            || self.verification_status.is_some()
+    }
+
+    /// Fetch commands for reply quotes drawn without their original message.
+    fn take_reply_fetches(&mut self) -> Vec<MatrixCommand> {
+        let pending = std::mem::take(&mut self.pending_reply_fetches);
+        pending
+            .into_iter()
+            .filter(|(_, event_id)| self.reply_fetches.insert(event_id.clone()))
+            .map(|(room_id, event_id)| MatrixCommand::FetchReplyPreview { room_id, event_id })
+            .collect()
+    }
+
+    fn take_link_fetches(&mut self) -> Vec<MatrixCommand> {
+        let pending = std::mem::take(&mut self.pending_link_fetches);
+        if self.bandwidth_saver {
+            return Vec::new();
+        }
+        pending
+            .into_iter()
+            .filter(|url| self.link_fetches.insert(url.clone()))
+            .map(|url| MatrixCommand::FetchLinkPreview { url })
+            .collect()
+    }
+
+    fn set_reply_preview(&mut self, room_id: &str, event_id: &str, sender: &str, body: &str) {
+        let preview = ReplyPreview {
+            sender_id: sender.to_string(),
+            sender: self.sender_label(room_id, sender),
+            text: strip_reply_fallback(body).to_string(),
+        };
+        self.reply_index
+            .entry(room_id.to_string())
+            .or_default()
+            .insert(event_id.to_string(), preview);
+    }
+
+    fn set_typing(&mut self, room_id: String, user_ids: Vec<String>) {
+        if user_ids.is_empty() {
+            self.typing.remove(&room_id);
+        } else {
+            self.typing.insert(room_id, (user_ids, Instant::now()));
+        }
+    }
+
+    /// `alice is typing…` for the selected room.
+    fn typing_label(&self) -> Option<String> {
+        let room_id = self.selected_room_id()?;
+        let (user_ids, since) = self.typing.get(room_id)?;
+        if since.elapsed() >= TYPING_TIMEOUT {
+            return None;
+        }
+        let names: Vec<String> = user_ids
+            .iter()
+            .map(|user_id| self.sender_label(room_id, user_id))
+            .collect();
+        Some(match names.as_slice() {
+            [] => return None,
+            [name] => format!("{} is typing…", name),
+            [first, second] => format!("{} and {} are typing…", first, second),
+            _ => "Several people are typing…".to_string(),
+        })
+    }
+
+    fn mark_read_receipt(&mut self, room_id: &str, event_id: &str) {
+        self.read_receipts
+            .entry(room_id.to_string())
+            .or_default()
+            .insert(event_id.to_string());
+    }
+
+    /// Index of the newest message in `messages` someone else has read;
+    /// a receipt covers everything before it too.
+    fn read_position(&self, room_id: &str, messages: &Timeline) -> Option<usize> {
+        let read = self
+            .read_receipts
+            .get(room_id)
+            .filter(|read| !read.is_empty())?;
+        messages.iter().rposition(|item| {
+            message_ids(item)
+                .0
+                .is_some_and(|event_id| read.contains(event_id))
+        })
+    }
+
+    /// `Some(read)` for own messages, `None` for everyone else's.
+    fn read_receipt_for(
+        &self,
+        sender_id: &str,
+        idx: usize,
+        read_upto: Option<usize>,
+    ) -> Option<bool> {
+        if !is_own_sender(sender_id, self.own_user_id.as_deref()) {
+            return None;
+        }
+        Some(read_upto.is_some_and(|upto| idx <= upto))
+    }
+
+    /// The last own message at or before the read position of a DM.
+    fn find_read_marker(&self) -> Option<String> {
+        let room = self
+            .selected_room()
+            .filter(|room| room.direct_user.is_some())?;
+        let messages = self.visible_messages();
+        let upto = self.read_position(&room.room_id, &messages)?;
+        let own = self.own_user_id.as_deref();
+        let marker = messages
+            .range(..=upto)
+            .rev()
+            .find(|item| message_sender(item).is_some_and(|sender| is_own_sender(sender, own)));
+        marker.and_then(|item| message_ids(item).0).map(str::to_string)
+    }
+
+    /// The first message someone else sent after the divider's `since`.
+    fn find_unread_divider(&self) -> Option<String> {
+        let since = self.unread_divider.as_ref()?.since;
+        let own = self.own_user_id.as_deref();
+        self.visible_messages()
+            .iter()
+            .rev()
+            .take_while(|item| message_timestamp(item).is_none_or(|ts| ts > since))
+            .filter(|item| message_sender(item).is_some_and(|sender| !is_own_sender(sender, own)))
+            .last()
+            .and_then(|item| message_ids(item).0)
+            .map(str::to_string)
+    }
+
+    fn selected_attachment_path(&self) -> Option<String> {
+        match self.selected_message() {
+            Some(MessageItem::Attachment { path, .. }) => Some(path.clone()),
+            _ => None,
+        }
+    }
+
+    fn poll_selected(&self) -> bool {
+        matches!(self.selected_message(), Some(MessageItem::Poll { .. }))
+    }
+
+    /// A number key or `/vote <n>` on the selected poll: votes for that
+    /// answer, or on polls that allow several picks adds or drops it.
+    fn vote_selected_poll(&mut self, number: usize) -> Option<MatrixCommand> {
+        let room_id = self.selected_room_id().map(str::to_string)?;
+        if self.locked_elsewhere() {
+            return None;
+        }
+        let Some(MessageItem::Poll {
+            poll,
+            event_id: Some(poll_id),
+            ..
+        }) = self.selected_message()
+        else {
+            self.set_status("Select a poll with Alt+Up/Down first");
+            return None;
+        };
+        let (poll, poll_id) = (poll.clone(), poll_id.clone());
+        if poll.ended.is_some() {
+            self.set_status("The poll has ended");
+            return None;
+        }
+        let Some(answer) = poll.answers.get(number - 1) else {
+            self.set_status(&format!("The poll has {} answers", poll.answers.len()));
+            return None;
+        };
+        let mut answers: Vec<String> = self
+            .poll_tally(Some(&poll_id), &poll)
+            .iter()
+            .zip(&poll.answers)
+            .filter(|((_, own), _)| *own)
+            .map(|(_, answer)| answer.id.clone())
+            .collect();
+        if poll.max_selections <= 1 {
+            answers = vec![answer.id.clone()];
+        } else if let Some(pos) = answers.iter().position(|id| *id == answer.id) {
+            answers.remove(pos);
+        } else if answers.len() as u64 >= poll.max_selections {
+            self.set_status(&format!(
+                "Pick at most {}; vote for a picked answer again to drop it",
+                poll.max_selections
+            ));
+            return None;
+        } else {
+            answers.push(answer.id.clone());
+        }
+        Some(MatrixCommand::VotePoll {
+            room_id,
+            poll_id,
+            answers,
+            stable: poll.stable,
+        })
+    }
+
+    /// Alt+K on an undecryptable message: asks for its keys and tries again.
+    fn retry_selected_decryption(&mut self) -> Option<MatrixCommand> {
+        let room_id = self.selected_room_id().map(str::to_string)?;
+        let event_id = match self.selected_message() {
+            Some(MessageItem::Message {
+                text,
+                event_id: Some(event_id),
+                ..
+            }) if text == UNDECRYPTABLE_TEXT => event_id.clone(),
+            _ => {
+                self.set_status("Select an undecryptable message to request its keys");
+                return None;
+            }
+        };
+        self.set_status("Requesting keys and checking the key backup...");
+        Some(MatrixCommand::RetryDecryption { room_id, event_id })
+    }
+
+    fn apply_decrypted(&mut self, room_id: &str, event_id: &str, body: &str) {
+        let Some(items) = self.messages_by_room.get_mut(room_id) else {
+            return;
+        };
+        let sender = items.iter_mut().find_map(|item| match item {
+            MessageItem::Message {
+                text,
+                sender_id,
+                event_id: Some(id),
+                ..
+            } if id == event_id && text == UNDECRYPTABLE_TEXT => {
+                *text = body.to_string();
+                Some(sender_id.clone())
+            }
+            _ => None,
+        });
+        if let Some(sender) = sender {
+            self.set_reply_preview(room_id, event_id, &sender, body);
+        }
+    }
+
+    /// Marks the selected attachment as missing and asks for a new copy.
+    fn redownload_selected_attachment(&mut self) -> Option<MatrixCommand> {
+        let room_id = self.selected_room_id().map(str::to_string)?;
+        let (event_id, filename) = match self.selected_message()? {
+            MessageItem::Attachment {
+                event_id, filename, ..
+            } => (event_id.clone(), filename.clone()),
+            _ => return None,
+        };
+        let Some(event_id) = event_id else {
+            self.set_status("Attachment file is missing and cannot be re-downloaded");
+            return None;
+        };
+        for item in self.messages_by_room.get_mut(&room_id)? {
+            if let MessageItem::Attachment {
+                missing,
+                event_id: Some(id),
+                ..
+            } = item
+            {
+                if *id == event_id {
+                    *missing = true;
+                }
+            }
+        }
+        self.set_status(&format!("{} is missing, downloading it again...", filename));
+        Some(MatrixCommand::RedownloadAttachment { room_id, event_id })
+    }
+
+    fn restore_attachment(&mut self, room_id: &str, event_id: &str, new_path: &str) {
+        let Some(items) = self.messages_by_room.get_mut(room_id) else {
+            return;
+        };
+        let restored = items.iter_mut().find_map(|item| match item {
+            MessageItem::Attachment {
+                path,
+                missing,
+                event_id: Some(id),
+                filename,
+                ..
+            } if id == event_id => {
+                *path = new_path.to_string();
+                *missing = false;
+                Some(filename.clone())
+            }
+            _ => None,
+        });
+        if let Some(filename) = restored {
+            self.set_status(&format!("{} downloaded again", filename));
+        }
+    }
+
+    fn selected_message_event_id(&self) -> Option<String> {
+        match self.selected_message() {
+            Some(MessageItem::Message { event_id, .. }) => event_id.clone(),
+            Some(MessageItem::Attachment { event_id, .. }) => event_id.clone(),
+            Some(MessageItem::Poll { event_id, .. }) => event_id.clone(),
+            _ => None,
+        }
+    }
+
+    fn selected_message(&self) -> Option<&MessageItem> {
+        let idx = self.message_selected?;
+        self.visible_messages().get(idx)
+    }
+
+    fn current_messages(&self) -> Option<&Vec<MessageItem>> {
+        self.messages_by_room.get(self.selected_room_id()?)
+    }
+
+    /// Messages of the selected room as shown in the timeline. In read-only
+    /// mode the input box acts as a case-insensitive filter.
+    fn visible_messages(&self) -> Timeline<'_> {
+        let (Some(room_id), Some(messages)) = (self.selected_room_id(), self.current_messages())
+        else {
+            return Timeline::default();
+        };
+        // Thread replies are listed under their root (Alt+T) unless the
+        // root isn't loaded.
+        let seen = self.seen_event_ids.get(room_id);
+        let filtering = self.read_only && !self.input.trim().is_empty();
+        let key = TimelineKey {
+            generation: self.messages_by_room.generation(room_id),
+            thread_generation: self.thread_generation,
+            hide_state_events: self.hide_state_events,
+        };
+        if !filtering {
+            if let Some(cache) = self.timeline_cache.borrow().as_ref() {
+                if cache.room_id == room_id && cache.key == key {
+                    return Timeline {
+                        items: messages,
+                        shown: cache.shown.clone(),
+                    };
+                }
+            }
+        }
+        let in_thread = |item: &MessageItem| {
+            message_ids(item)
+                .0
+                .and_then(|id| self.thread_roots.get(id))
+                .is_some_and(|root| seen.is_some_and(|seen| seen.contains(root)))
+        };
+        let hidden = |item: &MessageItem| {
+            in_thread(item)
+                || (self.hide_state_events
+                    && matches!(
+                        item,
+                        MessageItem::Notice {
+                            state_change: true,
+                            ..
+                        }
+                    ))
+        };
+        let positions = messages
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| !hidden(item));
+        if !filtering {
+            let shown: Rc<[usize]> = positions.map(|(pos, _)| pos).collect();
+            *self.timeline_cache.borrow_mut() = Some(TimelineCache {
+                room_id: room_id.to_string(),
+                key,
+                shown: shown.clone(),
+            });
+            return Timeline {
+                items: messages,
+                shown,
+            };
+        }
+        let filter = self.input.trim().to_lowercase();
+        Timeline {
+            items: messages,
+            shown: positions
+                .filter(|(_, item)| !matches!(item, MessageItem::Separator(_)))
+                .filter(|(_, item)| msg_string(item).to_lowercase().contains(&filter))
+                .map(|(pos, _)| pos)
+                .collect(),
+        }
+    }
+
+    fn ensure_room_entries(&mut self, room_id: &str) {
+        self.messages_by_room.get_or_default(room_id);
+        self.seen_event_ids.entry(room_id.to_string()).or_default();
+        self.reply_index.entry(room_id.to_string()).or_default();
+        self.read_receipts.entry(room_id.to_string()).or_default();
+        self.unread_counts.entry(room_id.to_string()).or_default();
+        self.last_seen_ts.entry(room_id.to_string()).or_default();
+        self.last_message_ts.entry(room_id.to_string()).or_default();
+    }
+
+    fn update_rooms(&mut self, rooms: Vec<RoomInfo>) {
+        for room in &rooms {
+            self.ensure_room_entries(&room.room_id);
+        }
+        let selected = self.selected_room_id().map(str::to_string);
+        self.rooms = rooms;
+        self.hidden_rooms.clear();
+        let kept = selected.and_then(|id| self.rooms.iter().position(|room| room.room_id == id));
+        self.selected = kept.unwrap_or(0);
+        self.apply_workspace();
+        self.is_syncing = false;
+        if kept.is_none() {
+            self.message_selected = None;
+            if let Some(room_id) = self.selected_room_id().map(str::to_string) {
+                self.mark_room_read(&room_id);
+            }
+        }
+    }
+
+    /// Inserts a room at the end of its group (joined rooms before invites),
+    /// keeping the current selection on the same room.
+    fn add_room(&mut self, room: RoomInfo) {
+        self.ensure_room_entries(&room.room_id);
+        let had_rooms = !self.rooms.is_empty();
+        let idx = match room.state {
+            RoomListState::Joined => self
+                .rooms
+                .iter()
+                .position(|r| r.state == RoomListState::Invited)
+                .unwrap_or(self.rooms.len()),
+            RoomListState::Invited => self.rooms.len(),
+        };
+        self.rooms.insert(idx, room);
+        if had_rooms && idx <= self.selected {
+            self.selected += 1;
+        }
+        if !had_rooms {
+            self.selected = 0;
+            let room_id = self.rooms[0].room_id.clone();
+            self.mark_room_read(&room_id);
+        }
+        if self.workspace.is_some() {
+            self.apply_workspace();
+        }
+    }
+
+    fn update_room(&mut self, room: RoomInfo) {
+        if let Some(hidden) = self.hidden_rooms.iter_mut().find(|r| r.room_id == room.room_id) {
+            *hidden = room;
+            self.apply_workspace();
+            return;
+        }
+        let Some(idx) = self.rooms.iter().position(|r| r.room_id == room.room_id) else {
+            self.add_room(room);
+            return;
+        };
+        if self.rooms[idx].state == room.state {
+            self.rooms[idx] = room;
+            if self.workspace.is_some() {
+                self.apply_workspace();
+            }
+            return;
+        }
+        let was_selected = idx == self.selected;
+        let room_id = room.room_id.clone();
+        self.remove_room(&room_id);
+        self.add_room(room);
+        if was_selected {
+            if let Some(idx) = self.rooms.iter().position(|r| r.room_id == room_id) {
+                self.selected = idx;
+                self.message_selected = None;
+                self.mark_room_read(&room_id);
+            }
+        }
+    }
+
+    fn remove_room(&mut self, room_id: &str) {
+        if self.compose_lock.as_deref() == Some(room_id) {
+            self.compose_lock = None;
+            self.set_status("Input lock released: the room is gone");
+        }
+        self.hidden_rooms.retain(|r| r.room_id != room_id);
+        let Some(idx) = self.rooms.iter().position(|r| r.room_id == room_id) else {
+            return;
+        };
+        self.rooms.remove(idx);
+        if idx < self.selected {
+            self.selected -= 1;
+        } else if idx == self.selected {
+            self.selected = self.selected.min(self.rooms.len().saturating_sub(1));
+            self.message_selected = None;
+            if let Some(room_id) = self.selected_room_id().map(str::to_string) {
+                self.mark_room_read(&room_id);
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn handle_incoming_message(
+        &mut self,
+        room_id: &str,
+        event_id: Option<&str>,
+        ts: i64,
+        sender: &str,
+        body: &str,
+        reply_to: Option<&str>,
+        muted: bool,
+    ) {
+        let is_selected = self.selected_room_id() == Some(room_id);
+        let last_seen = *self.last_seen_ts.get(room_id).unwrap_or(&0);
+        if !is_selected && !muted && ts > last_seen {
+            let mention = self.mentions_me(body);
+            self.bump_unread(room_id, mention);
+        }
+        self.push_message_with_time(room_id, event_id, ts, sender, body, reply_to);
+        if let Some(event_id) = event_id {
+            self.search_index.add_text(room_id, event_id, ts, body);
+        }
+        self.note_aliases(body);
+        if is_selected {
+            self.mark_room_read(room_id);
+        }
+        self.note_activity();
+    }
+
+    fn handle_incoming_poll(
+        &mut self,
+        room_id: &str,
+        event_id: &str,
+        ts: i64,
+        sender: &str,
+        poll: StoredPoll,
+    ) {
+        let is_selected = self.selected_room_id() == Some(room_id);
+        let last_seen = *self.last_seen_ts.get(room_id).unwrap_or(&0);
+        if !is_selected && ts > last_seen {
+            self.bump_unread(room_id, false);
+        }
+        self.search_index
+            .add_text(room_id, event_id, ts, &poll.question);
+        self.push_poll_with_time(room_id, event_id, ts, sender, poll);
+        if is_selected {
+            self.mark_room_read(room_id);
+        }
+        self.note_activity();
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn handle_incoming_attachment(
+        &mut self,
+        room_id: &str,
+        event_id: Option<&str>,
+        ts: i64,
+        sender: &str,
+        label: &str,
+        filename: &str,
+        path: &str,
+        reply_to: Option<&str>,
+        muted: bool,
+    ) {
+        let is_selected = self.selected_room_id() == Some(room_id);
+        let last_seen = *self.last_seen_ts.get(room_id).unwrap_or(&0);
+        if !is_selected && !muted && ts > last_seen {
+            self.bump_unread(room_id, false);
+        }
+        self.push_attachment_with_time(
+            room_id,
+            event_id,
+            ts,
+            sender,
+            label,
+            filename,
+            path,
+            reply_to,
+        );
+        if let Some(event_id) = event_id {
+            self.search_index.add_text(room_id, event_id, ts, filename);
+        }
+        if is_selected {
+            self.mark_room_read(room_id);
+        }
+        self.note_activity();
+    }
+
+    /// `marty — #rust (3 unread)`: the selected room and the unread
+    /// messages in the others, for the terminal title.
+    fn window_title(&self) -> String {
+        let mut title = match self.selected_room() {
+            Some(room) => format!("marty — {}", self.room_label(room)),
+            None => "marty".to_string(),
+        };
+        let unread: usize = self.unread_counts.values().sum();
+        if unread > 0 {
+            title.push_str(&format!(" ({} unread)", unread));
+        }
+        title
+    }
+
+    fn room_name<'a>(&'a self, room_id: &'a str) -> Cow<'a, str> {
+        self.rooms
+            .iter()
+            .chain(&self.hidden_rooms)
+            .find(|room| room.room_id == room_id)
+            .map_or(Cow::Borrowed(room_id), |room| self.room_label(room))
+    }
+
+    /// Room name as listed. Direct chats whose name another room shares get
+    /// the contact's server, and further DMs with the same contact a number,
+    /// so several "alice" entries can be told apart.
+    fn room_label<'a>(&self, room: &'a RoomInfo) -> Cow<'a, str> {
+        let Some(user) = &room.direct_user else {
+            return Cow::Borrowed(&room.name);
+        };
+        let ambiguous = self.rooms.iter().any(|other| {
+            other.room_id != room.room_id
+                && other.name == room.name
+                && other.direct_user.as_ref() != Some(user)
+        });
+        let mut label = match user.split_once(':') {
+            Some((_, server)) if ambiguous => Cow::Owned(format!("{} ({})", room.name, server)),
+            _ => Cow::Borrowed(room.name.as_str()),
+        };
+        let position = self
+            .rooms
+            .iter()
+            .filter(|other| other.direct_user.as_ref() == Some(user))
+            .position(|other| other.room_id == room.room_id);
+        if let Some(n) = position.filter(|idx| *idx > 0) {
+            label.to_mut().push_str(&format!(" #{}", n + 1));
+        }
+        label
+    }
+
+    fn should_notify(&self, room_id: &str, sender: &str) -> bool {
+        if !self.notifications_ready {
+            return false;
+        }
+        if self.selected_room_id() == Some(room_id) {
+            return false;
+        }
+        if let Some(own) = self.own_user_id.as_deref() {
+            if sender == own {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Whether `room_id`, or its alias, is in a configured room list.
+    fn room_listed(&self, list: &HashSet<String>, room_id: &str) -> bool {
+        room_listed(list, self.rooms.iter().chain(&self.hidden_rooms), room_id)
+    }
+
+    /// Severity of `text` when `room_id` is an alert room.
+    fn alert_severity(&self, room_id: Option<&str>, text: &str) -> Option<Severity> {
+        let rooms = self.rooms.iter().chain(&self.hidden_rooms);
+        alert_severity(&self.alert_rooms, rooms, room_id?, text)
+    }
+
+    /// `/ack`: replies to the selected alert, or the latest unresolved one,
+    /// with `alert_ack_template`.
+    fn acknowledge_alert(&mut self) -> Option<MatrixCommand> {
+        let room_id = self.selected_room_id().map(str::to_string)?;
+        if self.locked_elsewhere() {
+            return None;
+        }
+        if !self.room_listed(&self.alert_rooms, &room_id) {
+            self.set_status("Not an alert room (see alert_rooms)");
+            return None;
+        }
+        let selected = self.selected_message().cloned();
+        let unresolved = |item: &&MessageItem| match item {
+            MessageItem::Message { text, .. } => {
+                matches!(Severity::parse(text), Some(Severity::Critical | Severity::Warning))
+            }
+            _ => false,
+        };
+        let alert = selected.or_else(|| {
+            let items = self.messages_by_room.get(&room_id)?;
+            items.iter().rev().find(unresolved).cloned()
+        });
+        let Some(MessageItem::Message {
+            sender_id,
+            text,
+            event_id: Some(event_id),
+            ..
+        }) = alert
+        else {
+            self.set_status("No alert to acknowledge");
+            return None;
+        };
+        let severity = Severity::parse(&text).map_or("alert", Severity::name);
+        let summary = text.lines().next().unwrap_or_default();
+        let body = self
+            .alert_ack_template
+            .as_deref()
+            .unwrap_or("Acknowledged: {summary}")
+            .replace("{severity}", severity)
+            .replace("{sender}", &self.sender_label(&room_id, &sender_id))
+            .replace("{summary}", summary);
+        self.message_selected = None;
+        Some(MatrixCommand::SendMessage {
+            room_id,
+            body,
+            reply_to: Some(event_id),
+            mentions: Vec::new(),
+        })
+    }
+
+    /// Speaks a new message from someone else in one of `tts_rooms`,
+    /// whether or not the room is open.
+    fn announce(&self, room_id: &str, sender: &str, text: &str) {
+        let Some(speaker) = &self.speaker else {
+            return;
+        };
+        if !self.notifications_ready || self.own_user_id.as_deref() == Some(sender) {
+            return;
+        }
+        if self.room_listed(&self.tts_rooms, room_id) {
+            speaker.say(format!(
+                "{} in {}: {}",
+                self.sender_label(room_id, sender),
+                self.room_name(room_id),
+                text
+            ));
+        }
+    }
+
+    fn mark_room_read(&mut self, room_id: &str) {
+        // Only rooms with counted unreads get a divider; messages arriving
+        // in the open room are marked read without being counted.
+        if self
+            .unread_counts
+            .get(room_id)
+            .is_some_and(|count| *count > 0)
+        {
+            self.unread_divider = Some(UnreadDivider {
+                room_id: room_id.to_string(),
+                since: self.last_seen_ts.get(room_id).copied().unwrap_or_default(),
+                event_id: None,
+                shown: false,
+            });
+        }
+        if let Some(ts) = self.last_message_ts.get(room_id).copied() {
+            self.last_seen_ts.insert(room_id.to_string(), ts);
+        }
+        self.unread_counts.insert(room_id.to_string(), 0);
+        self.highlight_counts.remove(room_id);
+    }
+
+    fn push_message_with_time(
+        &mut self,
+        room_id: &str,
+        event_id: Option<&str>,
+        ts: i64,
+        sender: &str,
+        body: &str,
+        reply_to: Option<&str>,
+    ) {
+        let body = if reply_to.is_some() {
+            strip_reply_fallback(body)
+        } else {
+            body
+        };
+        if let Some(event_id) = event_id {
+            let seen = self.seen_event_ids.entry(room_id.to_string()).or_default();
+            if !seen.insert(event_id.to_string()) {
+                return;
+            }
+        }
+        let new_sender = self.note_sender(room_id, sender);
+        let name = self.sender_label(room_id, sender);
+        let date = format_date(ts);
+        let entry = self.messages_by_room.get_or_default(room_id);
+        let last_date = self.last_date_by_room.entry(room_id.to_string()).or_default();
+        if last_date != &date {
+            entry.push(MessageItem::Separator(date.clone()));
+            *last_date = date;
+        }
+        entry.push(MessageItem::Message {
+            time: format_timestamp(ts),
+            timestamp: ts,
+            sender_id: sender.to_string(),
+            name: name.clone(),
+            text: body.to_string(),
+            event_id: event_id.map(|id| id.to_string()),
+            reply_to: reply_to.map(|id| id.to_string()),
+            edited: false,
+        });
+        if let Some(event_id) = event_id {
+            let previews = self.reply_index.entry(room_id.to_string()).or_default();
+            previews.insert(
+                event_id.to_string(),
+                ReplyPreview {
+                    sender_id: sender.to_string(),
+                    sender: name.clone(),
+                    text: body.to_string(),
+                },
+            );
+        }
+        self.last_message_ts
+            .insert(room_id.to_string(), ts);
+        if new_sender {
+            self.relabel_if_ambiguous(room_id, sender, &name);
+        }
+    }
+
+    fn add_reaction(&mut self, target: &str, reaction: StoredReaction) {
+        let reactions = self.reactions.entry(target.to_string()).or_default();
+        if reactions.iter().any(|r| r.event_id == reaction.event_id) {
+            return;
+        }
+        self.reaction_targets
+            .insert(reaction.event_id.clone(), target.to_string());
+        reactions.push(reaction);
+    }
+
+    fn add_vote(&mut self, poll_id: &str, vote: StoredVote) {
+        let votes = self.poll_votes.entry(poll_id.to_string()).or_default();
+        if !votes.iter().any(|v| v.event_id == vote.event_id) {
+            votes.push(vote);
+        }
+    }
+
+    /// Drops a redacted vote; false when `event_id` isn't one.
+    fn remove_vote(&mut self, event_id: &str) -> bool {
+        for votes in self.poll_votes.values_mut() {
+            if let Some(pos) = votes.iter().position(|v| v.event_id == event_id) {
+                votes.remove(pos);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Votes per answer of `poll`, and whether we picked it. Only each
+    /// sender's latest response before the poll ended counts.
+    fn poll_tally(&self, event_id: Option<&str>, poll: &StoredPoll) -> Vec<(usize, bool)> {
+        let mut latest: HashMap<&str, &StoredVote> = HashMap::new();
+        let votes = event_id.and_then(|id| self.poll_votes.get(id));
+        let open = |vote: &&StoredVote| poll.ended.is_none_or(|ended| vote.timestamp <= ended);
+        for vote in votes.into_iter().flatten().filter(open) {
+            let newer = latest
+                .get(vote.sender.as_str())
+                .is_none_or(|seen| vote.timestamp >= seen.timestamp);
+            if newer {
+                latest.insert(&vote.sender, vote);
+            }
+        }
+        let own = self.own_user_id.as_deref();
+        let mut tally = vec![(0, false); poll.answers.len()];
+        for vote in latest.values() {
+            for idx in poll_picks(poll, &vote.answers) {
+                tally[idx].0 += 1;
+                tally[idx].1 |= own == Some(vote.sender.as_str());
+            }
+        }
+        tally
+    }
+
+    /// `👍 3  🎉 1`: reaction keys in the order first used, with counts.
+    fn reaction_summary(&self, event_id: Option<&str>) -> Option<String> {
+        let reactions = self.reactions.get(event_id?)?;
+        let mut counts: Vec<(&str, usize)> = Vec::new();
+        for reaction in reactions {
+            match counts.iter_mut().find(|(key, _)| *key == reaction.key) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((&reaction.key, 1)),
+            }
+        }
+        let parts: Vec<String> = counts
+            .iter()
+            .map(|(key, count)| format!("{} {}", key, count))
+            .collect();
+        (!parts.is_empty()).then(|| parts.join("  "))
+    }
+
+    fn mark_edited(&mut self, room_id: &str, event_id: Option<&str>) {
+        let Some(event_id) = event_id else {
+            return;
+        };
+        let item = self
+            .messages_by_room
+            .get_mut(room_id)
+            .and_then(|items| items.iter_mut().rev().find(|item| message_ids(item).0 == Some(event_id)));
+        if let Some(MessageItem::Message { edited, .. }) = item {
+            *edited = true;
+        }
+    }
+
+    /// Marks a loaded poll ended, when `sender` started it.
+    fn end_poll(&mut self, room_id: &str, target: &str, sender: &str, timestamp: i64) {
+        let item = self.messages_by_room.get_mut(room_id).and_then(|items| {
+            items.iter_mut().find(|item| {
+                message_ids(item).0 == Some(target) && message_sender(item) == Some(sender)
+            })
+        });
+        if let Some(MessageItem::Poll { poll, .. }) = item {
+            if poll.ended.is_none_or(|ended| ended > timestamp) {
+                poll.ended = Some(timestamp);
+            }
+        }
+    }
+
+    /// Applies an edit, reaction, vote, poll end or redaction to a loaded
+    /// message.
+    /// Drops the local traces of a redacted event besides its timeline
+    /// item: a reaction or vote is removed from its target, a message loses
+    /// its reactions, votes and search index words. Returns whether the
+    /// event was a message whose timeline item must be replaced.
+    fn purge_redacted(&mut self, target: &str) -> bool {
+        if self.remove_vote(target) {
+            return false;
+        }
+        self.poll_votes.remove(target);
+        if let Some(annotated) = self.reaction_targets.remove(target) {
+            if let Some(reactions) = self.reactions.get_mut(&annotated) {
+                reactions.retain(|r| r.event_id != target);
+                if reactions.is_empty() {
+                    self.reactions.remove(&annotated);
+                }
+            }
+            return false;
+        }
+        if let Some(reactions) = self.reactions.remove(target) {
+            for reaction in reactions {
+                self.reaction_targets.remove(&reaction.event_id);
+            }
+        }
+        self.search_index.remove(target);
+        true
+    }
+
+    fn apply_relation(&mut self, room_id: &str, relation: &EventRelation) {
+        let (target, sender, new_text) = match relation {
+            EventRelation::Edit {
+                target,
+                sender,
+                body,
+            } => (target, Some(sender), body.as_str()),
+            EventRelation::Redaction { target } => {
+                if !self.purge_redacted(target) {
+                    return;
+                }
+                (target, None, REDACTED_TEXT)
+            }
+            EventRelation::Reaction {
+                event_id,
+                target,
+                sender,
+                key,
+            } => {
+                self.add_reaction(
+                    target,
+                    StoredReaction {
+                        event_id: event_id.clone(),
+                        sender: sender.clone(),
+                        key: key.clone(),
+                    },
+                );
+                return;
+            }
+            EventRelation::PollVote {
+                event_id,
+                target,
+                sender,
+                answers,
+                timestamp,
+            } => {
+                self.add_vote(
+                    target,
+                    StoredVote {
+                        event_id: event_id.clone(),
+                        sender: sender.clone(),
+                        answers: answers.clone(),
+                        timestamp: *timestamp,
+                    },
+                );
+                return;
+            }
+            EventRelation::PollEnd {
+                target,
+                sender,
+                timestamp,
+            } => {
+                self.end_poll(room_id, target, sender, *timestamp);
+                return;
+            }
+        };
+        let Some(items) = self.messages_by_room.get_mut(room_id) else {
+            return;
+        };
+        let Some(item) = items.iter_mut().find(|item| {
+            message_ids(item).0 == Some(target.as_str())
+                && sender.is_none_or(|sender| message_sender(item) == Some(sender.as_str()))
+        }) else {
+            return;
+        };
+        match item {
+            MessageItem::Message { text, edited, .. } => {
+                *text = new_text.to_string();
+                *edited = sender.is_some();
+                if sender.is_some() {
+                    self.search_index.update_text(target, new_text);
+                }
+            }
+            MessageItem::Attachment {
+                time,
+                timestamp,
+                sender_id,
+                name,
+                event_id,
+                reply_to,
+                ..
+            } => {
+                // Edits of attachments only change their caption; keep the
+                // file, but drop it from view once redacted.
+                if sender.is_some() {
+                    return;
+                }
+                *item = MessageItem::Message {
+                    time: time.clone(),
+                    timestamp: *timestamp,
+                    sender_id: sender_id.clone(),
+                    name: name.clone(),
+                    text: new_text.to_string(),
+                    event_id: event_id.clone(),
+                    reply_to: reply_to.clone(),
+                    edited: false,
+                };
+            }
+            MessageItem::Poll {
+                time,
+                timestamp,
+                sender_id,
+                name,
+                event_id,
+                ..
+            } => {
+                if sender.is_some() {
+                    return;
+                }
+                *item = MessageItem::Message {
+                    time: time.clone(),
+                    timestamp: *timestamp,
+                    sender_id: sender_id.clone(),
+                    name: name.clone(),
+                    text: new_text.to_string(),
+                    event_id: event_id.clone(),
+                    reply_to: None,
+                    edited: false,
+                };
+            }
+            _ => return,
+        }
+        if let Some(preview) = self
+            .reply_index
+            .get_mut(room_id)
+            .and_then(|previews| previews.get_mut(target))
+        {
+            preview.text = new_text.to_string();
+        }
+    }
+
+    fn push_notice_with_time(&mut self, room_id: &str, ts: i64, text: &str, state_change: bool) {
+        let date = format_date(ts);
+        let entry = self.messages_by_room.get_or_default(room_id);
+        let last_date = self.last_date_by_room.entry(room_id.to_string()).or_default();
+        if last_date != &date {
+            entry.push(MessageItem::Separator(date.clone()));
+            *last_date = date;
+        }
+        entry.push(MessageItem::Notice {
+            time: format_timestamp(ts),
+            text: text.to_string(),
+            state_change,
+        });
+    }
+
+    fn push_poll_with_time(
+        &mut self,
+        room_id: &str,
+        event_id: &str,
+        ts: i64,
+        sender: &str,
+        poll: StoredPoll,
+    ) {
+        let seen = self.seen_event_ids.entry(room_id.to_string()).or_default();
+        if !seen.insert(event_id.to_string()) {
+            return;
+        }
+        let new_sender = self.note_sender(room_id, sender);
+        let name = self.sender_label(room_id, sender);
+        let date = format_date(ts);
+        let entry = self.messages_by_room.get_or_default(room_id);
+        let last_date = self.last_date_by_room.entry(room_id.to_string()).or_default();
+        if last_date != &date {
+            entry.push(MessageItem::Separator(date.clone()));
+            *last_date = date;
+        }
+        let previews = self.reply_index.entry(room_id.to_string()).or_default();
+        previews.insert(
+            event_id.to_string(),
+            ReplyPreview {
+                sender_id: sender.to_string(),
+                sender: name.clone(),
+                text: format!("[poll] {}", poll.question),
+            },
+        );
+        entry.push(MessageItem::Poll {
+            time: format_timestamp(ts),
+            timestamp: ts,
+            sender_id: sender.to_string(),
+            name: name.clone(),
+            poll,
+            event_id: Some(event_id.to_string()),
+        });
+        self.last_message_ts.insert(room_id.to_string(), ts);
+        if new_sender {
+            self.relabel_if_ambiguous(room_id, sender, &name);
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn push_attachment_with_time(
+        &mut self,
+        room_id: &str,
+        event_id: Option<&str>,
+        ts: i64,
+        sender: &str,
+        label: &str,
+        filename: &str,
+        path: &str,
+        reply_to: Option<&str>,
+    ) {
+        if let Some(event_id) = event_id {
+            let seen = self.seen_event_ids.entry(room_id.to_string()).or_default();
+            if !seen.insert(event_id.to_string()) {
+                return;
+            }
+        }
+        let new_sender = self.note_sender(room_id, sender);
+        let name = self.sender_label(room_id, sender);
+        let date = format_date(ts);
+        let entry = self.messages_by_room.get_or_default(room_id);
+        let last_date = self.last_date_by_room.entry(room_id.to_string()).or_default();
+        if last_date != &date {
+            entry.push(MessageItem::Separator(date.clone()));
+            *last_date = date;
+        }
+        entry.push(MessageItem::Attachment {
+            time: format_timestamp(ts),
+            timestamp: ts,
+            sender_id: sender.to_string(),
+            name: name.clone(),
+            label: label.to_string(),
+            filename: filename.to_string(),
+            path: path.to_string(),
+            missing: !Path::new(path).exists(),
+            event_id: event_id.map(|id| id.to_string()),
+            reply_to: reply_to.map(|id| id.to_string()),
+        });
+        if let Some(event_id) = event_id {
+            let previews = self.reply_index.entry(room_id.to_string()).or_default();
+            previews.insert(
+                event_id.to_string(),
+                ReplyPreview {
+                    sender_id: sender.to_string(),
+                    sender: name.clone(),
+                    text: format!("[{}] {}", label, filename),
+                },
+            );
+        }
+        self.last_message_ts
+            .insert(room_id.to_string(), ts);
+        if new_sender {
+            self.relabel_if_ambiguous(room_id, sender, &name);
+        }
+    }
+
+    /// Records `sender` as active in the room; true the first time.
+    fn note_sender(&mut self, room_id: &str, sender: &str) -> bool {
+        self.room_senders
+            .entry(room_id.to_string())
+            .or_default()
+            .insert(sender.to_string())
+    }
+
+    /// Label for `sender` under the configured `sender_format`.
+    fn sender_label(&self, room_id: &str, sender: &str) -> String {
+        sender_label(
+            self.sender_format,
+            self.nickname(room_id, sender),
+            self.room_members.get(room_id).map_or(&[], Vec::as_slice),
+            self.room_senders.get(room_id).into_iter().flatten(),
+            sender,
+        )
+    }
+
+    /// The local nickname for `user_id`, room-scoped ones first.
+    fn nickname(&self, room_id: &str, user_id: &str) -> Option<&str> {
+        nickname(&self.nicknames, &self.room_nicknames, room_id, user_id)
+    }
+
+    /// `/nickname`: sets or clears a local nickname, for the selected room
+    /// or (`global`) everywhere, and saves it to the config file.
+    fn set_nickname(&mut self, global: bool, user_id: String, nickname: Option<String>) {
+        let room_id = match (global, self.selected_room_id().map(str::to_string)) {
+            (true, _) => None,
+            (false, Some(room_id)) => Some(room_id),
+            (false, None) => {
+                self.set_status("No room selected");
+                return;
+            }
+        };
+        let apply = |nicknames: &mut HashMap<String, String>| match &nickname {
+            Some(nickname) => {
+                nicknames.insert(user_id.clone(), nickname.clone());
+            }
+            None => {
+                nicknames.remove(&user_id);
+            }
+        };
+        match &room_id {
+            Some(room_id) => {
+                let nicknames = self.room_nicknames.entry(room_id.clone()).or_default();
+                apply(nicknames);
+                if nicknames.is_empty() {
+                    self.room_nicknames.remove(room_id);
+                }
+            }
+            None => apply(&mut self.nicknames),
+        }
+        let saved = update_config(|cfg| match &room_id {
+            Some(room_id) => {
+                let nicknames = cfg.room_nicknames.entry(room_id.clone()).or_default();
+                apply(nicknames);
+                if nicknames.is_empty() {
+                    cfg.room_nicknames.remove(room_id);
+                }
+            }
+            None => apply(&mut cfg.nicknames),
+        });
+        let rooms: Vec<String> = match room_id {
+            Some(room_id) => vec![room_id],
+            None => self.messages_by_room.keys().cloned().collect(),
+        };
+        for room_id in rooms {
+            self.relabel_room(&room_id);
+        }
+        let action = match &nickname {
+            Some(nickname) => format!("{} is now {}", user_id, nickname),
+            None => format!("Nickname for {} cleared", user_id),
+        };
+        match saved {
+            Ok(()) => self.set_status(&action),
+            Err(err) => self.set_status(&format!("{} (not saved: {})", action, err)),
+        }
+    }
+
+    /// A new sender whose label collides with others changes their labels
+    /// too, so relabel the room.
+    fn relabel_if_ambiguous(&mut self, room_id: &str, sender: &str, label: &str) {
+        if self.sender_format != SenderFormat::Mxid && label != format_sender(sender) {
+            self.relabel_room(room_id);
+        }
+    }
+
+    fn relabel_room(&mut self, room_id: &str) {
+        let Some(mut items) = self.messages_by_room.remove(room_id) else {
+            return;
+        };
+        let mut labels: HashMap<String, String> = HashMap::new();
+        for item in &mut items {
+            if let MessageItem::Message {
+                sender_id, name, ..
+            }
+            | MessageItem::Attachment {
+                sender_id, name, ..
+            }
+            | MessageItem::Poll {
+                sender_id, name, ..
+            } = item
+            {
+                *name = labels
+                    .entry(sender_id.clone())
+                    .or_insert_with(|| self.sender_label(room_id, sender_id))
+                    .clone();
+            }
+        }
+        self.messages_by_room.insert(room_id.to_string(), items);
+        if let Some(mut previews) = self.reply_index.remove(room_id) {
+            for preview in previews.values_mut() {
+                preview.sender = labels
+                    .entry(preview.sender_id.clone())
+                    .or_insert_with(|| self.sender_label(room_id, &preview.sender_id))
+                    .clone();
+            }
+            self.reply_index.insert(room_id.to_string(), previews);
+        }
+    }
+}
+
+fn format_timestamp(ts: i64) -> String {
+    Local
+        .timestamp_millis_opt(ts)
+        .single()
+        .unwrap_or_else(Local::now)
+        .format("%H:%M")
+        .to_string()
+}
+
+fn format_full_timestamp(ts: i64) -> String {
+    Local
+        .timestamp_millis_opt(ts)
+        .single()
+        .unwrap_or_else(Local::now)
+        .format("%Y-%m-%d %H:%M:%S %:z")
+        .to_string()
+}
+
+fn format_date(ts: i64) -> String {
+    Local
+        .timestamp_millis_opt(ts)
+        .single()
+        .unwrap_or_else(Local::now)
+        .format("%A, %m/%d/%y")
+        .to_string()
+}
+
+/// `ts` relative to `now` (both ms): "just now", "5m ago", ... and the
+/// date past a week.
+fn format_age(ts: i64, now: i64) -> String {
+    let minutes = (now - ts).max(0) / 60_000;
+    match minutes {
+        0 => "just now".to_string(),
+        1..=59 => format!("{}m ago", minutes),
+        60..=1439 => format!("{}h ago", minutes / 60),
+        1440..=10079 => format!("{}d ago", minutes / 1440),
+        _ => Local
+            .timestamp_millis_opt(ts)
+            .single()
+            .map(|time| time.format("%Y-%m-%d").to_string())
+            .unwrap_or_default(),
+    }
+}
+
+/// Slash commands and their argument hints, for usage errors and Tab
+/// completion. `/search`, `/format`, `/nickname` and the key export commands
+/// are handled by the UI before `parse_command`.
+const COMMANDS: [(&str, &str); 24] = [
+    ("/join", "<#alias:server|!room_id:server>"),
+    ("/leave", ""),
+    ("/msg", "<@user|#alias|!room_id> <text>"),
+    ("/invite", "<@user:server>"),
+    ("/topic", "<text>"),
+    ("/nick", "<display name>"),
+    ("/device-name", "<name>"),
+    ("/nickname", "[--global] <@user:server> [nickname]"),
+    ("/upload", "<path>"),
+    ("/plain", "<text>"),
+    ("/format", "<plain|rich>"),
+    ("/mute-thread", ""),
+    ("/workspace", "[name]"),
+    ("/bridge", "<status|relink|action> [bridge]"),
+    ("/ack", ""),
+    ("/vote", "<answer number>"),
+    ("/template", "[name]"),
+    ("/schedule", "<HH:MM|+30m|+2h|+1d> <text>"),
+    ("/search", "<words>"),
+    ("/export-keys", "<path>"),
+    ("/import-keys", "<path>"),
+    ("/rotate-key", ""),
+    ("/export-user", "<@user:server> <path>"),
+    ("/send-event", "[--state] <type> [state_key] <json>"),
+];
+
+fn command_usage(name: &str) -> String {
+    let hint = COMMANDS
+        .iter()
+        .find(|(command, _)| *command == name)
+        .map(|(_, hint)| *hint)
+        .unwrap_or_default();
+    format!("Usage: {} {}", name, hint).trim_end().to_string()
+}
+
+/// Parses slash commands typed into the input. Returns `None` for plain
+/// messages (including `//`-escaped ones) and `Some(Err(..))` with a usage
+/// hint for unknown or malformed commands.
+pub fn parse_command(text: &str, room_id: Option<&str>) -> Option<Result<MatrixCommand, String>> {
+    if !text.starts_with('/') || text.starts_with("//") {
+        return None;
+    }
+    let (name, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let args = args.trim();
+    let room = room_id.map(str::to_string).ok_or("No room selected".to_string());
+    let parsed = match name {
+        "/join" if matrix_sdk::ruma::RoomOrAliasId::parse(args).is_ok() => {
+            Ok(MatrixCommand::JoinRoom {
+                room: args.to_string(),
+            })
+        }
+        "/leave" if args.is_empty() => room.map(|room_id| MatrixCommand::LeaveRoom { room_id }),
+        "/msg" => match args.split_once(char::is_whitespace) {
+            Some((target, body)) if target.starts_with(['@', '#', '!']) => {
+                Ok(MatrixCommand::SendTo {
+                    target: target.to_string(),
+                    body: body.trim().to_string(),
+                })
+            }
+            _ => Err(command_usage(name)),
+        },
+        "/invite" if matrix_sdk::ruma::UserId::parse(args).is_ok() => room.map(|room_id| {
+            MatrixCommand::InviteUser {
+                room_id,
+                user_id: args.to_string(),
+            }
+        }),
+        "/topic" if !args.is_empty() => room.map(|room_id| MatrixCommand::SetTopic {
+            room_id,
+            topic: args.to_string(),
+        }),
+        "/nick" if !args.is_empty() => Ok(MatrixCommand::SetDisplayName {
+            name: args.to_string(),
+        }),
+        "/device-name" if !args.is_empty() => Ok(MatrixCommand::RenameDevice {
+            name: args.to_string(),
+        }),
+        "/upload" if !args.is_empty() => {
+            if Path::new(args).is_file() {
+                room.map(|room_id| MatrixCommand::SendAttachment {
+                    room_id,
+                    path: args.to_string(),
+                    reply_to: None,
+                    temporary: false,
+                })
+            } else {
+                Err(format!("No such file: {}", args))
+            }
+        }
+        "/plain" if !args.is_empty() => room.map(|room_id| MatrixCommand::SendMessage {
+            room_id,
+            body: args.to_string(),
+            reply_to: None,
+            mentions: Vec::new(),
+        }),
+        "/export-user" => match args.split_once(char::is_whitespace) {
+            Some((user, path)) if matrix_sdk::ruma::UserId::parse(user).is_ok() => {
+                absolute_path(path.trim()).map(|path| MatrixCommand::ExportSender {
+                    user_id: user.to_string(),
+                    path,
+                })
+            }
+            _ => Err(command_usage(name)),
+        },
+        "/rotate-key" if args.is_empty() => {
+            room.map(|room_id| MatrixCommand::RotateRoomKey { room_id })
+        }
+        "/send-event" => parse_send_event(args, room_id),
+        _ if COMMANDS.iter().any(|(command, _)| *command == name) => Err(command_usage(name)),
+        _ => Err(format!(
+            "Unknown command: {} (Tab completes commands, // sends a literal /)",
+            name
+        )),
+    };
+    Some(parsed)
+}
+
+/// Strips the `//` escape that sends a message starting with `/`.
+fn unescape_slash(text: String) -> String {
+    match text.strip_prefix("//") {
+        Some(rest) => format!("/{}", rest),
+        None => text,
+    }
+}
+
+fn message_ids(item: &MessageItem) -> (Option<&str>, Option<&str>) {
+    match item {
+        MessageItem::Message {
+            event_id, reply_to, ..
+        }
+        | MessageItem::Attachment {
+            event_id, reply_to, ..
+        } => (event_id.as_deref(), reply_to.as_deref()),
+        MessageItem::Poll { event_id, .. } => (event_id.as_deref(), None),
+        _ => (None, None),
+    }
+}
+
+fn message_sender(item: &MessageItem) -> Option<&str> {
+    match item {
+        MessageItem::Message { sender_id, .. }
+        | MessageItem::Attachment { sender_id, .. }
+        | MessageItem::Poll { sender_id, .. } => Some(sender_id),
+        _ => None,
+    }
+}
+
+fn message_timestamp(item: &MessageItem) -> Option<i64> {
+    match item {
+        MessageItem::Message { timestamp, .. }
+        | MessageItem::Attachment { timestamp, .. }
+        | MessageItem::Poll { timestamp, .. } => Some(*timestamp),
+        _ => None,
+    }
+}
+
+/// In the compact layout, whether `messages[idx]` continues a run from the
+/// same sender and leaves out the time and name. Replies always show them.
+fn continues_group(app: &App, messages: &Timeline, idx: usize) -> bool {
+    if !app.compact_layout || idx == 0 || message_ids(&messages[idx]).1.is_some() {
+        return false;
+    }
+    let (item, prev) = (&messages[idx], &messages[idx - 1]);
+    let same_sender =
+        message_sender(item).is_some() && message_sender(item) == message_sender(prev);
+    let gap = message_timestamp(item)
+        .zip(message_timestamp(prev))
+        .map(|(ts, prev_ts)| ts - prev_ts);
+    same_sender && gap.is_some_and(|gap| gap <= GROUP_WINDOW_MS)
+}
+
+/// Blanks the time and name of a message prefix, keeping its width.
+fn hide_sender(spans: &mut [Span<'static>]) {
+    for span in spans.iter_mut().skip(1) {
+        *span = Span::raw(" ".repeat(span.width()));
+    }
+}
+
+fn chain_entry(item: &MessageItem, depth: usize, focused: bool) -> Option<ChainEntry> {
+    let (time, sender, text) = match item {
+        MessageItem::Message {
+            time, name, text, ..
+        } => (time, name, text.clone()),
+        MessageItem::Attachment {
+            time,
+            name,
+            label,
+            filename,
+            ..
+        } => (time, name, format!("[{}] {}", label, filename)),
+        MessageItem::Poll {
+            time, name, poll, ..
+        } => (time, name, format!("[poll] {}", poll.question)),
+        _ => return None,
+    };
+    Some(ChainEntry {
+        time: time.clone(),
+        sender: sender.clone(),
+        text,
+        depth,
+        focused,
+    })
+}
+
+/// `/format plain|rich`: `Some(Ok(true))` switches the room to plain text.
+fn parse_format(text: &str) -> Option<Result<bool, String>> {
+    let arg = text.strip_prefix("/format")?;
+    if !arg.is_empty() && !arg.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some(match arg.trim() {
+        "plain" => Ok(true),
+        "rich" => Ok(false),
+        _ => Err(command_usage("/format")),
+    })
+}
+
+/// The template name of `/template`, empty to list them.
+fn parse_template(text: &str) -> Option<&str> {
+    let arg = text.strip_prefix("/template")?;
+    (arg.is_empty() || arg.starts_with(char::is_whitespace)).then(|| arg.trim())
+}
+
+/// `/vote <n>`: the 1-based answer number.
+fn parse_vote(text: &str) -> Option<Result<usize, String>> {
+    let arg = text.strip_prefix("/vote")?;
+    if !arg.is_empty() && !arg.starts_with(char::is_whitespace) {
+        return None;
+    }
+    match arg.trim().parse() {
+        Ok(number) if number > 0 => Some(Ok(number)),
+        _ => Some(Err(command_usage("/vote"))),
+    }
+}
+
+/// The arguments of `/bridge`.
+fn parse_bridge(text: &str) -> Option<&str> {
+    let args = text.strip_prefix("/bridge")?;
+    (args.is_empty() || args.starts_with(char::is_whitespace)).then(|| args.trim())
+}
+
+/// `/schedule <HH:MM|+N[mhd]> <text>`: the next such time of day, or a delay
+/// from now. Bare `/schedule` (the list) is handled separately.
+fn parse_schedule(text: &str) -> Option<Result<(i64, String), String>> {
+    let args = text.strip_prefix("/schedule")?;
+    if !args.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let args = args.trim();
+    let (when, body) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    let body = body.trim();
+    match schedule_time(when, Local::now()) {
+        Some(send_at) if !body.is_empty() => Some(Ok((send_at, body.to_string()))),
+        _ => Some(Err(command_usage("/schedule"))),
+    }
+}
+
+fn schedule_time(when: &str, now: DateTime<Local>) -> Option<i64> {
+    if let Some(delay) = when.strip_prefix('+') {
+        let (split, unit) = delay.char_indices().last()?;
+        let amount: i64 = delay[..split].parse().ok().filter(|amount| *amount > 0)?;
+        let delay = match unit {
+            'm' => chrono::Duration::try_minutes(amount)?,
+            'h' => chrono::Duration::try_hours(amount)?,
+            'd' => chrono::Duration::try_days(amount)?,
+            _ => return None,
+        };
+        return now
+            .checked_add_signed(delay)
+            .map(|at| at.timestamp_millis());
+    }
+    let time = NaiveTime::parse_from_str(when, "%H:%M").ok()?;
+    let today = now.date_naive();
+    [Some(today), today.succ_opt()]
+        .into_iter()
+        .flatten()
+        .filter_map(|date| Local.from_local_datetime(&date.and_time(time)).earliest())
+        .find(|at| *at > now)
+        .map(|at| at.timestamp_millis())
+}
+
+/// `/workspace [name]`; no name lists every room.
+fn parse_workspace(text: &str) -> Option<Option<String>> {
+    let arg = text.strip_prefix("/workspace")?;
+    if !arg.is_empty() && !arg.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some(Some(arg.trim()).filter(|name| !name.is_empty()).map(str::to_string))
+}
+
+/// `/nickname [--global] <@user> [nickname]`; no nickname clears it.
+#[allow(clippy::type_complexity)]
+fn parse_nickname(text: &str) -> Option<Result<(bool, String, Option<String>), String>> {
+    let args = text.strip_prefix("/nickname")?;
+    if !args.is_empty() && !args.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let args = args.trim();
+    let (global, args) = match args.strip_prefix("--global") {
+        Some(rest) => (true, rest.trim_start()),
+        None => (false, args),
+    };
+    let (user, nickname) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    if matrix_sdk::ruma::UserId::parse(user).is_err() {
+        return Some(Err(command_usage("/nickname")));
+    }
+    let nickname = Some(nickname.trim()).filter(|n| !n.is_empty()).map(str::to_string);
+    Some(Ok((global, user.to_string(), nickname)))
+}
+
+/// `/export-keys <path>` or `/import-keys <path>`: `Some(Ok((true, path)))`
+/// for an export.
+fn parse_key_file(text: &str) -> Option<Result<(bool, PathBuf), String>> {
+    let (name, export, arg) = if let Some(arg) = text.strip_prefix("/export-keys") {
+        ("/export-keys", true, arg)
+    } else {
+        ("/import-keys", false, text.strip_prefix("/import-keys")?)
+    };
+    if !arg.is_empty() && !arg.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let arg = arg.trim();
+    if arg.is_empty() {
+        return Some(Err(command_usage(name)));
+    }
+    Some(absolute_path(arg).map(|path| (export, path)))
+}
+
+/// Expands `~/` and makes `arg` absolute, since a background instance may
+/// run in another directory.
+fn absolute_path(arg: &str) -> Result<PathBuf, String> {
+    let path = match (arg.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(arg),
+    };
+    std::path::absolute(&path).map_err(|err| err.to_string())
+}
+
+fn parse_search(text: &str) -> Option<&str> {
+    let query = text.strip_prefix("/search")?;
+    if !query.is_empty() && !query.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some(query.trim())
+}
+
+fn parse_send_event(args: &str, room_id: Option<&str>) -> Result<MatrixCommand, String> {
+    const USAGE: &str = "Usage: /send-event [--state] <type> [state_key] <json>";
+    let room_id = room_id.ok_or("No room selected")?;
+    let json_start = args.find('{').ok_or(USAGE)?;
+    let (head, json) = args.split_at(json_start);
+    let content: serde_json::Value =
+        serde_json::from_str(json).map_err(|e| format!("Invalid JSON: {}", e))?;
+    let mut words = head.split_whitespace().peekable();
+    let is_state = matches!(words.peek(), Some(&"--state") | Some(&"-s"));
+    if is_state {
+        words.next();
+    }
+    let event_type = words.next().ok_or(USAGE)?.to_string();
+    let state_key = if is_state {
+        Some(words.next().unwrap_or_default().to_string())
+    } else {
+        None
+    };
+    if words.next().is_some() {
+        return Err(USAGE.to_string());
+    }
+    Ok(MatrixCommand::SendCustomEvent {
+        room_id: room_id.to_string(),
+        event_type,
+        state_key,
+        content,
+    })
+}
+
+fn parse_file_input(text: &str) -> Option<String> {
+    let trimmed = text.trim();
+    let path = trimmed.strip_prefix("file://")?;
+    if path.is_empty() {
+        return None;
+    }
+    Some(path.to_string())
+}
+
+/// UI behaviour derived from command-line flags and the config file.
+pub struct UiOptions {
+    read_only: bool,
+    /// Running against a background instance, which owns the data dir.
+    pub attached: bool,
+    auto_away: Option<Duration>,
+    startup_commands: Vec<String>,
+    /// `--exec` commands, run after `startup_commands`. An attached UI runs
+    /// only these; the background instance runs the configured ones.
+    exec_commands: Vec<String>,
+    plain_text_rooms: HashSet<String>,
+    muted_threads: HashSet<String>,
+    sender_format: SenderFormat,
+    nicknames: HashMap<String, String>,
+    room_nicknames: HashMap<String, HashMap<String, String>>,
+    snippets: HashMap<String, String>,
+    bridge_commands: HashMap<String, String>,
+    templates: HashMap<String, String>,
+    room_templates: HashMap<String, HashMap<String, String>>,
+    clipboard: ClipboardProvider,
+    spell: Option<SpellChecker>,
+    input_max_lines: u16,
+    tick_rate: Duration,
+    hotlist: bool,
+    window_title: bool,
+    room_sort: RoomSort,
+    room_jump_modifier: JumpModifier,
+    room_numbers: bool,
+    private_read_receipts: bool,
+    compact_layout: bool,
+    hide_state_events: bool,
+    bandwidth_saver: bool,
+    pinned_rooms: Vec<String>,
+    speaker: Option<Speaker>,
+    long_message_lines: usize,
+    paster: Option<Paster>,
+    previews: Option<Previews>,
+    link_previews: bool,
+    sender_colors: Vec<Color>,
+    tts_rooms: HashSet<String>,
+    alert_rooms: HashSet<String>,
+    alert_ack_template: Option<String>,
+    workspaces: BTreeMap<String, Workspace>,
+    active_workspace: Option<String>,
+    profile: StartupProfile,
+    metrics: Metrics,
+}
+
+impl UiOptions {
+    pub fn new(
+        read_only: bool,
+        exec: &[String],
+        cfg: &config::AppConfig,
+        profile: &StartupProfile,
+        metrics: &Metrics,
+    ) -> Self {
+        Self {
+            read_only,
+            attached: false,
+            profile: profile.clone(),
+            metrics: metrics.clone(),
+            auto_away: cfg
+                .auto_away_minutes
+                .filter(|minutes| *minutes > 0)
+                .map(|minutes| Duration::from_secs(minutes * 60)),
+            startup_commands: cfg.startup_commands.clone(),
+            exec_commands: exec
+                .iter()
+                .flat_map(|script| split_exec(script))
+                .collect(),
+            plain_text_rooms: cfg.plain_text_rooms.iter().cloned().collect(),
+            muted_threads: cfg.muted_threads.iter().cloned().collect(),
+            sender_format: cfg.sender_format,
+            nicknames: cfg.nicknames.clone(),
+            snippets: cfg.snippets.clone(),
+            bridge_commands: cfg.bridge_commands.clone(),
+            templates: cfg.templates.clone(),
+            room_templates: cfg.room_templates.clone(),
+            room_nicknames: cfg.room_nicknames.clone(),
+            clipboard: cfg.clipboard,
+            spell: cfg
+                .spellcheck
+                .then(|| SpellChecker::new(cfg.spell_dictionary.clone())),
+            input_max_lines: cfg
+                .input_max_lines
+                .unwrap_or(DEFAULT_INPUT_MAX_LINES)
+                .max(1),
+            tick_rate: cfg
+                .tick_rate_ms
+                .map_or(DEFAULT_TICK_RATE, |ms| Duration::from_millis(ms.max(10))),
+            hotlist: cfg.hotlist,
+            window_title: cfg.window_title,
+            room_sort: cfg.room_sort,
+            room_jump_modifier: cfg.room_jump_modifier,
+            room_numbers: cfg.room_numbers,
+            private_read_receipts: cfg.private_read_receipts,
+            compact_layout: cfg.compact_layout,
+            hide_state_events: cfg.hide_state_events,
+            bandwidth_saver: cfg.bandwidth_saver,
+            pinned_rooms: cfg.pinned_rooms.clone(),
+            speaker: cfg.tts_command.as_deref().and_then(Speaker::new),
+            long_message_lines: cfg.long_message_lines.unwrap_or(DEFAULT_LONG_MESSAGE_LINES),
+            paster: cfg.paste_command.as_deref().and_then(Paster::new),
+            previews: Protocol::from_config(cfg.image_previews.as_deref()).map(Previews::new),
+            link_previews: cfg.link_previews,
+            sender_colors: cfg
+                .sender_colors
+                .iter()
+                .filter_map(|color| color.parse().ok())
+                .collect(),
+            tts_rooms: cfg.tts_rooms.iter().cloned().collect(),
+            alert_rooms: cfg.alert_rooms.iter().cloned().collect(),
+            alert_ack_template: cfg.alert_ack_template.clone(),
+            workspaces: cfg.workspaces.clone(),
+            active_workspace: cfg
+                .active_workspace
+                .clone()
+                .filter(|name| cfg.workspaces.contains_key(name)),
+        }
+    }
+}
+
+fn msg_string(item: &MessageItem) -> String {
+    match item {
+        MessageItem::Separator(label) => format!("==== {} ====", label),
+        MessageItem::Notice { time, text, .. } => format!("{} -- {}", time, text),
+        MessageItem::Message { time, name, text, .. } => {
+            format!("{} {}: {}", time, name, text)
+        }
+        MessageItem::Attachment {
+            time,
+            name,
+            label,
+            filename,
+            path,
+            ..
+        } => {
+            format!("{} {}: [{}] {} ({})", time, name, label, filename, path)
+        }
+        MessageItem::Poll {
+            time, name, poll, ..
+        } => format!("{} {}: [poll] {}", time, name, poll.question),
+    }
+}
+
+fn attachment_text(label: &str, filename: &str, path: &str, missing: bool) -> String {
+    if path.is_empty() {
+        format!("[{}] {} (not downloaded, Enter to download)", label, filename)
+    } else if missing {
+        format!("[{}] {} (missing, Enter to download again)", label, filename)
+    } else {
+        format!("[{}] {}", label, filename)
+    }
+}
+
+fn msg_content(item: &MessageItem) -> String {
+    match item {
+        MessageItem::Separator(label) => label.clone(),
+        MessageItem::Notice { text, .. } => text.clone(),
+        MessageItem::Message { text, .. } => text.clone(),
+        MessageItem::Attachment { label, filename, .. } => {
+            format!("[{}] {}", label, filename)
+        }
+        MessageItem::Poll { poll, .. } => format!("[poll] {}", poll.question),
+    }
+}
+
+/// The question, then a numbered line per answer with its vote count;
+/// `✓` marks our own picks.
+fn poll_text(app: &App, poll: &StoredPoll, event_id: Option<&str>) -> String {
+    let mut text = format!("[poll] {}", poll.question);
+    if poll.ended.is_some() {
+        text.push_str(" (ended)");
+    } else if poll.max_selections > 1 {
+        text.push_str(&format!(" (pick up to {})", poll.max_selections));
+    }
+    let tally = app.poll_tally(event_id, poll);
+    for (idx, (answer, (votes, own))) in poll.answers.iter().zip(tally).enumerate() {
+        text.push_str(&format!("\n{}. {} — {}", idx + 1, answer.text, votes));
+        if own {
+            text.push_str(" ✓");
+        }
+    }
+    text
+}
+
+/// Indexes of the answers a response picks: unknown ids and repeats are
+/// dropped, and picks past `max_selections` ignored.
+fn poll_picks(poll: &StoredPoll, answers: &[String]) -> Vec<usize> {
+    let mut picks: Vec<usize> = Vec::new();
+    for answer in answers {
+        if let Some(idx) = poll.answers.iter().position(|a| &a.id == answer) {
+            if !picks.contains(&idx) {
+                picks.push(idx);
+            }
+        }
+    }
+    picks.truncate(poll.max_selections.max(1) as usize);
+    picks
+}
+
+fn strip_reply_fallback(body: &str) -> &str {
+    if !body.starts_with("> ") {
+        return body;
+    }
+    if let Some(pos) = body.find("\r\n\r\n") {
+        return &body[pos + 4..];
+    }
+    if let Some(pos) = body.find("\n\n") {
+        return &body[pos + 2..];
+    }
+    body
+}
+
+enum WrapToken {
+    Text(String),
+    Newline,
+}
+
+fn wrap_tokens(text: &str) -> Vec<WrapToken> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut current_is_space = None;
+    for ch in text.chars() {
+        if ch == '\n' {
+            if !current.is_empty() {
+                tokens.push(WrapToken::Text(current));
+                current = String::new();
+                current_is_space = None;
+            }
+            tokens.push(WrapToken::Newline);
+            continue;
+        }
+        let is_space = ch.is_whitespace();
+        if current_is_space == Some(is_space) || current_is_space.is_none() {
+            current.push(ch);
+            current_is_space = Some(is_space);
+        } else {
+            tokens.push(WrapToken::Text(current));
+            current = String::new();
+            current.push(ch);
+            current_is_space = Some(is_space);
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(WrapToken::Text(current));
+    }
+    tokens
+}
+
+fn wrap_text_lines(text: &str, width: u16) -> Vec<String> {
+    let width = width.max(1) as usize;
+    if text.is_empty() {
+        return vec![String::new()];
+    }
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let mut line_len = 0usize;
+    for token in wrap_tokens(text) {
+        match token {
+            WrapToken::Newline => {
+                lines.push(line);
+                line = String::new();
+                line_len = 0;
+            }
+            WrapToken::Text(chunk) => {
+                let chunk_len = chunk.width();
+                if line_len > 0 && line_len + chunk_len > width {
+                    lines.push(line);
+                    line = String::new();
+                    line_len = 0;
+                }
+                if chunk_len > width {
+                    for grapheme in chunk.graphemes(true) {
+                        let grapheme_width = grapheme.width();
+                        if line_len > 0 && line_len + grapheme_width > width {
+                            lines.push(line);
+                            line = String::new();
+                            line_len = 0;
+                        }
+                        line.push_str(grapheme);
+                        line_len += grapheme_width;
+                    }
+                } else {
+                    line.push_str(&chunk);
+                    line_len += chunk_len;
+                }
+            }
+        }
+    }
+    if lines.is_empty() || !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+fn reply_preview_text(app: &App, room_id: Option<&str>, reply_id: &str) -> String {
+    let Some(room_id) = room_id else {
+        return "> (unknown)".to_string();
+    };
+    app.reply_preview(room_id, reply_id)
+        .map(|p| format!("> ({}) {}", p.sender, p.text))
+        .unwrap_or_else(|| "> (unknown)".to_string())
+}
+
+fn message_prefix_spans(
+    app: &App,
+    time: &str,
+    name: &str,
+    sender_id: &str,
+    read_receipt: Option<bool>,
+) -> (Vec<Span<'static>>, usize) {
+    let receipt_prefix = if let Some(read) = read_receipt {
+        if read { "● " } else { "○ " }
+    } else {
+        "  "
+    };
+    let mut spans = Vec::with_capacity(3);
+    spans.push(Span::styled(
+        receipt_prefix,
+        Style::default().fg(Color::Rgb(160, 160, 160)),
+    ));
+    spans.push(Span::styled(
+        format!("{} ", time),
+        Style::default().fg(Color::Rgb(238, 193, 99)),
+    ));
+    let name_color = color_for_sender(sender_id, app.own_user_id.as_deref(), &app.sender_colors);
+    spans.push(Span::styled(
+        format!("{}: ", name),
+        Style::default()
+            .fg(name_color)
+            .add_modifier(Modifier::BOLD),
+    ));
+    (spans, message_prefix_width(time, name))
+}
+
+/// Columns the `message_prefix_spans` (and `reply_prefix`) of a message
+/// take, without building them: the receipt mark, the time and the name.
+fn message_prefix_width(time: &str, name: &str) -> usize {
+    2 + time.width() + 1 + name.width() + 2
+}
+
+fn message_render_height(
+    app: &App,
+    room_id: Option<&str>,
+    item: &MessageItem,
+    width: u16,
+) -> u16 {
+    let width = width.max(1);
+    let reactions = app
+        .reaction_summary(message_ids(item).0)
+        .map_or(0, |summary| {
+            wrap_text_lines(&summary, width.saturating_sub(REACTION_INDENT as u16)).len() as u16
+        });
+    let marker = u16::from(is_read_marker(app, item));
+    let divider = u16::from(is_unread_divider(app, item));
+    let link = u16::from(link_preview(app, item).is_some());
+    let thread = u16::from(thread_replies(app, item) > 0);
+    let preview = image_preview(app, item, width).map_or(0, |(_, rows)| rows);
+    reactions
+        + marker
+        + divider
+        + link
+        + thread
+        + preview
+        + message_body_height(app, room_id, item, width)
+}
+
+/// Replies seen in the thread `item` starts.
+fn thread_replies(app: &App, item: &MessageItem) -> usize {
+    message_ids(item)
+        .0
+        .and_then(|id| app.thread_counts.get(id))
+        .copied()
+        .unwrap_or_default()
+}
+
+fn reply_count(count: usize) -> String {
+    if count == 1 {
+        "1 reply".to_string()
+    } else {
+        format!("{} replies", count)
+    }
+}
+
+/// The fetched page title of a message's first link, when link previews
+/// are on.
+fn link_preview<'a>(app: &'a App, item: &MessageItem) -> Option<&'a str> {
+    let MessageItem::Message { text, .. } = item else {
+        return None;
+    };
+    if !app.link_previews {
+        return None;
+    }
+    app.link_titles.get(extract_url(text)?)?.as_deref()
+}
+
+/// The file and row count of an image or sticker thumbnail, when
+/// previews are on and it fits beside the indent.
+fn image_preview<'a>(app: &App, item: &'a MessageItem, width: u16) -> Option<(&'a str, u16)> {
+    let MessageItem::Attachment {
+        label,
+        path,
+        missing: false,
+        ..
+    } = item
+    else {
+        return None;
+    };
+    if !matches!(label.as_str(), "image" | "sticker") || app.bandwidth_saver {
+        return None;
+    }
+    let (cols, rows) = app.previews.as_ref()?.size(path)?;
+    (cols + REACTION_INDENT as u16 <= width).then_some((path.as_str(), rows))
+}
+
+fn is_read_marker(app: &App, item: &MessageItem) -> bool {
+    app.read_marker.is_some() && app.read_marker.as_deref() == message_ids(item).0
+}
+
+fn is_unread_divider(app: &App, item: &MessageItem) -> bool {
+    app.unread_divider
+        .as_ref()
+        .and_then(|divider| divider.event_id.as_deref())
+        .is_some_and(|event_id| message_ids(item).0 == Some(event_id))
+}
+
+fn message_body_height(
+    app: &App,
+    room_id: Option<&str>,
+    item: &MessageItem,
+    width: u16,
+) -> u16 {
+    match item {
+        MessageItem::Separator(_) => 1,
+        MessageItem::Notice { time, text, .. } => {
+            let prefix_len = notice_prefix(time).width();
+            wrap_text_lines(text, width.saturating_sub(prefix_len as u16)).len() as u16
+        }
+        MessageItem::Message {
+            time,
+            name,
+            text,
+            reply_to,
+            edited,
+            ..
+        } => {
+            let text = &message_body(text, *edited);
+            let prefix_len = message_prefix_width(time, name);
+            if let Some(reply_id) = reply_to.as_deref() {
+                let preview = reply_preview_text(app, room_id, reply_id);
+                let preview_lines =
+                    wrap_text_lines(&preview, width.saturating_sub(prefix_len as u16)).len();
+                let body_lines =
+                    wrap_text_lines(text, width.saturating_sub(prefix_len as u16)).len();
+                (preview_lines + body_lines) as u16
+            } else {
+                wrap_text_lines(text, width.saturating_sub(prefix_len as u16)).len() as u16
+            }
+        }
+        MessageItem::Attachment {
+            time,
+            name,
+            label,
+            filename,
+            path,
+            missing,
+            reply_to,
+            ..
+        } => {
+            let prefix_len = message_prefix_width(time, name);
+            let text = attachment_text(label, filename, path, *missing);
+            if let Some(reply_id) = reply_to.as_deref() {
+                let preview = reply_preview_text(app, room_id, reply_id);
+                let preview_lines =
+                    wrap_text_lines(&preview, width.saturating_sub(prefix_len as u16)).len();
+                let body_lines =
+                    wrap_text_lines(&text, width.saturating_sub(prefix_len as u16)).len();
+                (preview_lines + body_lines) as u16
+            } else {
+                wrap_text_lines(&text, width.saturating_sub(prefix_len as u16)).len() as u16
+            }
+        }
+        MessageItem::Poll {
+            time,
+            name,
+            poll,
+            event_id,
+            ..
+        } => {
+            let prefix_len = message_prefix_width(time, name);
+            let text = poll_text(app, poll, event_id.as_deref());
+            wrap_text_lines(&text, width.saturating_sub(prefix_len as u16)).len() as u16
+        }
+    }
+}
+
+/// First message to draw so that `bottom` ends the viewport.
+fn message_window_start(
+    app: &App,
+    room_id: Option<&str>,
+    messages: &Timeline,
+    height: u16,
+    width: u16,
+    bottom: usize,
+) -> usize {
+    if messages.is_empty() || height == 0 {
+        return 0;
+    }
+    let mut idx = bottom.min(messages.len() - 1);
+    let start_idx = idx;
+    let mut remaining = height as i32;
+    loop {
+        let item_height = message_render_height(app, room_id, &messages[idx], width) as i32;
+        if remaining - item_height < 0 {
+            return if idx == start_idx { idx } else { idx + 1 };
+        }
+        remaining -= item_height;
+        if idx == 0 {
+            return 0;
+        }
+        idx = idx.saturating_sub(1);
+    }
+}
+
+/// Last message that fits entirely when drawing from `start`.
+fn message_window_end(
+    app: &App,
+    room_id: Option<&str>,
+    messages: &Timeline,
+    height: u16,
+    width: u16,
+    start: usize,
+) -> usize {
+    let mut remaining = height as usize;
+    let mut end = start;
+    for (idx, item) in messages.iter().enumerate().skip(start) {
+        let item_height = message_render_height(app, room_id, item, width) as usize;
+        if item_height > remaining {
+            break;
+        }
+        remaining -= item_height;
+        end = idx;
+    }
+    end
+}
+
+pub fn render_messages_area(
+    f: &mut ratatui::Frame,
+    area: Rect,
+    app: &mut App,
+) {
+    let title = match app.selected_room() {
+        Some(room) => messages_title(&app.room_label(room), room.topic.as_deref(), area.width),
+        None => "Messages".to_string(),
+    };
+    let block = Block::default().borders(Borders::ALL).title(title);
+    f.render_widget(&block, area);
+    let inner = block.inner(area);
+    if inner.width == 0 || inner.height == 0 {
+        return;
+    }
+    if let Some(room) = app.selected_room() {
+        if room.state == RoomListState::Invited {
+            let inviter = room.inviter.as_deref().unwrap_or("Unknown user");
+            let hint = if app.read_only {
+                "Read-only mode: invites can't be answered."
+            } else {
+                "Ctrl+A to accept, Ctrl+D to decline."
+            };
+            let lines = vec![
+                Line::from(format!("Invitation from {}", inviter)),
+                Line::from(hint),
+            ];
+            let text = Paragraph::new(lines).wrap(Wrap { trim: false });
+            f.render_widget(text, inner);
+            return;
+        }
+    }
+    if app.scroll_room.as_deref() != app.selected_room_id() {
+        app.scroll_room = app.selected_room_id().map(str::to_string);
+        app.message_scroll = 0;
+        if app
+            .unread_divider
+            .as_ref()
+            .map(|divider| divider.room_id.as_str())
+            != app.selected_room_id()
+        {
+            app.unread_divider = None;
+        }
+    }
+    app.read_marker = app.find_read_marker();
+    let divider_event = app.find_unread_divider();
+    if let Some(divider) = &mut app.unread_divider {
+        divider.event_id = divider_event;
+    }
+    let room_id = app.selected_room_id();
+    let messages = app.visible_messages();
+    let read_upto = room_id.and_then(|id| app.read_position(id, &messages));
+    let last = messages.len().saturating_sub(1);
+    let selected = app.message_selected.filter(|idx| *idx < messages.len());
+    // The viewport stays where it was scrolled to and only moves to bring
+    // the selected message into view.
+    let mut bottom = last.saturating_sub(app.message_scroll);
+    if let Some(sel) = selected {
+        bottom = bottom.max(sel);
+    }
+    let mut start = message_window_start(
+        app,
+        room_id,
+        &messages,
+        inner.height,
+        inner.width,
+        bottom,
+    );
+    if let Some(sel) = selected.filter(|sel| *sel < start) {
+        start = sel;
+        bottom = message_window_end(
+            app,
+            room_id,
+            &messages,
+            inner.height,
+            inner.width,
+            start,
+        );
+    }
+    let scroll = last - bottom.min(last);
+    let page = bottom - start.min(bottom) + 1;
+    let divider_at = app.unread_divider.as_ref().and_then(|_| {
+        messages
+            .iter()
+            .rposition(|item| is_unread_divider(app, item))
+    });
+    let mut missing_replies = Vec::new();
+    let mut missing_links = Vec::new();
+    let mut placements = Vec::new();
+    let buf = f.buffer_mut();
+    let mut y = inner.y;
+    let max_y = inner.y + inner.height;
+    for (idx, item) in (start..).zip(messages.range(start..)) {
+        if y >= max_y {
+            break;
+        }
+        let selected = app.message_selected == Some(idx);
+        let read_receipt =
+            message_sender(item).and_then(|sender| app.read_receipt_for(sender, idx, read_upto));
+        if divider_at == Some(idx) {
+            let line = format_separator("new messages", inner.width);
+            let style = Style::default().fg(NOTICE_BG);
+            draw_graphemes(buf, inner.x, y, &line, inner.width as usize, style);
+            y = y.saturating_add(1);
+            if y >= max_y {
+                break;
+            }
+        }
+        let top = y;
+        match item {
+            MessageItem::Separator(label) => {
+                let line = format_separator(label, inner.width);
+                draw_plain_line(buf, inner, y, &line, selected);
+                y = y.saturating_add(1);
+            }
+            MessageItem::Notice { time, text, .. } => {
+                let prefix = notice_prefix(time);
+                let prefix_len = prefix.width();
+                let prefix_spans = vec![Span::styled(prefix, Style::default().fg(NOTICE_FG))];
+                y = draw_wrapped_spans(
+                    buf,
+                    inner,
+                    y,
+                    max_y,
+                    &prefix_spans,
+                    prefix_len,
+                    text,
+                    Some(Style::default().fg(NOTICE_FG).add_modifier(Modifier::ITALIC)),
+                    selected,
+                );
+            }
+            MessageItem::Message {
+                time,
+                name,
+                sender_id,
+                text,
+                reply_to,
+                edited,
+                ..
+            } => {
+                if let Some(url) = extract_url(text).filter(|_| app.link_previews) {
+                    if !app.link_titles.contains_key(url) {
+                        missing_links.push(url.to_string());
+                    }
+                }
+                let text = &message_body(text, *edited);
+                let body_style = app
+                    .alert_severity(room_id, text)
+                    .map(|severity| Style::default().fg(severity_color(severity)));
+                if let (Some(reply_id), Some(room_id)) = (reply_to.as_deref(), room_id)
+                {
+                    if app.reply_preview(room_id, reply_id).is_none() {
+                        missing_replies.push((room_id.to_string(), reply_id.to_string()));
+                    }
+                    let reply_text = reply_preview_text(app, Some(room_id), reply_id);
+                    let (prefix_spans, prefix_len) =
+                        message_prefix_spans(app, time, name, sender_id, read_receipt);
+                    y = draw_wrapped_spans(
+                        buf,
+                        inner,
+                        y,
+                        max_y,
+                        &prefix_spans,
+                        prefix_len,
+                        &reply_text,
+                        Some(Style::default().fg(Color::Rgb(150, 150, 150))),
+                        selected,
+                    );
+                    if y >= max_y {
+                        break;
+                    }
+                    let prefix = reply_prefix(time, name, read_receipt);
+                    let prefix_len = prefix.width();
+                    let prefix_spans = vec![Span::raw(prefix)];
+                    y = draw_wrapped_spans(
+                        buf,
+                        inner,
+                        y,
+                        max_y,
+                        &prefix_spans,
+                        prefix_len,
+                        text,
+                        body_style,
+                        selected,
+                    );
+                } else {
+                    let (mut prefix_spans, prefix_len) =
+                        message_prefix_spans(app, time, name, sender_id, read_receipt);
+                    if continues_group(app, &messages, idx) {
+                        hide_sender(&mut prefix_spans);
+                    }
+                    y = draw_wrapped_spans(
+                        buf,
+                        inner,
+                        y,
+                        max_y,
+                        &prefix_spans,
+                        prefix_len,
+                        text,
+                        body_style,
+                        selected,
+                    );
+                }
+            }
+            MessageItem::Attachment {
+                time,
+                name,
+                sender_id,
+                label,
+                filename,
+                path,
+                missing,
+                reply_to,
+                ..
+            } => {
+                let text = attachment_text(label, filename, path, *missing);
+                if let (Some(reply_id), Some(room_id)) = (reply_to.as_deref(), room_id)
+                {
+                    if app.reply_preview(room_id, reply_id).is_none() {
+                        missing_replies.push((room_id.to_string(), reply_id.to_string()));
+                    }
+                    let reply_text = reply_preview_text(app, Some(room_id), reply_id);
+                    let (prefix_spans, prefix_len) =
+                        message_prefix_spans(app, time, name, sender_id, read_receipt);
+                    y = draw_wrapped_spans(
+                        buf,
+                        inner,
+                        y,
+                        max_y,
+                        &prefix_spans,
+                        prefix_len,
+                        &reply_text,
+                        Some(Style::default().fg(Color::Rgb(150, 150, 150))),
+                        selected,
+                    );
+                    if y >= max_y {
+                        break;
+                    }
+                    let prefix = reply_prefix(time, name, read_receipt);
+                    let prefix_len = prefix.width();
+                    let prefix_spans = vec![Span::raw(prefix)];
+                    y = draw_wrapped_spans(
+                        buf,
+                        inner,
+                        y,
+                        max_y,
+                        &prefix_spans,
+                        prefix_len,
+                        &text,
+                        None,
+                        selected,
+                    );
+                } else {
+                    let (mut prefix_spans, prefix_len) =
+                        message_prefix_spans(app, time, name, sender_id, read_receipt);
+                    if continues_group(app, &messages, idx) {
+                        hide_sender(&mut prefix_spans);
+                    }
+                    y = draw_wrapped_spans(
+                        buf,
+                        inner,
+                        y,
+                        max_y,
+                        &prefix_spans,
+                        prefix_len,
+                        &text,
+                        None,
+                        selected,
+                    );
+                }
+            }
+            MessageItem::Poll {
+                time,
+                name,
+                sender_id,
+                poll,
+                event_id,
+                ..
+            } => {
+                let text = poll_text(app, poll, event_id.as_deref());
+                let (mut prefix_spans, prefix_len) =
+                    message_prefix_spans(app, time, name, sender_id, read_receipt);
+                if continues_group(app, &messages, idx) {
+                    hide_sender(&mut prefix_spans);
+                }
+                y = draw_wrapped_spans(
+                    buf,
+                    inner,
+                    y,
+                    max_y,
+                    &prefix_spans,
+                    prefix_len,
+                    &text,
+                    None,
+                    selected,
+                );
+            }
+        }
+        let own =
+            message_sender(item).is_some_and(|sender| app.own_user_id.as_deref() == Some(sender));
+        if let Some(title) = link_preview(app, item) {
+            if y < max_y {
+                if selected {
+                    fill_line(buf, inner, y);
+                }
+                let line = format!("{}↳ {}", " ".repeat(REACTION_INDENT), title);
+                let style = Style::default().fg(NOTICE_FG).add_modifier(Modifier::DIM);
+                buf.set_stringn(inner.x, y, line, inner.width as usize, style);
+            }
+            y = y.saturating_add(1);
+        }
+        let replies = thread_replies(app, item);
+        if replies > 0 {
+            if y < max_y {
+                if selected {
+                    fill_line(buf, inner, y);
+                }
+                let line = format!(
+                    "{}💬 {} · Alt+T",
+                    " ".repeat(REACTION_INDENT),
+                    reply_count(replies)
+                );
+                let style = Style::default().fg(Color::Rgb(140, 200, 220));
+                buf.set_stringn(inner.x, y, line, inner.width as usize, style);
+            }
+            y = y.saturating_add(1);
+        }
+        if let Some((path, rows)) = image_preview(app, item, inner.width) {
+            // Left blank here; the thumbnail is drawn over it after the frame.
+            if y.saturating_add(rows) <= max_y {
+                placements.push((path.to_string(), inner.x + REACTION_INDENT as u16, y));
+            }
+            y = y.saturating_add(rows);
+        }
+        if let Some(summary) = app.reaction_summary(message_ids(item).0) {
+            let indent = vec![Span::raw(" ".repeat(REACTION_INDENT))];
+            y = draw_wrapped_spans(
+                buf,
+                inner,
+                y,
+                max_y,
+                &indent,
+                REACTION_INDENT,
+                &summary,
+                Some(Style::default().fg(NOTICE_FG)),
+                selected,
+            );
+        }
+        if y < max_y && is_read_marker(app, item) {
+            let label = "✓ read";
+            let x = inner.x + inner.width.saturating_sub(label.width() as u16 + 1);
+            if selected {
+                fill_line(buf, inner, y);
+            }
+            buf.set_stringn(x, y, label, inner.width as usize, Style::default().fg(NOTICE_FG));
+            y += 1;
+        }
+        if own && !selected {
+            let rows = Rect::new(inner.x, top, inner.width, y.min(max_y) - top);
+            buf.set_style(rows, Style::default().bg(OWN_BG));
+        }
+    }
+    let mut newer_width = 0;
+    if scroll > 0 && area.width > 4 {
+        let label = format!(" ↓ {} newer ", scroll);
+        newer_width = label.width() as u16 + 1;
+        buf.set_stringn(
+            area.x + 2,
+            area.y + area.height - 1,
+            label,
+            area.width as usize - 4,
+            Style::default().fg(NOTICE_FG),
+        );
+    }
+    if let Some(typing) = app.typing_label() {
+        // Right-aligned on the bottom border, clear of the scroll label.
+        let label = format!(" {} ", typing);
+        let room = area.width.saturating_sub(4 + newer_width);
+        let width = (label.width() as u16).min(room);
+        if width > 0 {
+            buf.set_stringn(
+                area.x + area.width - 2 - width,
+                area.y + area.height - 1,
+                label,
+                width as usize,
+                Style::default().fg(NOTICE_FG).add_modifier(Modifier::ITALIC),
+            );
+        }
+    }
+    if let (Some(divider), Some(at)) = (app.unread_divider.as_mut(), divider_at) {
+        if at < start && divider.shown {
+            app.unread_divider = None;
+        } else if at >= start && at <= bottom {
+            divider.shown = true;
+        }
+    }
+    app.message_scroll = scroll;
+    app.message_page = page;
+    app.pending_reply_fetches = missing_replies;
+    app.pending_link_fetches = missing_links;
+    app.preview_placements = placements;
+}
+
+/// A message's text with the `(edited)` marker.
+fn message_body(text: &str, edited: bool) -> Cow<'_, str> {
+    if edited {
+        Cow::Owned(format!("{} (edited)", text))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// `name — topic`, with the topic's first line cut to fit a block of
+/// `width` (Alt+I shows it in full).
+fn messages_title(name: &str, topic: Option<&str>, width: u16) -> String {
+    let Some(topic) = topic.and_then(|topic| topic.lines().next()) else {
+        return name.to_string();
+    };
+    let room = format!("{} — ", name);
+    let available = (width as usize)
+        .saturating_sub(2)
+        .saturating_sub(room.width());
+    if available < 2 {
+        return name.to_string();
+    }
+    let mut title = room;
+    if topic.width() > available {
+        let mut used = 0;
+        for grapheme in topic.graphemes(true) {
+            used += grapheme.width();
+            if used > available - 1 {
+                break;
+            }
+            title.push_str(grapheme);
+        }
+        title.push('…');
+    } else {
+        title.push_str(topic);
+    }
+    title
+}
+
+fn format_help_line(line: &str) -> String {
+    const KEY_COL: usize = 18;
+    let Some((left, right)) = line.split_once('\t') else {
+        return line.to_string();
+    };
+    let left_len = left.width();
+    let pad = if left_len >= KEY_COL { 1 } else { KEY_COL - left_len };
+    format!("{}{}{}", left, " ".repeat(pad), right)
+}
+
+fn reply_prefix(time: &str, name: &str, read_receipt: Option<bool>) -> String {
+    let receipt_prefix = if let Some(read) = read_receipt {
+        if read { "● " } else { "○ " }
+    } else {
+        "  "
+    };
+    format!("{}{} {}: ", receipt_prefix, time, name)
+}
+
+fn notice_prefix(time: &str) -> String {
+    format!("  {} -- ", time)
+}
+
+/// Row and display column of the char index `cursor` in `input` wrapped to
+/// `width` columns; wide characters take two columns and grapheme clusters
+/// are never split.
+fn cursor_position(input: &str, cursor: usize, width: u16) -> (u16, u16) {
+    let width = width.max(1) as usize;
+    let mut row = 0u16;
+    let mut col = 0usize;
+    let mut count = 0usize;
+    for token in wrap_tokens(input) {
+        if count >= cursor {
+            break;
+        }
+        match token {
+            WrapToken::Newline => {
+                count += 1;
+                row = row.saturating_add(1);
+                col = 0;
+            }
+            WrapToken::Text(chunk) => {
+                let chunk_width = chunk.width();
+                if col > 0 && col + chunk_width > width {
+                    row = row.saturating_add(1);
+                    col = 0;
+                }
+                for grapheme in chunk.graphemes(true) {
+                    if count >= cursor {
+                        break;
+                    }
+                    let grapheme_width = grapheme.width();
+                    if col > 0 && col + grapheme_width > width {
+                        row = row.saturating_add(1);
+                        col = 0;
+                    }
+                    col += grapheme_width;
+                    count += grapheme.chars().count();
+                }
+            }
+        }
+    }
+    (row, col as u16)
+}
+
+fn format_separator(label: &str, width: u16) -> String {
+    let content_width = width as usize;
+    let label_width = label.width();
+    if content_width == 0 {
+        return String::new();
+    }
+    if label_width + 2 >= content_width {
+        return label.to_string();
+    }
+    let fill = content_width - label_width - 2;
+    let left = fill / 2;
+    let right = fill - left;
+    format!("{} {} {}", "=".repeat(left), label, "=".repeat(right))
+}
+
+const SENDER_COLORS: [Color; 8] = [
+    Color::Rgb(109, 188, 226),
+    Color::Rgb(140, 210, 180),
+    Color::Rgb(232, 182, 113),
+    Color::Rgb(198, 140, 210),
+    Color::Rgb(220, 150, 150),
+    Color::Rgb(120, 200, 140),
+    Color::Rgb(230, 160, 200),
+    Color::Rgb(150, 170, 240),
+];
+
+/// A sender's name color from `palette` (the built-in one when empty),
+/// picked by the same user ID hash Element uses so it never changes
+/// between runs or builds.
+fn color_for_sender(sender_id: &str, own_user_id: Option<&str>, palette: &[Color]) -> Color {
+    if is_own_sender(sender_id, own_user_id) {
+        return Color::Rgb(180, 140, 210);
+    }
+    let palette = if palette.is_empty() {
+        &SENDER_COLORS[..]
+    } else {
+        palette
+    };
+    let hash = sender_id
+        .chars()
+        .fold(0i32, |hash, c| hash.wrapping_shl(5).wrapping_sub(hash).wrapping_add(c as i32));
+    palette[hash.unsigned_abs() as usize % palette.len()]
+}
+
+fn is_own_sender(sender_id: &str, own_user_id: Option<&str>) -> bool {
+    let Some(own) = own_user_id else {
+        return false;
+    };
+    if sender_id == own {
+        return true;
+    }
+    format_sender(sender_id) == format_sender(own)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn draw_wrapped_spans(
+    buf: &mut Buffer,
+    area: Rect,
+    mut y: u16,
+    max_y: u16,
+    prefix_spans: &[Span<'static>],
+    prefix_len: usize,
+    text: &str,
+    text_style: Option<Style>,
+    selected: bool,
+) -> u16 {
+    if y >= max_y {
+        return y;
+    }
+    let width = area.width.max(1);
+    let text_width = width.saturating_sub(prefix_len as u16).max(1);
+    let lines = wrap_text_lines(text, text_width);
+    for (idx, line) in lines.into_iter().enumerate() {
+        if y >= max_y {
+            break;
+        }
+        let mut spans: Vec<Span<'static>> = Vec::new();
+        if idx == 0 {
+            spans.extend(prefix_spans.iter().cloned());
+        } else {
+            spans.push(Span::raw(" ".repeat(prefix_len)));
+        }
+        let line_span = match text_style {
+            Some(style) => Span::styled(line, style),
+            None => Span::raw(line),
+        };
+        spans.push(line_span);
+        draw_spans_line(buf, area, y, &spans, selected);
+        y = y.saturating_add(1);
+    }
+    y
+}
+
+fn draw_plain_line(buf: &mut Buffer, area: Rect, y: u16, text: &str, selected: bool) {
+    if y >= area.y + area.height {
+        return;
+    }
+    if selected {
+        fill_line(buf, area, y);
+        let style = Style::default().bg(SELECTED_BG).fg(Color::Black);
+        draw_graphemes(buf, area.x, y, text, area.width as usize, style);
+    } else {
+        draw_graphemes(buf, area.x, y, text, area.width as usize, Style::default());
+    }
+}
+
+fn draw_spans_line(buf: &mut Buffer, area: Rect, y: u16, spans: &[Span], selected: bool) {
+    if y >= area.y + area.height {
+        return;
+    }
+    let mut x = area.x;
+    let max_width = area.width as usize;
+    if selected {
+        fill_line(buf, area, y);
+    }
+    for span in spans {
+        if (x - area.x) as usize >= max_width {
+            break;
+        }
+        let remaining = max_width.saturating_sub((x - area.x) as usize);
+        let style = if selected {
+            Style::default()
+                .bg(SELECTED_BG)
+                .fg(Color::Black)
+                .add_modifier(Modifier::BOLD)
+        } else {
+            span.style
+        };
+        x = draw_graphemes(buf, x, y, span.content.as_ref(), remaining, style);
+    }
+}
+
+/// Draws `text` from column `x`, at most `max_width` columns wide, and
+/// returns the column after it. Columns are counted like `wrap_text_lines`
+/// counts them, and unlike `Buffer::set_stringn` the cells covered by a
+/// wide glyph keep `style`, so selection highlights have no holes.
+fn draw_graphemes(
+    buf: &mut Buffer,
+    mut x: u16,
+    y: u16,
+    text: &str,
+    max_width: usize,
+    style: Style,
+) -> u16 {
+    let mut remaining = max_width;
+    for grapheme in text.graphemes(true) {
+        let width = grapheme.width();
+        if width == 0 {
+            continue;
+        }
+        if width > remaining {
+            break;
+        }
+        buf.get_mut(x, y).set_symbol(grapheme).set_style(style);
+        for offset in 1..width as u16 {
+            buf.get_mut(x + offset, y).set_symbol(" ").set_style(style);
+        }
+        x += width as u16;
+        remaining -= width;
+    }
+    x
+}
+
+fn fill_line(buf: &mut Buffer, area: Rect, y: u16) {
+    for x in 0..area.width {
+        buf.get_mut(area.x + x, y)
+            .set_symbol(" ")
+            .set_bg(SELECTED_BG)
+            .set_fg(Color::Black);
+    }
+}
+
+fn extract_url(text: &str) -> Option<&str> {
+    for part in text.split_whitespace() {
+        if part.starts_with("http://") || part.starts_with("https://") {
+            return Some(part.trim_end_matches([')', ',', '.']));
+        }
+    }
+    None
+}
+
+fn open_url(url: &str) -> bool {
+    #[cfg(target_os = "windows")]
+    {
+        return Command::new("cmd")
+            .args(["/C", "start", "", url])
+            .spawn()
+            .is_ok();
+    }
+    #[cfg(target_os = "macos")]
+    {
+        return Command::new("open").arg(url).spawn().is_ok();
+    }
+    #[cfg(all(not(target_os = "windows"), not(target_os = "macos")))]
+    {
+        Command::new("xdg-open").arg(url).spawn().is_ok()
+    }
+}
+
+fn open_path(path: &Path) -> bool {
+    #[cfg(target_os = "windows")]
+    {
+        return Command::new("cmd")
+            .args(["/C", "start", "", &path.display().to_string()])
+            .spawn()
+            .is_ok();
+    }
+    #[cfg(target_os = "macos")]
+    {
+        return Command::new("open").arg(path).spawn().is_ok();
+    }
+    #[cfg(all(not(target_os = "windows"), not(target_os = "macos")))]
+    {
+        Command::new("xdg-open").arg(path).spawn().is_ok()
+    }
+}
+
+/// Opens a downloaded attachment, or fetches it again when the file was
+/// deleted since it was downloaded.
+fn open_attachment(app: &mut App, cmd_tx: &mpsc::UnboundedSender<MatrixCommand>, path: &str) {
+    if Path::new(path).exists() {
+        let _ = open_path(Path::new(path));
+    } else if let Some(cmd) = app.redownload_selected_attachment() {
+        let _ = cmd_tx.send(cmd);
+    }
+}
+
+/// Splits an `--exec` script into commands at `;`; `\;` is a literal
+/// semicolon.
+pub fn split_exec(script: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let mut chars = script.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&';') => line.push(chars.next().unwrap_or(';')),
+            ';' => lines.push(std::mem::take(&mut line)),
+            c => line.push(c),
+        }
+    }
+    lines.push(line);
+    lines
+        .iter()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+pub fn run_ui(
+    evt_tx: mpsc::WeakUnboundedSender<MatrixEvent>,
+    evt_rx: mpsc::UnboundedReceiver<MatrixEvent>,
+    cmd_tx: mpsc::UnboundedSender<MatrixCommand>,
+    passphrase: String,
+    own_user_id: Option<String>,
+    ui: UiOptions,
+) -> Result<()> {
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen, EnableBracketedPaste)?;
+    let backend = CrosstermBackend::new(stdout);
+    let mut terminal = Terminal::new(backend)?;
+
+    let profile = ui.profile.clone();
+    let window_title = ui.window_title;
+    let res = run_app(&mut terminal, evt_tx, evt_rx, cmd_tx, passphrase, own_user_id, ui);
+
+    disable_raw_mode()?;
+    execute!(
+        terminal.backend_mut(),
+        DisableBracketedPaste,
+        LeaveAlternateScreen
+    )?;
+    if window_title {
+        // Most terminals go back to their own title when it's cleared.
+        execute!(terminal.backend_mut(), SetTitle(""))?;
+    }
+    terminal.show_cursor()?;
+    if let Some(report) = profile.report() {
+        eprint!("{}", report);
+    }
+
+    res?;
+    Ok(())
+}
+
+fn run_app(
+    terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
+    evt_tx: mpsc::WeakUnboundedSender<MatrixEvent>,
+    mut evt_rx: mpsc::UnboundedReceiver<MatrixEvent>,
+    cmd_tx: mpsc::UnboundedSender<MatrixCommand>,
+    passphrase: String,
+    own_user_id: Option<String>,
+    mut ui: UiOptions,
+) -> io::Result<()> {
+    let mut app = App::new();
+    app.own_user_id = own_user_id;
+    app.read_only = ui.read_only;
+    // The background instance runs the configured startup commands itself.
+    app.startup_commands = if ui.attached {
+        ui.exec_commands
+    } else {
+        [ui.startup_commands, ui.exec_commands].concat()
+    };
+    app.plain_text_rooms = ui.plain_text_rooms;
+    app.muted_threads = ui.muted_threads;
+    app.sender_format = ui.sender_format;
+    app.nicknames = ui.nicknames;
+    app.snippets = ui.snippets;
+    app.bridge_commands = ui.bridge_commands;
+    app.templates = ui.templates;
+    app.room_templates = ui.room_templates;
+    app.room_nicknames = ui.room_nicknames;
+    app.clipboard = ui.clipboard;
+    app.spell = ui.spell;
+    if let Some(previews) = ui.previews.as_mut() {
+        let events = evt_tx.clone();
+        previews.on_ready(move || {
+            if let Some(events) = events.upgrade() {
+                let _ = events.send(MatrixEvent::PreviewReady);
+            }
+        });
+    }
+    app.spell_events = Some(evt_tx);
+    app.input_max_lines = ui.input_max_lines;
+    app.hotlist = ui.hotlist;
+    app.room_sort = ui.room_sort;
+    app.room_jump_modifier = ui.room_jump_modifier;
+    app.room_numbers = ui.room_numbers;
+    app.private_read_receipts = ui.private_read_receipts;
+    app.compact_layout = ui.compact_layout;
+    app.hide_state_events = ui.hide_state_events;
+    app.bandwidth_saver = ui.bandwidth_saver;
+    app.pinned_rooms = ui.pinned_rooms;
+    app.speaker = ui.speaker;
+    app.long_message_lines = ui.long_message_lines;
+    app.paster = ui.paster;
+    app.previews = ui.previews;
+    app.link_previews = ui.link_previews;
+    app.sender_colors = ui.sender_colors;
+    app.tts_rooms = ui.tts_rooms;
+    app.alert_rooms = ui.alert_rooms;
+    app.alert_ack_template = ui.alert_ack_template;
+    app.workspaces = ui.workspaces;
+    app.workspace = ui.active_workspace;
+    app.auto_away = ui.auto_away;
+    app.owns_schedule = !ui.attached;
+    let tick_rate = ui.tick_rate;
+    let window_title = ui.window_title;
+    let mut shown_title: Option<String> = None;
+    let mut last_tick = Instant::now();
+    // Frames are only drawn when something changed, or when one of the
+    // clocks on screen ticks over.
+    let mut dirty = true;
+    let mut drawn_clocks = None;
+    let mut last_input = Instant::now();
+    let mut pending_evt = None;
+    if let Ok(base) = messages_dir() {
+        let loaded = ui
+            .profile
+            .time("load_all_messages", || load_all_messages(&base, &passphrase));
+        if let Ok(loaded) = loaded {
+            if !loaded.problems.is_empty() {
+                app.set_status(&format!("Storage: {}", loaded.problems.join("; ")));
+            }
+            let persisted = loaded.rooms;
+            app.search_index = load_search_index_for(&base, &passphrase, &persisted);
+            for (room_key, mut records) in persisted {
+                records.sort_by_key(|m| m.timestamp);
+                for record in records {
+                    let room_id = room_key.replace('_', ":");
+                    if let Some(event_id) = record.event_id.as_deref() {
+                        for reaction in &record.reactions {
+                            app.add_reaction(event_id, reaction.clone());
+                        }
+                        for vote in &record.votes {
+                            app.add_vote(event_id, vote.clone());
+                        }
+                        app.note_thread(event_id, record.thread_root.as_deref());
+                    }
+                    if let (Some(poll), Some(event_id)) = (&record.poll, &record.event_id) {
+                        app.push_poll_with_time(
+                            &room_id,
+                            event_id,
+                            record.timestamp,
+                            &record.sender,
+                            poll.clone(),
+                        );
+                    } else if let Some(path) = record.attachment_path.as_deref() {
+                        let label = record
+                            .attachment_kind
+                            .as_deref()
+                            .unwrap_or("file");
+                        let name = record
+                            .attachment_name
+                            .as_deref()
+                            .unwrap_or(&record.body);
+                        app.push_attachment_with_time(
+                            &room_id,
+                            record.event_id.as_deref(),
+                            record.timestamp,
+                            &record.sender,
+                            label,
+                            name,
+                            path,
+                            record.reply_to.as_deref(),
+                        );
+                    } else {
+                        let body = if record.redacted { REDACTED_TEXT } else { &record.body };
+                        app.push_message_with_time(
+                            &room_id,
+                            record.event_id.as_deref(),
+                            record.timestamp,
+                            &record.sender,
+                            body,
+                            record.reply_to.as_deref(),
+                        );
+                        if record.edited && !record.redacted {
+                            app.mark_edited(&room_id, record.event_id.as_deref());
+                        }
+                    }
+                }
+            }
+            for (room_id, ts) in app.last_message_ts.clone() {
+                app.last_seen_ts.entry(room_id).or_insert(ts);
+            }
+            let missing = app
+                .messages_by_room
+                .values()
+                .flatten()
+                .filter(|item| matches!(item, MessageItem::Attachment { missing: true, .. }))
+                .count();
+            if missing > 0 && app.status.is_none() {
+                app.set_status(&format!(
+                    "{} downloaded attachment(s) missing on disk; select one and press Enter to download it again",
+                    missing
+                ));
+            }
+        }
+        if let Ok(persisted) = load_all_read_receipts(&base, &passphrase) {
+            for (room_key, records) in persisted {
+                let room_id = room_key.replace('_', ":");
+                let set = app.read_receipts.entry(room_id).or_default();
+                for event_id in records {
+                    set.insert(event_id);
+                }
+            }
+        }
+        match load_drafts(&base, &passphrase) {
+            Ok(drafts) => app.drafts = drafts,
+            Err(err) => app.set_status(&format!("Drafts: {}", err)),
+        }
+        match load_scheduled(&base, &passphrase) {
+            Ok(scheduled) => app.scheduled = scheduled,
+            Err(err) => app.set_status(&format!("Scheduled messages: {}", err)),
+        }
+    }
+    let save_drafts = |app: &App| {
+        if let Ok(base) = messages_dir() {
+            let _ = store_drafts(&base, &passphrase, &app.all_drafts());
+        }
+    };
+    let save_scheduled = |app: &App| {
+        if !app.owns_schedule {
+            return;
+        }
+        if let Ok(base) = messages_dir() {
+            let _ = store_scheduled(&base, &passphrase, &app.scheduled);
+        }
+    };
+    // Only the instance holding the data dir writes the search index; an
+    // attached UI keeps its copy in memory and the background one saves.
+    let owns_index = !ui.attached;
+    let save_search_index = |app: &mut App| {
+        if owns_index && app.search_index.take_changed() {
+            if let Ok(base) = messages_dir() {
+                let _ = store_search_index(&base, &passphrase, &mut app.search_index);
+            }
+        }
+    };
+    let mut last_index_save: Option<Instant> = None;
+
+    loop {
+        while let Some(evt) = pending_evt.take().or_else(|| evt_rx.try_recv().ok()) {
+            dirty = true;
+            match evt {
+                MatrixEvent::Rooms(rooms) => app.update_rooms(rooms),
+                MatrixEvent::RoomAdded(room) => {
+                    if app.notifications_ready && room.state == RoomListState::Invited {
+                        let (title, body) = invite_notification(&room);
+                        notify_send(&title, &body);
+                        ui.metrics.incr(Counter::Notifications);
+                    }
+                    app.add_room(room);
+                }
+                MatrixEvent::RoomUpdated(room) => app.update_room(room),
+                MatrixEvent::RoomRemoved { room_id } => app.remove_room(&room_id),
+                MatrixEvent::Message {
+                    room_id,
+                    event_id,
+                    sender,
+                    body,
+                    timestamp,
+                    reply_to,
+                    thread_root,
+                } => {
+                    let muted = app.note_thread(&event_id, thread_root.as_deref());
+                    app.handle_incoming_message(
+                        &room_id,
+                        Some(&event_id),
+                        timestamp,
+                        &sender,
+                        &body,
+                        reply_to.as_deref(),
+                        muted,
+                    );
+                    if !muted {
+                        app.announce(&room_id, &sender, &body);
+                    }
+                    let critical =
+                        app.alert_severity(Some(&room_id), &body) == Some(Severity::Critical);
+                    // Criticals notify even while the room is open.
+                    let notify = if critical {
+                        app.notifications_ready
+                            && app.own_user_id.as_deref() != Some(sender.as_str())
+                    } else {
+                        app.should_notify(&room_id, &sender)
+                    };
+                    if !muted && notify {
+                        let label = app.sender_label(&room_id, &sender);
+                        notify_message(&app.room_name(&room_id), &label, &body, critical);
+                        ui.metrics.incr(Counter::Notifications);
+                    }
+                }
+                MatrixEvent::Attachment {
+                    room_id,
+                    event_id,
+                    sender,
+                    name,
+                    path,
+                    kind,
+                    timestamp,
+                    reply_to,
+                    thread_root,
+                } => {
+                    let muted = app.note_thread(&event_id, thread_root.as_deref());
+                    app.handle_incoming_attachment(
+                        &room_id,
+                        Some(&event_id),
+                        timestamp,
+                        &sender,
+                        &kind,
+                        &name,
+                        &path,
+                        reply_to.as_deref(),
+                        muted,
+                    );
+                    if !muted {
+                        app.announce(&room_id, &sender, &format!("sent {} {}", kind, name));
+                    }
+                    if !muted && app.should_notify(&room_id, &sender) {
+                        let title = format!("{} — {}", app.room_name(&room_id), app.sender_label(&room_id, &sender));
+                        let body = format!("[{}] {}", kind, name);
+                        notify_send(&title, &body);
+                        ui.metrics.incr(Counter::Notifications);
+                    }
+                }
+                MatrixEvent::Poll {
+                    room_id,
+                    event_id,
+                    sender,
+                    timestamp,
+                    poll,
+                } => {
+                    let question = poll.question.clone();
+                    app.handle_incoming_poll(&room_id, &event_id, timestamp, &sender, poll);
+                    app.announce(&room_id, &sender, &format!("started a poll: {}", question));
+                    if app.should_notify(&room_id, &sender) {
+                        let title = format!(
+                            "{} — {}",
+                            app.room_name(&room_id),
+                            app.sender_label(&room_id, &sender)
+                        );
+                        notify_send(&title, &format!("[poll] {}", question));
+                        ui.metrics.incr(Counter::Notifications);
+                    }
+                }
+                MatrixEvent::Typing { room_id, user_ids } => app.set_typing(room_id, user_ids),
+                MatrixEvent::Receipt { room_id, event_id } => {
+                    app.mark_read_receipt(&room_id, &event_id);
+                    if let Ok(base) = messages_dir() {
+                        if let Some(set) = app.read_receipts.get(&room_id) {
+                            let _ = store_read_receipts(&base, &passphrase, &room_id, set);
+                        }
+                    }
+                }
+                MatrixEvent::ServerNotice {
+                    room_id,
+                    event_id,
+                    body,
+                    timestamp,
+                } => {
+                    app.push_server_notice(&room_id, &event_id, timestamp, &body);
+                    if app.notifications_ready {
+                        notify_send("Server notice", &body);
+                        ui.metrics.incr(Counter::Notifications);
+                    }
+                }
+                MatrixEvent::RoomNotice {
+                    room_id,
+                    body,
+                    timestamp,
+                } => {
+                    app.push_notice_with_time(&room_id, timestamp, &body, false);
+                }
+                MatrixEvent::StateChange {
+                    room_id,
+                    body,
+                    timestamp,
+                } => {
+                    app.push_notice_with_time(&room_id, timestamp, &body, true);
+                }
+                MatrixEvent::Status { message } => {
+                    app.set_status(&message);
+                }
+                MatrixEvent::Spelling { text, result } => app.finish_spellcheck(text, result),
+                MatrixEvent::Scheduled(scheduled) => app.set_scheduled(scheduled),
+                // Only wakes the loop; the next frame reserves the rows.
+                MatrixEvent::PreviewReady => {}
+                MatrixEvent::SyncHealth { ok, timeout_secs } => {
+                    if ok {
+                        app.last_sync = Some(Instant::now());
+                    }
+                    app.sync_failing = !ok;
+                    app.sync_timeout = Duration::from_secs(timeout_secs);
+                }
+                MatrixEvent::RoundTrip { millis } => {
+                    app.round_trip_ms = Some(millis);
+                }
+                MatrixEvent::RoomState { room_id, entries } => {
+                    app.show_room_state(&room_id, entries);
+                }
+                MatrixEvent::EventInfo { event_id, details } => {
+                    app.show_event_info(&event_id, details);
+                }
+                MatrixEvent::Members { room_id, members } => {
+                    app.set_room_members(room_id, members);
+                }
+                MatrixEvent::MemberChanged {
+                    room_id,
+                    member,
+                    joined,
+                } => {
+                    app.update_room_member(&room_id, member, joined);
+                }
+                MatrixEvent::ServerInfo(info) => {
+                    app.server_info = Some(info);
+                }
+                MatrixEvent::Related { room_id, relation } => {
+                    app.apply_relation(&room_id, &relation);
+                }
+                MatrixEvent::AttachmentRestored {
+                    room_id,
+                    event_id,
+                    path,
+                } => {
+                    app.restore_attachment(&room_id, &event_id, &path);
+                }
+                MatrixEvent::Undecryptable {
+                    room_id,
+                    event_id,
+                    sender,
+                    timestamp,
+                } => {
+                    app.push_message_with_time(
+                        &room_id,
+                        Some(&event_id),
+                        timestamp,
+                        &sender,
+                        UNDECRYPTABLE_TEXT,
+                        None,
+                    );
+                }
+                MatrixEvent::Decrypted {
+                    room_id,
+                    event_id,
+                    body,
+                } => {
+                    app.apply_decrypted(&room_id, &event_id, &body);
+                }
+                MatrixEvent::ReplyPreview {
+                    room_id,
+                    event_id,
+                    sender,
+                    body,
+                } => {
+                    app.set_reply_preview(&room_id, &event_id, &sender, &body);
+                }
+                MatrixEvent::LinkPreview { url, title } => {
+                    app.link_titles.insert(url, title);
+                }
+                MatrixEvent::BackfillDone => {
+                    app.notifications_ready = true;
+                    if app.sender_format == SenderFormat::DisplayName {
+                        for room in app.rooms.iter().filter(|r| r.state == RoomListState::Joined) {
+                            let _ = cmd_tx.send(MatrixCommand::FetchMembers {
+                                room_id: room.room_id.clone(),
+                            });
+                        }
+                    }
+                    for cmd in app.run_startup_commands() {
+                        let _ = cmd_tx.send(cmd);
+                    }
+                }
+                MatrixEvent::VerificationRequest { user_id, device_id } => {
+                    app.verification_emojis = None;
+                    app.verification_status = Some(format!(
+                        "{} ({}) wants to verify. Alt+V to accept, Esc to dismiss.",
+                        user_id, device_id
+                    ));
+                    app.verification_until = None;
+                    let (title, body) = verification_notification(&user_id, &device_id);
+                    notify_send(&title, &body);
+                    ui.metrics.incr(Counter::Notifications);
+                }
+                MatrixEvent::VerificationEmojis { emojis } => {
+                    app.show_verification_emojis(emojis);
+                }
+                MatrixEvent::VerificationStatus { message } => {
+                    app.show_verification_status(&message);
+                }
+                MatrixEvent::VerificationDone => {
+                    app.show_verification_status("Verification complete.");
+                }
+                MatrixEvent::VerificationCancelled { reason } => {
+                    app.show_verification_status(&format!("Verification cancelled: {}", reason));
+                }
+            }
+        }
+        if let Some(cmd) = app.check_idle() {
+            let _ = cmd_tx.send(cmd);
+        }
+        if let Some(cmd) = app.take_due_leave(Instant::now()) {
+            let _ = cmd_tx.send(cmd);
+        }
+        if let Some(cmd) = app.take_due_receipt(Instant::now()) {
+            let _ = cmd_tx.send(cmd);
+        }
+        if app.status_until.is_some_and(|until| Instant::now() >= until) {
+            app.status = None;
+            app.status_until = None;
+            dirty = true;
+        }
+        if app.verification_emojis.is_none() {
+            if let Some(until) = app.verification_until {
+                if Instant::now() >= until {
+                    app.clear_verification();
+                    dirty = true;
+                }
+            }
+        }
+        if app.sync_draft() {
+            save_drafts(&app);
+        }
+        if !app.read_only {
+            for cmd in app.take_due_scheduled(Local::now().timestamp_millis()) {
+                let _ = cmd_tx.send(cmd);
+            }
+        }
+        for cmd in app.take_finished_pastes() {
+            let _ = cmd_tx.send(cmd);
+        }
+        if std::mem::take(&mut app.scheduled_changed) {
+            save_scheduled(&app);
+        }
+        if app.search_index.needs_purge()
+            || last_index_save.is_none_or(|at| at.elapsed() >= SEARCH_INDEX_SAVE_INTERVAL)
+        {
+            save_search_index(&mut app);
+            last_index_save = Some(Instant::now());
+        }
+
+        let clocks = app.clock_labels(Instant::now());
+        if dirty || drawn_clocks.as_ref() != Some(&clocks) {
+            dirty = false;
+            drawn_clocks = Some(clocks);
+            if window_title {
+                let title = app.window_title();
+                if shown_title.as_deref() != Some(title.as_str()) {
+                    execute!(terminal.backend_mut(), SetTitle(&title))?;
+                    shown_title = Some(title);
+                }
+            }
+            terminal.draw(|f| {
+                let size = f.size();
+
+                if app.help_open {
+                    let mut help_lines: Vec<Line> = app
+                        .help_lines()
+                        .into_iter()
+                        .map(|line| {
+                            let rendered = format_help_line(line);
+                            if line.starts_with(' ') || line.is_empty() {
+                                Line::from(Span::raw(rendered))
+                            } else {
+                                Line::from(Span::styled(
+                                    rendered,
+                                    Style::default()
+                                        .fg(Color::Rgb(140, 200, 220))
+                                        .add_modifier(Modifier::BOLD),
+                                ))
+                            }
+                        })
+                        .collect();
+                    if help_lines.is_empty() {
+                        help_lines.push(Line::from(Span::styled(
+                            "Nothing matches; Backspace or Esc to edit the filter.",
+                            Style::default().fg(NOTICE_FG),
+                        )));
+                    }
+                    let help_title = if app.help_filter.is_empty() {
+                        "Help — type to search".to_string()
+                    } else {
+                        format!("Help — search: {}", app.help_filter)
+                    };
+                    let help = Paragraph::new(help_lines)
+                        .block(Block::default().borders(Borders::ALL).title(help_title))
+                        .wrap(Wrap { trim: false })
+                        .scroll((app.help_scroll, 0));
+                    f.render_widget(help, size);
+                } else {
+                    let body_area = if app.server_notices.is_empty() {
+                        size
+                    } else {
+                        let chunks = Layout::default()
+                            .direction(Direction::Vertical)
+                            .constraints([Constraint::Length(3), Constraint::Min(1)])
+                            .split(size);
+                        render_server_notice(f, chunks[0], &app);
+                        chunks[1]
+                    };
+                    let body_area = if app.hotlist {
+                        let chunks = Layout::default()
+                            .direction(Direction::Vertical)
+                            .constraints([Constraint::Min(1), Constraint::Length(1)])
+                            .split(body_area);
+                        render_hotlist(f, chunks[1], &app);
+                        chunks[0]
+                    } else {
+                        body_area
+                    };
+                    let main_chunks = Layout::default()
+                        .direction(Direction::Horizontal)
+                        .constraints([Constraint::Length(app.channels_width()), Constraint::Min(1)])
+                        .split(body_area);
+
+                    // The input grows with the wrapped draft, up to
+                    // `input_max_lines` rows, and scrolls beyond that.
+                    let inner_width = main_chunks[1].width.saturating_sub(2);
+                    let (row, col) = cursor_position(&app.input, app.input_cursor, inner_width);
+                    let text_rows =
+                        (wrap_text_lines(&app.input, inner_width).len() as u16).max(row + 1);
+                    let min_rows = if app.input_multiline { 3 } else { 1 };
+                    let input_rows = text_rows.clamp(min_rows, app.input_max_lines.max(min_rows));
+                    let right_chunks = Layout::default()
+                        .direction(Direction::Vertical)
+                        .constraints([
+                            Constraint::Min(3),
+                            Constraint::Length(u16::from(app.replying.is_some())),
+                            Constraint::Length(input_rows + 2),
+                        ])
+                        .split(main_chunks[1]);
+
+                    render_channels(f, main_chunks[0], &app);
+
+                    render_messages_area(f, right_chunks[0], &mut app);
+                    if let Some(target) = &app.replying {
+                        render_reply_banner(f, right_chunks[1], target);
+                    }
+                    let input_area = right_chunks[2];
+                    let inner_height = input_area.height.saturating_sub(2);
+                    let scroll_y = row.saturating_sub(inner_height.saturating_sub(1));
+                    let restricted = app.compose_room().is_some_and(|room| room.send_restricted);
+                    let input_label = if app.read_only {
+                        "Filter".to_string()
+                    } else if restricted {
+                        "Input (read-only room)".to_string()
+                    } else if let Some(room_id) = &app.compose_lock {
+                        format!("Input (locked to {})", app.room_name(room_id))
+                    } else {
+                        "Input".to_string()
+                    };
+                    let input_title = match app.leave_notice().or_else(|| app.status.clone()) {
+                        Some(status) => format!("{} — {}", input_label, status),
+                        None => input_label,
+                    };
+                    let mut input_block = Block::default().borders(Borders::ALL).title(input_title);
+                    if restricted && !app.read_only {
+                        input_block = input_block.border_style(Style::default().fg(NOTICE_FG));
+                    }
+                    if let Some((health, stale)) = app.sync_health(Instant::now()) {
+                        let color = if stale { NOTICE_BG } else { NOTICE_FG };
+                        input_block = input_block.title_bottom(
+                            Line::from(Span::styled(health, Style::default().fg(color)))
+                                .alignment(Alignment::Right),
+                        );
+                    }
+                    let input = Paragraph::new(input_lines(&app))
+                        .block(input_block)
+                        .wrap(Wrap { trim: false })
+                        .scroll((scroll_y, 0));
+                    f.render_widget(input, input_area);
+                    let x = input_area.x + 1;
+                    let y = input_area.y + 1;
+                    let cursor_y = y
+                        .saturating_add(row.saturating_sub(scroll_y))
+                        .min(input_area.y + input_area.height - 2);
+                    let cursor_x = x + col.min(inner_width.saturating_sub(1));
+                    f.set_cursor(cursor_x, cursor_y);
+                    if let Some(ref completion) = app.completion {
+                        let anchor_x = if app.input_multiline {
+                            x
+                        } else {
+                            x + cursor_position(&app.input, completion.start, inner_width).1
+                        };
+                        render_completion(f, input_area, anchor_x, completion);
+                    }
+                }
+
+                if let Some(ref view) = app.state_view {
+                    render_state_view(f, size, &app, view);
+                }
+                if let Some(ref view) = app.search_view {
+                    render_search_view(f, size, &app, view);
+                }
+                if let Some(ref view) = app.scheduled_view {
+                    render_scheduled_view(f, size, &app, view);
+                }
+                if let Some(ref view) = app.reply_chain {
+                    render_reply_chain(f, size, view);
+                }
+                if let Some(ref view) = app.message_info {
+                    render_message_info(f, size, view);
+                }
+                if let Some(ref prompt) = app.prompt {
+                    render_prompt(f, size, prompt, app.paster.is_some());
+                }
+                if app.verification_emojis.is_some() || app.verification_status.is_some() {
+                    render_verification_overlay(f, size, &app);
+                }
+                if app.is_syncing && !app.help_open {
+                    render_sync_indicator(f, size);
+                }
+            })?;
+            for cmd in app
+                .take_reply_fetches()
+                .into_iter()
+                .chain(app.take_link_fetches())
+            {
+                let _ = cmd_tx.send(cmd);
+            }
+            if let Some(previews) = &app.previews {
+                let placements = if app.overlay_open() {
+                    Vec::new()
+                } else {
+                    std::mem::take(&mut app.preview_placements)
+                };
+                if placements != app.drawn_previews {
+                    let out = terminal.backend_mut();
+                    if !app.drawn_previews.is_empty() && !previews.clear(out)? {
+                        // Thumbnails drawn into the text: repaint the whole
+                        // screen, and draw them again after the next frame.
+                        terminal.clear()?;
+                        app.drawn_previews.clear();
+                    } else {
+                        for (path, x, y) in &placements {
+                            previews.draw(out, path, *x, *y)?;
+                        }
+                        out.flush()?;
+                        app.drawn_previews = placements;
+                    }
+                }
+            }
+        }
+
+        let input = if last_input.elapsed() >= IDLE_AFTER {
+            let now = Instant::now();
+            let mut wake = app.next_wake(now);
+            if owns_index {
+                let save = last_index_save.map(|at| at + SEARCH_INDEX_SAVE_INTERVAL);
+                wake = wake.into_iter().chain(save).min();
+            }
+            let timeout = wake.map(|at| at.saturating_duration_since(now));
+            wait_idle(&mut evt_rx, &mut pending_evt, timeout)?
+        } else {
+            let timeout = tick_rate
+                .checked_sub(last_tick.elapsed())
+                .unwrap_or_else(|| Duration::from_secs(0));
+            if event::poll(timeout)? {
+                Some(event::read()?)
+            } else {
+                None
+            }
+        };
+        if let Some(event) = input {
+            dirty = true;
+            last_input = Instant::now();
+            if let Event::Paste(text) = &event {
+                if !app.read_only {
+                    app.on_paste(text);
+                }
+            }
+            if let (Event::Resize(..), Some(previews)) = (&event, &app.previews) {
+                previews.clear(terminal.backend_mut())?;
+                app.drawn_previews.clear();
+            }
+            if let Event::Key(key) = event {
+                if key.kind == KeyEventKind::Press {
+                    if let Some(cmd) = app.register_activity() {
+                        let _ = cmd_tx.send(cmd);
+                    }
+                    if app.prompt.is_some() {
+                        match key.code {
+                            KeyCode::Esc => app.cancel_prompt(),
+                            KeyCode::Enter => {
+                                if let Some(cmd) = app.submit_prompt() {
+                                    let _ = cmd_tx.send(cmd);
+                                }
+                            }
+                            KeyCode::Backspace => app.prompt_backspace(),
+                            KeyCode::Tab => app.accept_prompt_suggestion(),
+                            KeyCode::Up => app.move_prompt_suggestion(-1),
+                            KeyCode::Down => app.move_prompt_suggestion(1),
+                            KeyCode::Char(c) => app.prompt_push(c),
+                            _ => {}
+                        }
+                        continue;
+                    }
+                    if app.help_open {
+                        match key.code {
+                            KeyCode::Char('q') if key.modifiers.contains(KeyModifiers::ALT) => {
+                                app.should_quit = true
+                            }
+                            KeyCode::F(1) => app.toggle_help(),
+                            KeyCode::Esc => app.help_escape(),
+                            KeyCode::Up => app.on_help_up(),
+                            KeyCode::Down | KeyCode::PageDown => app.on_help_down(),
+                            KeyCode::PageUp => app.on_help_up(),
+                            KeyCode::Backspace => app.help_filter_pop(),
+                            KeyCode::Char(c)
+                                if !key
+                                    .modifiers
+                                    .intersects(KeyModifiers::ALT | KeyModifiers::CONTROL) =>
+                            {
+                                app.help_filter_push(c)
+                            }
+                            _ => {}
+                        }
+                        if !app.should_quit {
+                            continue;
+                        }
+                    }
+                    if app.message_info.is_some() {
+                        match key.code {
+                            KeyCode::Char('q') if key.modifiers.contains(KeyModifiers::ALT) => {
+                                app.should_quit = true
+                            }
+                            KeyCode::Esc | KeyCode::Enter => app.message_info = None,
+                            _ => {}
+                        }
+                        if !app.should_quit {
+                            continue;
+                        }
+                    }
+                    if app.reply_chain.is_some() {
+                        match key.code {
+                            KeyCode::Esc => app.reply_chain = None,
+                            KeyCode::Up => app.scroll_reply_chain(-1),
+                            KeyCode::Down => app.scroll_reply_chain(1),
+                            KeyCode::PageUp => app.scroll_reply_chain(-10),
+                            KeyCode::PageDown => app.scroll_reply_chain(10),
+                            KeyCode::Char('q') if key.modifiers.contains(KeyModifiers::ALT) => {
+                                app.should_quit = true
+                            }
+                            _ => {}
+                        }
+                        if !app.should_quit {
+                            continue;
+                        }
+                    }
+                    if app.search_view.is_some() {
+                        match key.code {
+                            KeyCode::Esc => app.search_view = None,
+                            KeyCode::Up => app.scroll_search_view(-1),
+                            KeyCode::Down => app.scroll_search_view(1),
+                            KeyCode::PageUp => app.scroll_search_view(-10),
+                            KeyCode::PageDown => app.scroll_search_view(10),
+                            KeyCode::Char('q') if key.modifiers.contains(KeyModifiers::ALT) => {
+                                app.should_quit = true
+                            }
+                            _ => {}
+                        }
+                        if !app.should_quit {
+                            continue;
+                        }
+                    }
+                    if app.scheduled_view.is_some() {
+                        match key.code {
+                            KeyCode::Esc => app.scheduled_view = None,
+                            KeyCode::Up => app.move_scheduled_selection(-1),
+                            KeyCode::Down => app.move_scheduled_selection(1),
+                            KeyCode::Char('d') | KeyCode::Delete => {
+                                if let Some(cmd) = app.take_selected_scheduled(false) {
+                                    let _ = cmd_tx.send(cmd);
+                                }
+                            }
+                            KeyCode::Char('s') => {
+                                if let Some(cmd) = app.take_selected_scheduled(true) {
+                                    let _ = cmd_tx.send(cmd);
+                                }
+                            }
+                            KeyCode::Char('q') if key.modifiers.contains(KeyModifiers::ALT) => {
+                                app.should_quit = true
+                            }
+                            _ => {}
+                        }
+                        if !app.should_quit {
+                            continue;
+                        }
+                    }
+                    if app.state_view.is_some() {
+                        match key.code {
+                            KeyCode::Esc => app.state_view = None,
+                            KeyCode::Up => app.scroll_state_view(-1),
+                            KeyCode::Down => app.scroll_state_view(1),
+                            KeyCode::PageUp => app.scroll_state_view(-10),
+                            KeyCode::PageDown => app.scroll_state_view(10),
+                            KeyCode::Char('q') if key.modifiers.contains(KeyModifiers::ALT) => {
+                                app.should_quit = true
+                            }
+                            _ => {}
+                        }
+                        if !app.should_quit {
+                            continue;
+                        }
+                    }
+                    if app.completion.is_some() {
+                        match key.code {
+                            KeyCode::Up => {
+                                app.move_completion(-1);
+                                continue;
+                            }
+                            KeyCode::Down => {
+                                app.move_completion(1);
+                                continue;
+                            }
+                            KeyCode::Tab | KeyCode::Enter => {
+                                app.accept_completion();
+                                continue;
+                            }
+                            KeyCode::Esc => {
+                                app.completion = None;
+                                continue;
+                            }
+                            _ => {}
+                        }
+                    }
+                    if let Some(index) = app.room_jump_digit(&key) {
+                        app.jump_to_room(index);
+                        continue;
+                    }
+                    match key.code {
+                        KeyCode::Char('q') if key.modifiers.contains(KeyModifiers::ALT) => {
+                            app.should_quit = true
+                        }
+                        KeyCode::F(1) => app.toggle_help(),
+                        KeyCode::F(2) => app.cycle_workspace(),
+                        KeyCode::F(3) => app.toggle_room_numbers(),
+                        KeyCode::F(4) => {
+                            let _ = cmd_tx.send(app.toggle_bandwidth_saver());
+                        }
+                        KeyCode::F(5) => app.toggle_compact_layout(),
+                        KeyCode::F(6) => app.toggle_state_events(),
+                        KeyCode::Esc => {
+                            if app.verification_status.is_some()
+                                && app.verification_emojis.is_none()
+                            {
+                                app.clear_verification();
+                            } else {
+                                app.on_escape();
+                            }
+                        }
+                        KeyCode::Char('a' | 'j' | 'd' | 'v' | 'e' | 'x' | 'r' | 'l')
+                            if key.modifiers.contains(KeyModifiers::ALT) && app.read_only => {}
+                        KeyCode::Char('x') if key.modifiers.contains(KeyModifiers::ALT) => {
+                            app.start_redact_prompt();
+                        }
+                        KeyCode::Char('e') if key.modifiers.contains(KeyModifiers::ALT) => {
+                            app.start_edit();
+                        }
+                        KeyCode::Char('l') if key.modifiers.contains(KeyModifiers::ALT) => {
+                            app.start_react_prompt();
+                        }
+                        KeyCode::Char('a') if key.modifiers.contains(KeyModifiers::ALT) => {
+                            app.start_add_prompt();
+                        }
+                        KeyCode::Char('j') if key.modifiers.contains(KeyModifiers::ALT) => {
+                            app.start_add_prompt();
+                        }
+                        KeyCode::Char('d') if key.modifiers.contains(KeyModifiers::ALT) => {
+                            app.start_delete_prompt();
+                        }
+                        KeyCode::Char('s') if key.modifiers.contains(KeyModifiers::ALT) => {
+                            if let Some(cmd) = app.open_state_view() {
+                                let _ = cmd_tx.send(cmd);
+                            }
+                        }
+                        KeyCode::Char('p') if key.modifiers.contains(KeyModifiers::ALT) => {
+                            app.toggle_pin();
+                        }
+                        KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::ALT) => {
+                            app.start_reply();
+                        }
+                        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::ALT) => {
+                            app.open_reply_chain();
+                        }
+                        KeyCode::Char('t') if key.modifiers.contains(KeyModifiers::ALT) => {
+                            app.open_thread();
+                        }
+                        KeyCode::Char('k') if key.modifiers.contains(KeyModifiers::ALT) => {
+                            if let Some(cmd) = app.retry_selected_decryption() {
+                                let _ = cmd_tx.send(cmd);
+                            }
+                        }
+                        KeyCode::Char('i') if key.modifiers.contains(KeyModifiers::ALT) => {
+                            if let Some(cmd) = app.open_message_info() {
+                                let _ = cmd_tx.send(cmd);
+                            }
+                        }
+                        KeyCode::Char('v') if key.modifiers.contains(KeyModifiers::ALT) => {
+                            let _ = cmd_tx.send(MatrixCommand::StartVerification);
+                            app.show_verification_status("Waiting for verification...");
+                        }
+                        KeyCode::Enter if key.modifiers.contains(KeyModifiers::ALT) => {
+                            app.input_multiline = !app.input_multiline;
+                        }
+                        KeyCode::Char('n') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            app.acknowledge_server_notice();
+                        }
+                        KeyCode::Char('z') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            app.undo_leave();
+                        }
+                        KeyCode::Char('l')
+                            if key.modifiers.contains(KeyModifiers::CONTROL) && !app.read_only =>
+                        {
+                            app.toggle_compose_lock();
+                        }
+                        KeyCode::Char('s')
+                            if key.modifiers.contains(KeyModifiers::CONTROL) && !app.read_only =>
+                        {
+                            app.cycle_spelling();
+                        }
+                        KeyCode::Char('a' | 'd' | 'e')
+                            if key.modifiers.contains(KeyModifiers::CONTROL) && app.read_only => {}
+                        KeyCode::Char('e') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            match compose_in_editor(terminal, &app.input)? {
+                                Some(text) if !text.trim().is_empty() => {
+                                    let text = app.expand_snippets(text.trim_end().to_string());
+                                    if let Some(cmd) = app.take_edit(&text) {
+                                        let _ = cmd_tx.send(cmd);
+                                        app.input.clear();
+                                        app.input_cursor = 0;
+                                        continue;
+                                    }
+                                    let target = app
+                                        .compose_room_id()
+                                        .filter(|_| !app.compose_room_is_invited());
+                                    if let Some(room_id) = target {
+                                        app.remember_input(&room_id, &text);
+                                        if app.hold_long_message(&room_id, &text) {
+                                            app.input.clear();
+                                            app.input_cursor = 0;
+                                            continue;
+                                        }
+                                        let reply_to = app.take_reply_to(&room_id);
+                                        let (body, mentions) = app.format_outgoing(&room_id, text);
+                                        let _ = cmd_tx.send(MatrixCommand::SendMessage {
+                                            room_id,
+                                            body,
+                                            reply_to,
+                                            mentions,
+                                        });
+                                        app.input.clear();
+                                        app.input_cursor = 0;
+                                        app.message_selected = None;
+                                    } else {
+                                        app.restore_input(text);
+                                    }
+                                }
+                                Some(_) => app.set_status("Empty message, nothing sent"),
+                                None => app.set_status("Editor failed, draft kept"),
+                            }
+                        }
+                        KeyCode::Char('a') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            let invited = app.selected_room_is_invited();
+                            if let Some(room_id) = app.selected_room_id().filter(|_| invited) {
+                                let room_id = room_id.to_string();
+                                let _ = cmd_tx.send(MatrixCommand::AcceptInvite { room_id });
+                            }
+                        }
+                        KeyCode::Char('d') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            let invited = app.selected_room_is_invited();
+                            if let Some(room_id) = app.selected_room_id().filter(|_| invited) {
+                                let room_id = room_id.to_string();
+                                let _ = cmd_tx.send(MatrixCommand::RejectInvite { room_id });
+                            }
+                        }
+                        KeyCode::Char('y') if app.verification_emojis.is_some() => {
+                            let _ = cmd_tx.send(MatrixCommand::ConfirmVerification);
+                            app.show_verification_status("Verification confirmed.");
+                        }
+                        KeyCode::Char('n') if app.verification_emojis.is_some() => {
+                            let _ = cmd_tx.send(MatrixCommand::CancelVerification);
+                            app.show_verification_status("Verification cancelled.");
+                        }
+                        KeyCode::Up
+                            if key
+                                .modifiers
+                                .contains(KeyModifiers::ALT | KeyModifiers::SHIFT) =>
+                        {
+                            app.select_own_message(true)
+                        }
+                        KeyCode::Down
+                            if key
+                                .modifiers
+                                .contains(KeyModifiers::ALT | KeyModifiers::SHIFT) =>
+                        {
+                            app.select_own_message(false)
+                        }
+                        KeyCode::Up if key.modifiers == KeyModifiers::SHIFT => app.move_pin(-1),
+                        KeyCode::Down if key.modifiers == KeyModifiers::SHIFT => app.move_pin(1),
+                        KeyCode::Up if key.modifiers.contains(KeyModifiers::ALT) => {
+                            app.on_message_up()
+                        }
+                        KeyCode::Down if key.modifiers.contains(KeyModifiers::ALT) => {
+                            app.on_message_down()
+                        }
+                        KeyCode::Up
+                            if key.modifiers.contains(KeyModifiers::CONTROL) && !app.read_only =>
+                        {
+                            app.recall_input(true)
+                        }
+                        KeyCode::Down
+                            if key.modifiers.contains(KeyModifiers::CONTROL) && !app.read_only =>
+                        {
+                            app.recall_input(false)
+                        }
+                        KeyCode::Up => app.on_up(),
+                        KeyCode::Down => app.on_down(),
+                        KeyCode::PageUp => app.scroll_messages_page(true),
+                        KeyCode::PageDown => app.scroll_messages_page(false),
+                        KeyCode::Enter if app.read_only => {
+                            if let Some(path) = app.selected_attachment_path() {
+                                open_attachment(&mut app, &cmd_tx, &path);
+                            } else {
+                                app.on_open_url();
+                            }
+                        }
+                        KeyCode::Enter => {
+                            if app.input_multiline {
+                                app.input_insert_char('\n');
+                            } else if app.input.trim().is_empty() {
+                                if let Some(path) = app.selected_attachment_path() {
+                                    open_attachment(&mut app, &cmd_tx, &path);
+                                } else {
+                                    app.on_open_url();
+                                }
+                            } else if let Some(path) = parse_file_input(&app.input) {
+                                if Path::new(&path).is_file() {
+                                    if let Some(room_id) = app.compose_room_id() {
+                                        if app.compose_room_is_invited() {
+                                            continue;
+                                        }
+                                        let reply_to = app.take_reply_to(&room_id);
+                                        let _ = cmd_tx.send(MatrixCommand::SendAttachment {
+                                            room_id,
+                                            path,
+                                            reply_to,
+                                            temporary: false,
+                                        });
+                                        app.input.clear();
+                                        app.input_cursor = 0;
+                                        app.message_selected = None;
+                                    }
+                                }
+                            } else if let Some(text) = app.on_enter() {
+                                let text = app.expand_snippets(text);
+                                let room_id = app.compose_room_id();
+                                if let Some(cmd) = app.take_edit(&text) {
+                                    let _ = cmd_tx.send(cmd);
+                                } else if let Some(query) = parse_search(&text) {
+                                    app.open_search(query);
+                                } else if let Some(parsed) = parse_key_file(&text) {
+                                    match parsed {
+                                        Ok((export, path)) => app.start_key_prompt(export, path),
+                                        Err(err) => {
+                                            app.set_status(&err);
+                                            app.restore_input(text);
+                                        }
+                                    }
+                                } else if let Some(parsed) = parse_nickname(&text) {
+                                    match parsed {
+                                        Ok((global, user_id, nickname)) => {
+                                            app.set_nickname(global, user_id, nickname)
+                                        }
+                                        Err(err) => {
+                                            app.set_status(&err);
+                                            app.restore_input(text);
+                                        }
+                                    }
+                                } else if text.trim() == "/mute-thread" {
+                                    app.toggle_thread_mute();
+                                } else if let Some(name) = parse_workspace(&text) {
+                                    app.switch_workspace(name);
+                                } else if let Some(name) = parse_template(&text) {
+                                    app.start_template(name);
+                                } else if text.trim() == "/ack" {
+                                    if let Some(cmd) = app.acknowledge_alert() {
+                                        let _ = cmd_tx.send(cmd);
+                                    }
+                                } else if let Some(parsed) = parse_vote(&text) {
+                                    match parsed {
+                                        Ok(number) => {
+                                            if let Some(cmd) = app.vote_selected_poll(number) {
+                                                let _ = cmd_tx.send(cmd);
+                                            }
+                                        }
+                                        Err(err) => {
+                                            app.set_status(&err);
+                                            app.restore_input(text);
+                                        }
+                                    }
+                                } else if text.trim() == "/schedule" {
+                                    app.open_scheduled();
+                                } else if let Some(parsed) = parse_schedule(&text) {
+                                    match parsed {
+                                        Ok((send_at, body)) => {
+                                            if let Some(cmd) = app.schedule_message(send_at, body) {
+                                                let _ = cmd_tx.send(cmd);
+                                            }
+                                        }
+                                        Err(err) => {
+                                            app.set_status(&err);
+                                            app.restore_input(text);
+                                        }
+                                    }
+                                } else if let Some(args) = parse_bridge(&text) {
+                                    if let Some(cmd) = app.bridge_command(args) {
+                                        let _ = cmd_tx.send(cmd);
+                                    }
+                                } else if let Some(parsed) = parse_format(&text) {
+                                    match parsed {
+                                        Ok(plain) => app.set_room_plain_text(plain),
+                                        Err(err) => {
+                                            app.set_status(&err);
+                                            app.restore_input(text);
+                                        }
+                                    }
+                                } else if let Some(parsed) = parse_command(&text, room_id.as_deref()) {
+                                    match parsed {
+                                        Ok(cmd) => {
+                                            if let Some(reason) = app.unsupported_reason(&cmd) {
+                                                app.set_status(reason);
+                                                app.restore_input(text);
+                                                continue;
+                                            }
+                                            if let Some(room_id) = &room_id {
+                                                app.remember_input(room_id, &text);
+                                            }
+                                            let cmd = match cmd {
+                                                MatrixCommand::LeaveRoom { room_id } => {
+                                                    app.schedule_leave(room_id)
+                                                }
+                                                cmd => Some(cmd),
+                                            };
+                                            if let Some(cmd) = cmd {
+                                                let _ = cmd_tx.send(cmd);
+                                            }
+                                        }
+                                        Err(err) => {
+                                            app.set_status(&err);
+                                            app.restore_input(text);
+                                        }
+                                    }
+                                } else if let Some(room_id) = room_id {
+                                    if app.compose_room_is_invited() {
+                                        continue;
+                                    }
+                                    app.remember_input(&room_id, &text);
+                                    if app.hold_long_message(&room_id, &text) {
+                                        continue;
+                                    }
+                                    let reply_to = app.take_reply_to(&room_id);
+                                    let (body, mentions) = app.format_outgoing(&room_id, unescape_slash(text));
+                                    let _ = cmd_tx.send(MatrixCommand::SendMessage {
+                                        room_id,
+                                        body,
+                                        reply_to,
+                                        mentions,
+                                    });
+                                    app.message_selected = None;
+                                }
+                            }
+                        }
+                        KeyCode::Tab if !app.read_only => {
+                            app.on_tab();
+                        }
+                        KeyCode::Char('y') if key.modifiers.contains(KeyModifiers::ALT) => {
+                            app.on_copy_message();
+                        }
+                        KeyCode::Backspace => {
+                            app.input_backspace();
+                        }
+                        KeyCode::Delete => {
+                            app.input_delete();
+                        }
+                        KeyCode::Home => {
+                            app.input_move_home();
+                        }
+                        KeyCode::End => {
+                            app.input_move_end();
+                        }
+                        KeyCode::Left
+                            if key
+                                .modifiers
+                                .intersects(KeyModifiers::ALT | KeyModifiers::CONTROL) =>
+                        {
+                            app.input_move_word_left();
+                        }
+                        KeyCode::Right
+                            if key
+                                .modifiers
+                                .intersects(KeyModifiers::ALT | KeyModifiers::CONTROL) =>
+                        {
+                            app.input_move_word_right();
+                        }
+                        KeyCode::Left => {
+                            app.input_move_left();
+                        }
+                        KeyCode::Right => {
+                            app.input_move_right();
+                        }
+                        KeyCode::Char(c @ '1'..='9')
+                            if !app.read_only && app.input.is_empty() && app.poll_selected() =>
+                        {
+                            if let Some(cmd) = app.vote_selected_poll(c as usize - '0' as usize) {
+                                let _ = cmd_tx.send(cmd);
+                            }
+                        }
+                        KeyCode::Char(c) => {
+                            if app.input_blocked(c.encode_utf8(&mut [0; 4])) {
+                                app.set_status("You can't post in this room");
+                            } else {
+                                app.input_insert_char(c);
+                            }
+                        }
+                        _ => {}
+                    }
+                    match key.code {
+                        KeyCode::Char(_) | KeyCode::Backspace | KeyCode::Delete
+                            if !app.read_only =>
+                        {
+                            if let Some(cmd) = app.update_completion() {
+                                let _ = cmd_tx.send(cmd);
+                            }
+                        }
+                        _ => app.completion = None,
+                    }
+                }
+            }
+        }
+
+        if last_tick.elapsed() >= tick_rate {
+            last_tick = Instant::now();
+            dirty |= app.refresh_spelling();
+        }
+
+        if app.should_quit {
+            if !app.read_only {
+                save_drafts(&app);
+            }
+            save_search_index(&mut app);
+            // A confirmed leave goes out now rather than being dropped.
+            if let Some(leave) = app.pending_leave.take() {
+                let _ = cmd_tx.send(MatrixCommand::LeaveRoom {
+                    room_id: leave.room_id,
+                });
+            }
+            return Ok(());
+        }
+    }
+}
+
+/// Blocks until terminal input, a Matrix event (left in `pending`) or the
+/// end of `timeout`, returning the input if that came first.
+fn wait_idle(
+    evt_rx: &mut mpsc::UnboundedReceiver<MatrixEvent>,
+    pending: &mut Option<MatrixEvent>,
+    timeout: Option<Duration>,
+) -> io::Result<Option<Event>> {
+    // Dropped before returning, which also stops its reader thread so that
+    // nothing else reads the terminal while an editor runs.
+    let mut input = EventStream::new();
+    let open = !evt_rx.is_closed();
+    let wait = async {
+        tokio::select! {
+            event = input.next() => event.transpose(),
+            evt = evt_rx.recv(), if open => {
+                *pending = evt;
+                Ok(None)
+            }
+        }
+    };
+    tokio::task::block_in_place(|| {
+        Handle::current().block_on(async {
+            match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, wait)
+                    .await
+                    .unwrap_or(Ok(None)),
+                None => wait.await,
+            }
+        })
+    })
+}
+
+/// Creates a new owner-only file `<stem>.<ext>` in the private `tmp` dir
+/// under the data dir, numbering the name when it is taken.
+fn create_private_file(stem: &str, ext: &str) -> io::Result<(PathBuf, fs::File)> {
+    use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+
+    let dir = data_dir()?.join("tmp");
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)?;
+    for n in 1..100 {
+        let name = match n {
+            1 => format!("{}.{}", stem, ext),
+            n => format!("{}-{}.{}", stem, n, ext),
+        };
+        let path = dir.join(name);
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+        {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("too many {} files in {}", stem, dir.display()),
+    ))
+}
+
+/// Writes a long message to a private file for sending as an attachment;
+/// the file is removed once the upload is done.
+fn write_paste_file(text: &str) -> io::Result<PathBuf> {
+    let stamp = Local::now().format("%Y%m%d-%H%M%S");
+    let (path, mut file) = create_private_file(&format!("marty-paste-{}", stamp), "txt")?;
+    if let Err(err) = file.write_all(text.as_bytes()) {
+        let _ = fs::remove_file(&path);
+        return Err(err);
+    }
+    Ok(path)
+}
+
+/// Suspends the TUI and edits `draft` in `$VISUAL`/`$EDITOR` (default `vi`).
+/// Returns the saved text, or `None` if the editor could not run or failed.
+fn compose_in_editor(
+    terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
+    draft: &str,
+) -> io::Result<Option<String>> {
+    let (path, mut file) =
+        create_private_file(&format!("marty-draft-{}", std::process::id()), "txt")?;
+    let written = file.write_all(draft.as_bytes());
+    drop(file);
+    if let Err(err) = written {
+        let _ = fs::remove_file(&path);
+        return Err(err);
+    }
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or("vi");
+
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    let status = Command::new(program).args(words).arg(&path).status();
+    enable_raw_mode()?;
+    execute!(terminal.backend_mut(), EnterAlternateScreen, EnableBracketedPaste)?;
+    terminal.clear()?;
+
+    let text = fs::read_to_string(&path).ok();
+    let _ = fs::remove_file(&path);
+    Ok(text.filter(|_| status.is_ok_and(|status| status.success())))
+}
+
+/// The input split into lines, with misspelled words underlined while the
+/// spell check result is current; a dimmed hint when it is empty.
+fn input_lines(app: &App) -> Vec<Line<'static>> {
+    if app.input.is_empty() {
+        let hint = Style::default().fg(NOTICE_FG).add_modifier(Modifier::ITALIC);
+        return vec![Line::from(Span::styled(app.input_placeholder(), hint))];
+    }
+    let current = app.input == app.spell_checked;
+    let misspelled = Style::default()
+        .fg(Color::Red)
+        .add_modifier(Modifier::UNDERLINED);
+    let mut words = app.misspellings.iter().filter(|_| current).peekable();
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    for line in app.input.split('\n') {
+        let len = line.chars().count();
+        let mut spans = Vec::new();
+        let mut pos = 0;
+        while let Some(word) = words.next_if(|word| word.start < offset + len) {
+            let start = word.start - offset;
+            let end = (word.end() - offset).min(len);
+            spans.push(Span::raw(line.chars().skip(pos).take(start - pos).collect::<String>()));
+            spans.push(Span::styled(
+                line.chars().skip(start).take(end - start).collect::<String>(),
+                misspelled,
+            ));
+            pos = end;
+        }
+        spans.push(Span::raw(line.chars().skip(pos).collect::<String>()));
+        lines.push(Line::from(spans));
+        offset += len + 1;
+    }
+    lines
+}
+
+fn render_reply_banner(f: &mut ratatui::Frame, area: Rect, target: &ReplyTarget) {
+    let first_line = target.text.lines().next().unwrap_or_default();
+    let banner = Line::from(vec![
+        Span::styled(" ↳ Replying to ", Style::default().fg(NOTICE_FG)),
+        Span::styled(
+            target.sender.as_str(),
+            Style::default().add_modifier(Modifier::BOLD),
+        ),
+        Span::styled(format!(": {}", first_line), Style::default().fg(NOTICE_FG)),
+    ]);
+    f.render_widget(Paragraph::new(banner), area);
+}
+
+fn render_prompt(f: &mut ratatui::Frame, area: Rect, prompt: &PromptState, has_paster: bool) {
+    let popup = centered_rect(60, 3, area);
+    f.render_widget(Clear, popup);
+    let title = match (&prompt.mode, &prompt.error) {
+        (PromptMode::Add, Some(error)) => format!("Add chat — {}", error),
+        (PromptMode::Add, None) => "Add chat (@user or #room, Tab completes)".to_string(),
+        (
+            PromptMode::Delete {
+                room_name, details, ..
+            },
+            _,
+        ) => format!("Delete chat \"{}\" ({})? (y/n)", room_name, details),
+        (PromptMode::Redact { .. }, _) => {
+            "Delete this message? (y, or type a reason; n/Esc cancels)".to_string()
+        }
+        (PromptMode::KeyPassphrase { room_id: Some(_), .. }, _) => {
+            "Passphrase for the key export".to_string()
+        }
+        (PromptMode::KeyPassphrase { room_id: None, .. }, _) => {
+            "Passphrase of the key file".to_string()
+        }
+        (PromptMode::LongMessage { text, .. }, _) => format!(
+            "{} lines: f sends a file, {}s sends as is (Esc edits)",
+            text.lines().count(),
+            if has_paster { "p a paste link, " } else { "" }
+        ),
+        (PromptMode::React { .. }, Some(error)) => format!("React — {}", error),
+        (PromptMode::React { .. }, None) => {
+            "React (emoji or :shortcode:, Up/Down picks, Enter sends)".to_string()
+        }
+        (
+            PromptMode::TemplateField {
+                name,
+                fields,
+                values,
+                ..
+            },
+            _,
+        ) => format!(
+            "{} — {} ({}/{})",
+            name,
+            fields[values.len()],
+            values.len() + 1,
+            fields.len()
+        ),
+    };
+    let title_style = if prompt.error.is_some() {
+        Style::default().fg(Color::Red)
+    } else {
+        Style::default()
+    };
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(Span::styled(title, title_style));
+    f.render_widget(&block, popup);
+    let inner = block.inner(popup);
+    let shown = match prompt.mode {
+        PromptMode::KeyPassphrase { .. } => "*".repeat(prompt.input.chars().count()),
+        _ => prompt.input.clone(),
+    };
+    let text = Paragraph::new(shown.as_str());
+    f.render_widget(text, inner);
+    let x = inner.x + (shown.width().min(inner.width as usize) as u16);
+    f.set_cursor(x, inner.y);
+
+    if prompt.suggestions.is_empty() {
+        return;
+    }
+    let height = (prompt.suggestions.len() as u16 + 2).min(area.bottom().saturating_sub(popup.bottom()));
+    if height < 3 {
+        return;
+    }
+    let list_area = Rect::new(popup.x, popup.bottom(), popup.width, height);
+    let items: Vec<ListItem> = prompt
+        .suggestions
+        .iter()
+        .map(|suggestion| ListItem::new(suggestion.label.as_str()))
+        .collect();
+    let mut state = ListState::default();
+    state.select(Some(prompt.suggestion));
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL))
+        .highlight_style(Style::default().bg(SELECTED_BG).fg(Color::Black));
+    f.render_widget(Clear, list_area);
+    f.render_stateful_widget(list, list_area, &mut state);
+}
+
+fn render_verification_overlay(f: &mut ratatui::Frame, area: Rect, app: &App) {
+    let popup = centered_rect(70, 7, area);
+    let block = Block::default().borders(Borders::ALL).title("Verification");
+    f.render_widget(&block, popup);
+    let inner = block.inner(popup);
+    let mut lines = Vec::new();
+    if let Some(ref emojis) = app.verification_emojis {
+        let symbols = emojis
+            .iter()
+            .map(|(symbol, _)| format!("{:^6}", symbol))
+            .collect::<Vec<_>>()
+            .join("");
+        let labels = emojis
+            .iter()
+            .map(|(_, desc)| format!("{:^6}", desc))
+            .collect::<Vec<_>>()
+            .join("");
+        lines.push(Line::from(symbols));
+        lines.push(Line::from(labels));
+    }
+    if let Some(ref status) = app.verification_status {
+        lines.push(Line::from(status.as_str()));
+    }
+    let content = Paragraph::new(lines).wrap(Wrap { trim: false });
+    f.render_widget(content, inner);
+}
+
+fn render_server_notice(f: &mut ratatui::Frame, area: Rect, app: &App) {
+    let Some(notice) = app.server_notices.first() else {
+        return;
+    };
+    let mut title = format!(
+        "Server notice {} — {}",
+        notice.time,
+        app.room_name(&notice.room_id)
+    );
+    if app.server_notices.len() > 1 {
+        title.push_str(&format!(" (1/{})", app.server_notices.len()));
+    }
+    title.push_str(" · Ctrl+N to acknowledge");
+    let style = Style::default()
+        .bg(NOTICE_BG)
+        .fg(Color::White)
+        .add_modifier(Modifier::BOLD);
+    let text = notice.text.replace('\n', " ");
+    let banner = Paragraph::new(text)
+        .style(style)
+        .block(Block::default().borders(Borders::ALL).title(title));
+    f.render_widget(banner, area);
+}
+
+fn render_state_view(f: &mut ratatui::Frame, area: Rect, app: &App, view: &StateView) {
+    f.render_widget(Clear, area);
+    let mut lines = Vec::new();
+    let count = match &view.entries {
+        None => {
+            lines.push(Line::from("Loading room state..."));
+            0
+        }
+        Some(entries) => {
+            for entry in entries {
+                let mut header = entry.event_type.clone();
+                if !entry.state_key.is_empty() {
+                    header.push_str(&format!(" [{}]", entry.state_key));
+                }
+                lines.push(Line::from(Span::styled(
+                    header,
+                    Style::default()
+                        .fg(Color::Rgb(140, 200, 220))
+                        .add_modifier(Modifier::BOLD),
+                )));
+                for content_line in entry.content.lines() {
+                    lines.push(Line::from(format!("  {}", content_line)));
+                }
+            }
+            entries.len()
+        }
+    };
+    let title = format!(
+        "Room state — {} ({} events) · Esc to close",
+        app.room_name(&view.room_id),
+        count
+    );
+    let panel = Paragraph::new(lines)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .scroll((view.scroll, 0));
+    f.render_widget(panel, area);
+}
+
+/// The channel list, with unread counts and the server in the border.
+pub fn render_channels(f: &mut ratatui::Frame, area: Rect, app: &App) {
+    let channels: Vec<ListItem> = app
+        .rooms
+        .iter()
+        .enumerate()
+        .map(|(idx, room)| {
+            let label = if room.state == RoomListState::Invited {
+                Cow::Owned(format!("[invite] {}", app.room_label(room)))
+            } else {
+                app.room_label(room)
+            };
+            let label = if app.is_pinned(&room.room_id) {
+                Cow::Owned(format!("📌 {}", label))
+            } else {
+                label
+            };
+            let label = match (app.room_numbers, idx) {
+                (true, 0..=8) => Cow::Owned(format!("{} {}", idx + 1, label)),
+                (true, _) => Cow::Owned(format!("  {}", label)),
+                (false, _) => label,
+            };
+            let unread = *app.unread_counts.get(&room.room_id).unwrap_or(&0);
+            let display = if unread > 0 {
+                Cow::Owned(format!("{} [{}]", label, unread))
+            } else {
+                label
+            };
+            let style = if unread > 0 {
+                Style::default().add_modifier(Modifier::BOLD)
+            } else {
+                Style::default()
+            };
+            ListItem::new(Line::from(Span::styled(display, style)))
+        })
+        .collect();
+
+    let mut list_state = ListState::default();
+    if !app.rooms.is_empty() {
+        list_state.select(Some(app.selected));
+    }
+
+    let channels_title = match &app.workspace {
+        Some(name) => format!("Channels — {}", name),
+        None => "Channels".to_string(),
+    };
+    let mut channels_block = Block::default().borders(Borders::ALL).title(channels_title);
+    if let Some(info) = &app.server_info {
+        let server = match &info.latest_version {
+            Some(version) => format!("{} · {}", info.server_name, version),
+            None => info.server_name.clone(),
+        };
+        channels_block =
+            channels_block.title_bottom(Span::styled(server, Style::default().fg(NOTICE_FG)));
+    }
+    let channels_list = List::new(channels).block(channels_block).highlight_style(
+        Style::default()
+            .bg(SELECTED_BG)
+            .fg(Color::Black)
+            .add_modifier(Modifier::BOLD),
+    );
+
+    f.render_stateful_widget(channels_list, area, &mut list_state);
+}
+
+/// weechat-style activity bar: `Act: 3:#rust(2!) 7:alice(1)`, by channel
+/// number, with rooms that mention us or DMs highlighted.
+fn render_hotlist(f: &mut ratatui::Frame, area: Rect, app: &App) {
+    let mut spans = vec![Span::styled("Act:", Style::default().fg(NOTICE_FG))];
+    for (idx, room) in app.rooms.iter().enumerate() {
+        let unread = *app.unread_counts.get(&room.room_id).unwrap_or(&0);
+        if unread == 0 {
+            continue;
+        }
+        let highlights = *app.highlight_counts.get(&room.room_id).unwrap_or(&0);
+        let (count, style) = if highlights > 0 {
+            (
+                format!("{}!", highlights),
+                Style::default()
+                    .fg(Color::Yellow)
+                    .add_modifier(Modifier::BOLD),
+            )
+        } else {
+            (unread.to_string(), Style::default())
+        };
+        spans.push(Span::raw(" "));
+        spans.push(Span::styled(
+            format!("{}:{}({})", idx + 1, app.room_label(room), count),
+            style,
+        ));
+    }
+    f.render_widget(Paragraph::new(Line::from(spans)), area);
+}
+
+/// Draws the autocomplete list just above the input box, starting at
+/// column `anchor_x`.
+fn render_completion(f: &mut ratatui::Frame, input_area: Rect, anchor_x: u16, completion: &Completion) {
+    let width = completion
+        .items
+        .iter()
+        .map(|item| item.label.width() as u16 + 2)
+        .max()
+        .unwrap_or(0)
+        .min(input_area.width);
+    let height = (completion.items.len() as u16 + 2).min(input_area.y);
+    if height < 3 {
+        return;
+    }
+    let x = anchor_x.min(input_area.x + input_area.width - width);
+    let popup = Rect::new(x, input_area.y - height, width, height);
+    let items: Vec<ListItem> = completion
+        .items
+        .iter()
+        .map(|item| ListItem::new(item.label.as_str()))
+        .collect();
+    let mut state = ListState::default();
+    state.select(Some(completion.selected));
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL))
+        .highlight_style(Style::default().bg(SELECTED_BG).fg(Color::Black));
+    f.render_widget(Clear, popup);
+    f.render_stateful_widget(list, popup, &mut state);
+}
+
+fn render_message_info(f: &mut ratatui::Frame, area: Rect, view: &MessageInfoView) {
+    let label_style = Style::default().fg(Color::Rgb(140, 200, 220));
+    let mut lines: Vec<Line> = Vec::new();
+    let remote = view.remote.as_deref().unwrap_or_default();
+    for (label, value) in view.local.iter().chain(remote) {
+        lines.push(Line::from(vec![
+            Span::styled(format!("{:<14}", label), label_style),
+            Span::raw(value.clone()),
+        ]));
+    }
+    if view.remote.is_none() && view.event_id.is_some() {
+        lines.push(Line::from(Span::styled(
+            "Loading encryption info...",
+            Style::default().fg(Color::DarkGray),
+        )));
+    }
+    let height = (lines.len() as u16 + 2).min(area.height);
+    let popup = centered_rect(80, height, area);
+    f.render_widget(Clear, popup);
+    let panel = Paragraph::new(lines)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!("{} · Esc to close", view.title)),
+        )
+        .wrap(Wrap { trim: false });
+    f.render_widget(panel, popup);
+}
+
+fn render_reply_chain(f: &mut ratatui::Frame, area: Rect, view: &ReplyChainView) {
+    let height = (view.entries.len() as u16 + 2).clamp(3, area.height.saturating_sub(2));
+    let popup = centered_rect(80, height, area);
+    f.render_widget(Clear, popup);
+    let lines: Vec<Line> = view
+        .entries
+        .iter()
+        .map(|entry| {
+            let style = if entry.focused {
+                Style::default().bg(SELECTED_BG).fg(Color::Black)
+            } else {
+                Style::default()
+            };
+            Line::from(vec![
+                Span::styled(format!("{}{} ", "  ".repeat(entry.depth), entry.time), style),
+                Span::styled(
+                    format!("{}: ", entry.sender),
+                    style.add_modifier(Modifier::BOLD),
+                ),
+                Span::styled(entry.text.replace('\n', " "), style),
+            ])
+        })
+        .collect();
+    let panel = Paragraph::new(lines)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!("{} · Esc to close", view.title)),
+        )
+        .scroll((view.scroll, 0));
+    f.render_widget(panel, popup);
+}
+
+fn render_search_view(f: &mut ratatui::Frame, area: Rect, app: &App, view: &SearchView) {
+    f.render_widget(Clear, area);
+    let mut lines = Vec::new();
+    if view.hits.is_empty() {
+        lines.push(Line::from("No matches."));
+    }
+    for hit in &view.hits {
+        lines.push(Line::from(vec![
+            Span::styled(
+                format!("{} ", hit.time),
+                Style::default().fg(Color::DarkGray),
+            ),
+            Span::styled(
+                format!("[{}] ", app.room_name(&hit.room_id)),
+                Style::default().fg(Color::Rgb(140, 200, 220)),
+            ),
+            Span::styled(
+                format!("{}: ", hit.sender),
+                Style::default().add_modifier(Modifier::BOLD),
+            ),
+            Span::raw(hit.text.replace('\n', " ")),
+        ]));
+    }
+    let title = format!(
+        "Search \"{}\" ({} results) · Esc to close",
+        view.query,
+        view.hits.len()
+    );
+    let panel = Paragraph::new(lines)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .scroll((view.scroll, 0));
+    f.render_widget(panel, area);
+}
+
+fn render_scheduled_view(f: &mut ratatui::Frame, area: Rect, app: &App, view: &ScheduledView) {
+    let now = Local::now().timestamp_millis();
+    let mut lines = Vec::new();
+    if app.scheduled.is_empty() {
+        lines.push(Line::from("Nothing scheduled."));
+    }
+    for (idx, msg) in app.scheduled.iter().enumerate() {
+        let style = if idx == view.selected {
+            Style::default().bg(SELECTED_BG).fg(Color::Black)
+        } else {
+            Style::default()
+        };
+        let mut spans = vec![
+            Span::styled(
+                format!("{} {} ", format_date(msg.send_at), format_timestamp(msg.send_at)),
+                style.fg(Color::DarkGray),
+            ),
+            Span::styled(
+                format!("[{}] ", app.room_name(&msg.room_id)),
+                style.fg(Color::Rgb(140, 200, 220)),
+            ),
+        ];
+        if now - msg.send_at > SCHEDULE_GRACE_MS {
+            spans.push(Span::styled("(missed) ", style.fg(Color::Red)));
+        }
+        spans.push(Span::styled(msg.body.replace('\n', " "), style));
+        lines.push(Line::from(spans));
+    }
+    let height = (lines.len() as u16 + 2).clamp(3, area.height.saturating_sub(2));
+    let popup = centered_rect(80, height, area);
+    f.render_widget(Clear, popup);
+    let scroll = (view.selected as u16).saturating_sub(height.saturating_sub(3));
+    let panel = Paragraph::new(lines)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("Scheduled · s send now · d cancel · Esc to close"),
+        )
+        .scroll((scroll, 0));
+    f.render_widget(panel, popup);
+}
+
+fn render_sync_indicator(f: &mut ratatui::Frame, area: Rect) {
+    let width = 18;
+    let height = 3;
+    let x = area.x + area.width.saturating_sub(width) - 1;
+    let y = area.y + 1;
+    let rect = Rect { x, y, width, height };
+    let block = Block::default().borders(Borders::ALL).title("Sync");
+    f.render_widget(&block, rect);
+    let inner = block.inner(rect);
+    let text = Paragraph::new("Syncing...");
+    f.render_widget(text, inner);
+}
+
+fn severity_color(severity: Severity) -> Color {
+    match severity {
+        Severity::Critical => Color::Rgb(230, 80, 70),
+        Severity::Warning => Color::Rgb(220, 180, 60),
+        Severity::Resolved => Color::Rgb(100, 190, 110),
+    }
+}
+
+fn centered_rect(percent_x: u16, height: u16, area: Rect) -> Rect {
+    let width = area.width.saturating_mul(percent_x) / 100;
+    let x = area.x + (area.width.saturating_sub(width) / 2);
+    let y = area.y + (area.height.saturating_sub(height) / 2);
+    Rect { x, y, width, height }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noon() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 3, 12, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn schedule_time_delays() {
+        let now = noon();
+        let at = |minutes| (now + chrono::Duration::minutes(minutes)).timestamp_millis();
+        assert_eq!(schedule_time("+5m", now), Some(at(5)));
+        assert_eq!(schedule_time("+2h", now), Some(at(120)));
+        assert_eq!(schedule_time("+1d", now), Some(at(24 * 60)));
+        assert_eq!(schedule_time("+5s", now), None);
+        assert_eq!(schedule_time("+m", now), None);
+    }
+
+    #[test]
+    fn schedule_time_of_day() {
+        let now = noon();
+        let today = Local.with_ymd_and_hms(2024, 3, 12, 18, 30, 0).unwrap();
+        let tomorrow = Local.with_ymd_and_hms(2024, 3, 13, 9, 15, 0).unwrap();
+        assert_eq!(schedule_time("18:30", now), Some(today.timestamp_millis()));
+        assert_eq!(
+            schedule_time("09:15", now),
+            Some(tomorrow.timestamp_millis())
+        );
+        assert_eq!(schedule_time("25:00", now), None);
+    }
+
+    #[test]
+    fn split_exec_keeps_escaped_semicolons() {
+        assert_eq!(
+            split_exec("/join #a:example.org; /msg @b:example.org hi\\; there;;"),
+            ["/join #a:example.org", "/msg @b:example.org hi; there"]
+        );
+    }
+
+    #[test]
+    fn help_follows_the_jump_modifier_and_finds_rotate_key() {
+        let mut app = App::new();
+        app.room_jump_modifier = JumpModifier::Ctrl;
+        let lines = app.help_lines();
+        assert!(lines.contains(&HELP_JUMP_CTRL) && !lines.contains(&HELP_JUMP_ALT));
+        app.room_jump_modifier = JumpModifier::Off;
+        assert!(!app.help_lines().iter().any(|line| line.contains("1..9")));
+        app.help_filter = "rotate".to_string();
+        assert!(app.help_lines().iter().any(|line| line.contains("/rotate-key")));
+    }
+
+    #[test]
+    fn parse_vote_takes_an_answer_number() {
+        assert_eq!(parse_vote("/vote 2"), Some(Ok(2)));
+        assert!(matches!(parse_vote("/vote"), Some(Err(_))));
+        assert!(matches!(parse_vote("/vote 0"), Some(Err(_))));
+        assert_eq!(parse_vote("/voter"), None);
+        assert_eq!(parse_vote("1 more thing"), None);
+    }
+
+    fn joined_room(room_id: &str) -> RoomInfo {
+        RoomInfo {
+            room_id: room_id.to_string(),
+            name: room_id.to_string(),
+            state: RoomListState::Joined,
+            inviter: None,
+            alias: None,
+            direct_user: None,
+            topic: None,
+            send_restricted: false,
+            can_invite: true,
+            can_set_topic: true,
+            can_redact_others: false,
+            member_count: 2,
+        }
+    }
+
+    #[test]
+    fn locked_schedule_queues_for_the_locked_room() {
+        let mut app = App::new();
+        app.rooms.push(joined_room("!a:example.org"));
+        app.rooms.push(joined_room("!b:example.org"));
+        app.select_room("!a:example.org");
+        app.toggle_compose_lock();
+        app.select_room("!b:example.org");
+        let send_at = noon().timestamp_millis();
+        app.schedule_message(send_at, "standup in 5".to_string());
+        assert_eq!(app.scheduled.len(), 1);
+        assert_eq!(app.scheduled[0].room_id, "!a:example.org");
+    }
+
+    #[test]
+    fn schedule_time_rejects_overflow_and_negative_delays() {
+        let now = noon();
+        assert_eq!(schedule_time("+100000000d", now), None);
+        assert_eq!(schedule_time("+99999999999999999999m", now), None);
+        assert_eq!(schedule_time("+-5m", now), None);
+        assert_eq!(schedule_time("+0m", now), None);
+        assert!(matches!(
+            parse_schedule("/schedule +100000000d hi"),
+            Some(Err(_))
+        ));
+    }
+
+    /// Man, ZWJ, woman, ZWJ, girl: five chars drawn as one glyph.
+    const FAMILY: &str = "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}";
+
+    #[test]
+    fn cursor_steps_over_whole_grapheme_clusters() {
+        let combining = "e\u{301}x";
+        assert_eq!(App::next_grapheme(combining, 0), 2);
+        assert_eq!(App::prev_grapheme(combining, 2), 0);
+        assert_eq!(App::next_grapheme(combining, 2), 3);
+
+        let input = format!("a{}b", FAMILY);
+        assert_eq!(App::next_grapheme(&input, 1), 6);
+        assert_eq!(App::prev_grapheme(&input, 6), 1);
+        assert_eq!(App::prev_grapheme(&input, 7), 6);
+
+        assert_eq!(App::next_grapheme("日本", 0), 1);
+        assert_eq!(App::prev_grapheme("日本", 2), 1);
+        assert_eq!(App::next_grapheme("日本", 2), 2);
+        assert_eq!(App::prev_grapheme("日本", 0), 0);
+    }
+
+    #[test]
+    fn cursor_position_counts_display_columns() {
+        assert_eq!(cursor_position("日本語", 2, 10), (0, 4));
+        assert_eq!(cursor_position("日本語", 3, 4), (1, 2));
+        assert_eq!(cursor_position("e\u{301}e\u{301}", 4, 10), (0, 2));
+        let input = format!("{}!", FAMILY);
+        assert_eq!(cursor_position(&input, 5, 10), (0, FAMILY.width() as u16));
+        assert_eq!(cursor_position("ab\n日", 4, 10), (1, 2));
+    }
+
+    #[test]
+    fn wrap_never_splits_wide_or_joined_glyphs() {
+        assert_eq!(wrap_text_lines("日本語", 5), ["日本", "語"]);
+        assert_eq!(wrap_text_lines("ab 日本", 5), ["ab ", "日本"]);
+        assert_eq!(
+            wrap_text_lines("e\u{301}e\u{301}e\u{301}", 2),
+            ["e\u{301}e\u{301}", "e\u{301}"]
+        );
+        let family = FAMILY.repeat(3);
+        let lines = wrap_text_lines(&family, FAMILY.width() as u16);
+        assert_eq!(lines, [FAMILY, FAMILY, FAMILY]);
+        for line in wrap_text_lines("日本語 e\u{301}t\u{e9} 中文", 3) {
+            assert!(line.width() <= 3, "{:?}", line);
+        }
+    }
+}